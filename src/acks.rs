@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+// Tracks the PUSH_DATA tokens sent to a server within the current stats window
+// and the PUSH_ACKs received for them, so that the acknowledgement ratio is
+// also correct when multiple PUSH_DATA datagrams are in-flight. The send time
+// of each token is kept to measure the PUSH_ACK latency.
+#[derive(Default)]
+pub struct AckTracker {
+    pending: HashMap<u16, SystemTime>,
+    sent: u32,
+    acked: u32,
+}
+
+impl AckTracker {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn sent(&mut self, token: u16, now: SystemTime) {
+        self.pending.insert(token, now);
+        self.sent += 1;
+    }
+
+    // Returns the latency when the token belongs to a PUSH_DATA awaiting its
+    // PUSH_ACK.
+    pub fn acked(&mut self, token: u16, now: SystemTime) -> Option<Duration> {
+        let sent_at = self.pending.remove(&token)?;
+        self.acked += 1;
+        Some(now.duration_since(sent_at).unwrap_or_default())
+    }
+
+    // Returns the number of PUSH_DATA awaiting their PUSH_ACK.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    // Returns the percentage of acknowledged PUSH_DATA datagrams and starts
+    // a new window.
+    pub fn get_and_reset_ackr(&mut self) -> f32 {
+        let ackr = match self.sent {
+            0 => 0.0,
+            _ => self.acked as f32 / self.sent as f32 * 100.0,
+        };
+
+        self.pending.clear();
+        self.sent = 0;
+        self.acked = 0;
+
+        ackr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ack_tracker() {
+        let mut t = AckTracker::new();
+        let now = SystemTime::now();
+        assert_eq!(t.get_and_reset_ackr(), 0.0);
+
+        t.sent(1, now);
+        t.sent(2, now);
+        t.sent(3, now);
+        t.sent(4, now);
+
+        assert_eq!(
+            t.acked(2, now + Duration::from_millis(20)),
+            Some(Duration::from_millis(20))
+        );
+        assert!(t.acked(1, now).is_some());
+        assert!(t.acked(1, now).is_none());
+        assert!(t.acked(5, now).is_none());
+        assert_eq!(t.get_and_reset_ackr(), 50.0);
+
+        // pending tokens do not carry over to the next window
+        assert!(t.acked(3, now).is_none());
+        assert_eq!(t.get_and_reset_ackr(), 0.0);
+    }
+}
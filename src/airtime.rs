@@ -0,0 +1,120 @@
+use std::time::Duration;
+
+use chirpstack_api::gw;
+
+// Default LoRa preamble length (symbols).
+const LORA_PREAMBLE: u32 = 8;
+
+// FSK preamble, sync word, length and CRC size (bytes), as used by the
+// Semtech packet-forwarder.
+const FSK_PREAMBLE: usize = 5;
+const FSK_SYNC_WORD: usize = 3;
+const FSK_LENGTH: usize = 1;
+const FSK_CRC: usize = 2;
+
+// Returns the LoRa time-on-air (Semtech AN1200.13). The code rate is given as
+// 1 - 4 for 4/5 - 4/8.
+pub fn lora(
+    sf: u32,
+    bw_hz: u32,
+    cr: u32,
+    payload_len: usize,
+    preamble: u32,
+    crc: bool,
+    implicit_header: bool,
+) -> Duration {
+    if bw_hz == 0 || sf == 0 {
+        return Duration::default();
+    }
+
+    let t_sym = (1u64 << sf) as f64 / bw_hz as f64;
+    // Low data-rate optimization.
+    let de = if sf >= 11 && bw_hz == 125000 { 1 } else { 0 };
+    let h = if implicit_header { 1 } else { 0 };
+    let crc = if crc { 1 } else { 0 };
+
+    let n = (8 * payload_len as i64 - 4 * sf as i64 + 28 + 16 * crc - 20 * h) as f64
+        / (4 * (sf as i64 - 2 * de)) as f64;
+    let payload_symbols = 8.0 + (n.ceil() * (cr + 4) as f64).max(0.0);
+    let preamble_symbols = preamble as f64 + 4.25;
+
+    Duration::from_secs_f64((preamble_symbols + payload_symbols) * t_sym)
+}
+
+// Returns the FSK time-on-air.
+pub fn fsk(bitrate: u32, payload_len: usize) -> Duration {
+    if bitrate == 0 {
+        return Duration::default();
+    }
+
+    let bytes = FSK_PREAMBLE + FSK_SYNC_WORD + FSK_LENGTH + payload_len + FSK_CRC;
+    Duration::from_secs_f64((bytes * 8) as f64 / bitrate as f64)
+}
+
+// Returns the time-on-air of the downlink item, None for unsupported
+// modulations.
+pub fn downlink(tx_info: &gw::DownlinkTxInfo, payload_len: usize) -> Option<Duration> {
+    // Downlinks are sent without CRC.
+    modulation(tx_info.modulation.as_ref()?, payload_len, false)
+}
+
+// Returns the time-on-air of the uplink, None for unsupported modulations.
+pub fn uplink(tx_info: &gw::UplinkTxInfo, payload_len: usize) -> Option<Duration> {
+    modulation(tx_info.modulation.as_ref()?, payload_len, true)
+}
+
+// Returns the data-rate label of the modulation, e.g. SF7BW125 or FSK50000.
+pub fn label(modulation: &gw::Modulation) -> String {
+    match &modulation.parameters {
+        Some(gw::modulation::Parameters::Lora(v)) => {
+            format!("SF{}BW{}", v.spreading_factor, v.bandwidth / 1000)
+        }
+        Some(gw::modulation::Parameters::Fsk(v)) => format!("FSK{}", v.datarate),
+        Some(gw::modulation::Parameters::LrFhss(_)) => "LR-FHSS".into(),
+        None => "UNKNOWN".into(),
+    }
+}
+
+fn modulation(modulation: &gw::Modulation, payload_len: usize, crc: bool) -> Option<Duration> {
+    match modulation.parameters.as_ref()? {
+        gw::modulation::Parameters::Lora(v) => Some(lora(
+            v.spreading_factor,
+            v.bandwidth,
+            code_rate(v.code_rate()),
+            payload_len,
+            LORA_PREAMBLE,
+            crc,
+            false,
+        )),
+        gw::modulation::Parameters::Fsk(v) => Some(fsk(v.datarate, payload_len)),
+        gw::modulation::Parameters::LrFhss(_) => None,
+    }
+}
+
+fn code_rate(cr: gw::CodeRate) -> u32 {
+    match cr {
+        gw::CodeRate::Cr46 => 2,
+        gw::CodeRate::Cr47 => 3,
+        gw::CodeRate::Cr48 => 4,
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lora() {
+        // Reference values from the Semtech LoRa calculator.
+        let ms = |sf, bw, len, crc| lora(sf, bw, 1, len, 8, crc, false).as_micros() as f64 / 1000.0;
+
+        assert!((ms(7, 125000, 13, true) - 46.336).abs() < 0.01);
+        assert!((ms(12, 125000, 13, true) - 1155.072).abs() < 0.01);
+        assert!((ms(9, 125000, 51, false) - 328.704).abs() < 0.01);
+        assert!((ms(7, 250000, 20, true) - 28.288).abs() < 0.01);
+
+        // (5 + 3 + 1 + 10 + 2) bytes at 50 kbps
+        assert!((fsk(50000, 10).as_secs_f64() - 0.00336).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,348 @@
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Result;
+use chirpstack_api::gw;
+use prost::Message;
+use rand::Rng;
+
+use super::commands;
+use super::config::{self, BackendType, UdpForwarder};
+use super::events::{self, Event};
+use super::helpers;
+
+// Max. duration for handing a downlink to the Concentratord, when its socket
+// is (temporarily) not ready, and the interval between the attempts.
+const DOWNLINK_SEND_TIMEOUT: Duration = Duration::from_millis(50);
+const DOWNLINK_SEND_RETRY_INTERVAL: Duration = Duration::from_millis(5);
+
+// Source of the uplinks and stats and sink of the downlinks of a forwarder.
+// The UDP frontend only depends on this trait, such that other backends (e.g.
+// a mock backend for testing) can be plugged in.
+pub trait Backend: Send + Sync {
+    // Returns the next uplink or stats event, or Event::Timeout when no event
+    // was received within the timeout.
+    fn next_event(&self, timeout: Duration) -> Event;
+
+    // Sends the downlink and returns its TX ack. On error, the failure reason
+    // is returned (used as metrics label).
+    fn send_downlink(
+        &self,
+        pl: &gw::DownlinkFrame,
+    ) -> std::result::Result<gw::DownlinkTxAck, (&'static str, anyhow::Error)>;
+}
+
+// Returns the configured backend.
+pub fn new(conf: &UdpForwarder, event_url: &str, command_url: &str) -> Result<Box<dyn Backend>> {
+    Ok(match conf.backend {
+        BackendType::Concentratord => Box::new(Concentratord::new(event_url, command_url)?),
+        BackendType::Mock => Box::new(Mock::new(&conf.mock_backend)?),
+    })
+}
+
+// ChirpStack Concentratord backend, using the ZMQ event and command API.
+pub struct Concentratord {
+    event_sock: Mutex<zmq::Socket>,
+    command_sock: Mutex<zmq::Socket>,
+}
+
+impl Concentratord {
+    pub fn new(event_url: &str, command_url: &str) -> Result<Self> {
+        Ok(Concentratord {
+            event_sock: Mutex::new(events::get_socket(event_url)?),
+            command_sock: Mutex::new(commands::get_socket(command_url)?),
+        })
+    }
+}
+
+impl Backend for Concentratord {
+    fn next_event(&self, timeout: Duration) -> Event {
+        let event_sock = self.event_sock.lock().unwrap();
+        events::Reader::new(&event_sock, timeout)
+            .next()
+            .unwrap_or(Event::Timeout)
+    }
+
+    // The send is retried while the socket is not ready (EAGAIN), within the
+    // DOWNLINK_SEND_TIMEOUT.
+    fn send_downlink(
+        &self,
+        pl: &gw::DownlinkFrame,
+    ) -> std::result::Result<gw::DownlinkTxAck, (&'static str, anyhow::Error)> {
+        let mut buf = Vec::new();
+        pl.encode(&mut buf)
+            .map_err(|e| ("ENCODE_ERROR", anyhow::Error::from(e)))?;
+
+        let sock = self.command_sock.lock().unwrap();
+        let deadline = Instant::now() + DOWNLINK_SEND_TIMEOUT;
+        loop {
+            match sock.send_multipart(["down".as_bytes(), &buf], zmq::DONTWAIT) {
+                Ok(_) => break,
+                Err(zmq::Error::EAGAIN) if Instant::now() < deadline => {
+                    thread::sleep(DOWNLINK_SEND_RETRY_INTERVAL);
+                }
+                Err(e) => return Err(("SEND_ERROR", e.into())),
+            }
+        }
+
+        // set poller so that we can timeout after 100ms
+        let mut items = [sock.as_poll_item(zmq::POLLIN)];
+        if let Err(e) = zmq::poll(&mut items, 100) {
+            return Err(("RECEIVE_ERROR", e.into()));
+        }
+        if !items[0].is_readable() {
+            return Err(("TIMEOUT", anyhow!("could not read down response")));
+        }
+
+        // read tx ack response.
+        let resp_b = sock
+            .recv_bytes(0)
+            .map_err(|e| ("RECEIVE_ERROR", anyhow::Error::from(e)))?;
+        gw::DownlinkTxAck::decode(resp_b.as_slice())
+            .map_err(|e| ("DECODE_ERROR", anyhow!("decode DownlinkTxAck error: {}", e)))
+    }
+}
+
+// Backend generating synthetic uplinks and stats and acknowledging the
+// downlinks with the scripted TX ack statuses (cycled), for integration
+// testing and demos without radio hardware.
+pub struct Mock {
+    conf: config::MockBackend,
+    gateway_id: String,
+    tx_ack_statuses: Vec<gw::TxAckStatus>,
+    started: Instant,
+    state: Mutex<MockState>,
+}
+
+struct MockState {
+    next_uplink: Option<Instant>,
+    next_stats: Option<Instant>,
+    uplink_id: u32,
+    rx_received: u32,
+    tx_received: u32,
+    tx_emitted: u32,
+    tx_ack_index: usize,
+}
+
+impl Mock {
+    pub fn new(conf: &config::MockBackend) -> Result<Self> {
+        let gateway_id = hex::encode(helpers::parse_gateway_id(&conf.gateway_id)?);
+        let tx_ack_statuses = conf
+            .tx_ack_statuses
+            .iter()
+            .map(|s| {
+                gw::TxAckStatus::from_str_name(s)
+                    .ok_or_else(|| anyhow!("invalid tx_ack_status: {}", s))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if conf.spreading_factors.is_empty() || conf.frequencies.is_empty() {
+            return Err(anyhow!(
+                "spreading_factors and frequencies must not be empty"
+            ));
+        }
+
+        let now = Instant::now();
+        Ok(Mock {
+            conf: conf.clone(),
+            gateway_id,
+            tx_ack_statuses,
+            started: now,
+            state: Mutex::new(MockState {
+                next_uplink: match conf.uplink_interval_ms {
+                    0 => None,
+                    v => Some(now + Duration::from_millis(v)),
+                },
+                next_stats: match conf.stats_interval_secs {
+                    0 => None,
+                    v => Some(now + Duration::from_secs(v)),
+                },
+                uplink_id: 0,
+                rx_received: 0,
+                tx_received: 0,
+                tx_emitted: 0,
+                tx_ack_index: 0,
+            }),
+        })
+    }
+
+    fn uplink(&self, state: &mut MockState) -> gw::UplinkFrame {
+        let mut rng = rand::thread_rng();
+        state.uplink_id += 1;
+        state.rx_received += 1;
+
+        // UnconfirmedDataUp with random DevAddr, FCnt, FRMPayload and MIC,
+        // the PHYPayload is at least MHDR + FHDR + FPort + MIC.
+        let min_size = self.conf.min_payload_size.max(13);
+        let size = rng.gen_range(min_size..=self.conf.max_payload_size.max(min_size));
+        let mut phy_payload: Vec<u8> = (0..size).map(|_| rng.gen()).collect();
+        phy_payload[0] = 0x40;
+        phy_payload[5] = 0x00;
+
+        let channel = rng.gen_range(0..self.conf.frequencies.len());
+        let spreading_factors = &self.conf.spreading_factors;
+
+        gw::UplinkFrame {
+            phy_payload,
+            tx_info: Some(gw::UplinkTxInfo {
+                frequency: self.conf.frequencies[channel],
+                modulation: Some(gw::Modulation {
+                    parameters: Some(gw::modulation::Parameters::Lora(gw::LoraModulationInfo {
+                        bandwidth: 125000,
+                        spreading_factor: spreading_factors
+                            [rng.gen_range(0..spreading_factors.len())],
+                        code_rate: gw::CodeRate::Cr45.into(),
+                        ..Default::default()
+                    })),
+                }),
+            }),
+            rx_info: Some(gw::UplinkRxInfo {
+                gateway_id: self.gateway_id.clone(),
+                uplink_id: state.uplink_id,
+                time: Some(SystemTime::now().into()),
+                rssi: rng.gen_range(-120..-40),
+                snr: rng.gen_range(-150..100) as f32 / 10.0,
+                channel: channel as u32,
+                crc_status: gw::CrcStatus::CrcOk.into(),
+                // The concentrator counter (tmst), in microseconds.
+                context: (self.started.elapsed().as_micros() as u32)
+                    .to_be_bytes()
+                    .to_vec(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn stats(&self, state: &mut MockState) -> gw::GatewayStats {
+        let stats = gw::GatewayStats {
+            gateway_id: self.gateway_id.clone(),
+            time: Some(SystemTime::now().into()),
+            rx_packets_received: state.rx_received,
+            rx_packets_received_ok: state.rx_received,
+            tx_packets_received: state.tx_received,
+            tx_packets_emitted: state.tx_emitted,
+            ..Default::default()
+        };
+        state.rx_received = 0;
+        state.tx_received = 0;
+        state.tx_emitted = 0;
+        stats
+    }
+}
+
+impl Backend for Mock {
+    fn next_event(&self, timeout: Duration) -> Event {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+
+        if let Some(next) = state.next_stats.filter(|v| *v <= now) {
+            state.next_stats = Some(next + Duration::from_secs(self.conf.stats_interval_secs));
+            return Event::Stats(Box::new(self.stats(&mut state)));
+        }
+        if let Some(next) = state.next_uplink.filter(|v| *v <= now) {
+            state.next_uplink = Some(next + Duration::from_millis(self.conf.uplink_interval_ms));
+            return Event::Uplink(Box::new(self.uplink(&mut state)));
+        }
+
+        // Wait for the next event, within the timeout.
+        let wait = [state.next_uplink, state.next_stats]
+            .iter()
+            .flatten()
+            .map(|v| v.saturating_duration_since(now))
+            .min()
+            .unwrap_or(timeout)
+            .min(timeout);
+        drop(state);
+        thread::sleep(wait);
+        Event::Timeout
+    }
+
+    fn send_downlink(
+        &self,
+        pl: &gw::DownlinkFrame,
+    ) -> std::result::Result<gw::DownlinkTxAck, (&'static str, anyhow::Error)> {
+        let mut state = self.state.lock().unwrap();
+        let status = if self.tx_ack_statuses.is_empty() {
+            gw::TxAckStatus::Ok
+        } else {
+            self.tx_ack_statuses[state.tx_ack_index % self.tx_ack_statuses.len()]
+        };
+        state.tx_ack_index += 1;
+        state.tx_received += 1;
+        if status == gw::TxAckStatus::Ok {
+            state.tx_emitted += 1;
+        }
+
+        info!(
+            "Mock backend received downlink, downlink_id: {}, items: {}, status: {}",
+            pl.downlink_id,
+            pl.items.len(),
+            status.as_str_name()
+        );
+
+        Ok(gw::DownlinkTxAck {
+            gateway_id: pl.gateway_id.clone(),
+            downlink_id: pl.downlink_id,
+            items: pl
+                .items
+                .iter()
+                .map(|_| gw::DownlinkTxAckItem {
+                    status: status.into(),
+                })
+                .collect(),
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock() {
+        let backend = Mock::new(&config::MockBackend {
+            uplink_interval_ms: 1,
+            stats_interval_secs: 0,
+            spreading_factors: vec![9],
+            min_payload_size: 0,
+            max_payload_size: 0,
+            tx_ack_statuses: vec!["OK".into(), "TOO_LATE".into()],
+            ..Default::default()
+        })
+        .unwrap();
+
+        let up = loop {
+            if let Event::Uplink(v) = backend.next_event(Duration::from_millis(10)) {
+                break v;
+            }
+        };
+        assert_eq!(up.phy_payload.len(), 13);
+        assert_eq!(up.phy_payload[0], 0x40);
+        let rx_info = up.rx_info.unwrap();
+        assert_eq!(rx_info.gateway_id, "0000000000000001");
+        assert_eq!(rx_info.context.len(), 4);
+        match up.tx_info.unwrap().modulation.unwrap().parameters.unwrap() {
+            gw::modulation::Parameters::Lora(v) => assert_eq!(v.spreading_factor, 9),
+            _ => panic!("expected LoRa modulation"),
+        }
+
+        let pl = gw::DownlinkFrame {
+            downlink_id: 123,
+            items: vec![gw::DownlinkFrameItem::default()],
+            ..Default::default()
+        };
+        let tx_ack = backend.send_downlink(&pl).unwrap();
+        assert_eq!(tx_ack.downlink_id, 123);
+        assert_eq!(tx_ack.items[0].status(), gw::TxAckStatus::Ok);
+        let tx_ack = backend.send_downlink(&pl).unwrap();
+        assert_eq!(tx_ack.items[0].status(), gw::TxAckStatus::TooLate);
+
+        assert!(Mock::new(&config::MockBackend {
+            tx_ack_statuses: vec!["INVALID".into()],
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
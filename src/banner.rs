@@ -0,0 +1,340 @@
+use std::env;
+
+use super::config::{self, Configuration, StoreBackend};
+
+// Returns the build information as key / value pairs.
+pub fn build_info() -> Vec<(String, String)> {
+    vec![
+        ("version".into(), config::VERSION.into()),
+        ("target_os".into(), env::consts::OS.into()),
+        ("target_arch".into(), env::consts::ARCH.into()),
+        (
+            "profile".into(),
+            if cfg!(debug_assertions) {
+                "debug".into()
+            } else {
+                "release".into()
+            },
+        ),
+    ]
+}
+
+// Returns a summary of the enabled subsystems as key / value pairs.
+pub fn capabilities(conf: &Configuration) -> Vec<(String, String)> {
+    let mut out: Vec<(String, String)> = vec![
+        (
+            "concentratord.event_url".into(),
+            conf.concentratord.event_url.clone(),
+        ),
+        (
+            "concentratord.command_url".into(),
+            conf.concentratord.command_url.clone(),
+        ),
+        ("log_level".into(), conf.udp_forwarder.log_level.clone()),
+        (
+            "log_format".into(),
+            format!("{:?}", conf.udp_forwarder.log_format).to_uppercase(),
+        ),
+        (
+            "log_to_syslog".into(),
+            match (
+                conf.udp_forwarder.log_to_syslog,
+                conf.udp_forwarder.syslog.server.as_str(),
+            ) {
+                (false, _) => "false".into(),
+                (true, "") => format!("true (local, {})", conf.udp_forwarder.syslog.facility),
+                (true, v) => format!("true ({}, {})", v, conf.udp_forwarder.syslog.facility),
+            },
+        ),
+        (
+            "metrics_bind".into(),
+            match conf.udp_forwarder.metrics_bind.as_str() {
+                "" => "disabled".into(),
+                v => v.into(),
+            },
+        ),
+        (
+            "status_bind".into(),
+            match conf.udp_forwarder.status_bind.as_str() {
+                "" => "disabled".into(),
+                v => v.into(),
+            },
+        ),
+        (
+            "crash_report_path".into(),
+            match conf.udp_forwarder.crash_report_path.as_str() {
+                "" => "disabled".into(),
+                v => v.into(),
+            },
+        ),
+        (
+            "usage_path".into(),
+            match conf.udp_forwarder.usage_path.as_str() {
+                "" => "memory".into(),
+                v => v.into(),
+            },
+        ),
+        (
+            "duty_cycle".into(),
+            format!("{:?}", conf.udp_forwarder.duty_cycle).to_uppercase(),
+        ),
+        (
+            "location".into(),
+            match (
+                conf.udp_forwarder.location.gpsd_server.as_str(),
+                conf.udp_forwarder.location.latitude != 0.0
+                    || conf.udp_forwarder.location.longitude != 0.0,
+            ) {
+                ("", false) => "concentratord".into(),
+                ("", true) => "concentratord, static".into(),
+                (v, false) => format!("concentratord, gpsd={}", v),
+                (v, true) => format!("concentratord, gpsd={}, static", v),
+            },
+        ),
+        (
+            "tmms_conversion".into(),
+            match conf.udp_forwarder.tmms_conversion {
+                true => format!("leap_seconds={}", conf.udp_forwarder.gps_leap_seconds),
+                false => "disabled".into(),
+            },
+        ),
+        (
+            "capture".into(),
+            match (
+                conf.udp_forwarder.capture.path.as_str(),
+                conf.udp_forwarder.capture.pcap_path.as_str(),
+            ) {
+                ("", "") => "disabled".into(),
+                (jsonl, pcap) => format!(
+                    "jsonl={}, pcapng={} (max_size_kb={}, max_files={})",
+                    if jsonl.is_empty() { "disabled" } else { jsonl },
+                    if pcap.is_empty() { "disabled" } else { pcap },
+                    conf.udp_forwarder.capture.max_size_kb,
+                    conf.udp_forwarder.capture.max_files
+                ),
+            },
+        ),
+        (
+            "shutdown".into(),
+            format!(
+                "SIGTERM / SIGINT, drain_timeout={}s",
+                conf.udp_forwarder.drain_timeout_secs
+            ),
+        ),
+        (
+            "backend".into(),
+            match conf.udp_forwarder.backend {
+                config::BackendType::Concentratord => "concentratord".into(),
+                config::BackendType::Mock => format!(
+                    "mock (uplink_interval={}ms, tx_ack_statuses={})",
+                    conf.udp_forwarder.mock_backend.uplink_interval_ms,
+                    conf.udp_forwarder.mock_backend.tx_ack_statuses.join(",")
+                ),
+            },
+        ),
+        (
+            "mqtt".into(),
+            match conf.udp_forwarder.mqtt.server.as_str() {
+                "" => "disabled".into(),
+                server => format!(
+                    "{} (topic_prefix={}, ack_required_events={}, read_only={})",
+                    server,
+                    conf.udp_forwarder.mqtt.topic_prefix,
+                    conf.udp_forwarder.mqtt.ack_required_events.join(","),
+                    conf.udp_forwarder.mqtt.read_only
+                ),
+            },
+        ),
+        ("config_reload".into(), "SIGHUP".into()),
+    ];
+
+    for (i, s) in conf.udp_forwarder.get_servers().iter().enumerate() {
+        let mut uplink: Vec<&str> = vec![];
+        if s.forward_crc_ok {
+            uplink.push("crc_ok");
+        }
+        if s.forward_crc_invalid {
+            uplink.push("crc_invalid");
+        }
+        if s.forward_crc_missing {
+            uplink.push("crc_missing");
+        }
+
+        let mut subsystems: Vec<String> = vec![];
+        if s.replay_window_secs != 0 {
+            subsystems.push(format!("replay_window={}s", s.replay_window_secs));
+        }
+        if s.store_backend != StoreBackend::Memory {
+            subsystems.push(format!("store={:?}", s.store_backend).to_lowercase());
+        }
+        if s.batch_window_ms != 0 {
+            subsystems.push(format!(
+                "batch={}ms/{}B",
+                s.batch_window_ms, s.batch_max_size
+            ));
+        }
+        if s.dedup_ttl_ms != 0 {
+            subsystems.push(format!("dedup_ttl={}ms", s.dedup_ttl_ms));
+        }
+        if s.push_data_retransmit_count != 0 {
+            subsystems.push(format!(
+                "retransmit={}x{}ms",
+                s.push_data_retransmit_count, s.push_data_retransmit_timeout_ms
+            ));
+        }
+        if s.buffer_max_size != 0 {
+            subsystems.push(format!(
+                "buffer={}/{}s",
+                s.buffer_max_size, s.buffer_max_age_secs
+            ));
+        }
+        if s.filters != config::Filters::default() {
+            subsystems.push("filters".into());
+        }
+        if s.default_route {
+            subsystems.push("default_route".into());
+        }
+        if s.synthetic_stats.interval_secs != 0 {
+            subsystems.push(format!(
+                "synthetic_stats={}s",
+                s.synthetic_stats.interval_secs
+            ));
+        }
+        if s.downlink_fallback.frequency != 0 {
+            subsystems.push(format!(
+                "downlink_fallback={}Hz",
+                s.downlink_fallback.frequency
+            ));
+        }
+        if s.buffer_max_size != 0 && !s.buffer_path.is_empty() {
+            subsystems.push(format!("buffer_path={}", s.buffer_path));
+        }
+        if s.stats_interval_secs != 0 {
+            subsystems.push(format!("stats_interval={}s", s.stats_interval_secs));
+        }
+        if s.interval_jitter_percent != 0 {
+            subsystems.push(format!("jitter={}%", s.interval_jitter_percent));
+        }
+        if s.dns_refresh_interval_secs != 0 {
+            subsystems.push(format!("dns_refresh={}s", s.dns_refresh_interval_secs));
+        }
+        if s.probe_addresses {
+            subsystems.push("probe_addresses".into());
+        }
+        if s.address_family != config::AddressFamily::Auto {
+            subsystems.push(format!("address_family={:?}", s.address_family));
+        }
+        if !s.bind.is_empty() {
+            subsystems.push(format!("bind={}", s.bind));
+        }
+        if !s.bind_interface.is_empty() {
+            subsystems.push(format!("bind_interface={}", s.bind_interface));
+        }
+        if s.reuse_port {
+            subsystems.push("reuse_port".into());
+        }
+        if s.split_sockets {
+            match s.downlink_port {
+                0 => subsystems.push("split_sockets".into()),
+                port => subsystems.push(format!("split_sockets, downlink_port={}", port)),
+            }
+        }
+        if !s.source_allowlist.is_empty() {
+            subsystems.push(format!("source_allowlist={}", s.source_allowlist.join(",")));
+        }
+        if !s.hmac.key.is_empty() {
+            subsystems.push(format!("hmac={:?}", s.hmac.mode));
+        }
+        if s.dscp != 0 {
+            subsystems.push(format!("dscp={}", s.dscp));
+        }
+        if s.transport == config::Transport::Tcp {
+            subsystems.push("transport=tcp".into());
+        }
+        if !s.encryption.keys.is_empty() {
+            subsystems.push(format!("encryption, keys={}", s.encryption.keys.len()));
+        }
+        if s.tls.enabled {
+            subsystems.push("tls".into());
+        }
+        if !s.gateway_id.is_empty() {
+            subsystems.push(format!("gateway_id={}", s.gateway_id));
+        }
+        if s.downlink_timing_check {
+            subsystems.push("downlink_timing_check".into());
+        }
+        if s.stat_with_rxpk {
+            subsystems.push("stat_with_rxpk".into());
+        }
+        if s.forward_rssis {
+            subsystems.push("rssis".into());
+        }
+        if !s.channels.is_empty() {
+            subsystems.push(format!(
+                "channels={} ({:?})",
+                s.channels.len(),
+                s.channel_check
+            ));
+        }
+        if s.stat_fields != config::StatFields::default() {
+            subsystems.push("stat_fields".into());
+        }
+        if !s.rssi_offsets.is_empty() {
+            subsystems.push(format!("rssi_offsets={}", s.rssi_offsets.len()));
+        }
+        if s.downlink_plan != config::DownlinkPlan::default() {
+            subsystems.push("downlink_plan".into());
+        }
+        if !s.data_rate_index_region.is_empty() {
+            subsystems.push(format!("dr_index={}", s.data_rate_index_region));
+        }
+        if s.json_version != 1 {
+            subsystems.push(format!("json_version={}", s.json_version));
+        }
+        if s.role == config::ServerRole::Mirror {
+            subsystems.push(match s.mirror_stats {
+                true => "mirror (stats)".into(),
+                false => "mirror".into(),
+            });
+        }
+        if !s.enricher.url.is_empty() {
+            subsystems.push(format!("enricher={}", s.enricher.url));
+        }
+        if s.token_seed != 0 {
+            subsystems.push(format!("token_seed={}", s.token_seed));
+        }
+        if s.read_only {
+            subsystems.push("read_only".into());
+        }
+        if !s.fine_timestamp {
+            subsystems.push("fine_timestamp=disabled".into());
+        }
+        if s.stat_bridge_object {
+            subsystems.push("stat_bridge_object".into());
+        }
+
+        out.push((format!("servers[{}].server", i), s.server.clone()));
+        out.push((
+            format!("servers[{}].keepalive", i),
+            format!(
+                "{}s, max_failures={}",
+                s.keepalive_interval_secs, s.keepalive_max_failures
+            ),
+        ));
+        out.push((format!("servers[{}].forward", i), uplink.join(",")));
+        out.push((
+            format!("servers[{}].tx_ack_on_success", i),
+            s.tx_ack_on_success.to_string(),
+        ));
+        out.push((
+            format!("servers[{}].subsystems", i),
+            if subsystems.is_empty() {
+                "none".into()
+            } else {
+                subsystems.join(",")
+            },
+        ));
+    }
+
+    out
+}
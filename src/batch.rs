@@ -0,0 +1,91 @@
+use std::time::{Duration, Instant};
+
+// Coalesces the uplinks received within the batching window, such that these
+// are sent in a single PUSH_DATA. The batch is also flushed when adding an
+// uplink would exceed the max. (JSON) size.
+pub struct Batcher<T> {
+    window: Duration,
+    max_size: usize,
+    items: Vec<T>,
+    size: usize,
+    started_at: Option<Instant>,
+}
+
+impl<T> Batcher<T> {
+    pub fn new(window: Duration, max_size: usize) -> Self {
+        Batcher {
+            window,
+            max_size,
+            items: vec![],
+            size: 0,
+            started_at: None,
+        }
+    }
+
+    // Adds the item with the given size and returns the batch which must be
+    // sent first, when the item does not fit.
+    pub fn push(&mut self, item: T, size: usize, now: Instant) -> Option<Vec<T>> {
+        let out = match !self.items.is_empty() && self.size + size > self.max_size {
+            true => self.take(),
+            false => None,
+        };
+
+        if self.items.is_empty() {
+            self.started_at = Some(now);
+        }
+        self.items.push(item);
+        self.size += size;
+
+        out
+    }
+
+    // Returns the batch when its window has elapsed.
+    pub fn due(&mut self, now: Instant) -> Option<Vec<T>> {
+        match self.started_at {
+            Some(v) if now.saturating_duration_since(v) >= self.window => self.take(),
+            _ => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn take(&mut self) -> Option<Vec<T>> {
+        self.started_at = None;
+        self.size = 0;
+        match self.items.is_empty() {
+            true => None,
+            false => Some(std::mem::take(&mut self.items)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batcher() {
+        let mut b = Batcher::new(Duration::from_millis(20), 100);
+        let now = Instant::now();
+
+        assert_eq!(b.due(now), None);
+        assert_eq!(b.push(1, 40, now), None);
+        assert_eq!(b.push(2, 40, now + Duration::from_millis(5)), None);
+        assert_eq!(b.due(now + Duration::from_millis(19)), None);
+
+        // max. size exceeded, the new item starts a new batch
+        assert_eq!(
+            b.push(3, 40, now + Duration::from_millis(10)),
+            Some(vec![1, 2])
+        );
+        assert_eq!(b.due(now + Duration::from_millis(29)), None);
+        assert_eq!(b.due(now + Duration::from_millis(30)), Some(vec![3]));
+        assert_eq!(b.take(), None);
+
+        // an item larger than the max. size is sent on its own
+        assert_eq!(b.push(4, 200, now), None);
+        assert_eq!(b.push(5, 10, now), Some(vec![4]));
+    }
+}
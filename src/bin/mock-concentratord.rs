@@ -0,0 +1,444 @@
+// Mock Concentratord, used for local integration testing of the forwarder
+// without gateway hardware. It implements the Concentratord ZMQ API: the
+// scripted uplink and stats events are published on the event socket, the
+// gateway_id and down commands are answered on the command socket and the
+// received downlinks are validated.
+#[macro_use]
+extern crate log;
+
+use std::fs;
+use std::process;
+use std::time::{Duration, Instant};
+
+use base64::{engine::general_purpose, Engine as _};
+use chirpstack_api::gw;
+use clap::Parser;
+use prost::Message;
+use serde::Deserialize;
+
+#[derive(Parser)]
+#[command(author, version, about = "Mock Concentratord", long_about = None)]
+struct Cli {
+    /// Event API bind
+    #[arg(long, default_value = "ipc:///tmp/concentratord_event")]
+    event_bind: String,
+
+    /// Command API bind
+    #[arg(long, default_value = "ipc:///tmp/concentratord_command")]
+    command_bind: String,
+
+    /// Gateway ID
+    #[arg(short, long, default_value = "0102030405060708")]
+    gateway_id: String,
+
+    /// JSON script with the events to publish
+    #[arg(short, long, value_name = "FILE")]
+    script: Option<String>,
+
+    /// Status of the TX ack returned for each downlink
+    #[arg(long, default_value = "OK")]
+    tx_ack_status: String,
+
+    /// Expected number of downlinks, checked on exit
+    #[arg(long)]
+    expect_downlinks: Option<usize>,
+
+    /// Exit after the given number of seconds, 0 = run forever
+    #[arg(short, long, default_value_t = 0)]
+    duration_secs: u64,
+}
+
+// Event to publish, delay_ms after the previous one.
+#[derive(Deserialize)]
+struct ScriptItem {
+    #[serde(default)]
+    delay_ms: u64,
+    #[serde(flatten)]
+    event: ScriptEvent,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ScriptEvent {
+    Up(Uplink),
+    Stats(Stats),
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct Uplink {
+    phy_payload: String,
+    frequency: u32,
+    spreading_factor: u32,
+    bandwidth: u32,
+    rssi: i32,
+    snr: f32,
+    crc_status: String,
+}
+
+impl Default for Uplink {
+    fn default() -> Self {
+        Uplink {
+            phy_payload: "".into(),
+            frequency: 868100000,
+            spreading_factor: 7,
+            bandwidth: 125000,
+            rssi: -50,
+            snr: 5.5,
+            crc_status: "CRC_OK".into(),
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Stats {
+    rx_packets_received: u32,
+    rx_packets_received_ok: u32,
+    tx_packets_received: u32,
+    tx_packets_emitted: u32,
+}
+
+struct Mock {
+    gateway_id: String,
+    tx_ack_status: gw::TxAckStatus,
+    started: Instant,
+    uplink_id: u32,
+    downlinks: usize,
+    violations: usize,
+}
+
+impl Mock {
+    fn violation(&mut self, msg: String) {
+        warn!("Violation: {}", msg);
+        self.violations += 1;
+    }
+
+    fn publish(&mut self, sock: &zmq::Socket, event: ScriptEvent) -> Result<(), String> {
+        let (name, b) = match event {
+            ScriptEvent::Up(v) => ("up", self.uplink(v)?.encode_to_vec()),
+            ScriptEvent::Stats(v) => ("stats", self.stats(v).encode_to_vec()),
+        };
+
+        info!("Publishing event, event: {}", name);
+        sock.send_multipart([name.as_bytes(), &b], 0)
+            .map_err(|e| format!("publish event error: {}", e))
+    }
+
+    fn uplink(&mut self, up: Uplink) -> Result<gw::UplinkFrame, String> {
+        let crc_status = gw::CrcStatus::from_str_name(&up.crc_status)
+            .ok_or_else(|| format!("invalid crc_status: {}", up.crc_status))?;
+        self.uplink_id += 1;
+
+        Ok(gw::UplinkFrame {
+            phy_payload: general_purpose::STANDARD
+                .decode(&up.phy_payload)
+                .map_err(|e| format!("phy_payload error: {}", e))?,
+            tx_info: Some(gw::UplinkTxInfo {
+                frequency: up.frequency,
+                modulation: Some(gw::Modulation {
+                    parameters: Some(gw::modulation::Parameters::Lora(gw::LoraModulationInfo {
+                        bandwidth: up.bandwidth,
+                        spreading_factor: up.spreading_factor,
+                        code_rate: gw::CodeRate::Cr45.into(),
+                        ..Default::default()
+                    })),
+                }),
+            }),
+            rx_info: Some(gw::UplinkRxInfo {
+                gateway_id: self.gateway_id.clone(),
+                uplink_id: self.uplink_id,
+                rssi: up.rssi,
+                snr: up.snr,
+                crc_status: crc_status.into(),
+                // The concentrator counter (tmst), in microseconds.
+                context: (self.started.elapsed().as_micros() as u32)
+                    .to_be_bytes()
+                    .to_vec(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    fn stats(&self, stats: Stats) -> gw::GatewayStats {
+        gw::GatewayStats {
+            gateway_id: self.gateway_id.clone(),
+            rx_packets_received: stats.rx_packets_received,
+            rx_packets_received_ok: stats.rx_packets_received_ok,
+            tx_packets_received: stats.tx_packets_received,
+            tx_packets_emitted: stats.tx_packets_emitted,
+            ..Default::default()
+        }
+    }
+
+    // Handles the command and returns the response.
+    fn command(&mut self, msg: Vec<Vec<u8>>) -> Vec<u8> {
+        if msg.len() != 2 {
+            self.violation(format!("command must have two frames, got: {}", msg.len()));
+            return vec![];
+        }
+
+        match String::from_utf8_lossy(&msg[0]).as_ref() {
+            "gateway_id" => hex::decode(&self.gateway_id).unwrap_or_default(),
+            "down" => self.downlink(&msg[1]),
+            v => {
+                self.violation(format!("unexpected command: {}", v));
+                vec![]
+            }
+        }
+    }
+
+    fn downlink(&mut self, b: &[u8]) -> Vec<u8> {
+        self.downlinks += 1;
+
+        let pl = match gw::DownlinkFrame::decode(b) {
+            Ok(v) => v,
+            Err(e) => {
+                self.violation(format!("decode downlink error: {}", e));
+                return vec![];
+            }
+        };
+
+        info!(
+            "Downlink received, downlink_id: {}, items: {}",
+            pl.downlink_id,
+            pl.items.len()
+        );
+
+        if pl.gateway_id != self.gateway_id {
+            self.violation(format!(
+                "downlink gateway_id {} does not match {}",
+                pl.gateway_id, self.gateway_id
+            ));
+        }
+        if pl.items.is_empty() {
+            self.violation("downlink without items".into());
+        }
+        for item in &pl.items {
+            if item.tx_info.is_none() {
+                self.violation("downlink item without tx_info".into());
+            }
+            if item.phy_payload.is_empty() {
+                self.violation("downlink item without phy_payload".into());
+            }
+        }
+
+        gw::DownlinkTxAck {
+            gateway_id: pl.gateway_id.clone(),
+            downlink_id: pl.downlink_id,
+            items: pl
+                .items
+                .iter()
+                .map(|_| gw::DownlinkTxAckItem {
+                    status: self.tx_ack_status.into(),
+                })
+                .collect(),
+            ..Default::default()
+        }
+        .encode_to_vec()
+    }
+}
+
+fn read_script(path: &str) -> Result<Vec<ScriptItem>, String> {
+    let b = fs::read(path).map_err(|e| format!("read script error: {}", e))?;
+    serde_json::from_slice(&b).map_err(|e| format!("parse script error: {}", e))
+}
+
+fn main() {
+    let cli = Cli::parse();
+    simple_logger::init_with_level(log::Level::Info).unwrap();
+
+    let mut script = match &cli.script {
+        Some(v) => read_script(v).unwrap_or_else(|e| {
+            error!("{}", e);
+            process::exit(1);
+        }),
+        None => Vec::new(),
+    }
+    .into_iter();
+
+    let tx_ack_status = gw::TxAckStatus::from_str_name(&cli.tx_ack_status).unwrap_or_else(|| {
+        error!("Invalid tx_ack_status: {}", cli.tx_ack_status);
+        process::exit(1);
+    });
+
+    let ctx = zmq::Context::new();
+    let event_sock = ctx.socket(zmq::PUB).unwrap();
+    event_sock
+        .bind(&cli.event_bind)
+        .expect("bind event socket error");
+    let command_sock = ctx.socket(zmq::REP).unwrap();
+    command_sock
+        .bind(&cli.command_bind)
+        .expect("bind command socket error");
+    info!(
+        "Listening, event_bind: {}, command_bind: {}",
+        cli.event_bind, cli.command_bind
+    );
+
+    let mut mock = Mock {
+        gateway_id: cli.gateway_id.clone(),
+        tx_ack_status,
+        started: Instant::now(),
+        uplink_id: 0,
+        downlinks: 0,
+        violations: 0,
+    };
+    let mut next = script
+        .next()
+        .map(|v| (Instant::now() + Duration::from_millis(v.delay_ms), v));
+
+    loop {
+        if cli.duration_secs != 0
+            && mock.started.elapsed() >= Duration::from_secs(cli.duration_secs)
+        {
+            break;
+        }
+
+        let mut items = [command_sock.as_poll_item(zmq::POLLIN)];
+        zmq::poll(&mut items, 100).unwrap();
+        if items[0].is_readable() {
+            let msg = command_sock.recv_multipart(0).unwrap();
+            let resp = mock.command(msg);
+            command_sock.send(resp, 0).unwrap();
+        }
+
+        if let Some((at, _)) = &next {
+            if Instant::now() >= *at {
+                let (_, item) = next.take().unwrap();
+                if let Err(e) = mock.publish(&event_sock, item.event) {
+                    error!("{}", e);
+                    process::exit(1);
+                }
+                next = script
+                    .next()
+                    .map(|v| (Instant::now() + Duration::from_millis(v.delay_ms), v));
+            }
+        }
+    }
+
+    if let Some(expected) = cli.expect_downlinks {
+        if mock.downlinks != expected {
+            mock.violation(format!(
+                "expected {} downlinks, received: {}",
+                expected, mock.downlinks
+            ));
+        }
+    }
+
+    info!(
+        "Mock completed, downlinks: {}, violations: {}",
+        mock.downlinks, mock.violations
+    );
+
+    if mock.violations != 0 {
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mock() -> Mock {
+        Mock {
+            gateway_id: "0102030405060708".into(),
+            tx_ack_status: gw::TxAckStatus::Ok,
+            started: Instant::now(),
+            uplink_id: 0,
+            downlinks: 0,
+            violations: 0,
+        }
+    }
+
+    #[test]
+    fn test_uplink() {
+        let ctx = zmq::Context::new();
+        let event_sock = ctx.socket(zmq::PUB).unwrap();
+        event_sock.bind("inproc://test_uplink").unwrap();
+        let sub_sock = ctx.socket(zmq::SUB).unwrap();
+        sub_sock.connect("inproc://test_uplink").unwrap();
+        sub_sock.set_subscribe(b"").unwrap();
+
+        let mut mock = mock();
+
+        // The subscription is propagated asynchronously, publish until the
+        // event is received.
+        let mut msg = None;
+        for _ in 0..50 {
+            let script: Vec<ScriptItem> = serde_json::from_str(
+                r#"[{"delay_ms":0,"up":{"phy_payload":"AQID","frequency":868300000,"spreading_factor":9}}]"#,
+            )
+            .unwrap();
+            let item = script.into_iter().next().unwrap();
+            mock.publish(&event_sock, item.event).unwrap();
+
+            let mut items = [sub_sock.as_poll_item(zmq::POLLIN)];
+            zmq::poll(&mut items, 100).unwrap();
+            if items[0].is_readable() {
+                msg = Some(sub_sock.recv_multipart(0).unwrap());
+                break;
+            }
+        }
+        let msg = msg.expect("no event received");
+
+        assert_eq!(msg.len(), 2);
+        assert_eq!(msg[0], b"up");
+        let pl = gw::UplinkFrame::decode(msg[1].as_slice()).unwrap();
+        assert_eq!(pl.phy_payload, vec![1, 2, 3]);
+
+        let tx_info = pl.tx_info.unwrap();
+        assert_eq!(tx_info.frequency, 868300000);
+        match tx_info.modulation.unwrap().parameters.unwrap() {
+            gw::modulation::Parameters::Lora(v) => {
+                assert_eq!(v.spreading_factor, 9);
+                assert_eq!(v.bandwidth, 125000);
+            }
+            _ => panic!("expected LoRa modulation"),
+        }
+
+        let rx_info = pl.rx_info.unwrap();
+        assert_eq!(rx_info.gateway_id, "0102030405060708");
+        assert_eq!(rx_info.crc_status(), gw::CrcStatus::CrcOk);
+        assert_eq!(rx_info.context.len(), 4);
+        assert_eq!(mock.violations, 0);
+    }
+
+    #[test]
+    fn test_downlink() {
+        let ctx = zmq::Context::new();
+        let command_sock = ctx.socket(zmq::REP).unwrap();
+        command_sock.bind("inproc://test_downlink").unwrap();
+        let req_sock = ctx.socket(zmq::REQ).unwrap();
+        req_sock.connect("inproc://test_downlink").unwrap();
+
+        let mut mock = mock();
+
+        let down = gw::DownlinkFrame {
+            gateway_id: "0102030405060708".into(),
+            downlink_id: 123,
+            items: vec![gw::DownlinkFrameItem {
+                phy_payload: vec![1, 2, 3],
+                tx_info: Some(Default::default()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        req_sock
+            .send_multipart([b"down".to_vec(), down.encode_to_vec()], 0)
+            .unwrap();
+
+        let msg = command_sock.recv_multipart(0).unwrap();
+        let resp = mock.command(msg);
+        command_sock.send(resp, 0).unwrap();
+
+        let ack = gw::DownlinkTxAck::decode(req_sock.recv_bytes(0).unwrap().as_slice()).unwrap();
+        assert_eq!(ack.gateway_id, "0102030405060708");
+        assert_eq!(ack.downlink_id, 123);
+        assert_eq!(ack.items.len(), 1);
+        assert_eq!(ack.items[0].status(), gw::TxAckStatus::Ok);
+        assert_eq!(mock.downlinks, 1);
+        assert_eq!(mock.violations, 0);
+    }
+}
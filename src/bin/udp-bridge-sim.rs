@@ -0,0 +1,318 @@
+// Fake Semtech UDP network-server, used for end-to-end testing of the
+// forwarder without a real ChirpStack instance. It acknowledges PUSH_DATA and
+// PULL_DATA, injects the PULL_RESP downlinks from a JSON script and validates
+// the received frames against the GWMP spec.
+#[macro_use]
+extern crate log;
+
+use std::collections::HashSet;
+use std::fs;
+use std::net::{SocketAddr, UdpSocket};
+use std::process;
+use std::time::{Duration, Instant};
+
+use base64::{engine::general_purpose, Engine as _};
+use chirpstack_udp_forwarder::protocol::{self, Frame};
+use clap::Parser;
+use serde::Deserialize;
+
+#[derive(Parser)]
+#[command(author, version, about = "Semtech UDP protocol simulator", long_about = None)]
+struct Cli {
+    /// Address to listen on
+    #[arg(short, long, default_value = "0.0.0.0:1700")]
+    bind: String,
+
+    /// JSON script with the downlinks to inject
+    #[arg(short, long, value_name = "FILE")]
+    script: Option<String>,
+
+    /// Exit after the given number of seconds, 0 = run forever
+    #[arg(short, long, default_value_t = 0)]
+    duration_secs: u64,
+}
+
+// Downlink to inject, sent delay_ms after the previous one (or after the
+// first PULL_DATA for the first item).
+#[derive(Deserialize)]
+struct ScriptItem {
+    #[serde(default)]
+    delay_ms: u64,
+    txpk: protocol::TxPk,
+}
+
+#[derive(Default)]
+struct Simulator {
+    gateway_id: Option<[u8; 8]>,
+    pull_addr: Option<SocketAddr>,
+    pending_tx_ack: HashSet<u16>,
+    frames: usize,
+    violations: usize,
+}
+
+impl Simulator {
+    fn violation(&mut self, msg: String) {
+        warn!("Protocol violation: {}", msg);
+        self.violations += 1;
+    }
+
+    fn check_gateway_id(&mut self, gateway_id: [u8; 8]) {
+        match self.gateway_id {
+            Some(v) if v != gateway_id => self.violation(format!(
+                "gateway_id changed from {} to {}",
+                hex::encode(v),
+                hex::encode(gateway_id)
+            )),
+            Some(_) => {}
+            None => self.gateway_id = Some(gateway_id),
+        }
+    }
+
+    fn handle(&mut self, socket: &UdpSocket, b: &[u8], addr: SocketAddr) {
+        self.frames += 1;
+
+        let frame = match Frame::from_bytes(b) {
+            Ok(v) => v,
+            Err(e) => {
+                self.violation(format!("decode frame error: {}, addr: {}", e, addr));
+                return;
+            }
+        };
+
+        match frame {
+            Frame::PushData(v) => {
+                self.check_gateway_id(v.gateway_id);
+                for rxpk in &v.payload.rxpk {
+                    match general_purpose::STANDARD.decode(&rxpk.data) {
+                        Ok(data) if data.len() != rxpk.size as usize => self.violation(format!(
+                            "rxpk size {} does not match data length {}",
+                            rxpk.size,
+                            data.len()
+                        )),
+                        Ok(_) => {}
+                        Err(e) => self.violation(format!("rxpk data error: {}", e)),
+                    }
+                }
+
+                info!(
+                    "PUSH_DATA received, token: {}, rxpk: {}, stat: {}",
+                    v.random_token,
+                    v.payload.rxpk.len(),
+                    v.payload.stat.is_some()
+                );
+                self.send(
+                    socket,
+                    &protocol::PushAck {
+                        random_token: v.random_token,
+                    }
+                    .to_bytes(),
+                    addr,
+                );
+            }
+            Frame::PullData(v) => {
+                self.check_gateway_id(v.gateway_id);
+                debug!("PULL_DATA received, token: {}", v.random_token);
+                self.pull_addr = Some(addr);
+                self.send(
+                    socket,
+                    &protocol::PullAck {
+                        random_token: v.random_token,
+                    }
+                    .to_bytes(),
+                    addr,
+                );
+            }
+            Frame::TxAck(v) => {
+                self.check_gateway_id(v.gateway_id);
+                if !self.pending_tx_ack.remove(&v.random_token) {
+                    self.violation(format!("unexpected TX_ACK token: {}", v.random_token));
+                }
+                info!(
+                    "TX_ACK received, token: {}, error: {}",
+                    v.random_token,
+                    v.payload.txpk_ack.error.as_str()
+                );
+            }
+            v => self.violation(format!(
+                "unexpected {} from forwarder",
+                v.message_type().as_str()
+            )),
+        }
+    }
+
+    fn send_downlink(&mut self, socket: &UdpSocket, random_token: u16, txpk: protocol::TxPk) {
+        let addr = match self.pull_addr {
+            Some(v) => v,
+            None => return,
+        };
+
+        let pull_resp = protocol::PullResp {
+            random_token,
+            payload: protocol::PullRespPayload { txpk },
+        };
+        info!("Sending PULL_RESP, token: {}", random_token);
+        self.pending_tx_ack.insert(random_token);
+        self.send(socket, &pull_resp.to_bytes(), addr);
+    }
+
+    fn send(&self, socket: &UdpSocket, b: &[u8], addr: SocketAddr) {
+        if let Err(e) = socket.send_to(b, addr) {
+            error!("Send error: {}, addr: {}", e, addr);
+        }
+    }
+}
+
+fn read_script(path: &str) -> Result<Vec<ScriptItem>, String> {
+    let b = fs::read(path).map_err(|e| format!("read script error: {}", e))?;
+    serde_json::from_slice(&b).map_err(|e| format!("parse script error: {}", e))
+}
+
+fn main() {
+    let cli = Cli::parse();
+    simple_logger::init_with_level(log::Level::Info).unwrap();
+
+    let mut script = match &cli.script {
+        Some(v) => read_script(v).unwrap_or_else(|e| {
+            error!("{}", e);
+            process::exit(1);
+        }),
+        None => Vec::new(),
+    }
+    .into_iter();
+
+    let socket = UdpSocket::bind(&cli.bind).expect("bind socket error");
+    socket
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    info!("Listening, bind: {}", cli.bind);
+
+    let started = Instant::now();
+    let mut sim = Simulator::default();
+    let mut next: Option<(Instant, ScriptItem)> = None;
+    let mut random_token: u16 = 0;
+    let mut buffer: [u8; 65535] = [0; 65535];
+
+    loop {
+        if cli.duration_secs != 0 && started.elapsed() >= Duration::from_secs(cli.duration_secs) {
+            break;
+        }
+
+        if let Ok((size, addr)) = socket.recv_from(&mut buffer) {
+            sim.handle(&socket, &buffer[..size], addr);
+        }
+
+        // The script starts once the downlink path is known.
+        if next.is_none() && sim.pull_addr.is_some() {
+            next = script
+                .next()
+                .map(|v| (Instant::now() + Duration::from_millis(v.delay_ms), v));
+        }
+
+        if let Some((at, _)) = &next {
+            if Instant::now() >= *at {
+                let (_, item) = next.take().unwrap();
+                random_token = random_token.wrapping_add(1);
+                sim.send_downlink(&socket, random_token, item.txpk);
+            }
+        }
+    }
+
+    for token in &sim.pending_tx_ack {
+        sim.violations += 1;
+        warn!("Protocol violation: no TX_ACK received, token: {}", token);
+    }
+
+    info!(
+        "Simulation completed, frames: {}, violations: {}",
+        sim.frames, sim.violations
+    );
+
+    if sim.violations != 0 {
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recv(socket: &UdpSocket) -> Frame {
+        let mut buffer: [u8; 65535] = [0; 65535];
+        let (size, _) = socket.recv_from(&mut buffer).unwrap();
+        Frame::from_bytes(&buffer[..size]).unwrap()
+    }
+
+    #[test]
+    fn test_uplink_downlink() {
+        let sim_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let gw_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        gw_socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let gw_addr = gw_socket.local_addr().unwrap();
+        let gateway_id = [1, 2, 3, 4, 5, 6, 7, 8];
+
+        let mut sim = Simulator::default();
+
+        // Uplink.
+        let push_data = protocol::PushData {
+            random_token: 1,
+            gateway_id,
+            payload: serde_json::from_str(
+                r#"{"rxpk":[{"time":"2023-05-01T00:00:00+00:00","tmst":1,"freq":868.1,"chan":0,"rfch":0,"stat":1,"modu":"LORA","datr":"SF7BW125","codr":"4/5","rssi":-50,"lsnr":5.5,"size":3,"data":"AQID"}]}"#,
+            )
+            .unwrap(),
+        };
+        sim.handle(&sim_socket, &push_data.to_bytes(), gw_addr);
+        match recv(&gw_socket) {
+            Frame::PushAck(v) => assert_eq!(v.random_token, 1),
+            v => panic!("expected PUSH_ACK, got: {}", v.message_type().as_str()),
+        }
+
+        // The downlink path is known after PULL_DATA.
+        let pull_data = protocol::PullData {
+            random_token: 2,
+            gateway_id,
+        };
+        sim.handle(&sim_socket, &pull_data.to_bytes(), gw_addr);
+        match recv(&gw_socket) {
+            Frame::PullAck(v) => assert_eq!(v.random_token, 2),
+            v => panic!("expected PULL_ACK, got: {}", v.message_type().as_str()),
+        }
+
+        // Downlink, as read from the script.
+        let script: Vec<ScriptItem> = serde_json::from_str(
+            r#"[{"delay_ms":0,"txpk":{"imme":true,"freq":869.525,"rfch":0,"powe":14,"modu":"LORA","datr":"SF9BW125","codr":"4/5","ipol":true,"size":3,"data":"AQID"}}]"#,
+        )
+        .unwrap();
+        let item = script.into_iter().next().unwrap();
+        sim.send_downlink(&sim_socket, 3, item.txpk);
+        match recv(&gw_socket) {
+            Frame::PullResp(v) => {
+                assert_eq!(v.random_token, 3);
+                assert_eq!(v.payload.txpk.data, "AQID");
+            }
+            v => panic!("expected PULL_RESP, got: {}", v.message_type().as_str()),
+        }
+        assert!(sim.pending_tx_ack.contains(&3));
+
+        let tx_ack = protocol::TxAck {
+            random_token: 3,
+            gateway_id,
+            payload: protocol::TxAckPayload {
+                txpk_ack: protocol::TxAckPayloadError {
+                    error: protocol::TxAckError::None,
+                    warn: None,
+                    value: None,
+                },
+            },
+        };
+        sim.handle(&sim_socket, &tx_ack.to_bytes(), gw_addr);
+
+        assert_eq!(sim.gateway_id, Some(gateway_id));
+        assert_eq!(sim.pull_addr, Some(gw_addr));
+        assert!(sim.pending_tx_ack.is_empty());
+        assert_eq!(sim.frames, 3);
+        assert_eq!(sim.violations, 0);
+    }
+}
@@ -0,0 +1,274 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Result;
+use chirpstack_udp_forwarder::protocol::RxPk;
+use chrono::{DateTime, TimeZone, Utc};
+
+// Bounded store-and-forward queue holding the uplinks received while the
+// server is unreachable.
+//
+// When a path is configured, the queue is also written to disk as an
+// append-only file, one JSON encoded (queued_at, rxpk) tuple per line, so that
+// the buffered uplinks survive a restart.
+pub struct UplinkBuffer {
+    max_size: usize,
+    max_age: Duration,
+    items: VecDeque<(DateTime<Utc>, RxPk)>,
+    path: Option<PathBuf>,
+    // Number of lines written to the file since it was last rewritten.
+    written: usize,
+}
+
+impl UplinkBuffer {
+    pub fn new(max_size: usize, max_age: Duration, path: Option<PathBuf>) -> Self {
+        let mut b = UplinkBuffer {
+            max_size,
+            max_age,
+            items: VecDeque::new(),
+            path,
+            written: 0,
+        };
+
+        if let Err(e) = b.load() {
+            error!("Loading uplink buffer from disk error: {}", e);
+        }
+
+        b
+    }
+
+    // Adds the rxpk to the buffer and returns the number of (oldest) items
+    // that were dropped to make room for it.
+    pub fn push(&mut self, rxpk: RxPk, now: DateTime<Utc>) -> usize {
+        let dropped = self.push_memory(rxpk, now);
+
+        if let Err(e) = self.append() {
+            error!("Writing uplink buffer to disk error: {}", e);
+        }
+
+        dropped
+    }
+
+    // Returns the buffered items in the order in which they were received
+    // and the number of items that were dropped because they expired.
+    pub fn drain(&mut self, now: DateTime<Utc>) -> (Vec<RxPk>, usize) {
+        let mut out: Vec<RxPk> = Vec::with_capacity(self.items.len());
+        let mut expired = 0;
+
+        for (queued_at, rxpk) in self.items.drain(..) {
+            match (now - queued_at).to_std() {
+                Ok(age) if age > self.max_age => expired += 1,
+                _ => out.push(rxpk),
+            }
+        }
+
+        if let Err(e) = self.rewrite() {
+            error!("Writing uplink buffer to disk error: {}", e);
+        }
+
+        (out, expired)
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    fn push_memory(&mut self, rxpk: RxPk, now: DateTime<Utc>) -> usize {
+        let mut dropped = 0;
+        while !self.items.is_empty() && self.items.len() >= self.max_size {
+            self.items.pop_front();
+            dropped += 1;
+        }
+
+        self.items.push_back((now, rxpk));
+        dropped
+    }
+
+    fn load(&mut self) -> Result<()> {
+        let path = match &self.path {
+            Some(v) => v.clone(),
+            None => return Ok(()),
+        };
+
+        let f = match File::open(&path) {
+            Ok(v) => v,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        for line in BufReader::new(f).lines() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+
+            let (queued_at, rxpk): (i64, RxPk) = match serde_json::from_str(&line) {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("Skipping invalid uplink buffer record, error: {}", e);
+                    continue;
+                }
+            };
+
+            let queued_at = Utc
+                .timestamp_millis_opt(queued_at)
+                .single()
+                .unwrap_or_else(Utc::now);
+            self.push_memory(rxpk, queued_at);
+        }
+
+        info!(
+            "Uplink buffer loaded from disk, path: {}, count: {}",
+            path.display(),
+            self.items.len()
+        );
+
+        self.rewrite()
+    }
+
+    fn append(&mut self) -> Result<()> {
+        let path = match &self.path {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        // Compact the file once it contains (a lot) more records than the
+        // buffer, e.g. because of dropped items.
+        if self.written >= self.max_size * 2 {
+            return self.rewrite();
+        }
+
+        let (queued_at, rxpk) = match self.items.back() {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        let mut f = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut line = serde_json::to_vec(&(queued_at.timestamp_millis(), rxpk))?;
+        line.push(b'\n');
+        f.write_all(&line)?;
+        self.written += 1;
+
+        Ok(())
+    }
+
+    fn rewrite(&mut self) -> Result<()> {
+        let path = match &self.path {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+
+        let mut b: Vec<u8> = vec![];
+        for (queued_at, rxpk) in &self.items {
+            let line = serde_json::to_vec(&(queued_at.timestamp_millis(), rxpk))?;
+            b.extend_from_slice(&line);
+            b.push(b'\n');
+        }
+
+        fs::write(&tmp, &b)?;
+        fs::rename(&tmp, path)?;
+        self.written = self.items.len();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rxpk(tmst: u32) -> RxPk {
+        serde_json::from_str(&format!(
+            r#"{{"time":"1970-01-01T00:00:00+00:00","tmst":{},"freq":868.1,"chan":0,"rfch":0,"stat":1,"modu":"LORA","datr":"SF7BW125","codr":"4/5","lsnr":5.5,"rssi":-50,"size":1,"data":"AA=="}}"#,
+            tmst
+        ))
+        .unwrap()
+    }
+
+    fn tmst(items: &[RxPk]) -> Vec<u32> {
+        items.iter().map(|v| v.tmst).collect()
+    }
+
+    fn lines(path: &PathBuf) -> usize {
+        fs::read_to_string(path).unwrap().lines().count()
+    }
+
+    #[test]
+    fn test_uplink_buffer() {
+        let now = Utc::now();
+        let mut b = UplinkBuffer::new(3, Duration::from_secs(60), None);
+
+        assert_eq!(b.push(rxpk(1), now - chrono::Duration::seconds(90)), 0);
+        assert_eq!(b.push(rxpk(2), now - chrono::Duration::seconds(30)), 0);
+        assert_eq!(b.push(rxpk(3), now), 0);
+        assert_eq!(b.len(), 3);
+
+        // The oldest item is dropped.
+        assert_eq!(b.push(rxpk(4), now), 1);
+        assert_eq!(b.len(), 3);
+
+        let (items, expired) = b.drain(now);
+        assert_eq!(tmst(&items), vec![2, 3, 4]);
+        assert_eq!(expired, 0);
+        assert_eq!(b.len(), 0);
+
+        // Expired items are not returned.
+        b.push(rxpk(5), now - chrono::Duration::seconds(90));
+        b.push(rxpk(6), now);
+        let (items, expired) = b.drain(now);
+        assert_eq!(tmst(&items), vec![6]);
+        assert_eq!(expired, 1);
+    }
+
+    #[test]
+    fn test_uplink_buffer_disk() {
+        let path = std::env::temp_dir().join(format!("uplink-buffer-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let now = Utc::now();
+
+        {
+            let mut b = UplinkBuffer::new(3, Duration::from_secs(60), Some(path.clone()));
+            b.push(rxpk(1), now);
+            b.push(rxpk(2), now);
+            assert_eq!(lines(&path), 2);
+        }
+
+        // The items are loaded after a restart, invalid records are skipped.
+        let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+        f.write_all(b"{invalid\n\n").unwrap();
+        drop(f);
+
+        let mut b = UplinkBuffer::new(3, Duration::from_secs(60), Some(path.clone()));
+        assert_eq!(b.len(), 2);
+        assert_eq!(lines(&path), 2);
+
+        // The file is compacted once it contains twice the max. size records,
+        // the dropped items are removed.
+        for i in 3..=6 {
+            b.push(rxpk(i), now);
+        }
+        assert_eq!(lines(&path), 6);
+        b.push(rxpk(7), now);
+        assert_eq!(lines(&path), 3);
+        drop(b);
+
+        // The items are loaded in the order in which they were received.
+        let mut b = UplinkBuffer::new(3, Duration::from_secs(60), Some(path.clone()));
+        let (items, expired) = b.drain(now);
+        assert_eq!(tmst(&items), vec![5, 6, 7]);
+        assert_eq!(expired, 0);
+
+        // Draining the buffer empties the file.
+        assert_eq!(lines(&path), 0);
+        let b = UplinkBuffer::new(3, Duration::from_secs(60), Some(path.clone()));
+        assert_eq!(b.len(), 0);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
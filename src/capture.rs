@@ -0,0 +1,230 @@
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use base64::{engine::general_purpose, Engine as _};
+use chirpstack_udp_forwarder::protocol::MessageType;
+use chrono::{SecondsFormat, Utc};
+use serde_json::json;
+
+use super::config;
+use super::pcap;
+use super::transport;
+
+lazy_static! {
+    static ref CAPTURE: Mutex<Capture> = Mutex::new(Capture::default());
+}
+
+#[derive(Default)]
+struct Capture {
+    jsonl: Option<RotatingFile>,
+    pcap: Option<RotatingFile>,
+}
+
+// File which is rotated (path.1, path.2, ...) when it exceeds the max. size,
+// keeping max_files rotated files. The header is written each time the file
+// is opened.
+struct RotatingFile {
+    path: String,
+    max_size: u64,
+    max_files: u32,
+    header: Vec<u8>,
+    file: Option<File>,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn new(path: &str, conf: &config::Capture, header: Vec<u8>) -> Option<Self> {
+        match path.is_empty() {
+            true => None,
+            false => Some(RotatingFile {
+                path: path.to_string(),
+                max_size: conf.max_size_kb * 1024,
+                max_files: conf.max_files,
+                header,
+                file: None,
+                size: 0,
+            }),
+        }
+    }
+
+    fn write(&mut self, b: &[u8]) -> Result<()> {
+        if self.file.is_some() && self.size + b.len() as u64 > self.max_size {
+            self.file = None;
+            self.rotate()?;
+        }
+
+        if self.file.is_none() {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            file.write_all(&self.header)?;
+            self.size = file.metadata()?.len();
+            self.file = Some(file);
+        }
+
+        if let Some(file) = &mut self.file {
+            file.write_all(b)?;
+            self.size += b.len() as u64;
+        }
+
+        Ok(())
+    }
+
+    fn rotate(&self) -> Result<()> {
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+            return Ok(());
+        }
+
+        for i in (1..self.max_files).rev() {
+            let from = format!("{}.{}", self.path, i);
+            if fs::metadata(&from).is_ok() {
+                fs::rename(&from, format!("{}.{}", self.path, i + 1))?;
+            }
+        }
+        fs::rename(&self.path, format!("{}.1", self.path))?;
+
+        Ok(())
+    }
+}
+
+pub fn setup(conf: &config::Capture) {
+    *CAPTURE.lock().unwrap() = Capture {
+        jsonl: RotatingFile::new(&conf.path, conf, vec![]),
+        pcap: RotatingFile::new(&conf.pcap_path, conf, pcap::header()),
+    };
+}
+
+// Captures the datagram, the direction is SENT or RECEIVED.
+pub fn datagram(direction: &str, server: &str, socket: &transport::Socket, b: &[u8]) {
+    let mut capture = CAPTURE.lock().unwrap();
+    if capture.jsonl.is_none() && capture.pcap.is_none() {
+        return;
+    }
+
+    let local = socket.local_addr().ok();
+    let peer = socket.peer_addr().ok();
+
+    if let Some(f) = &mut capture.jsonl {
+        let line = format!("{}\n", record(direction, server, peer, b));
+        if let Err(e) = f.write(line.as_bytes()) {
+            warn!("Write capture error: {}, path: {}", e, f.path);
+        }
+    }
+
+    if let (Some(f), Some(local), Some(peer)) = (&mut capture.pcap, local, peer) {
+        let outbound = direction == "SENT";
+        let (src, dst) = match outbound {
+            true => (local, peer),
+            false => (peer, local),
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        if let Err(e) = f.write(&pcap::packet(outbound, src, dst, b, timestamp)) {
+            warn!("Write capture error: {}, path: {}", e, f.path);
+        }
+    }
+}
+
+// Returns the JSON record of the datagram, with the decoded header and the
+// JSON body (or the base64 encoded body, when it is not JSON).
+fn record(direction: &str, server: &str, peer: Option<SocketAddr>, b: &[u8]) -> serde_json::Value {
+    let mut record = json!({
+        "time": Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+        "direction": direction,
+        "server": server,
+        "peer": peer.map(|v| v.to_string()),
+        "size": b.len(),
+    });
+
+    if b.len() < 4 {
+        record["raw"] = general_purpose::STANDARD.encode(b).into();
+        return record;
+    }
+
+    let message_type = MessageType::try_from(b[3]).ok();
+    record["version"] = b[0].into();
+    record["token"] = u16::from_be_bytes([b[1], b[2]]).into();
+    record["type"] = message_type
+        .as_ref()
+        .map_or("UNKNOWN", |v| v.as_str())
+        .into();
+
+    // PUSH_DATA, PULL_DATA and TX_ACK contain the gateway ID.
+    let offset = match message_type {
+        Some(MessageType::PushData) | Some(MessageType::PullData) | Some(MessageType::TxAck)
+            if b.len() >= 12 =>
+        {
+            record["gateway_id"] = hex::encode(&b[4..12]).into();
+            12
+        }
+        _ => 4,
+    };
+
+    let body = &b[offset..];
+    if !body.is_empty() {
+        record["payload"] = match serde_json::from_slice::<serde_json::Value>(body) {
+            Ok(v) => v,
+            Err(_) => general_purpose::STANDARD.encode(body).into(),
+        };
+    }
+
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capture() {
+        let mut b = vec![2, 0x12, 0x34, 0, 1, 2, 3, 4, 5, 6, 7, 8];
+        b.extend_from_slice(br#"{"rxpk":[]}"#);
+        let r = record("SENT", "localhost:1700", None, &b);
+        assert_eq!(r["token"], 0x1234);
+        assert_eq!(r["type"], "PUSH_DATA");
+        assert_eq!(r["gateway_id"], "0102030405060708");
+        assert_eq!(r["payload"], json!({"rxpk": []}));
+
+        let r = record("RECEIVED", "localhost:1700", None, &[2, 0, 1, 1]);
+        assert_eq!(r["type"], "PUSH_ACK");
+        assert!(r.get("payload").is_none());
+
+        let dir = std::env::temp_dir().join(format!("capture-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("capture.jsonl").display().to_string();
+
+        let mut f = RotatingFile {
+            path: path.clone(),
+            max_size: 10,
+            max_files: 2,
+            header: vec![],
+            file: None,
+            size: 0,
+        };
+        for line in ["aaaaaa\n", "bbbbbb\n", "cccccc\n", "dddddd\n"].iter() {
+            f.write(line.as_bytes()).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "dddddd\n");
+        assert_eq!(
+            fs::read_to_string(format!("{}.1", path)).unwrap(),
+            "cccccc\n"
+        );
+        assert_eq!(
+            fs::read_to_string(format!("{}.2", path)).unwrap(),
+            "bbbbbb\n"
+        );
+        assert!(fs::metadata(format!("{}.3", path)).is_err());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
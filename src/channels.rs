@@ -0,0 +1,90 @@
+use super::config::{Channel, ChannelCheck};
+
+// Channel plan of the gateway, the index of a channel is its channel number
+// (chan).
+pub struct ChannelPlan {
+    channels: Vec<Channel>,
+    check: ChannelCheck,
+}
+
+impl ChannelPlan {
+    pub fn new(channels: Vec<Channel>, check: ChannelCheck) -> Self {
+        ChannelPlan { channels, check }
+    }
+
+    // Returns the channel number and channel for the given frequency (Hz).
+    pub fn get(&self, frequency: u32) -> Option<(u32, &Channel)> {
+        self.channels
+            .iter()
+            .enumerate()
+            .find(|(_, c)| c.frequency == frequency)
+            .map(|(i, c)| (i as u32, c))
+    }
+
+    // Validates the chan and rfch reported for an uplink on the given
+    // frequency against the channel plan, returning the implausible fields.
+    // In CORRECT mode, these are replaced by the values of the channel plan.
+    pub fn check(&self, frequency: u32, chan: &mut u32, rfch: &mut u32) -> Vec<&'static str> {
+        let (i, c) = match self.get(frequency) {
+            Some(v) => v,
+            None => return vec!["freq"],
+        };
+
+        let mut out = vec![];
+        if *chan != i {
+            out.push("chan");
+            if self.check == ChannelCheck::Correct {
+                *chan = i;
+            }
+        }
+        if *rfch != c.rf_chain {
+            out.push("rfch");
+            if self.check == ChannelCheck::Correct {
+                *rfch = c.rf_chain;
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let channels = vec![
+            Channel {
+                name: "EU868 ch0".into(),
+                sub_band: "".into(),
+                frequency: 868100000,
+                rf_chain: 1,
+            },
+            Channel {
+                name: "EU868 ch1".into(),
+                sub_band: "".into(),
+                frequency: 868300000,
+                rf_chain: 1,
+            },
+        ];
+
+        let plan = ChannelPlan::new(channels.clone(), ChannelCheck::Flag);
+        let (mut chan, mut rfch) = (1, 1);
+        assert!(plan.check(868300000, &mut chan, &mut rfch).is_empty());
+        assert_eq!(plan.check(867100000, &mut chan, &mut rfch), vec!["freq"]);
+
+        let (mut chan, mut rfch) = (5, 0);
+        assert_eq!(
+            plan.check(868300000, &mut chan, &mut rfch),
+            vec!["chan", "rfch"]
+        );
+        assert_eq!((chan, rfch), (5, 0));
+
+        let plan = ChannelPlan::new(channels, ChannelCheck::Correct);
+        assert_eq!(
+            plan.check(868300000, &mut chan, &mut rfch),
+            vec!["chan", "rfch"]
+        );
+        assert_eq!((chan, rfch), (1, 1));
+    }
+}
@@ -0,0 +1,438 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+
+use anyhow::Result;
+use chirpstack_api::gw;
+use chirpstack_udp_forwarder::protocol;
+use log::LevelFilter;
+
+use super::config::{BackendType, Configuration, ServerRole, StoreBackend, Transport};
+use super::encryption;
+use super::forwarder;
+use super::helpers;
+use super::source_filter;
+use super::tls;
+
+#[derive(Debug, PartialEq)]
+enum Severity {
+    Error,
+    Warning,
+}
+
+// Validates the configuration, printing the diagnostics. An error is returned
+// when the configuration contains errors, warnings are only printed.
+pub fn run(filenames: &[String]) -> Result<()> {
+    let conf = Configuration::get(filenames)?;
+
+    let mut diagnostics = check(&conf);
+    diagnostics.extend(check_hostnames(&conf));
+
+    for (severity, msg) in &diagnostics {
+        match severity {
+            Severity::Error => println!("ERROR: {}", msg),
+            Severity::Warning => println!("WARNING: {}", msg),
+        }
+    }
+
+    let errors = diagnostics
+        .iter()
+        .filter(|(s, _)| *s == Severity::Error)
+        .count();
+    if errors != 0 {
+        return Err(anyhow!("configuration contains {} error(s)", errors));
+    }
+
+    println!("Configuration OK");
+    Ok(())
+}
+
+fn check(conf: &Configuration) -> Vec<(Severity, String)> {
+    let mut out: Vec<(Severity, String)> = vec![];
+    let mut error = |msg: String| out.push((Severity::Error, msg));
+    let uf = &conf.udp_forwarder;
+
+    if log::Level::from_str(&uf.log_level).is_err() {
+        error(format!("invalid log_level: {}", uf.log_level));
+    }
+    for (k, v) in &uf.log_levels {
+        if LevelFilter::from_str(v).is_err() {
+            error(format!("invalid log_levels level: {}, module: {}", v, k));
+        }
+    }
+    if !uf.gateway_id.is_empty() && helpers::parse_gateway_id(&uf.gateway_id).is_err() {
+        error(format!("invalid gateway_id: {}", uf.gateway_id));
+    }
+    if !(uf.syslog.server.is_empty()
+        || uf.syslog.server.starts_with("udp://")
+        || uf.syslog.server.starts_with("tcp://"))
+    {
+        error(format!("unsupported syslog server: {}", uf.syslog.server));
+    }
+    if !uf.metrics_bind.is_empty() && uf.metrics_bind == uf.status_bind {
+        error(format!(
+            "metrics_bind and status_bind conflict: {}",
+            uf.metrics_bind
+        ));
+    }
+    if !uf.capture.path.is_empty() && uf.capture.path == uf.capture.pcap_path {
+        error(format!(
+            "capture path and pcap_path conflict: {}",
+            uf.capture.path
+        ));
+    }
+
+    if uf.backend == BackendType::Mock {
+        let mock = &uf.mock_backend;
+        if helpers::parse_gateway_id(&mock.gateway_id).is_err() {
+            error(format!(
+                "invalid mock_backend gateway_id: {}",
+                mock.gateway_id
+            ));
+        }
+        if mock.spreading_factors.is_empty() || mock.frequencies.is_empty() {
+            error("mock_backend spreading_factors and frequencies must not be empty".into());
+        }
+        for v in &mock.tx_ack_statuses {
+            if gw::TxAckStatus::from_str_name(v).is_none() {
+                error(format!("invalid mock_backend tx_ack_status: {}", v));
+            }
+        }
+    }
+
+    if uf.mqtt.server.contains("://") && !uf.mqtt.server.starts_with("tcp://") {
+        error(format!("unsupported mqtt server: {}", uf.mqtt.server));
+    }
+    for v in &uf.mqtt.ack_required_events {
+        if !["up", "stats", "ack"].contains(&v.as_str()) {
+            error(format!("invalid mqtt ack_required_events event: {}", v));
+        }
+    }
+    if !uf.mqtt.ack_required_events.is_empty() && uf.mqtt.max_inflight == 0 {
+        error("mqtt ack_required_events requires max_inflight of at least 1".to_string());
+    }
+
+    let servers = uf.get_servers();
+    for (i, s) in servers.iter().enumerate() {
+        if servers[..i].iter().any(|v| v.server == s.server) {
+            error(format!("duplicate server: {}", s.server));
+        }
+        if s.json_version != 1 && s.json_version != 2 {
+            error(format!(
+                "invalid json_version: {}, expected 1 or 2, server: {}",
+                s.json_version, s.server
+            ));
+        }
+        if s.interval_jitter_percent > 50 {
+            error(format!(
+                "invalid interval_jitter_percent: {}, expected 0 - 50, server: {}",
+                s.interval_jitter_percent, s.server
+            ));
+        }
+        if !s.data_rate_index_region.is_empty()
+            && !protocol::is_known_region(&s.data_rate_index_region)
+        {
+            error(format!(
+                "invalid data_rate_index_region: {}, server: {}",
+                s.data_rate_index_region, s.server
+            ));
+        }
+        if s.rxpk_data_rate_index && s.data_rate_index_region.is_empty() {
+            error(format!(
+                "rxpk_data_rate_index requires data_rate_index_region, server: {}",
+                s.server
+            ));
+        }
+        if !s.gateway_id.is_empty() && helpers::parse_gateway_id(&s.gateway_id).is_err() {
+            error(format!(
+                "invalid gateway_id: {}, server: {}",
+                s.gateway_id, s.server
+            ));
+        }
+        match s.store_backend {
+            StoreBackend::Memory => {}
+            StoreBackend::File | StoreBackend::Sled => {
+                if s.store_path.is_empty() {
+                    error(format!(
+                        "store_backend {} requires store_path, server: {}",
+                        format!("{:?}", s.store_backend).to_uppercase(),
+                        s.server
+                    ));
+                } else if servers[..i]
+                    .iter()
+                    .any(|v| v.store_backend != StoreBackend::Redis && v.store_path == s.store_path)
+                {
+                    error(format!(
+                        "store_path conflict: {}, server: {}",
+                        s.store_path, s.server
+                    ));
+                }
+            }
+            StoreBackend::Redis => {
+                if s.store_url.is_empty() {
+                    error(format!(
+                        "store_backend REDIS requires store_url, server: {}",
+                        s.server
+                    ));
+                }
+            }
+        }
+        if (s.store_backend == StoreBackend::Sled && !cfg!(feature = "sled"))
+            || (s.store_backend == StoreBackend::Redis && !cfg!(feature = "redis"))
+        {
+            error(format!(
+                "store_backend {} is not enabled in this build, server: {}",
+                format!("{:?}", s.store_backend).to_uppercase(),
+                s.server
+            ));
+        }
+        if let Err(e) = source_filter::SourceFilter::new(&s.source_allowlist) {
+            error(format!(
+                "invalid source_allowlist: {}, server: {}",
+                e, s.server
+            ));
+        }
+        if let Err(e) = s.hmac.get_key() {
+            error(format!("{}, server: {}", e, s.server));
+        }
+        if let Err(e) = encryption::Keys::from_config(&s.encryption) {
+            error(format!("{}, server: {}", e, s.server));
+        }
+        if !s.encryption.keys.is_empty() && s.transport != Transport::Tcp {
+            error(format!(
+                "encryption requires transport TCP, server: {}",
+                s.server
+            ));
+        }
+        if let Err(e) = tls::Connector::from_config(&s.tls, &s.server) {
+            error(format!("{}, server: {}", e, s.server));
+        }
+        if s.tls.enabled && s.transport != Transport::Tcp {
+            error(format!("tls requires transport TCP, server: {}", s.server));
+        }
+        if s.downlink_queue_size == 0 {
+            error(format!(
+                "invalid downlink_queue_size: 0, expected at least 1, server: {}",
+                s.server
+            ));
+        }
+        if s.dscp > 63 {
+            error(format!(
+                "invalid dscp: {}, expected 0 - 63, server: {}",
+                s.dscp, s.server
+            ));
+        }
+        match s.bind.parse::<SocketAddr>() {
+            Err(_) if !s.bind.is_empty() => {
+                error(format!("invalid bind: {}, server: {}", s.bind, s.server));
+            }
+            // Sharing a fixed port requires reuse_port on all sockets.
+            Ok(addr) if addr.port() != 0 => {
+                let shared = servers[..i].iter().any(|v| {
+                    v.bind.parse::<SocketAddr>().ok() == Some(addr)
+                        && !(v.reuse_port && s.reuse_port)
+                });
+                if shared {
+                    error(format!(
+                        "bind conflict: {}, enable reuse_port to share the port, server: {}",
+                        s.bind, s.server
+                    ));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut warning = |msg: String| out.push((Severity::Warning, msg));
+    if servers.is_empty() {
+        warning("no servers configured".into());
+    }
+    for s in &servers {
+        if !(s.forward_crc_ok || s.forward_crc_invalid || s.forward_crc_missing) {
+            warning(format!(
+                "all forward_crc_* options are disabled, server: {}",
+                s.server
+            ));
+        }
+        if s.keepalive_interval_secs > forwarder::NAT_TIMEOUT_SECS {
+            warning(format!(
+                "keepalive_interval_secs is longer than the typical NAT timeout of {}s, server: {}",
+                forwarder::NAT_TIMEOUT_SECS,
+                s.server
+            ));
+        }
+        if s.read_only && s.buffer_max_size != 0 {
+            warning(format!(
+                "buffer_max_size is ignored in read-only mode, server: {}",
+                s.server
+            ));
+        }
+        if !s.buffer_path.is_empty() && s.buffer_max_size == 0 {
+            warning(format!(
+                "buffer_path is ignored, as buffer_max_size is 0, server: {}",
+                s.server
+            ));
+        }
+        if s.mirror_stats && s.role != ServerRole::Mirror {
+            warning(format!(
+                "mirror_stats is ignored, as the role is not MIRROR, server: {}",
+                s.server
+            ));
+        }
+        if s.transport == Transport::Tcp && s.probe_addresses {
+            warning(format!(
+                "probe_addresses is ignored with the TCP transport, server: {}",
+                s.server
+            ));
+        }
+        if s.transport == Transport::Tcp
+            && (!s.bind.is_empty()
+                || s.split_sockets
+                || !s.source_allowlist.is_empty()
+                || !s.bind_interface.is_empty()
+                || s.reuse_port
+                || s.dscp != 0
+                || s.send_buffer_size != 0
+                || s.recv_buffer_size != 0)
+        {
+            warning(format!(
+                "the UDP socket options (bind, split_sockets, source_allowlist, dscp, buffer sizes) are ignored with the TCP transport, server: {}",
+                s.server
+            ));
+        }
+        if s.downlink_port != 0 && !s.split_sockets {
+            warning(format!(
+                "downlink_port is ignored, as split_sockets is disabled, server: {}",
+                s.server
+            ));
+        }
+        if s.token_seed != 0 {
+            warning(format!(
+                "token_seed must not be used in production, server: {}",
+                s.server
+            ));
+        }
+    }
+
+    out
+}
+
+// Returns an error for each server (and gpsd and MQTT server) of which the
+// hostname can not be resolved.
+fn check_hostnames(conf: &Configuration) -> Vec<(Severity, String)> {
+    let mut hosts: Vec<&str> = conf
+        .udp_forwarder
+        .servers
+        .iter()
+        .map(|s| s.server.as_str())
+        .collect();
+    if !conf.udp_forwarder.location.gpsd_server.is_empty() {
+        hosts.push(&conf.udp_forwarder.location.gpsd_server);
+    }
+    let mqtt_server = &conf.udp_forwarder.mqtt.server;
+    if !mqtt_server.is_empty() {
+        hosts.push(mqtt_server.strip_prefix("tcp://").unwrap_or(mqtt_server));
+    }
+
+    hosts
+        .into_iter()
+        .filter_map(|host| match host.to_socket_addrs().map(|mut v| v.next()) {
+            Ok(Some(_)) => None,
+            Ok(None) => Some(format!("hostname does not resolve: {}", host)),
+            Err(e) => Some(format!("hostname does not resolve: {}, error: {}", host, e)),
+        })
+        .map(|msg| (Severity::Error, msg))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{
+        Concentratord, Encryption, EncryptionKey, Hmac, Server, Tls, UdpForwarder,
+    };
+
+    #[test]
+    fn test_check() {
+        let mut conf = Configuration {
+            udp_forwarder: UdpForwarder::default(),
+            concentratord: Concentratord::default(),
+        };
+        assert_eq!(
+            check(&conf),
+            vec![(Severity::Warning, "no servers configured".to_string())]
+        );
+
+        conf.udp_forwarder.metrics_bind = "0.0.0.0:9800".into();
+        conf.udp_forwarder.status_bind = "0.0.0.0:9800".into();
+        conf.udp_forwarder.mqtt.ack_required_events = vec!["up".into(), "down".into()];
+        conf.udp_forwarder.servers = vec![
+            Server::default(),
+            Server {
+                rxpk_data_rate_index: true,
+                store_backend: StoreBackend::File,
+                ..Default::default()
+            },
+            Server {
+                server: "127.0.0.1:1701".into(),
+                bind: "0.0.0.0:1700".into(),
+                reuse_port: true,
+                ..Default::default()
+            },
+            Server {
+                server: "127.0.0.1:1702".into(),
+                bind: "0.0.0.0:1700".into(),
+                hmac: Hmac {
+                    key: "0011".into(),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            Server {
+                server: "127.0.0.1:1703".into(),
+                encryption: Encryption {
+                    keys: vec![EncryptionKey {
+                        id: 1,
+                        key: "0011".into(),
+                        ..Default::default()
+                    }],
+                },
+                ..Default::default()
+            },
+            Server {
+                server: "127.0.0.1:1704".into(),
+                tls: Tls {
+                    enabled: true,
+                    server_name: "not a hostname".into(),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        ];
+        let errors: Vec<String> = check(&conf)
+            .into_iter()
+            .filter(|(s, _)| *s == Severity::Error)
+            .map(|(_, msg)| msg)
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "metrics_bind and status_bind conflict: 0.0.0.0:9800".to_string(),
+                "invalid mqtt ack_required_events event: down".to_string(),
+                "duplicate server: 127.0.0.1:1700".to_string(),
+                "rxpk_data_rate_index requires data_rate_index_region, server: 127.0.0.1:1700"
+                    .to_string(),
+                "store_backend FILE requires store_path, server: 127.0.0.1:1700".to_string(),
+                "hmac key is shorter than 16 bytes, server: 127.0.0.1:1702".to_string(),
+                "bind conflict: 0.0.0.0:1700, enable reuse_port to share the port, server: 127.0.0.1:1702"
+                    .to_string(),
+                "encryption key must be 32 bytes, id: 1, server: 127.0.0.1:1703".to_string(),
+                "encryption requires transport TCP, server: 127.0.0.1:1703".to_string(),
+                "invalid tls server_name: not a hostname, error: invalid dns name, server: 127.0.0.1:1704"
+                    .to_string(),
+                "tls requires transport TCP, server: 127.0.0.1:1704".to_string(),
+            ]
+        );
+
+        assert!(check_hostnames(&conf).is_empty());
+    }
+}
@@ -3,7 +3,7 @@ use anyhow::Result;
 use super::socket::ZMQ_CONTEXT;
 
 pub fn get_socket(endpoint: &str) -> Result<zmq::Socket> {
-    info!(
+    debug!(
         "Creating new socket for sending commands, endpoint: {}",
         endpoint
     );
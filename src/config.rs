@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::fs;
 
 use anyhow::Result;
@@ -9,9 +10,32 @@ pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 #[serde(default)]
 pub struct UdpForwarder {
     pub log_level: String,
+    pub log_levels: BTreeMap<String, String>,
+    pub log_format: LogFormat,
+    pub log_frames: bool,
     #[serde(default)]
     pub log_to_syslog: bool,
+    pub syslog: Syslog,
     pub metrics_bind: String,
+    pub status_bind: String,
+    pub crash_report_path: String,
+    pub usage_path: String,
+    pub usage_retention_days: u32,
+    pub duty_cycle: DutyCycleMode,
+    pub gps_leap_seconds: i64,
+    pub tmms_conversion: bool,
+    pub drain_timeout_secs: u64,
+    pub backend: BackendType,
+    pub gateway_id: String,
+    pub channels: Vec<Channel>,
+    pub channel_check: ChannelCheck,
+    pub downlink_plan: DownlinkPlan,
+    pub rssi_offsets: Vec<RssiOffset>,
+    pub location: Location,
+    pub stat_fields: StatFields,
+    pub capture: Capture,
+    pub mock_backend: MockBackend,
+    pub mqtt: Mqtt,
     pub servers: Vec<Server>,
 }
 
@@ -19,38 +43,619 @@ impl Default for UdpForwarder {
     fn default() -> Self {
         UdpForwarder {
             log_level: "INFO".to_string(),
+            log_levels: BTreeMap::new(),
+            log_format: LogFormat::Text,
+            log_frames: false,
             log_to_syslog: false,
+            syslog: Syslog::default(),
             metrics_bind: "".to_string(),
+            status_bind: "".to_string(),
+            crash_report_path: "".to_string(),
+            usage_path: "".to_string(),
+            usage_retention_days: 31,
+            duty_cycle: DutyCycleMode::Disabled,
+            gps_leap_seconds: 18,
+            tmms_conversion: false,
+            drain_timeout_secs: 5,
+            backend: BackendType::Concentratord,
+            gateway_id: "".to_string(),
+            channels: vec![],
+            channel_check: ChannelCheck::Flag,
+            downlink_plan: DownlinkPlan::default(),
+            rssi_offsets: vec![],
+            location: Location::default(),
+            stat_fields: StatFields::default(),
+            capture: Capture::default(),
+            mock_backend: MockBackend::default(),
+            mqtt: Mqtt::default(),
             servers: vec![],
         }
     }
 }
 
-#[derive(Deserialize)]
+impl UdpForwarder {
+    // Returns the servers, with the global settings applied.
+    pub fn get_servers(&self) -> Vec<Server> {
+        // The filters of the servers, by which the uplinks are routed to
+        // these servers instead of the default route.
+        let routes: Vec<Filters> = self
+            .servers
+            .iter()
+            .filter(|s| !s.default_route)
+            .map(|s| s.filters.clone())
+            .collect();
+
+        self.servers
+            .iter()
+            .cloned()
+            .map(|mut s| {
+                if s.gateway_id.is_empty() {
+                    s.gateway_id = self.gateway_id.clone();
+                }
+                if s.default_route {
+                    s.routes = routes.clone();
+                }
+                s.channels = self.channels.clone();
+                s.channel_check = self.channel_check;
+                s.downlink_plan = self.downlink_plan.clone();
+                s.rssi_offsets = self.rssi_offsets.clone();
+                s.stat_fields = self.stat_fields.clone();
+                s
+            })
+            .collect()
+    }
+}
+
+#[derive(Deserialize, Clone, PartialEq)]
 #[serde(default)]
 pub struct Server {
     pub server: String,
+    pub gateway_id: String,
     pub keepalive_interval_secs: u64,
     pub keepalive_max_failures: u32,
+    pub stats_interval_secs: u64,
+    pub interval_jitter_percent: u8,
+    pub token_seed: u64,
+    pub dns_refresh_interval_secs: u64,
+    pub dns_refresh_missed_acks: u32,
+    pub probe_addresses: bool,
+    pub transport: Transport,
+    pub address_family: AddressFamily,
+    pub bind: String,
+    pub bind_interface: String,
+    pub reuse_port: bool,
+    pub dscp: u8,
+    pub send_buffer_size: usize,
+    pub recv_buffer_size: usize,
+    pub split_sockets: bool,
+    pub downlink_port: u16,
+    pub source_allowlist: Vec<String>,
     pub forward_crc_ok: bool,
     pub forward_crc_invalid: bool,
     pub forward_crc_missing: bool,
+    pub tx_ack_on_success: bool,
+    pub downlink_timing_check: bool,
+    pub downlink_queue_size: usize,
+    pub stat_bridge_object: bool,
+    pub stat_with_rxpk: bool,
+    pub fine_timestamp: bool,
+    pub read_only: bool,
+    pub role: ServerRole,
+    pub mirror_stats: bool,
+    pub json_version: u8,
+    pub data_rate_index_region: String,
+    pub rxpk_data_rate_index: bool,
+    pub forward_rssis: bool,
+    pub replay_window_secs: u64,
+    pub store_backend: StoreBackend,
+    pub store_path: String,
+    pub store_url: String,
+    pub dedup_ttl_ms: u64,
+    pub dedup_tmst_window_us: u32,
+    pub push_data_retransmit_count: u32,
+    pub push_data_retransmit_timeout_ms: u64,
+    pub buffer_max_size: usize,
+    pub buffer_max_age_secs: u64,
+    pub buffer_path: String,
+    pub batch_window_ms: u64,
+    pub batch_max_size: usize,
+    pub filters: Filters,
+    pub default_route: bool,
+    #[serde(skip)]
+    pub routes: Vec<Filters>,
+    #[serde(skip)]
+    pub channels: Vec<Channel>,
+    #[serde(skip)]
+    pub channel_check: ChannelCheck,
+    #[serde(skip)]
+    pub downlink_plan: DownlinkPlan,
+    #[serde(skip)]
+    pub rssi_offsets: Vec<RssiOffset>,
+    #[serde(skip)]
+    pub stat_fields: StatFields,
+    pub synthetic_stats: SyntheticStats,
+    pub enricher: Enricher,
+    pub downlink_fallback: DownlinkFallback,
+    pub downlink_power: DownlinkPower,
+    pub hmac: Hmac,
+    pub encryption: Encryption,
+    pub tls: Tls,
 }
 
 impl Default for Server {
     fn default() -> Self {
         Server {
             server: "127.0.0.1:1700".into(),
+            gateway_id: "".into(),
             keepalive_interval_secs: 10,
             keepalive_max_failures: 12,
+            stats_interval_secs: 0,
+            interval_jitter_percent: 0,
+            token_seed: 0,
+            dns_refresh_interval_secs: 0,
+            dns_refresh_missed_acks: 1,
+            probe_addresses: false,
+            transport: Transport::Udp,
+            address_family: AddressFamily::Auto,
+            bind: "".into(),
+            bind_interface: "".into(),
+            reuse_port: false,
+            dscp: 0,
+            send_buffer_size: 0,
+            recv_buffer_size: 0,
+            split_sockets: false,
+            downlink_port: 0,
+            source_allowlist: vec![],
             forward_crc_ok: true,
             forward_crc_invalid: false,
             forward_crc_missing: false,
+            tx_ack_on_success: true,
+            downlink_timing_check: false,
+            downlink_queue_size: 16,
+            stat_bridge_object: false,
+            stat_with_rxpk: false,
+            fine_timestamp: true,
+            read_only: false,
+            role: ServerRole::Normal,
+            mirror_stats: false,
+            json_version: 1,
+            data_rate_index_region: "".into(),
+            rxpk_data_rate_index: false,
+            forward_rssis: false,
+            replay_window_secs: 0,
+            store_backend: StoreBackend::Memory,
+            store_path: "".into(),
+            store_url: "".into(),
+            dedup_ttl_ms: 0,
+            dedup_tmst_window_us: 0,
+            push_data_retransmit_count: 0,
+            push_data_retransmit_timeout_ms: 500,
+            buffer_max_size: 0,
+            buffer_max_age_secs: 3600,
+            buffer_path: "".into(),
+            batch_window_ms: 0,
+            batch_max_size: 1400,
+            filters: Filters::default(),
+            default_route: false,
+            routes: vec![],
+            channels: vec![],
+            channel_check: ChannelCheck::Flag,
+            downlink_plan: DownlinkPlan::default(),
+            rssi_offsets: vec![],
+            stat_fields: StatFields::default(),
+            synthetic_stats: SyntheticStats::default(),
+            enricher: Enricher::default(),
+            downlink_fallback: DownlinkFallback::default(),
+            downlink_power: DownlinkPower::default(),
+            hmac: Hmac::default(),
+            encryption: Encryption::default(),
+            tls: Tls::default(),
         }
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum StoreBackend {
+    #[serde(alias = "memory")]
+    #[default]
+    Memory,
+    #[serde(alias = "file")]
+    File,
+    #[serde(alias = "sled")]
+    Sled,
+    #[serde(alias = "redis")]
+    Redis,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum LogFormat {
+    #[serde(alias = "text")]
+    #[default]
+    Text,
+    #[serde(alias = "json")]
+    Json,
+}
+
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct Syslog {
+    pub server: String,
+    pub facility: String,
+    pub stdout: bool,
+}
+
+impl Default for Syslog {
+    fn default() -> Self {
+        Syslog {
+            server: "".into(),
+            facility: "USER".into(),
+            stdout: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Transport {
+    #[serde(alias = "udp")]
+    #[default]
+    Udp,
+    #[serde(alias = "tcp")]
+    Tcp,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum AddressFamily {
+    #[serde(alias = "auto")]
+    #[default]
+    Auto,
+    #[serde(alias = "ipv4")]
+    Ipv4,
+    #[serde(alias = "ipv6")]
+    Ipv6,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum BackendType {
+    #[serde(alias = "concentratord")]
+    #[default]
+    Concentratord,
+    #[serde(alias = "mock")]
+    Mock,
+}
+
+// Synthetic uplinks and scripted downlink outcomes, for testing without
+// radio hardware. The spreading factor and frequency of each uplink are
+// picked randomly from the lists (repeat a value to increase its weight).
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct MockBackend {
+    pub gateway_id: String,
+    pub uplink_interval_ms: u64,
+    pub spreading_factors: Vec<u32>,
+    pub frequencies: Vec<u32>,
+    pub min_payload_size: usize,
+    pub max_payload_size: usize,
+    pub stats_interval_secs: u64,
+    pub tx_ack_statuses: Vec<String>,
+}
+
+impl Default for MockBackend {
+    fn default() -> Self {
+        MockBackend {
+            gateway_id: "0000000000000001".into(),
+            uplink_interval_ms: 1000,
+            spreading_factors: vec![7, 8, 9, 10, 11, 12],
+            frequencies: vec![868100000, 868300000, 868500000],
+            min_payload_size: 13,
+            max_payload_size: 64,
+            stats_interval_secs: 30,
+            tx_ack_statuses: vec!["OK".into()],
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum DutyCycleMode {
+    #[default]
+    Disabled,
+    Track,
+    Enforce,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum ServerRole {
+    #[default]
+    Normal,
+    Mirror,
+}
+
+#[derive(Deserialize, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct Channel {
+    pub name: String,
+    pub sub_band: String,
+    pub frequency: u32,
+    pub rf_chain: u32,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum ChannelCheck {
+    #[default]
+    Flag,
+    Correct,
+}
+
+#[derive(Deserialize, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct StatFields {
+    pub platform: String,
+    pub email: String,
+    pub description: String,
+    pub forward_metadata: bool,
+    pub custom: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: u32,
+    pub gpsd_server: String,
+}
+
+// Capture of the UDP datagrams (JSONL and / or pcapng), disabled when the
+// path is empty.
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct Capture {
+    pub path: String,
+    pub pcap_path: String,
+    pub max_size_kb: u64,
+    pub max_files: u32,
+}
+
+impl Default for Capture {
+    fn default() -> Self {
+        Capture {
+            path: "".into(),
+            pcap_path: "".into(),
+            max_size_kb: 10240,
+            max_files: 5,
+        }
+    }
+}
+
+// MQTT frontend, using the ChirpStack v4 gateway topics, disabled when the
+// server is empty.
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct Mqtt {
+    pub server: String,
+    pub topic_prefix: String,
+    pub client_id: String,
+    pub username: String,
+    pub password: String,
+    pub keep_alive_secs: u16,
+    pub reconnect_interval_secs: u64,
+    pub ack_required_events: Vec<String>,
+    pub max_inflight: usize,
+    pub redelivery_interval_secs: u64,
+    pub read_only: bool,
+}
+
+impl Default for Mqtt {
+    fn default() -> Self {
+        Mqtt {
+            server: "".into(),
+            topic_prefix: "eu868".into(),
+            client_id: "".into(),
+            username: "".into(),
+            password: "".into(),
+            keep_alive_secs: 30,
+            reconnect_interval_secs: 5,
+            ack_required_events: vec![],
+            max_inflight: 16,
+            redelivery_interval_secs: 10,
+            read_only: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct RssiOffset {
+    pub rf_chain: u32,
+    pub antenna: u32,
+    pub offset: i32,
+}
+
+// Limits of the downlinks, a value of 0 (or an empty list) is not checked.
+#[derive(Deserialize, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct DownlinkPlan {
+    pub frequencies: Vec<u32>,
+    pub min_frequency: u32,
+    pub max_frequency: u32,
+    pub min_power: i32,
+    pub max_power: i32,
+    pub data_rates: Vec<String>,
+}
+
+#[derive(Deserialize, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct Filters {
+    pub dev_addr_prefixes: Vec<String>,
+    pub net_ids: Vec<String>,
+    pub join_eui_prefixes: Vec<String>,
+    pub deny_dev_addr_prefixes: Vec<String>,
+    pub deny_net_ids: Vec<String>,
+    pub deny_join_eui_prefixes: Vec<String>,
+}
+
+#[derive(Deserialize, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct SyntheticStats {
+    pub interval_secs: u64,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: u32,
+}
+
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct Enricher {
+    pub url: String,
+    pub timeout_ms: u64,
+    pub max_failures: u32,
+    pub open_secs: u64,
+}
+
+impl Default for Enricher {
+    fn default() -> Self {
+        Enricher {
+            url: "".into(),
+            timeout_ms: 20,
+            max_failures: 5,
+            open_secs: 30,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct DownlinkFallback {
+    pub frequency: u32,
+    pub spreading_factor: u32,
+    pub bandwidth: u32,
+    pub power: i32,
+    pub delay_ms: u32,
+}
+
+impl Default for DownlinkFallback {
+    fn default() -> Self {
+        DownlinkFallback {
+            frequency: 0,
+            spreading_factor: 0,
+            bandwidth: 0,
+            power: 0,
+            delay_ms: 1000,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum PowerReference {
+    Eirp,
+    Conducted,
+}
+
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct DownlinkPower {
+    pub reference: PowerReference,
+    pub antenna_gain: i32,
+    pub max_power: i32,
+    pub rf_chains: Vec<RfChainPower>,
+}
+
+impl Default for DownlinkPower {
+    fn default() -> Self {
+        DownlinkPower {
+            reference: PowerReference::Eirp,
+            antenna_gain: 0,
+            max_power: 0,
+            rf_chains: vec![],
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct RfChainPower {
+    pub rf_chain: u32,
+    pub antenna_gain: i32,
+    pub cable_loss: i32,
+}
+
+#[derive(Deserialize, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct Encryption {
+    pub keys: Vec<EncryptionKey>,
+}
+
+#[derive(Deserialize, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct EncryptionKey {
+    pub id: u8,
+    pub key: String,
+    // RFC3339 time from which the key is used, empty to use it immediately.
+    pub not_before: String,
+}
+
+#[derive(Deserialize, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct Tls {
+    pub enabled: bool,
+    // PEM file of the CA certificate(s), empty to use the built-in roots.
+    pub ca_cert: String,
+    // Name to verify the server certificate against, empty to use the host
+    // of the server.
+    pub server_name: String,
+}
+
+#[derive(Deserialize, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct Hmac {
+    pub key: String,
+    pub mode: HmacMode,
+}
+
+impl Hmac {
+    // Min. size of the key (bytes).
+    pub const MIN_KEY_SIZE: usize = 16;
+
+    // Returns the decoded key, None when HMAC is disabled.
+    pub fn get_key(&self) -> Result<Option<Vec<u8>>> {
+        if self.key.is_empty() {
+            return match self.mode {
+                HmacMode::Required => Err(anyhow!("hmac mode REQUIRED requires a key")),
+                HmacMode::Optional => Ok(None),
+            };
+        }
+
+        let key = hex::decode(&self.key).map_err(|e| anyhow!("invalid hmac key: {}", e))?;
+        if key.len() < Hmac::MIN_KEY_SIZE {
+            return Err(anyhow!(
+                "hmac key is shorter than {} bytes",
+                Hmac::MIN_KEY_SIZE
+            ));
+        }
+
+        Ok(Some(key))
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum HmacMode {
+    #[serde(alias = "optional")]
+    #[default]
+    Optional,
+    #[serde(alias = "required")]
+    Required,
+}
+
+#[derive(Deserialize, Clone, PartialEq)]
 #[serde(default)]
 pub struct Concentratord {
     pub event_url: String,
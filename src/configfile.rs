@@ -0,0 +1,32 @@
+// The configuration example of the README, which documents all options.
+const README: &str = include_str!("../README.md");
+
+// Prints the fully commented configuration example.
+pub fn run() {
+    print!("{}", example());
+}
+
+fn example() -> &'static str {
+    let start = README.find("```toml\n").map(|i| i + 8).unwrap_or(0);
+    let end = README[start..]
+        .find("\n```")
+        .map(|i| start + i + 1)
+        .unwrap_or(start);
+    &README[start..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Configuration;
+
+    #[test]
+    fn test_example() {
+        let example = example();
+        assert!(example.starts_with("# UDP Forwarder configuration.\n[udp_forwarder]\n"));
+        assert!(example.ends_with("\n"));
+
+        let conf: Configuration = toml::from_str(example).unwrap();
+        assert!(!conf.udp_forwarder.servers.is_empty());
+    }
+}
@@ -0,0 +1,120 @@
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ConnectionState {
+    // No acknowledgement received yet.
+    Unknown,
+    Online,
+    // Acknowledgements are missing, the server might be unreachable.
+    Degraded,
+    Offline,
+}
+
+impl ConnectionState {
+    pub const ALL: [ConnectionState; 4] = [
+        ConnectionState::Unknown,
+        ConnectionState::Online,
+        ConnectionState::Degraded,
+        ConnectionState::Offline,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConnectionState::Unknown => "UNKNOWN",
+            ConnectionState::Online => "ONLINE",
+            ConnectionState::Degraded => "DEGRADED",
+            ConnectionState::Offline => "OFFLINE",
+        }
+    }
+}
+
+// Tracks the connection state of a server, based on the time since the last
+// acknowledgement (PULL_ACK or PUSH_ACK). The server is online when an
+// acknowledgement was received within two keepalive intervals (allowing for
+// a single missed PULL_ACK), degraded until the max. keepalive failures have
+// been reached and offline after that.
+pub struct ConnectionTracker {
+    state: ConnectionState,
+    last_ack: Option<Instant>,
+    online_timeout: Duration,
+    offline_timeout: Duration,
+}
+
+impl ConnectionTracker {
+    pub fn new(keepalive_interval: Duration, keepalive_max_failures: u32) -> Self {
+        ConnectionTracker {
+            state: ConnectionState::Unknown,
+            last_ack: None,
+            online_timeout: keepalive_interval * 2,
+            offline_timeout: keepalive_interval * (keepalive_max_failures + 1).max(2),
+        }
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    // Registers an acknowledgement and returns the state transition, if any.
+    pub fn ack_received(&mut self, now: Instant) -> Option<(ConnectionState, ConnectionState)> {
+        self.last_ack = Some(now);
+        self.update(now)
+    }
+
+    // Updates the state and returns the state transition, if any.
+    pub fn update(&mut self, now: Instant) -> Option<(ConnectionState, ConnectionState)> {
+        let state = match self.last_ack {
+            None => ConnectionState::Unknown,
+            Some(v) => {
+                let elapsed = now.saturating_duration_since(v);
+                if elapsed < self.online_timeout {
+                    ConnectionState::Online
+                } else if elapsed < self.offline_timeout {
+                    ConnectionState::Degraded
+                } else {
+                    ConnectionState::Offline
+                }
+            }
+        };
+
+        if state == self.state {
+            return None;
+        }
+
+        let previous = std::mem::replace(&mut self.state, state);
+        Some((previous, state))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_tracker() {
+        let mut t = ConnectionTracker::new(Duration::from_secs(10), 3);
+        let now = Instant::now();
+
+        assert_eq!(t.update(now), None);
+        assert_eq!(t.state(), ConnectionState::Unknown);
+
+        assert_eq!(
+            t.ack_received(now),
+            Some((ConnectionState::Unknown, ConnectionState::Online))
+        );
+        assert_eq!(t.ack_received(now + Duration::from_secs(10)), None);
+
+        assert_eq!(t.update(now + Duration::from_secs(25)), None);
+        assert_eq!(
+            t.update(now + Duration::from_secs(30)),
+            Some((ConnectionState::Online, ConnectionState::Degraded))
+        );
+        assert_eq!(
+            t.update(now + Duration::from_secs(50)),
+            Some((ConnectionState::Degraded, ConnectionState::Offline))
+        );
+        assert_eq!(
+            t.ack_received(now + Duration::from_secs(60)),
+            Some((ConnectionState::Offline, ConnectionState::Online))
+        );
+    }
+}
@@ -0,0 +1,91 @@
+use std::backtrace::Backtrace;
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::fs;
+use std::panic;
+use std::process;
+use std::sync::Mutex;
+use std::thread;
+
+use chrono::Utc;
+
+use super::config;
+
+// Number of recent events included in the crash report.
+const RECENT_EVENTS: usize = 32;
+
+lazy_static! {
+    static ref RECENT: Mutex<VecDeque<String>> = Mutex::new(VecDeque::with_capacity(RECENT_EVENTS));
+}
+
+// Records an event, to be included in the crash report.
+pub fn record(event: String) {
+    let mut recent = RECENT.lock().unwrap();
+    if recent.len() == RECENT_EVENTS {
+        recent.pop_front();
+    }
+    recent.push_back(format!("{} {}", Utc::now().to_rfc3339(), event));
+}
+
+// Installs a panic hook which logs the crash report, writes it to the given
+// path (if set) and aborts the process, so that it is restarted by the init
+// system instead of continuing with a dead thread.
+pub fn install(path: String) {
+    panic::set_hook(Box::new(move |info| {
+        let report = report(&info.to_string());
+        error!("Panic, crash report:\n{}", report);
+
+        if !path.is_empty() {
+            if let Err(e) = fs::write(&path, &report) {
+                error!("Write crash report error: {}, path: {}", e, path);
+            }
+        }
+
+        process::abort();
+    }));
+}
+
+fn report(panic: &str) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "time: {}", Utc::now().to_rfc3339());
+    let _ = writeln!(out, "version: {}", config::VERSION);
+    let _ = writeln!(
+        out,
+        "thread: {}",
+        thread::current().name().unwrap_or("<unnamed>")
+    );
+    let _ = writeln!(out, "panic: {}", panic);
+
+    // The lock might be held by the panicking thread.
+    let _ = writeln!(out, "recent events:");
+    match RECENT.try_lock() {
+        Ok(recent) => {
+            for e in recent.iter() {
+                let _ = writeln!(out, "  {}", e);
+            }
+        }
+        Err(_) => {
+            let _ = writeln!(out, "  <unavailable>");
+        }
+    }
+
+    let _ = writeln!(out, "backtrace:\n{}", Backtrace::force_capture());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        for i in 0..RECENT_EVENTS + 1 {
+            record(format!("event {}", i));
+        }
+
+        let report = report("boom");
+        assert!(report.contains("panic: boom\n"));
+        assert!(!report.contains("event 0\n"));
+        assert!(report.contains(&format!("event {}\n", RECENT_EVENTS)));
+    }
+}
@@ -0,0 +1,194 @@
+use std::convert::TryFrom;
+
+use anyhow::Result;
+use base64::{engine::general_purpose, Engine as _};
+use chirpstack_udp_forwarder::protocol::{Frame, MessageType};
+
+const MTYPES: [&str; 8] = [
+    "JoinRequest",
+    "JoinAccept",
+    "UnconfirmedDataUp",
+    "UnconfirmedDataDown",
+    "ConfirmedDataUp",
+    "ConfirmedDataDown",
+    "RejoinRequest",
+    "Proprietary",
+];
+
+// Decodes the (hex or base64 encoded) datagram and prints its description.
+pub fn run(input: &str) -> Result<()> {
+    let b = parse_input(input)?;
+    print!("{}", describe(&b)?);
+    Ok(())
+}
+
+// Returns the datagram bytes, the input is either hex (optionally 0x
+// prefixed) or base64 encoded.
+fn parse_input(input: &str) -> Result<Vec<u8>> {
+    let input: String = input.chars().filter(|c| !c.is_whitespace()).collect();
+    let hex_input = input.strip_prefix("0x").unwrap_or(&input);
+    if let Ok(v) = hex::decode(hex_input) {
+        return Ok(v);
+    }
+
+    general_purpose::STANDARD
+        .decode(&input)
+        .map_err(|_| anyhow!("input is neither hex nor base64 encoded"))
+}
+
+// Returns the header fields, the pretty-printed JSON body and a summary of
+// the PHYPayloads of the datagram.
+fn describe(b: &[u8]) -> Result<String> {
+    if b.len() < 4 {
+        return Err(anyhow!("expected at least 4 bytes, got: {}", b.len()));
+    }
+
+    let message_type = MessageType::try_from(b[3])?;
+    let mut out = String::new();
+    out.push_str(&format!("Version:    {}\n", b[0]));
+    let token = u16::from_be_bytes([b[1], b[2]]);
+    out.push_str(&format!("Token:      0x{:04x} ({})\n", token, token));
+    out.push_str(&format!("Type:       {}\n", message_type.as_str()));
+
+    // PUSH_DATA, PULL_DATA and TX_ACK contain the gateway ID.
+    let offset = match message_type {
+        MessageType::PushData | MessageType::PullData | MessageType::TxAck if b.len() >= 12 => {
+            out.push_str(&format!("Gateway ID: {}\n", hex::encode(&b[4..12])));
+            12
+        }
+        _ => 4,
+    };
+
+    // The frame is still printed when it does not strictly conform to the
+    // protocol, e.g. when sent by a different packet-forwarder.
+    if let Err(e) = Frame::from_bytes(b) {
+        out.push_str(&format!("Warning:    {}\n", e));
+    }
+
+    // Trailing null bytes are ignored.
+    let mut end = b.len();
+    while end > offset && b[end - 1] == 0 {
+        end -= 1;
+    }
+    let body = &b[offset..end];
+    if body.is_empty() {
+        return Ok(out);
+    }
+
+    let json: serde_json::Value = serde_json::from_slice(body)?;
+    out.push_str(&format!(
+        "Payload:\n{}\n",
+        serde_json::to_string_pretty(&json)?
+    ));
+
+    let mut pks: Vec<(String, &serde_json::Value)> = vec![];
+    if let Some(rxpk) = json["rxpk"].as_array() {
+        for (i, pk) in rxpk.iter().enumerate() {
+            pks.push((format!("rxpk[{}]", i), pk));
+        }
+    }
+    if json["txpk"].is_object() {
+        pks.push(("txpk".into(), &json["txpk"]));
+    }
+
+    for (name, pk) in pks {
+        if let Some(data) = pk["data"].as_str() {
+            let summary = match general_purpose::STANDARD.decode(data) {
+                Ok(v) => phy_payload_summary(&v),
+                Err(e) => format!("invalid base64: {}", e),
+            };
+            out.push_str(&format!("{} PHYPayload: {}\n", name, summary));
+        }
+    }
+
+    Ok(out)
+}
+
+// Returns the summary of the LoRaWAN PHYPayload. The payload is not decrypted
+// nor validated.
+fn phy_payload_summary(b: &[u8]) -> String {
+    if b.len() < 5 {
+        return format!("too short ({} bytes)", b.len());
+    }
+
+    let mut out = format!(
+        "MType={}, Major={}, size={}",
+        MTYPES[(b[0] >> 5) as usize],
+        b[0] & 0x03,
+        b.len()
+    );
+
+    match b[0] >> 5 {
+        // JoinRequest: MHDR + JoinEUI + DevEUI + DevNonce + MIC
+        0x00 if b.len() == 23 => {
+            let mut join_eui = b[1..9].to_vec();
+            join_eui.reverse();
+            let mut dev_eui = b[9..17].to_vec();
+            dev_eui.reverse();
+            out.push_str(&format!(
+                ", JoinEUI={}, DevEUI={}, DevNonce={}",
+                hex::encode(join_eui),
+                hex::encode(dev_eui),
+                u16::from_le_bytes([b[17], b[18]])
+            ));
+        }
+        // Data frames: MHDR + DevAddr + FCtrl + FCnt + FOpts + [FPort] + MIC
+        0x02..=0x05 if b.len() >= 12 => {
+            let mut dev_addr = b[1..5].to_vec();
+            dev_addr.reverse();
+            let f_ctrl = b[5];
+            out.push_str(&format!(
+                ", DevAddr={}, ADR={}, ACK={}, FCnt={}, FOptsLen={}",
+                hex::encode(dev_addr),
+                f_ctrl & 0x80 != 0,
+                f_ctrl & 0x20 != 0,
+                u16::from_le_bytes([b[6], b[7]]),
+                f_ctrl & 0x0f
+            ));
+
+            let f_port = 8 + (f_ctrl & 0x0f) as usize;
+            if f_port < b.len() - 4 {
+                out.push_str(&format!(", FPort={}", b[f_port]));
+            }
+        }
+        _ => {}
+    }
+
+    out.push_str(&format!(", MIC={}", hex::encode(&b[b.len() - 4..])));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let mut b = vec![2, 0x12, 0x34, 0, 1, 2, 3, 4, 5, 6, 7, 8];
+        b.extend_from_slice(br#"{"rxpk":[{"data":"QAQDAgGACgEBAgMEBQY="}]}"#);
+        assert_eq!(parse_input(&hex::encode(&b)).unwrap(), b);
+        assert_eq!(
+            parse_input(&general_purpose::STANDARD.encode(&b)).unwrap(),
+            b
+        );
+
+        // the rxpk is incomplete
+        let out = describe(&b).unwrap();
+        assert!(out.contains("Warning:    "));
+        assert!(out.contains("Token:      0x1234 (4660)\n"));
+        assert!(out.contains("Type:       PUSH_DATA\n"));
+        assert!(out.contains("Gateway ID: 0102030405060708\n"));
+        assert!(out.contains(
+            "rxpk[0] PHYPayload: MType=UnconfirmedDataUp, Major=0, size=14, DevAddr=01020304, ADR=true, ACK=false, FCnt=266, FOptsLen=0, FPort=1, MIC=03040506\n"
+        ));
+
+        let out = describe(&[2, 0x12, 0x34, 4]).unwrap();
+        assert_eq!(
+            out,
+            "Version:    2\nToken:      0x1234 (4660)\nType:       PULL_ACK\n"
+        );
+
+        assert!(describe(&[2, 0x12]).is_err());
+        assert!(parse_input("not-valid!").is_err());
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::hash_map::DefaultHasher;
+use std::convert::TryInto;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, SystemTime};
+
+use super::store::SharedStore;
+
+// Cache of the PHYPayload hashes (and tmst) of the uplinks seen within the
+// TTL. An uplink with the same PHYPayload and a tmst within the tmst window
+// is considered a duplicate, e.g. the same frame emitted twice by the
+// Concentratord. The hashes are kept in the state store.
+pub struct DedupCache {
+    ttl: Duration,
+    // When 0, the tmst is not compared (e.g. multiple concentrators with
+    // unrelated counters).
+    tmst_window_us: u32,
+    store: SharedStore,
+}
+
+impl DedupCache {
+    pub fn new(ttl: Duration, tmst_window_us: u32, store: SharedStore) -> Self {
+        DedupCache {
+            ttl,
+            tmst_window_us,
+            store,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.store
+            .lock()
+            .unwrap()
+            .scan(b"dedup/", SystemTime::now())
+            .len()
+    }
+
+    // Returns true when the uplink is a duplicate of an uplink seen within
+    // the TTL.
+    pub fn is_duplicate(&mut self, phy_payload: &[u8], tmst: u32, now: SystemTime) -> bool {
+        let mut hasher = DefaultHasher::new();
+        phy_payload.hash(&mut hasher);
+        let key = [&b"dedup/"[..], &hasher.finish().to_be_bytes()].concat();
+
+        let mut store = self.store.lock().unwrap();
+        let seen = store
+            .get(&key, now)
+            .and_then(|v| v.as_slice().try_into().ok())
+            .map(u32::from_be_bytes);
+
+        let duplicate = match seen {
+            Some(seen_tmst) => {
+                // wrapping_sub handles the tmst rollover
+                let diff = tmst
+                    .wrapping_sub(seen_tmst)
+                    .min(seen_tmst.wrapping_sub(tmst));
+                self.tmst_window_us == 0 || diff <= self.tmst_window_us
+            }
+            None => false,
+        };
+
+        if !duplicate {
+            store.put(&key, &tmst.to_be_bytes(), self.ttl, now);
+        }
+        duplicate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::store::MemoryStore;
+
+    fn memory_store() -> SharedStore {
+        Arc::new(Mutex::new(Box::new(MemoryStore::default())))
+    }
+
+    #[test]
+    fn test_dedup_cache() {
+        let mut cache = DedupCache::new(Duration::from_millis(500), 1000, memory_store());
+        let now = SystemTime::now();
+
+        assert!(!cache.is_duplicate(&[1, 2, 3], 100, now));
+        assert!(!cache.is_duplicate(&[1, 2, 4], 100, now));
+        assert!(cache.is_duplicate(&[1, 2, 3], 600, now));
+
+        // tmst outside the window, including the rollover
+        assert!(!cache.is_duplicate(&[1, 2, 4], 5000, now));
+        assert!(!cache.is_duplicate(&[5], u32::MAX - 100, now));
+        assert!(cache.is_duplicate(&[5], 200, now));
+
+        // outside the ttl
+        assert!(!cache.is_duplicate(&[1, 2, 3], 100, now + Duration::from_millis(500)));
+
+        // tmst not compared
+        let mut cache = DedupCache::new(Duration::from_millis(500), 0, memory_store());
+        assert!(!cache.is_duplicate(&[1, 2, 3], 100, now));
+        assert!(cache.is_duplicate(&[1, 2, 3], 900000, now));
+    }
+}
@@ -0,0 +1,398 @@
+use anyhow::Result;
+use chirpstack_api::gw;
+
+use super::airtime;
+use super::config;
+
+// Min. time between the downlink being handed to the Concentratord and its
+// transmission, below which it can not be scheduled anymore.
+const JIT_MIN_LEAD_US: i64 = 20_000;
+
+// Max. time a downlink can be scheduled in advance, like the TX_MAX_ADVANCE_DELAY
+// of the Semtech packet-forwarder JIT queue.
+const JIT_MAX_ADVANCE_US: i64 = 3 * 128 * 1_000_000;
+
+// Appends a fallback item to the downlink, derived from the first item, e.g.
+// to transmit in RX2 when the RX1 transmission can not be scheduled. This is
+// only possible for downlinks timed relative to the uplink (tmst), as the
+// fallback item is delayed relative to the first item.
+pub fn add_fallback_item(pl: &mut gw::DownlinkFrame, conf: &config::DownlinkFallback) {
+    let mut item = match pl.items.first() {
+        Some(v) => v.clone(),
+        None => return,
+    };
+
+    let tx_info = match &mut item.tx_info {
+        Some(v) => v,
+        None => return,
+    };
+
+    let delay_timing = matches!(
+        tx_info.timing.as_ref().and_then(|v| v.parameters.as_ref()),
+        Some(gw::timing::Parameters::Delay(_))
+    );
+    if !delay_timing || tx_info.context.len() != 4 {
+        return;
+    }
+
+    let mut tmst: [u8; 4] = [0; 4];
+    tmst.copy_from_slice(&tx_info.context);
+    let tmst = u32::from_be_bytes(tmst).wrapping_add(conf.delay_ms * 1000);
+
+    tx_info.context = tmst.to_be_bytes().to_vec();
+    tx_info.frequency = conf.frequency;
+    if conf.power != 0 {
+        tx_info.power = conf.power;
+    }
+
+    if let Some(gw::modulation::Parameters::Lora(v)) = tx_info
+        .modulation
+        .as_mut()
+        .and_then(|v| v.parameters.as_mut())
+    {
+        if conf.spreading_factor != 0 {
+            v.spreading_factor = conf.spreading_factor;
+        }
+        if conf.bandwidth != 0 {
+            v.bandwidth = conf.bandwidth;
+        }
+    }
+
+    pl.items.push(item);
+}
+
+// Converts the power of each item to EIRP, as expected by the Concentratord,
+// using the antenna gain and cable loss of the RF chain, and caps it at the
+// max. EIRP. Returns the (EIRP, conducted) power of the first item and
+// whether it was capped.
+pub fn apply_power(
+    pl: &mut gw::DownlinkFrame,
+    conf: &config::DownlinkPower,
+    rf_chain: u32,
+) -> (i32, i32, bool) {
+    let gain = match conf.rf_chains.iter().find(|v| v.rf_chain == rf_chain) {
+        Some(v) => v.antenna_gain - v.cable_loss,
+        None => conf.antenna_gain,
+    };
+    let mut out = (0, 0, false);
+
+    for (i, item) in pl.items.iter_mut().enumerate() {
+        let tx_info = match &mut item.tx_info {
+            Some(v) => v,
+            None => continue,
+        };
+
+        let (mut eirp, mut conducted) = match conf.reference {
+            config::PowerReference::Eirp => (tx_info.power, tx_info.power - gain),
+            config::PowerReference::Conducted => (tx_info.power + gain, tx_info.power),
+        };
+        let capped = conf.max_power != 0 && eirp > conf.max_power;
+        if capped {
+            conducted -= eirp - conf.max_power;
+            eirp = conf.max_power;
+        }
+        tx_info.power = eirp;
+
+        if i == 0 {
+            out = (eirp, conducted, capped);
+        }
+    }
+
+    out
+}
+
+// Returns the index of the transmitted item (if any) and the status to report.
+// When no item was transmitted, the status of the first item is returned.
+pub fn get_tx_ack_status(ack: &gw::DownlinkTxAck) -> Result<(Option<usize>, gw::TxAckStatus)> {
+    if ack.items.is_empty() {
+        return Err(anyhow!("tx ack does not contain any items"));
+    }
+
+    match ack
+        .items
+        .iter()
+        .position(|v| v.status() == gw::TxAckStatus::Ok)
+    {
+        Some(i) => Ok((Some(i), gw::TxAckStatus::Ok)),
+        None => Ok((None, ack.items[0].status())),
+    }
+}
+
+// Validates the timing of the downlink items (timed relative to the uplink)
+// against the estimated concentrator counter. Returns the status to report
+// when none of the items can be scheduled, None otherwise.
+pub fn check_timing(pl: &gw::DownlinkFrame, counter: u32) -> Option<gw::TxAckStatus> {
+    let mut status = None;
+
+    for item in &pl.items {
+        let tx_info = match &item.tx_info {
+            Some(v) if v.context.len() == 4 => v,
+            _ => return None,
+        };
+        let delay_us = match tx_info.timing.as_ref().and_then(|v| v.parameters.as_ref()) {
+            Some(gw::timing::Parameters::Delay(v)) => v
+                .delay
+                .as_ref()
+                .map(|d| d.seconds * 1_000_000 + (d.nanos / 1000) as i64)
+                .unwrap_or_default(),
+            _ => return None,
+        };
+
+        let mut tmst: [u8; 4] = [0; 4];
+        tmst.copy_from_slice(&tx_info.context);
+        // The signed difference handles the counter rollover.
+        let lead_us = u32::from_be_bytes(tmst).wrapping_sub(counter) as i32 as i64 + delay_us;
+
+        let item_status = if lead_us < JIT_MIN_LEAD_US {
+            gw::TxAckStatus::TooLate
+        } else if lead_us > JIT_MAX_ADVANCE_US {
+            gw::TxAckStatus::TooEarly
+        } else {
+            return None;
+        };
+        status.get_or_insert(item_status);
+    }
+
+    status
+}
+
+// Validates the downlink items (EIRP) against the downlink plan. Returns the
+// status and reason to report for the first item outside the plan, None
+// otherwise. The Semtech protocol does not define a data-rate error, an
+// illegal data-rate is reported as TX_FREQ.
+pub fn check_plan(
+    pl: &gw::DownlinkFrame,
+    plan: &config::DownlinkPlan,
+) -> Option<(gw::TxAckStatus, &'static str)> {
+    for item in &pl.items {
+        let tx_info = match &item.tx_info {
+            Some(v) => v,
+            None => continue,
+        };
+
+        let freq = tx_info.frequency;
+        if (!plan.frequencies.is_empty() && !plan.frequencies.contains(&freq))
+            || (plan.min_frequency != 0 && freq < plan.min_frequency)
+            || (plan.max_frequency != 0 && freq > plan.max_frequency)
+        {
+            return Some((gw::TxAckStatus::TxFreq, "TX_FREQ"));
+        }
+
+        let power = tx_info.power;
+        if (plan.min_power != 0 && power < plan.min_power)
+            || (plan.max_power != 0 && power > plan.max_power)
+        {
+            return Some((gw::TxAckStatus::TxPower, "TX_POWER"));
+        }
+
+        if !plan.data_rates.is_empty() {
+            let datr = tx_info
+                .modulation
+                .as_ref()
+                .map(airtime::label)
+                .unwrap_or_default();
+            if !plan.data_rates.contains(&datr) {
+                return Some((gw::TxAckStatus::TxFreq, "DATA_RATE"));
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_fallback_item() {
+        let mut pl = gw::DownlinkFrame {
+            items: vec![gw::DownlinkFrameItem {
+                tx_info: Some(gw::DownlinkTxInfo {
+                    frequency: 868100000,
+                    power: 14,
+                    modulation: Some(gw::Modulation {
+                        parameters: Some(gw::modulation::Parameters::Lora(
+                            gw::LoraModulationInfo {
+                                bandwidth: 125000,
+                                spreading_factor: 7,
+                                ..Default::default()
+                            },
+                        )),
+                    }),
+                    timing: Some(gw::Timing {
+                        parameters: Some(gw::timing::Parameters::Delay(
+                            gw::DelayTimingInfo::default(),
+                        )),
+                    }),
+                    context: vec![0, 0, 0, 1],
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        add_fallback_item(
+            &mut pl,
+            &config::DownlinkFallback {
+                frequency: 869525000,
+                spreading_factor: 12,
+                bandwidth: 0,
+                power: 27,
+                delay_ms: 1000,
+            },
+        );
+
+        assert_eq!(pl.items.len(), 2);
+        let tx_info = pl.items[1].tx_info.as_ref().unwrap();
+        assert_eq!(tx_info.frequency, 869525000);
+        assert_eq!(tx_info.power, 27);
+        assert_eq!(tx_info.context, 1_000_001u32.to_be_bytes().to_vec());
+        match tx_info
+            .modulation
+            .as_ref()
+            .and_then(|v| v.parameters.as_ref())
+        {
+            Some(gw::modulation::Parameters::Lora(v)) => {
+                assert_eq!(v.spreading_factor, 12);
+                assert_eq!(v.bandwidth, 125000);
+            }
+            _ => panic!("LoRa modulation expected"),
+        }
+
+        let (eirp, conducted, capped) = apply_power(
+            &mut pl,
+            &config::DownlinkPower {
+                reference: config::PowerReference::Conducted,
+                antenna_gain: 2,
+                ..Default::default()
+            },
+            0,
+        );
+        assert_eq!((eirp, conducted, capped), (16, 14, false));
+        assert_eq!(pl.items[1].tx_info.as_ref().unwrap().power, 29);
+
+        // RF chain gain and loss, capped at the max. EIRP
+        let conf = config::DownlinkPower {
+            reference: config::PowerReference::Eirp,
+            antenna_gain: 2,
+            max_power: 20,
+            rf_chains: vec![config::RfChainPower {
+                rf_chain: 1,
+                antenna_gain: 6,
+                cable_loss: 2,
+            }],
+        };
+        assert_eq!(apply_power(&mut pl, &conf, 1), (16, 12, false));
+        assert_eq!(pl.items[1].tx_info.as_ref().unwrap().power, 20);
+        pl.items[0].tx_info.as_mut().unwrap().power = 23;
+        assert_eq!(apply_power(&mut pl, &conf, 1), (20, 16, true));
+
+        let ack = gw::DownlinkTxAck {
+            items: vec![
+                gw::DownlinkTxAckItem {
+                    status: gw::TxAckStatus::TooLate.into(),
+                },
+                gw::DownlinkTxAckItem {
+                    status: gw::TxAckStatus::Ok.into(),
+                },
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            get_tx_ack_status(&ack).unwrap(),
+            (Some(1), gw::TxAckStatus::Ok)
+        );
+    }
+
+    #[test]
+    fn test_check_timing() {
+        let item = |tmst: u32| gw::DownlinkFrameItem {
+            tx_info: Some(gw::DownlinkTxInfo {
+                timing: Some(gw::Timing {
+                    parameters: Some(gw::timing::Parameters::Delay(gw::DelayTimingInfo::default())),
+                }),
+                context: tmst.to_be_bytes().to_vec(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let mut pl = gw::DownlinkFrame {
+            items: vec![item(1_000_000)],
+            ..Default::default()
+        };
+        assert_eq!(check_timing(&pl, 0), None);
+        assert_eq!(check_timing(&pl, 990_000), Some(gw::TxAckStatus::TooLate));
+        assert_eq!(check_timing(&pl, 2_000_000), Some(gw::TxAckStatus::TooLate));
+        assert_eq!(
+            check_timing(&pl, 1_000_000u32.wrapping_sub(400_000_000)),
+            Some(gw::TxAckStatus::TooEarly)
+        );
+
+        // counter rollover
+        assert_eq!(check_timing(&pl, u32::MAX - 1_000_000), None);
+
+        // the fallback item can still be scheduled
+        pl.items.push(item(2_000_000));
+        assert_eq!(check_timing(&pl, 990_000), None);
+    }
+
+    #[test]
+    fn test_check_plan() {
+        let pl = |frequency, power, sf| gw::DownlinkFrame {
+            items: vec![gw::DownlinkFrameItem {
+                tx_info: Some(gw::DownlinkTxInfo {
+                    frequency,
+                    power,
+                    modulation: Some(gw::Modulation {
+                        parameters: Some(gw::modulation::Parameters::Lora(
+                            gw::LoraModulationInfo {
+                                bandwidth: 125000,
+                                spreading_factor: sf,
+                                ..Default::default()
+                            },
+                        )),
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let plan = config::DownlinkPlan {
+            frequencies: vec![],
+            min_frequency: 863000000,
+            max_frequency: 870000000,
+            min_power: 0,
+            max_power: 16,
+            data_rates: vec!["SF12BW125".into(), "SF7BW125".into()],
+        };
+
+        assert_eq!(check_plan(&pl(868100000, 14, 7), &plan), None);
+        assert_eq!(
+            check_plan(&pl(902300000, 14, 7), &plan),
+            Some((gw::TxAckStatus::TxFreq, "TX_FREQ"))
+        );
+        assert_eq!(
+            check_plan(&pl(868100000, 27, 7), &plan),
+            Some((gw::TxAckStatus::TxPower, "TX_POWER"))
+        );
+        assert_eq!(
+            check_plan(&pl(868100000, 14, 9), &plan),
+            Some((gw::TxAckStatus::TxFreq, "DATA_RATE"))
+        );
+
+        let plan = config::DownlinkPlan {
+            frequencies: vec![868100000],
+            ..Default::default()
+        };
+        assert_eq!(check_plan(&pl(868100000, 14, 9), &plan), None);
+        assert_eq!(
+            check_plan(&pl(868300000, 14, 9), &plan),
+            Some((gw::TxAckStatus::TxFreq, "TX_FREQ"))
+        );
+    }
+}
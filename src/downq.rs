@@ -0,0 +1,72 @@
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+// Bounded queue of the downlinks waiting to be sent to the Concentratord,
+// such that a slow Concentratord does not back up the receive loop and a
+// full queue can be reported to the server immediately.
+pub struct DownlinkQueue<T> {
+    queue: Mutex<VecDeque<T>>,
+    ready: Condvar,
+    max_size: usize,
+}
+
+impl<T> DownlinkQueue<T> {
+    pub fn new(max_size: usize) -> Self {
+        DownlinkQueue {
+            queue: Mutex::new(VecDeque::new()),
+            ready: Condvar::new(),
+            max_size,
+        }
+    }
+
+    // Queues the downlink, the downlink is returned when the queue is full.
+    pub fn push(&self, item: T) -> Result<(), T> {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() >= self.max_size {
+            return Err(item);
+        }
+
+        queue.push_back(item);
+        self.ready.notify_one();
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+
+    // Returns the oldest downlink, or None when no downlink has been queued
+    // within the timeout.
+    pub fn pop(&self, timeout: Duration) -> Option<T> {
+        let queue = self.queue.lock().unwrap();
+        let (mut queue, _) = self
+            .ready
+            .wait_timeout_while(queue, timeout, |q| q.is_empty())
+            .unwrap();
+
+        queue.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_downlink_queue() {
+        let q = DownlinkQueue::new(2);
+        assert_eq!(q.pop(Duration::from_millis(1)), None);
+
+        assert_eq!(q.push(1), Ok(()));
+        assert_eq!(q.push(2), Ok(()));
+        assert_eq!(q.push(3), Err(3));
+        assert_eq!(q.len(), 2);
+
+        assert_eq!(q.pop(Duration::from_millis(1)), Some(1));
+        assert_eq!(q.push(4), Ok(()));
+        assert_eq!(q.pop(Duration::from_millis(1)), Some(2));
+        assert_eq!(q.pop(Duration::from_millis(1)), Some(4));
+        assert_eq!(q.len(), 0);
+    }
+}
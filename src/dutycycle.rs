@@ -0,0 +1,176 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::config::DutyCycleMode;
+use super::metrics;
+
+// The duty cycle is accounted over a sliding window of one hour.
+const WINDOW: Duration = Duration::from_secs(3600);
+
+struct SubBand {
+    name: &'static str,
+    min_frequency: u32,
+    max_frequency: u32,
+    // Max. duty cycle, in permille.
+    limit_permille: u32,
+}
+
+// EU868 sub-bands (ETSI EN 300 220).
+const EU868_SUB_BANDS: [SubBand; 6] = [
+    SubBand {
+        name: "863-865",
+        min_frequency: 863000000,
+        max_frequency: 865000000,
+        limit_permille: 1,
+    },
+    SubBand {
+        name: "865-868",
+        min_frequency: 865000000,
+        max_frequency: 868000000,
+        limit_permille: 10,
+    },
+    SubBand {
+        name: "g1",
+        min_frequency: 868000000,
+        max_frequency: 868600000,
+        limit_permille: 10,
+    },
+    SubBand {
+        name: "g2",
+        min_frequency: 868700000,
+        max_frequency: 869200000,
+        limit_permille: 1,
+    },
+    SubBand {
+        name: "g3",
+        min_frequency: 869400000,
+        max_frequency: 869650000,
+        limit_permille: 100,
+    },
+    SubBand {
+        name: "g4",
+        min_frequency: 869700000,
+        max_frequency: 870000000,
+        limit_permille: 10,
+    },
+];
+
+lazy_static! {
+    static ref DUTY_CYCLE: Mutex<DutyCycle> = Mutex::new(DutyCycle::new(DutyCycleMode::Disabled));
+}
+
+// Time-on-air of the transmitted downlinks per sub-band. The accounting is
+// shared by all servers, as these share the same radio.
+struct DutyCycle {
+    mode: DutyCycleMode,
+    transmissions: Vec<VecDeque<(Instant, Duration)>>,
+}
+
+impl DutyCycle {
+    fn new(mode: DutyCycleMode) -> Self {
+        DutyCycle {
+            mode,
+            transmissions: EU868_SUB_BANDS.iter().map(|_| VecDeque::new()).collect(),
+        }
+    }
+
+    fn sub_band(frequency: u32) -> Option<usize> {
+        EU868_SUB_BANDS
+            .iter()
+            .position(|b| frequency >= b.min_frequency && frequency < b.max_frequency)
+    }
+
+    // Returns the remaining time-on-air budget of the sub-band.
+    fn remaining(&mut self, i: usize, now: Instant) -> Duration {
+        let transmissions = &mut self.transmissions[i];
+        while let Some((at, _)) = transmissions.front() {
+            if now.saturating_duration_since(*at) < WINDOW {
+                break;
+            }
+            transmissions.pop_front();
+        }
+
+        let used: Duration = transmissions.iter().map(|(_, d)| *d).sum();
+        let budget = WINDOW * EU868_SUB_BANDS[i].limit_permille / 1000;
+        budget.saturating_sub(used)
+    }
+
+    fn is_allowed(&mut self, frequency: u32, airtime: Duration, now: Instant) -> bool {
+        if self.mode != DutyCycleMode::Enforce {
+            return true;
+        }
+
+        match DutyCycle::sub_band(frequency) {
+            Some(i) => airtime <= self.remaining(i, now),
+            // Outside the EU868 sub-bands, nothing is enforced.
+            None => true,
+        }
+    }
+
+    fn transmitted(&mut self, frequency: u32, airtime: Duration, now: Instant) {
+        if self.mode == DutyCycleMode::Disabled {
+            return;
+        }
+
+        if let Some(i) = DutyCycle::sub_band(frequency) {
+            self.transmissions[i].push_back((now, airtime));
+            let remaining = self.remaining(i, now);
+            metrics::set_duty_cycle_remaining(EU868_SUB_BANDS[i].name, remaining);
+        }
+    }
+}
+
+pub fn setup(mode: DutyCycleMode) {
+    *DUTY_CYCLE.lock().unwrap() = DutyCycle::new(mode);
+
+    if mode != DutyCycleMode::Disabled {
+        for b in EU868_SUB_BANDS.iter() {
+            metrics::set_duty_cycle_remaining(b.name, WINDOW * b.limit_permille / 1000);
+        }
+    }
+}
+
+// Returns false when the transmission would exceed the duty cycle of the
+// sub-band and the duty cycle is enforced.
+pub fn is_allowed(frequency: u32, airtime: Duration) -> bool {
+    DUTY_CYCLE
+        .lock()
+        .unwrap()
+        .is_allowed(frequency, airtime, Instant::now())
+}
+
+pub fn transmitted(frequency: u32, airtime: Duration) {
+    DUTY_CYCLE
+        .lock()
+        .unwrap()
+        .transmitted(frequency, airtime, Instant::now());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duty_cycle() {
+        let mut dc = DutyCycle::new(DutyCycleMode::Enforce);
+        let now = Instant::now();
+
+        // g2 (0.1%): 3.6s per hour
+        assert!(dc.is_allowed(868800000, Duration::from_millis(3600), now));
+        dc.transmitted(868800000, Duration::from_secs(3), now);
+        assert!(!dc.is_allowed(868800000, Duration::from_secs(1), now));
+        assert!(dc.is_allowed(868800000, Duration::from_millis(600), now));
+
+        // other sub-bands are not affected
+        assert!(dc.is_allowed(869525000, Duration::from_secs(10), now));
+
+        // outside the window
+        assert!(dc.is_allowed(868800000, Duration::from_secs(1), now + WINDOW));
+
+        // only tracked
+        let mut dc = DutyCycle::new(DutyCycleMode::Track);
+        dc.transmitted(868800000, Duration::from_secs(10), now);
+        assert!(dc.is_allowed(868800000, Duration::from_secs(1), now));
+    }
+}
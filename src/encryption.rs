@@ -0,0 +1,322 @@
+// ChaCha20-Poly1305 encryption of the frames sent over the TCP transport, a
+// proprietary extension for tunnels of which both ends are under control (e.g.
+// a relay in front of the server). Each frame is sealed in an envelope:
+// key ID (1 byte) + nonce (12 bytes) + ciphertext + tag (16 bytes).
+//
+// The pre-shared keys are rotated by schedule: a key is used for sending from
+// its not_before time, until the next key becomes active. Received frames are
+// accepted with the current and the previous key, such that a peer can roll
+// the keys at its own pace.
+
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+
+use super::config;
+
+pub const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+
+pub const OVERHEAD: usize = 1 + NONCE_SIZE + TAG_SIZE;
+
+// The next key is already accepted this long before its not_before time, to
+// allow for clock differences between both ends.
+const CLOCK_SKEW: Duration = Duration::from_secs(300);
+
+#[derive(Clone)]
+struct Key {
+    id: u8,
+    not_before: SystemTime,
+    cipher: ChaCha20Poly1305,
+}
+
+// Keys of a server, sorted by not_before.
+#[derive(Clone)]
+pub struct Keys {
+    keys: Vec<Key>,
+}
+
+impl Keys {
+    // Returns the keys, None when encryption is disabled.
+    pub fn from_config(conf: &config::Encryption) -> Result<Option<Keys>> {
+        if conf.keys.is_empty() {
+            return Ok(None);
+        }
+
+        let mut ids = HashSet::new();
+        let mut keys = Vec::with_capacity(conf.keys.len());
+        for k in &conf.keys {
+            if !ids.insert(k.id) {
+                return Err(anyhow!("duplicate encryption key id: {}", k.id));
+            }
+
+            let key = hex::decode(&k.key)
+                .map_err(|e| anyhow!("invalid encryption key, id: {}, error: {}", k.id, e))?;
+            if key.len() != KEY_SIZE {
+                return Err(anyhow!(
+                    "encryption key must be {} bytes, id: {}",
+                    KEY_SIZE,
+                    k.id
+                ));
+            }
+
+            let not_before = match k.not_before.as_str() {
+                "" => SystemTime::UNIX_EPOCH,
+                v => chrono::DateTime::parse_from_rfc3339(v)
+                    .map_err(|e| {
+                        anyhow!(
+                            "invalid encryption key not_before, id: {}, error: {}",
+                            k.id,
+                            e
+                        )
+                    })?
+                    .into(),
+            };
+
+            keys.push(Key {
+                id: k.id,
+                not_before,
+                cipher: ChaCha20Poly1305::new_from_slice(&key).unwrap(),
+            });
+        }
+        keys.sort_by_key(|k| k.not_before);
+
+        Ok(Some(Keys { keys }))
+    }
+
+    // Returns the index of the key used for sending at the given time.
+    fn current(&self, now: SystemTime) -> Option<usize> {
+        self.keys.iter().rposition(|k| k.not_before <= now)
+    }
+
+    // Returns the ID of the key used for sending at the given time.
+    pub fn current_id(&self, now: SystemTime) -> Option<u8> {
+        self.current(now).map(|i| self.keys[i].id)
+    }
+
+    // Returns the envelope of the frame, sealed with the current key.
+    pub fn seal(&self, frame: &[u8], now: SystemTime) -> Result<Vec<u8>> {
+        let key = match self.current(now) {
+            Some(i) => &self.keys[i],
+            None => return Err(anyhow!("no active encryption key")),
+        };
+
+        let nonce: [u8; NONCE_SIZE] = rand::random();
+        let ciphertext = key
+            .cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: frame,
+                    aad: &[key.id],
+                },
+            )
+            .map_err(|_| anyhow!("encrypt frame error"))?;
+
+        let mut out = Vec::with_capacity(frame.len() + OVERHEAD);
+        out.push(key.id);
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&ciphertext);
+        Ok(out)
+    }
+
+    // Returns the frame of the envelope. Only the current and the previous
+    // key are accepted, and the next key within the clock skew.
+    pub fn open(&self, envelope: &[u8], now: SystemTime) -> Result<Vec<u8>> {
+        if envelope.len() < OVERHEAD {
+            return Err(anyhow!("envelope too short"));
+        }
+
+        let id = envelope[0];
+        let i = match self.keys.iter().position(|k| k.id == id) {
+            Some(v) => v,
+            None => return Err(anyhow!("unknown encryption key id: {}", id)),
+        };
+
+        let accepted = match self.current(now) {
+            Some(current) if i + 1 == current || i == current => true,
+            Some(current) if i == current + 1 => self.keys[i].not_before <= now + CLOCK_SKEW,
+            None if i == 0 => self.keys[i].not_before <= now + CLOCK_SKEW,
+            _ => false,
+        };
+        if !accepted {
+            return Err(anyhow!("encryption key is not active, id: {}", id));
+        }
+
+        self.keys[i]
+            .cipher
+            .decrypt(
+                Nonce::from_slice(&envelope[1..1 + NONCE_SIZE]),
+                Payload {
+                    msg: &envelope[1 + NONCE_SIZE..],
+                    aad: &[id],
+                },
+            )
+            .map_err(|_| anyhow!("decrypt frame error, key id: {}", id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> Keys {
+        Keys::from_config(&config::Encryption {
+            keys: vec![
+                config::EncryptionKey {
+                    id: 2,
+                    key: "01".repeat(KEY_SIZE),
+                    not_before: "2024-07-01T00:00:00Z".into(),
+                },
+                config::EncryptionKey {
+                    id: 1,
+                    key: "00".repeat(KEY_SIZE),
+                    not_before: "".into(),
+                },
+                config::EncryptionKey {
+                    id: 3,
+                    key: "02".repeat(KEY_SIZE),
+                    not_before: "2025-01-01T00:00:00Z".into(),
+                },
+            ],
+        })
+        .unwrap()
+        .unwrap()
+    }
+
+    fn time(s: &str) -> SystemTime {
+        chrono::DateTime::parse_from_rfc3339(s).unwrap().into()
+    }
+
+    #[test]
+    fn test_from_config() {
+        assert!(Keys::from_config(&config::Encryption::default())
+            .unwrap()
+            .is_none());
+
+        let tests = [
+            ("00", "", "encryption key must be 32 bytes, id: 1"),
+            (
+                "zz",
+                "",
+                "invalid encryption key, id: 1, error: Invalid character 'z' at position 0",
+            ),
+        ];
+        for (key, not_before, err) in tests {
+            let conf = config::Encryption {
+                keys: vec![config::EncryptionKey {
+                    id: 1,
+                    key: key.into(),
+                    not_before: not_before.into(),
+                }],
+            };
+            assert_eq!(Keys::from_config(&conf).err().unwrap().to_string(), err);
+        }
+
+        let conf = config::Encryption {
+            keys: vec![config::EncryptionKey {
+                id: 1,
+                key: "00".repeat(KEY_SIZE),
+                not_before: "2024-07-01".into(),
+            }],
+        };
+        assert!(Keys::from_config(&conf)
+            .err()
+            .unwrap()
+            .to_string()
+            .starts_with("invalid encryption key not_before, id: 1"));
+
+        let conf = config::Encryption {
+            keys: vec![
+                config::EncryptionKey {
+                    id: 1,
+                    key: "00".repeat(KEY_SIZE),
+                    not_before: "".into(),
+                },
+                config::EncryptionKey {
+                    id: 1,
+                    key: "01".repeat(KEY_SIZE),
+                    not_before: "".into(),
+                },
+            ],
+        };
+        assert_eq!(
+            Keys::from_config(&conf).err().unwrap().to_string(),
+            "duplicate encryption key id: 1"
+        );
+    }
+
+    #[test]
+    fn test_seal_open() {
+        let keys = keys();
+        let now = time("2024-03-01T00:00:00Z");
+        let frame = [2, 1, 2, 0, 1, 2, 3, 4, 5, 6, 7, 8];
+
+        let envelope = keys.seal(&frame, now).unwrap();
+        assert_eq!(envelope.len(), frame.len() + OVERHEAD);
+        assert_eq!(envelope[0], 1);
+        assert_eq!(keys.open(&envelope, now).unwrap(), frame);
+
+        // The nonce is random.
+        assert_ne!(keys.seal(&frame, now).unwrap(), envelope);
+
+        // Tampered envelope, or key ID.
+        let mut tampered = envelope.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(keys.open(&tampered, now).is_err());
+        let mut tampered = envelope.clone();
+        tampered[0] = 2;
+        assert!(keys.open(&tampered, now).is_err());
+
+        assert!(keys.open(&envelope[..OVERHEAD - 1], now).is_err());
+    }
+
+    #[test]
+    fn test_rotation() {
+        let keys = keys();
+        let frame = [2, 1, 2, 0];
+
+        // (time, current key, accepted keys)
+        let tests = [
+            ("2024-03-01T00:00:00Z", Some(1), vec![1]),
+            ("2024-06-30T23:56:00Z", Some(1), vec![1, 2]),
+            ("2024-07-01T00:00:00Z", Some(2), vec![1, 2]),
+            ("2024-12-31T23:56:00Z", Some(2), vec![1, 2, 3]),
+            ("2025-01-01T00:00:00Z", Some(3), vec![2, 3]),
+        ];
+
+        for (now, current, accepted) in tests {
+            let now = time(now);
+            assert_eq!(keys.current_id(now), current, "{:?}", now);
+
+            for id in 1..=3 {
+                // Sealed by the peer using key `id`.
+                let i = keys.keys.iter().position(|k| k.id == id).unwrap();
+                let envelope = keys.seal(&frame, keys.keys[i].not_before).unwrap();
+                assert_eq!(
+                    keys.open(&envelope, now).is_ok(),
+                    accepted.contains(&id),
+                    "{:?}, key id: {}",
+                    now,
+                    id
+                );
+            }
+        }
+
+        // No key is active yet.
+        let keys = Keys::from_config(&config::Encryption {
+            keys: vec![config::EncryptionKey {
+                id: 1,
+                key: "00".repeat(KEY_SIZE),
+                not_before: "2024-07-01T00:00:00Z".into(),
+            }],
+        })
+        .unwrap()
+        .unwrap();
+        assert!(keys.seal(&frame, time("2024-03-01T00:00:00Z")).is_err());
+    }
+}
@@ -0,0 +1,577 @@
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use chirpstack_udp_forwarder::protocol::RxPk;
+use serde_json::json;
+
+use super::config;
+
+// Max. number of uplinks waiting for the enricher. When full, the uplinks are
+// forwarded without metadata.
+const QUEUE_SIZE: usize = 16;
+
+// Max. size of a response.
+const MAX_RESPONSE_SIZE: usize = 64 * 1024;
+
+// External service, consulted per uplink to append metadata (e.g. the device
+// owner) to the rxpk. The requests are made by a worker thread, the uplink
+// waits for the metadata at most the timeout (budget) and is forwarded
+// without metadata otherwise. After max_failures consecutive failures the
+// enricher is skipped for open_secs (circuit breaker).
+//
+// The request is the JSON object {"gateway_id", "freq", "size", "data"},
+// the response a JSON object with string values. For unix:// URLs, the
+// request and response are a single line, for http:// URLs the request is
+// POSTed to the URL.
+pub struct Enricher {
+    url: String,
+    timeout: Duration,
+    breaker: Arc<Mutex<Breaker>>,
+    requests: SyncSender<Request>,
+}
+
+struct Request {
+    body: String,
+    deadline: Instant,
+    response: SyncSender<BTreeMap<String, String>>,
+}
+
+impl Enricher {
+    // Starts the worker thread, which stops when the enricher is dropped.
+    pub fn new(conf: &config::Enricher) -> Self {
+        let breaker = Arc::new(Mutex::new(Breaker::new(
+            conf.max_failures,
+            Duration::from_secs(conf.open_secs),
+        )));
+        let (requests, receiver) = sync_channel(QUEUE_SIZE);
+
+        thread::spawn({
+            let url = conf.url.clone();
+            let breaker = breaker.clone();
+            move || worker(url, breaker, receiver)
+        });
+
+        Enricher {
+            url: conf.url.clone(),
+            timeout: Duration::from_millis(conf.timeout_ms),
+            breaker,
+            requests,
+        }
+    }
+
+    // Returns the metadata, None when the circuit is open, the request failed
+    // or did not complete within the timeout.
+    pub fn enrich(&self, gateway_id: &str, rxpk: &RxPk) -> Option<BTreeMap<String, String>> {
+        let now = Instant::now();
+        if self.breaker.lock().unwrap().is_open(now) {
+            return None;
+        }
+
+        let body = json!({
+            "gateway_id": gateway_id,
+            "freq": rxpk.freq,
+            "size": rxpk.size,
+            "data": rxpk.data,
+        });
+
+        let (response, receiver) = sync_channel(1);
+        let req = Request {
+            body: body.to_string(),
+            deadline: now + self.timeout,
+            response,
+        };
+        match self.requests.try_send(req) {
+            Ok(_) => {}
+            Err(TrySendError::Full(_)) => {
+                debug!(
+                    "Enricher queue full, forwarding without metadata, url: {}",
+                    self.url
+                );
+                return None;
+            }
+            Err(TrySendError::Disconnected(_)) => return None,
+        }
+
+        // The worker drops the response sender when the request failed.
+        match receiver.recv_timeout(self.timeout) {
+            Ok(v) => Some(v),
+            Err(RecvTimeoutError::Disconnected) => None,
+            Err(RecvTimeoutError::Timeout) => {
+                debug!(
+                    "Enricher budget exceeded, forwarding without metadata, url: {}",
+                    self.url
+                );
+                None
+            }
+        }
+    }
+}
+
+// Circuit breaker, opened for the open duration after max_failures
+// consecutive failures.
+struct Breaker {
+    max_failures: u32,
+    open_duration: Duration,
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl Breaker {
+    fn new(max_failures: u32, open_duration: Duration) -> Self {
+        Breaker {
+            max_failures,
+            open_duration,
+            failures: 0,
+            open_until: None,
+        }
+    }
+
+    fn is_open(&self, now: Instant) -> bool {
+        matches!(self.open_until, Some(v) if now < v)
+    }
+
+    fn success(&mut self) {
+        self.failures = 0;
+        self.open_until = None;
+    }
+
+    // Returns true when the circuit has been opened.
+    fn failure(&mut self, now: Instant) -> bool {
+        self.failures += 1;
+        if self.failures < self.max_failures {
+            return false;
+        }
+
+        self.open_until = Some(now + self.open_duration);
+        self.failures = 0;
+        true
+    }
+}
+
+fn worker(url: String, breaker: Arc<Mutex<Breaker>>, receiver: Receiver<Request>) {
+    for req in receiver {
+        // The uplink has already been forwarded.
+        if Instant::now() >= req.deadline {
+            continue;
+        }
+
+        match request(&url, &req.body, req.deadline) {
+            Ok(v) => {
+                breaker.lock().unwrap().success();
+                let _ = req.response.send(v);
+            }
+            Err(e) => {
+                let mut breaker = breaker.lock().unwrap();
+                let opened = breaker.failure(Instant::now());
+                warn!(
+                    "Enricher request error: {}, url: {}, failures: {}",
+                    e, url, breaker.failures
+                );
+                if opened {
+                    warn!(
+                        "Enricher circuit open, url: {}, duration: {:?}",
+                        url, breaker.open_duration
+                    );
+                }
+            }
+        }
+    }
+}
+
+fn request(url: &str, body: &str, deadline: Instant) -> Result<BTreeMap<String, String>> {
+    let resp = if let Some(path) = url.strip_prefix("unix://") {
+        let mut stream = UnixStream::connect(path)?;
+        let resp = exchange(
+            &mut stream,
+            format!("{}\n", body).as_bytes(),
+            deadline,
+            |b| b.contains(&b'\n'),
+        )?;
+        if !resp.contains(&b'\n') {
+            return Err(anyhow!("incomplete response"));
+        }
+        resp
+    } else if let Some(v) = url.strip_prefix("http://") {
+        let (host, path) = match v.find('/') {
+            Some(i) => (&v[..i], &v[i..]),
+            None => (v, "/"),
+        };
+        let addr = resolve(host, deadline)?;
+
+        let mut stream = TcpStream::connect_timeout(&addr, remaining(deadline)?)?;
+        let resp = exchange(
+            &mut stream,
+            format!(
+                "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                path,
+                host,
+                body.len(),
+                body
+            )
+            .as_bytes(),
+            deadline,
+            |b| !matches!(parse_http_response(b, false), Ok(None)),
+        )?;
+        match parse_http_response(&resp, true)? {
+            Some(v) => v,
+            None => return Err(anyhow!("incomplete response")),
+        }
+    } else {
+        return Err(anyhow!("unsupported url scheme"));
+    };
+
+    let meta: BTreeMap<String, serde_json::Value> =
+        serde_json::from_str(String::from_utf8_lossy(&resp).trim())?;
+    Ok(meta
+        .into_iter()
+        .map(|(k, v)| match v {
+            serde_json::Value::String(v) => (k, v),
+            v => (k, v.to_string()),
+        })
+        .collect())
+}
+
+// Stream of which each read and write is limited by a timeout.
+trait TimeoutStream: Read + Write {
+    fn set_timeout(&self, timeout: Duration) -> io::Result<()>;
+}
+
+impl TimeoutStream for UnixStream {
+    fn set_timeout(&self, timeout: Duration) -> io::Result<()> {
+        self.set_read_timeout(Some(timeout))?;
+        self.set_write_timeout(Some(timeout))
+    }
+}
+
+impl TimeoutStream for TcpStream {
+    fn set_timeout(&self, timeout: Duration) -> io::Result<()> {
+        self.set_read_timeout(Some(timeout))?;
+        self.set_write_timeout(Some(timeout))
+    }
+}
+
+// Writes the request and reads the response until complete (or EOF). The
+// timeout of each read is the time remaining until the deadline, such that
+// the whole exchange completes within the deadline.
+fn exchange<S, F>(stream: &mut S, req: &[u8], deadline: Instant, complete: F) -> Result<Vec<u8>>
+where
+    S: TimeoutStream,
+    F: Fn(&[u8]) -> bool,
+{
+    stream.set_timeout(remaining(deadline)?)?;
+    stream.write_all(req)?;
+
+    let mut resp = vec![];
+    let mut buffer = [0; 4096];
+    loop {
+        stream.set_timeout(remaining(deadline)?)?;
+        let size = stream.read(&mut buffer)?;
+        if size == 0 {
+            return Ok(resp);
+        }
+
+        resp.extend_from_slice(&buffer[..size]);
+        if resp.len() > MAX_RESPONSE_SIZE {
+            return Err(anyhow!("response too large"));
+        }
+        if complete(&resp) {
+            return Ok(resp);
+        }
+    }
+}
+
+// Resolves the host within the deadline. The lookup itself can not be
+// cancelled, it continues in the background after the deadline.
+fn resolve(host: &str, deadline: Instant) -> Result<SocketAddr> {
+    if let Ok(v) = host.parse() {
+        return Ok(v);
+    }
+
+    let (tx, rx) = sync_channel(1);
+    thread::spawn({
+        let host = host.to_string();
+        move || {
+            let _ = tx.send(host.to_socket_addrs().map(|mut v| v.next()));
+        }
+    });
+
+    match rx.recv_timeout(remaining(deadline)?) {
+        Ok(Ok(Some(v))) => Ok(v),
+        Ok(Ok(None)) => Err(anyhow!("resolve {} error", host)),
+        Ok(Err(e)) => Err(anyhow!("resolve {} error: {}", host, e)),
+        Err(_) => Err(anyhow!("resolve {} timeout", host)),
+    }
+}
+
+// Returns the body of the HTTP response, None when the response is not yet
+// complete. When eof is set, the connection has been closed and the body is
+// delimited by the end of the response (when it has no Content-Length).
+fn parse_http_response(b: &[u8], eof: bool) -> Result<Option<Vec<u8>>> {
+    let i = match b.windows(4).position(|v| v == b"\r\n\r\n") {
+        Some(v) => v,
+        None if eof => return Err(anyhow!("response without body")),
+        None => return Ok(None),
+    };
+    let head = String::from_utf8_lossy(&b[..i]);
+    let body = &b[i + 4..];
+
+    // e.g. "HTTP/1.1 200 OK"
+    let mut lines = head.lines();
+    let status = lines.next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(anyhow!("unexpected response: {}", status));
+    }
+
+    let mut chunked = false;
+    let mut content_length = None;
+    for line in lines {
+        if let Some((k, v)) = line.split_once(':') {
+            match k.trim().to_lowercase().as_str() {
+                "transfer-encoding" => chunked = v.to_lowercase().contains("chunked"),
+                "content-length" => content_length = Some(v.trim().parse::<usize>()?),
+                _ => {}
+            }
+        }
+    }
+
+    let out = if chunked {
+        decode_chunked(body)?
+    } else if let Some(len) = content_length {
+        body.get(..len).map(|v| v.to_vec())
+    } else if eof {
+        Some(body.to_vec())
+    } else {
+        None
+    };
+
+    if out.is_none() && eof {
+        return Err(anyhow!("incomplete response"));
+    }
+    Ok(out)
+}
+
+// Decodes a chunked body, None when the last chunk has not yet been
+// received. Chunk extensions and trailers are ignored.
+fn decode_chunked(b: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut out = vec![];
+    let mut b = b;
+    loop {
+        let i = match b.windows(2).position(|v| v == b"\r\n") {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let line = String::from_utf8_lossy(&b[..i]);
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size =
+            usize::from_str_radix(size, 16).map_err(|_| anyhow!("invalid chunk size: {}", size))?;
+        if size == 0 {
+            return Ok(Some(out));
+        }
+
+        b = &b[i + 2..];
+        if b.len() < size + 2 {
+            return Ok(None);
+        }
+        if &b[size..size + 2] != b"\r\n" {
+            return Err(anyhow!("invalid chunk"));
+        }
+        out.extend_from_slice(&b[..size]);
+        b = &b[size + 2..];
+    }
+}
+
+fn remaining(deadline: Instant) -> Result<Duration> {
+    let d = deadline.saturating_duration_since(Instant::now());
+    if d == Duration::default() {
+        return Err(anyhow!("timeout"));
+    }
+    Ok(d)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::net::UnixListener;
+
+    use super::*;
+
+    #[test]
+    fn test_enricher() {
+        let path = std::env::temp_dir().join(format!("enricher-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        thread::spawn(move || {
+            // The first request is answered, the others time out.
+            for (i, stream) in listener.incoming().enumerate() {
+                let stream = stream.unwrap();
+                let mut line = String::new();
+                BufReader::new(&stream).read_line(&mut line).unwrap();
+                if i == 0 {
+                    (&stream)
+                        .write_all(b"{\"owner\":\"acme\",\"asset\":42}\n")
+                        .unwrap();
+                } else {
+                    thread::sleep(Duration::from_millis(100));
+                }
+            }
+        });
+
+        let e = Enricher::new(&config::Enricher {
+            url: format!("unix://{}", path.display()),
+            timeout_ms: 50,
+            max_failures: 2,
+            open_secs: 60,
+        });
+        let rxpk: RxPk = serde_json::from_str(
+            r#"{"time":"1970-01-01T00:00:00+00:00","tmst":1,"freq":868.1,"chan":0,"rfch":0,"stat":1,"modu":"LORA","datr":"SF7BW125","codr":"4/5","lsnr":5.5,"rssi":-50,"size":1,"data":"AA=="}"#,
+        )
+        .unwrap();
+
+        let meta = e.enrich("0102030405060708", &rxpk).unwrap();
+        assert_eq!(meta.get("owner").map(|v| v.as_str()), Some("acme"));
+        assert_eq!(meta.get("asset").map(|v| v.as_str()), Some("42"));
+
+        // The uplink does not wait longer than the timeout.
+        for _ in 0..2 {
+            let start = Instant::now();
+            assert!(e.enrich("0102030405060708", &rxpk).is_none());
+            assert!(start.elapsed() < Duration::from_millis(100));
+
+            // let the worker record the failure
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        // the circuit is open, the enricher is not consulted
+        let start = Instant::now();
+        assert!(e.enrich("0102030405060708", &rxpk).is_none());
+        assert!(start.elapsed() < Duration::from_millis(20));
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_breaker() {
+        let now = Instant::now();
+        let mut b = Breaker::new(3, Duration::from_secs(30));
+        assert!(!b.is_open(now));
+
+        // A success resets the consecutive failures.
+        assert!(!b.failure(now));
+        assert!(!b.failure(now));
+        b.success();
+        assert!(!b.failure(now));
+        assert!(!b.failure(now));
+        assert!(!b.is_open(now));
+
+        assert!(b.failure(now));
+        assert!(b.is_open(now));
+        assert!(b.is_open(now + Duration::from_secs(29)));
+        assert!(!b.is_open(now + Duration::from_secs(30)));
+
+        // The failures are counted again once closed.
+        let later = now + Duration::from_secs(30);
+        assert!(!b.failure(later));
+        assert!(!b.failure(later));
+        assert!(b.failure(later));
+        assert!(b.is_open(later));
+    }
+
+    #[test]
+    fn test_parse_http_response() {
+        let tests = vec![
+            // content-length
+            (
+                "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}",
+                false,
+                Some("{}"),
+            ),
+            ("HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\n{}", false, None),
+            ("HTTP/1.1 200 OK\r\nContent-Len", false, None),
+            // delimited by the end of the connection
+            ("HTTP/1.1 200 OK\r\n\r\n{\"a\"", false, None),
+            (
+                "HTTP/1.1 200 OK\r\n\r\n{\"a\":1}",
+                true,
+                Some("{\"a\":1}"),
+            ),
+            // chunked
+            (
+                "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4;ext=1\r\n{\"a\"\r\n3\r\n:1}\r\n0\r\n\r\n",
+                false,
+                Some("{\"a\":1}"),
+            ),
+            (
+                "HTTP/1.1 200 OK\r\ntransfer-encoding: Chunked\r\n\r\n4\r\n{\"a\"\r\n3\r\n:1",
+                false,
+                None,
+            ),
+        ];
+        for (b, eof, expected) in tests {
+            assert_eq!(
+                parse_http_response(b.as_bytes(), eof).unwrap().as_deref(),
+                expected.map(|v| v.as_bytes()),
+                "response: {}",
+                b
+            );
+        }
+
+        for (b, eof) in [
+            (&b"HTTP/1.1 500 Internal Server Error\r\n\r\n"[..], false),
+            (b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\n{}", true),
+            (b"HTTP/1.1 200 OK\r\n", true),
+            (
+                b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n",
+                false,
+            ),
+            (
+                b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\n{}\r\n",
+                true,
+            ),
+        ] {
+            assert!(
+                parse_http_response(b, eof).is_err(),
+                "response: {}",
+                String::from_utf8_lossy(b)
+            );
+        }
+    }
+
+    #[test]
+    fn test_http_enricher() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buffer = [0; 1024];
+            let _ = stream.read(&mut buffer).unwrap();
+
+            // The connection is kept open, the response is complete after the
+            // last chunk.
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n")
+                .unwrap();
+            thread::sleep(Duration::from_millis(5));
+            stream
+                .write_all(b"e\r\n{\"owner\":\"acme\r\n2\r\n\"}\r\n0\r\n\r\n")
+                .unwrap();
+            thread::sleep(Duration::from_secs(1));
+        });
+
+        let meta = request(
+            &format!("http://{}/enrich", addr),
+            "{}",
+            Instant::now() + Duration::from_millis(500),
+        )
+        .unwrap();
+        assert_eq!(meta.get("owner").map(|v| v.as_str()), Some("acme"));
+    }
+}
@@ -19,6 +19,7 @@ pub fn get_socket(endpoint: &str) -> Result<zmq::Socket, zmq::Error> {
     Ok(sock)
 }
 
+#[derive(Clone)]
 pub enum Event {
     // Reading event timed out.
     Timeout,
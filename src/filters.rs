@@ -0,0 +1,255 @@
+use anyhow::Result;
+
+use super::config;
+use super::lorawan;
+
+// Number of NwkID bits within the DevAddr, by DevAddr type.
+const NWK_ID_BITS: [u32; 8] = [6, 6, 9, 11, 12, 13, 15, 17];
+
+// Bit prefix of a DevAddr (32 bits) or JoinEUI (64 bits), configured as
+// "hex/bits", e.g. "26000000/7". When the bits are omitted, the full value
+// must match.
+struct Prefix {
+    value: u64,
+    bits: u32,
+    size: u32,
+}
+
+impl Prefix {
+    fn parse(s: &str, size: u32) -> Result<Self> {
+        let (hex, bits) = match s.split_once('/') {
+            Some((hex, bits)) => (hex, bits.parse::<u32>()?),
+            None => (s, size),
+        };
+
+        if hex.len() as u32 != size / 4 {
+            return Err(anyhow!(
+                "expected {} hex characters, prefix: {}",
+                size / 4,
+                s
+            ));
+        }
+
+        if bits > size {
+            return Err(anyhow!("expected at most {} bits, prefix: {}", size, s));
+        }
+
+        Ok(Prefix {
+            value: u64::from_str_radix(hex, 16)?,
+            bits,
+            size,
+        })
+    }
+
+    fn matches(&self, v: u64) -> bool {
+        if self.bits == 0 {
+            return true;
+        }
+
+        let shift = self.size - self.bits;
+        (v >> shift) == (self.value >> shift)
+    }
+}
+
+// LoRaWAN NetID, configured as 6 hex characters.
+struct NetId {
+    typ: u32,
+    id: u32,
+}
+
+impl NetId {
+    fn parse(s: &str) -> Result<Self> {
+        if s.len() != 6 {
+            return Err(anyhow!("expected 6 hex characters, net_id: {}", s));
+        }
+
+        let v = u32::from_str_radix(s, 16)?;
+        Ok(NetId {
+            typ: v >> 21,
+            id: v & 0x1fffff,
+        })
+    }
+
+    // Returns true when the DevAddr contains the NwkID of this NetID.
+    fn matches(&self, dev_addr: u32) -> bool {
+        let typ = dev_addr.leading_ones().min(7);
+        if typ != self.typ {
+            return false;
+        }
+
+        let nwk_id_bits = NWK_ID_BITS[typ as usize];
+        let nwk_id = (dev_addr >> (32 - (typ + 1) - nwk_id_bits)) & ((1 << nwk_id_bits) - 1);
+        nwk_id == self.id & ((1 << nwk_id_bits) - 1)
+    }
+}
+
+// Uplink filters, applied on the PHYPayload before forwarding. Data-up
+// frames are filtered by DevAddr prefix and NetID, join-requests by JoinEUI
+// prefix. Any other frame is always forwarded.
+pub struct Filters {
+    dev_addr_prefixes: Vec<Prefix>,
+    net_ids: Vec<NetId>,
+    join_eui_prefixes: Vec<Prefix>,
+    deny_dev_addr_prefixes: Vec<Prefix>,
+    deny_net_ids: Vec<NetId>,
+    deny_join_eui_prefixes: Vec<Prefix>,
+}
+
+impl Filters {
+    pub fn from_config(conf: &config::Filters) -> Result<Self> {
+        let prefixes = |v: &[String], size: u32| -> Result<Vec<Prefix>> {
+            v.iter().map(|s| Prefix::parse(s, size)).collect()
+        };
+        let net_ids =
+            |v: &[String]| -> Result<Vec<NetId>> { v.iter().map(|s| NetId::parse(s)).collect() };
+
+        Ok(Filters {
+            dev_addr_prefixes: prefixes(&conf.dev_addr_prefixes, 32)?,
+            net_ids: net_ids(&conf.net_ids)?,
+            join_eui_prefixes: prefixes(&conf.join_eui_prefixes, 64)?,
+            deny_dev_addr_prefixes: prefixes(&conf.deny_dev_addr_prefixes, 32)?,
+            deny_net_ids: net_ids(&conf.deny_net_ids)?,
+            deny_join_eui_prefixes: prefixes(&conf.deny_join_eui_prefixes, 64)?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dev_addr_prefixes.is_empty()
+            && self.net_ids.is_empty()
+            && self.join_eui_prefixes.is_empty()
+            && self.deny_dev_addr_prefixes.is_empty()
+            && self.deny_net_ids.is_empty()
+            && self.deny_join_eui_prefixes.is_empty()
+    }
+
+    // Returns true when the uplink is matched by an allow list, i.e. it is
+    // explicitly routed to this server.
+    pub fn is_routed(&self, phy_payload: &[u8]) -> bool {
+        let has_allow_list = match lorawan::PhyPayload::from_slice(phy_payload) {
+            Ok(phy) => match phy.payload {
+                lorawan::Payload::DataUp { .. } => {
+                    !(self.dev_addr_prefixes.is_empty() && self.net_ids.is_empty())
+                }
+                lorawan::Payload::JoinRequest { .. } => !self.join_eui_prefixes.is_empty(),
+                lorawan::Payload::Other => false,
+            },
+            Err(_) => false,
+        };
+
+        has_allow_list && self.is_allowed(phy_payload)
+    }
+
+    // Returns true when the uplink must be forwarded.
+    pub fn is_allowed(&self, phy_payload: &[u8]) -> bool {
+        let phy = match lorawan::PhyPayload::from_slice(phy_payload) {
+            Ok(v) => v,
+            Err(_) => return true,
+        };
+
+        match phy.payload {
+            lorawan::Payload::DataUp { dev_addr, .. } => {
+                let dev_addr = u32::from_be_bytes(dev_addr);
+
+                if self
+                    .deny_dev_addr_prefixes
+                    .iter()
+                    .any(|p| p.matches(dev_addr as u64))
+                    || self.deny_net_ids.iter().any(|n| n.matches(dev_addr))
+                {
+                    return false;
+                }
+
+                if self.dev_addr_prefixes.is_empty() && self.net_ids.is_empty() {
+                    return true;
+                }
+
+                self.dev_addr_prefixes
+                    .iter()
+                    .any(|p| p.matches(dev_addr as u64))
+                    || self.net_ids.iter().any(|n| n.matches(dev_addr))
+            }
+            lorawan::Payload::JoinRequest { join_eui } => {
+                let join_eui = u64::from_be_bytes(join_eui);
+
+                if self
+                    .deny_join_eui_prefixes
+                    .iter()
+                    .any(|p| p.matches(join_eui))
+                {
+                    return false;
+                }
+
+                self.join_eui_prefixes.is_empty()
+                    || self.join_eui_prefixes.iter().any(|p| p.matches(join_eui))
+            }
+            lorawan::Payload::Other => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data_up(dev_addr: [u8; 4]) -> Vec<u8> {
+        let mut b = vec![0x40];
+        b.extend(dev_addr.iter().rev());
+        b.extend_from_slice(&[0x00, 0x01, 0x00, 0x01, 0x02, 0x03, 0x04]);
+        b
+    }
+
+    fn join_request(join_eui: [u8; 8]) -> Vec<u8> {
+        let mut b = vec![0x00];
+        b.extend(join_eui.iter().rev());
+        b.extend_from_slice(&[0; 14]);
+        b
+    }
+
+    #[test]
+    fn test_filters() {
+        let f = Filters::from_config(&config::Filters {
+            dev_addr_prefixes: vec!["26000000/7".into()],
+            net_ids: vec!["600013".into()],
+            deny_join_eui_prefixes: vec!["0102000000000000/16".into()],
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(!f.is_empty());
+
+        // DevAddr prefix
+        assert!(f.is_allowed(&data_up([0x26, 0x01, 0x02, 0x03])));
+        assert!(!f.is_allowed(&data_up([0x01, 0x01, 0x02, 0x03])));
+
+        // NetID 600013 (type 3, NwkID 0x13)
+        assert!(f.is_allowed(&data_up([0xe0, 0x26, 0xff, 0xff])));
+        assert!(!f.is_allowed(&data_up([0xe0, 0x28, 0xff, 0xff])));
+
+        // JoinEUI
+        assert!(f.is_allowed(&join_request([0x01, 0x03, 0, 0, 0, 0, 0, 0])));
+        assert!(!f.is_allowed(&join_request([0x01, 0x02, 0, 0, 0, 0, 0, 1])));
+
+        // other frames
+        assert!(f.is_allowed(&[0xe0, 0x01, 0x02, 0x03, 0x04]));
+
+        // Only the frames matching an allow list are routed.
+        assert!(f.is_routed(&data_up([0x26, 0x01, 0x02, 0x03])));
+        assert!(!f.is_routed(&data_up([0x48, 0x01, 0x02, 0x03])));
+        assert!(!f.is_routed(&join_request([0x01, 0x03, 0, 0, 0, 0, 0, 0])));
+        assert!(!f.is_routed(&[0xe0, 0x01, 0x02, 0x03, 0x04]));
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(Filters::from_config(&config::Filters {
+            dev_addr_prefixes: vec!["2600/7".into()],
+            ..Default::default()
+        })
+        .is_err());
+
+        assert!(Filters::from_config(&config::Filters {
+            net_ids: vec!["zz0013".into()],
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
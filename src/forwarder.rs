@@ -1,19 +1,101 @@
-use std::net::UdpSocket;
-use std::sync::mpsc::Receiver;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::convert::{TryFrom, TryInto};
+use std::net::SocketAddr;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
 use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
 use std::{thread, time};
 
 use anyhow::Result;
 use chirpstack_api::gw;
+use chirpstack_udp_forwarder::protocol;
+use chrono::Utc;
 use prost::Message;
-use rand::Rng;
-
-use super::commands;
-use super::config::Server;
-use super::events;
+use tracing::info_span;
+
+use super::acks;
+use super::airtime;
+use super::backend::Backend;
+use super::batch;
+use super::buffer;
+use super::capture;
+use super::channels;
+use super::config::{
+    AddressFamily, DownlinkFallback, DownlinkPlan, DownlinkPower, HmacMode, PowerReference,
+    RssiOffset, Server, ServerRole, StatFields, SyntheticStats, Transport,
+};
+use super::connection::{ConnectionState, ConnectionTracker};
+use super::crash;
+use super::dedup;
+use super::downlink;
+use super::downq;
+use super::dutycycle;
+use super::encryption;
+use super::enricher;
+use super::filters;
+use super::frontend::{self, Frontend};
+use super::gps_time;
+use super::helpers;
+use super::hmac;
+use super::location;
+use super::logging;
 use super::metrics;
+use super::probe;
+use super::replay;
+use super::retransmit;
+use super::sendq;
 use super::signals;
-use super::structs;
+use super::source_filter;
+use super::stats;
+use super::status;
+use super::store;
+use super::tls;
+use super::tokens;
+use super::transport;
+use super::udp;
+use super::usage;
+
+// Max. (JSON) size of the buffered rxpk sent in a single PUSH_DATA, such
+// that the datagram is not fragmented.
+const BUFFER_FLUSH_MAX_SIZE: usize = 1400;
+
+// Most NAT devices expire UDP mappings after 30 seconds of inactivity, after
+// which the downlinks (PULL_RESP) can no longer be received.
+pub const NAT_TIMEOUT_SECS: u64 = 30;
+
+// Max. number of low priority (PUSH_DATA) datagrams waiting to be sent.
+const SEND_QUEUE_SIZE: usize = 1024;
+
+// Interval between the attempts to setup the forwarder, e.g. when the server
+// could not be resolved.
+const RETRY_INTERVAL: time::Duration = time::Duration::from_secs(5);
+
+// Max. time a queued downlink is kept in the state store, such that it is
+// still sent after a restart. Older downlinks can not be sent in time.
+const DOWNLINK_STORE_TTL: time::Duration = time::Duration::from_secs(10);
+
+// How the PULL_DATA loop ended.
+enum Exit {
+    // Stopped through the stop_receive channel.
+    Stopped,
+    // Must be restarted, e.g. after missed keepalives.
+    Restart,
+    // Must continue using the new configuration.
+    Reload(Box<Server>),
+}
+
+// Resources of the forwarder which are kept on reload, such that e.g. the
+// NAT mapping and the PULL_RESP route of the server are not lost.
+struct Reused {
+    sockets: Option<(
+        transport::Socket,
+        Option<transport::Socket>,
+        Vec<SocketAddr>,
+    )>,
+    buffer: Option<Arc<Mutex<buffer::UplinkBuffer>>>,
+    store: store::SharedStore,
+}
 
 struct State {
     server: String,
@@ -21,24 +103,85 @@ struct State {
     forward_crc_ok: bool,
     forward_crc_invalid: bool,
     forward_crc_missing: bool,
+    tx_ack_on_success: bool,
+    downlink_timing_check: bool,
+    // Concentrator counter (tmst) of the last uplink and when it was received.
+    last_counter: Mutex<Option<(u32, Instant)>>,
+    stat_bridge_object: bool,
+    stat_with_rxpk: bool,
+    held_stat: Mutex<Option<(protocol::Stat, Instant)>>,
+    fine_timestamp: bool,
+    read_only: bool,
+    role: ServerRole,
+    mirror_stats: bool,
+    json_version: u8,
+    data_rate_index_region: String,
+    rxpk_data_rate_index: bool,
+    forward_rssis: bool,
+    downlink_fallback: Option<DownlinkFallback>,
+    downlink_power: DownlinkPower,
+    downlink_plan: DownlinkPlan,
+    rssi_offsets: Vec<RssiOffset>,
+    stat_fields: StatFields,
     keepalive_max_failures: u32,
+    stats_interval: Option<time::Duration>,
+    interval_jitter_percent: u8,
+    dns_refresh_interval: Option<time::Duration>,
+    dns_refresh_missed_acks: u32,
+    // Set by the PULL_DATA loop when the PULL_ACKs stopped.
+    dns_refresh_requested: Mutex<bool>,
+    address_family: AddressFamily,
+    downlink_port: u16,
     gateway_id: Vec<u8>,
-    socket: UdpSocket,
-    push_data_token: Mutex<u16>,
-    push_data_sent: Mutex<u32>,
-    push_data_acked: Mutex<u32>,
+    server_gateway_id: [u8; 8],
+    socket: transport::Socket,
+    // Socket of the PULL_DATA, PULL_RESP and TX_ACK frames, when these are
+    // split from the PUSH_DATA frames.
+    downlink_socket: Option<transport::Socket>,
+    // Resolved server addresses, from which datagrams are accepted.
+    server_addrs: Mutex<Vec<SocketAddr>>,
+    source_filter: Arc<source_filter::SourceFilter>,
+    hmac: Option<Mutex<hmac::Session>>,
+    // Last PUSH_DATA sent and PUSH_ACK received, used by the keepalive of the
+    // uplink socket.
+    push_data_sent_at: Mutex<Instant>,
+    push_ack_received_at: Mutex<Instant>,
+    uplink_keepalive_failed: Mutex<bool>,
+    send_queue: sendq::SendQueue,
+    downlink_queue: downq::DownlinkQueue<QueuedDownlink>,
+    store: store::SharedStore,
+    push_data_acks: Mutex<acks::AckTracker>,
+    tokens: tokens::TokenGenerator,
     pull_data_token: Mutex<u16>,
     pull_data_token_acked: Mutex<u16>,
     rxfw: Mutex<u32>,
-    event_sock: Mutex<zmq::Socket>,
-    command_sock: Mutex<zmq::Socket>,
+    replay_cache: Option<Mutex<replay::ReplayCache>>,
+    dedup_cache: Option<Mutex<dedup::DedupCache>>,
+    batcher: Option<Mutex<batch::Batcher<protocol::RxPk>>>,
+    filters: Option<Arc<filters::Filters>>,
+    default_route: bool,
+    routes: Arc<Vec<filters::Filters>>,
+    channel_plan: Option<Arc<channels::ChannelPlan>>,
+    enricher: Option<enricher::Enricher>,
+    retransmitter: Option<Mutex<retransmit::Retransmitter>>,
+    buffer: Option<Arc<Mutex<buffer::UplinkBuffer>>>,
+    connected: Mutex<bool>,
+    // Set on shutdown, no new uplinks are accepted.
+    draining: Mutex<bool>,
+    connection: Mutex<ConnectionTracker>,
+    synthetic_stats: Option<SyntheticStats>,
+    stats_counters: Mutex<stats::Counters>,
+    bridge_counters: Mutex<stats::BridgeCounters>,
+    last_stats: Mutex<Instant>,
+    pending_stats: Mutex<Option<protocol::Stat>>,
+    next_stats: Mutex<Option<Instant>>,
+    backend: Arc<dyn Backend>,
 }
 
 impl State {
     fn set_pull_data_token(&self) -> u16 {
-        let mut rng = rand::thread_rng();
         let mut token = self.pull_data_token.lock().unwrap();
-        *token = rng.gen();
+        *token = self.tokens.next();
         *token
     }
 
@@ -55,44 +198,87 @@ impl State {
         *token = t
     }
 
-    fn set_push_data_token(&self) -> u16 {
-        let mut rng = rand::thread_rng();
-        let mut token = self.push_data_token.lock().unwrap();
-        *token = rng.gen();
-        *token
+    fn new_push_data_token(&self) -> u16 {
+        self.tokens.next()
     }
 
-    fn get_push_data_token(&self) -> u16 {
-        return *self.push_data_token.lock().unwrap();
+    fn push_data_sent(&self, token: u16) {
+        *self.push_data_sent_at.lock().unwrap() = Instant::now();
+        self.push_data_acks
+            .lock()
+            .unwrap()
+            .sent(token, SystemTime::now());
     }
 
-    fn incr_push_data_sent(&self) {
-        let mut sent = self.push_data_sent.lock().unwrap();
-        *sent += 1;
+    fn push_data_acked(&self, token: u16, received_at: SystemTime) -> Option<time::Duration> {
+        self.push_data_acks
+            .lock()
+            .unwrap()
+            .acked(token, received_at)
     }
 
-    fn get_and_reset_push_data_sent(&self) -> u32 {
-        let mut sent = self.push_data_sent.lock().unwrap();
-        let out = *sent;
-        *sent = 0;
-        out
+    fn get_and_reset_ackr(&self) -> f32 {
+        self.push_data_acks.lock().unwrap().get_and_reset_ackr()
     }
 
-    fn incr_push_data_acked(&self) {
-        let mut acked = self.push_data_acked.lock().unwrap();
-        *acked += 1;
+    // Queues the datagram to be sent by the send loop. Nothing is sent in
+    // read-only mode.
+    fn send(&self, priority: sendq::Priority, bytes: &[u8], rxpk_count: u32) {
+        if self.read_only {
+            debug!(
+                "Read-only mode, not sending datagram, server: {}",
+                self.server
+            );
+            return;
+        }
+
+        let datagram = sendq::Datagram {
+            bytes: bytes.to_vec(),
+            rxpk_count,
+        };
+
+        if self.send_queue.push(priority, datagram).is_some() {
+            warn!(
+                "Send queue full, dropping PUSH_DATA, server: {}",
+                self.server
+            );
+            metrics::incr_uplink_dropped_count(&self.server, "SEND_QUEUE_FULL");
+        }
     }
 
-    fn get_and_reset_push_data_acked(&self) -> u32 {
-        let mut acked = self.push_data_acked.lock().unwrap();
-        let out = *acked;
-        *acked = 0;
-        out
+    // Returns the socket on which the datagram must be sent.
+    fn socket_for(&self, bytes: &[u8]) -> &transport::Socket {
+        let downlink = matches!(
+            bytes.get(3).map(|v| protocol::MessageType::try_from(*v)),
+            Some(Ok(protocol::MessageType::PullData)) | Some(Ok(protocol::MessageType::TxAck))
+        );
+
+        match &self.downlink_socket {
+            Some(v) if downlink => v,
+            _ => &self.socket,
+        }
+    }
+
+    // Returns the frame with the HMAC trailer appended, when a key has been
+    // configured and the server supports it (or it is required).
+    fn sign<'a>(&self, bytes: &'a [u8]) -> Cow<'a, [u8]> {
+        match self
+            .hmac
+            .as_ref()
+            .and_then(|v| v.lock().unwrap().sign(bytes))
+        {
+            Some(v) => Cow::Owned(v),
+            None => Cow::Borrowed(bytes),
+        }
     }
 
-    fn incr_rxfw(&self) {
+    fn incr_rxfw(&self, count: u32) {
         let mut rxfw = self.rxfw.lock().unwrap();
-        *rxfw += 1;
+        *rxfw += count;
+    }
+
+    fn is_connected(&self) -> bool {
+        *self.connected.lock().unwrap()
     }
 
     fn get_and_reset_rxfw(&self) -> u32 {
@@ -103,47 +289,436 @@ impl State {
     }
 }
 
-pub fn start(conf: &Server, event_url: String, command_url: String, gateway_id: Vec<u8>) {
+pub fn start(
+    conf: &Server,
+    dispatcher: Arc<frontend::Dispatcher>,
+    gateway_id: Vec<u8>,
+    stop_receive: Receiver<signals::Signal>,
+) {
+    let mut conf = conf.clone();
+    let mut reused = None;
+
+    // On reload, the forwarder is started again using the new configuration.
+    while let Some((v, r)) = run(
+        &conf,
+        &dispatcher,
+        &gateway_id,
+        &stop_receive,
+        reused.take(),
+    ) {
+        conf = v;
+        reused = Some(r);
+    }
+}
+
+// The parsed configuration options, which can be invalid.
+struct Parsed {
+    hmac_key: Option<Vec<u8>>,
+    encryption_keys: Option<encryption::Keys>,
+    tls: Option<tls::Connector>,
+    bind: Option<SocketAddr>,
+    gateway_id: Option<[u8; 8]>,
+    filters: Option<Arc<filters::Filters>>,
+    source_filter: Arc<source_filter::SourceFilter>,
+}
+
+fn parse(conf: &Server) -> Result<Parsed> {
+    if conf.json_version != 1 && conf.json_version != 2 {
+        return Err(anyhow!(
+            "invalid json_version: {}, expected 1 or 2",
+            conf.json_version
+        ));
+    }
+
+    if conf.interval_jitter_percent > 50 {
+        return Err(anyhow!(
+            "invalid interval_jitter_percent: {}, expected 0 - 50",
+            conf.interval_jitter_percent
+        ));
+    }
+
+    if !conf.data_rate_index_region.is_empty()
+        && !protocol::is_known_region(&conf.data_rate_index_region)
+    {
+        return Err(anyhow!(
+            "invalid data_rate_index_region: {}",
+            conf.data_rate_index_region
+        ));
+    }
+
+    Ok(Parsed {
+        hmac_key: conf
+            .hmac
+            .get_key()
+            .map_err(|e| anyhow!("invalid hmac configuration: {}", e))?,
+        encryption_keys: encryption::Keys::from_config(&conf.encryption)
+            .map_err(|e| anyhow!("invalid encryption configuration: {}", e))?,
+        tls: tls::Connector::from_config(&conf.tls, &conf.server)
+            .map_err(|e| anyhow!("invalid tls configuration: {}", e))?,
+        bind: match conf.bind.as_str() {
+            "" => None,
+            v => Some(
+                v.parse()
+                    .map_err(|e| anyhow!("invalid bind address: {}, error: {}", v, e))?,
+            ),
+        },
+        gateway_id: match conf.gateway_id.as_str() {
+            "" => None,
+            v => Some(
+                helpers::parse_gateway_id(v)
+                    .map_err(|e| anyhow!("invalid gateway_id configuration: {}", e))?,
+            ),
+        },
+        filters: match filters::Filters::from_config(&conf.filters)
+            .map_err(|e| anyhow!("invalid filters configuration: {}", e))?
+        {
+            v if v.is_empty() => None,
+            v => Some(Arc::new(v)),
+        },
+        source_filter: Arc::new(
+            source_filter::SourceFilter::new(&conf.source_allowlist)
+                .map_err(|e| anyhow!("invalid source_allowlist configuration: {}", e))?,
+        ),
+    })
+}
+
+// Validates the server configuration, such that an invalid configuration is
+// rejected on reload instead of stopping the running forwarder.
+pub fn validate(conf: &Server) -> Result<()> {
+    parse(conf).map(|_| ())
+}
+
+// Runs the forwarder until it is stopped. On reload, the new configuration is
+// returned together with the resources to reuse.
+fn run(
+    conf: &Server,
+    dispatcher: &Arc<frontend::Dispatcher>,
+    gateway_id: &[u8],
+    stop_receive: &Receiver<signals::Signal>,
+    reused: Option<Reused>,
+) -> Option<(Server, Reused)> {
+    let (mut sockets, buffer, store) = match reused {
+        Some(v) => (v.sockets, v.buffer, Some(v.store)),
+        None => (None, new_buffer(conf), None),
+    };
+
+    let Parsed {
+        hmac_key,
+        encryption_keys,
+        tls,
+        bind,
+        gateway_id: gateway_id_override,
+        filters,
+        source_filter,
+    } = match parse(conf) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Invalid configuration: {}, server: {}", e, conf.server);
+            return None;
+        }
+    };
+
+    if let Some(keys) = &encryption_keys {
+        info!(
+            "TCP encryption enabled, key_id: {:?}, server: {}",
+            keys.current_id(SystemTime::now()),
+            conf.server
+        );
+    }
+
+    if conf.role == ServerRole::Mirror {
+        info!(
+            "Mirror role, downlinks will be ignored, server: {}, mirror_stats: {}",
+            conf.server, conf.mirror_stats
+        );
+    }
+
+    if conf.token_seed != 0 {
+        warn!(
+            "Deterministic random tokens, token_seed must not be used in production, server: {}",
+            conf.server
+        );
+    }
+
+    if conf.read_only {
+        warn!(
+            "Read-only mode, nothing will be sent to the server or Concentratord, server: {}",
+            conf.server
+        );
+    }
+
+    if conf.keepalive_interval_secs > NAT_TIMEOUT_SECS {
+        warn!(
+            "keepalive_interval_secs is longer than the typical NAT timeout of {}s, server: {}",
+            NAT_TIMEOUT_SECS, conf.server
+        );
+    }
+
+    if !(conf.forward_crc_ok || conf.forward_crc_invalid || conf.forward_crc_missing) {
+        warn!(
+            "All forward_crc_* options are disabled, no uplinks will be forwarded, server: {}",
+            conf.server
+        );
+    }
+
+    // The gateway ID advertised to the server, the Concentratord gateway ID is
+    // used for the downlinks.
+    let server_gateway_id = match gateway_id_override {
+        None => {
+            let mut id: [u8; 8] = [0; 8];
+            id.copy_from_slice(gateway_id);
+            id
+        }
+        Some(id) => {
+            info!(
+                "Overriding gateway ID, gateway_id: {}, server_gateway_id: {}, server: {}",
+                hex::encode(gateway_id),
+                conf.gateway_id,
+                conf.server
+            );
+            id
+        }
+    };
+
+    // The invalid filters are reported by the forwarder of the server they
+    // belong to.
+    let routes: Arc<Vec<filters::Filters>> = Arc::new(
+        conf.routes
+            .iter()
+            .filter_map(|f| filters::Filters::from_config(f).ok())
+            .collect(),
+    );
+
+    let channel_plan = match conf.channels.is_empty() {
+        true => None,
+        false => Some(Arc::new(channels::ChannelPlan::new(
+            conf.channels.clone(),
+            conf.channel_check,
+        ))),
+    };
+
+    // The state store is shared by the forwarder restarts.
+    let store = match store {
+        Some(v) => v,
+        None => match store::new(
+            conf,
+            &format!("{}/{}", hex::encode(gateway_id), conf.server),
+        ) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Open state store error: {}, server: {}", e, conf.server);
+                return None;
+            }
+        },
+    };
+    if conf.replay_window_secs != 0 {
+        let count = store
+            .lock()
+            .unwrap()
+            .scan(b"replay/", SystemTime::now())
+            .len();
+        info!(
+            "Replay window entries restored from state store, server: {}, count: {}",
+            conf.server, count
+        );
+    }
+
     // loop so that we can restart the forwarder
     loop {
         info!("Starting forwarder, server: {}", conf.server);
+        status::set_connected(&conf.server, false);
+        if let Some(buffer) = &buffer {
+            status::set_buffered(&conf.server, buffer.lock().unwrap().len());
+        }
 
-        // setup udp socket
-        let socket = UdpSocket::bind("0.0.0.0:0").expect("udp socket bind error");
-        socket
-            .connect(&conf.server)
-            .expect("connect udp socket error");
-        socket
-            .set_read_timeout(Some(time::Duration::from_millis(100)))
-            .unwrap();
+        // setup socket(s)
+        // The sockets are reused on reload.
+        let (socket, downlink_socket, server_addrs) = match sockets.take() {
+            Some(v) => v,
+            None => match conf.transport {
+                Transport::Tcp => (
+                    transport::Socket::Tcp(transport::TcpConnection::new(
+                        &conf.server,
+                        conf.address_family,
+                        encryption_keys.clone(),
+                        tls.clone(),
+                    )),
+                    None,
+                    vec![],
+                ),
+                Transport::Udp => {
+                    let sockets = match conf.probe_addresses && !conf.read_only {
+                        true => {
+                            probe::select_address(conf, bind, server_gateway_id).map(|v| vec![v])
+                        }
+                        false => transport::resolve(&conf.server, conf.address_family)
+                            .map_err(|e| e.into()),
+                    }
+                    .map_err(|e| anyhow!("resolve server error: {}", e))
+                    .and_then(|addrs| udp_sockets(conf, bind, addrs));
+                    match sockets {
+                        Ok(v) => v,
+                        Err(e) => {
+                            error!("Setup UDP socket error: {}, server: {}", e, conf.server);
+                            match wait_retry(stop_receive) {
+                                None => continue,
+                                Some(signals::Signal::Reload(v)) => {
+                                    info!("Reloading forwarder, server: {}", conf.server);
+                                    return Some((
+                                        *v,
+                                        Reused {
+                                            sockets: None,
+                                            buffer,
+                                            store,
+                                        },
+                                    ));
+                                }
+                                Some(_) => {
+                                    info!("Forwarder stopped, server: {}", conf.server);
+                                    status::remove(&conf.server);
+                                    return None;
+                                }
+                            }
+                        }
+                    }
+                }
+            },
+        };
 
         // setup state
+        let keepalive_interval = match conf.keepalive_interval_secs {
+            0 => time::Duration::from_secs(5),
+            _ => time::Duration::from_secs(conf.keepalive_interval_secs),
+        };
         let state = State {
             socket,
+            downlink_socket,
+            server_addrs: Mutex::new(server_addrs),
+            source_filter: source_filter.clone(),
+            hmac: hmac_key.clone().map(|key| {
+                Mutex::new(hmac::Session::new(
+                    key,
+                    conf.hmac.mode == HmacMode::Required,
+                ))
+            }),
+            push_data_sent_at: Mutex::new(Instant::now()),
+            push_ack_received_at: Mutex::new(Instant::now()),
+            uplink_keepalive_failed: Mutex::new(false),
+            send_queue: sendq::SendQueue::new(SEND_QUEUE_SIZE),
+            downlink_queue: downq::DownlinkQueue::new(conf.downlink_queue_size),
+            store: store.clone(),
             server: conf.server.clone(),
-            keepalive_interval: match conf.keepalive_interval_secs {
-                0 => time::Duration::from_secs(5),
-                _ => time::Duration::from_secs(conf.keepalive_interval_secs),
-            },
+            keepalive_interval,
             forward_crc_ok: conf.forward_crc_ok,
             forward_crc_invalid: conf.forward_crc_invalid,
             forward_crc_missing: conf.forward_crc_missing,
+            tx_ack_on_success: conf.tx_ack_on_success,
+            downlink_timing_check: conf.downlink_timing_check,
+            last_counter: Mutex::new(None),
+            stat_bridge_object: conf.stat_bridge_object,
+            stat_with_rxpk: conf.stat_with_rxpk,
+            held_stat: Mutex::new(None),
+            fine_timestamp: conf.fine_timestamp,
+            read_only: conf.read_only,
+            role: conf.role,
+            mirror_stats: conf.mirror_stats,
+            json_version: conf.json_version,
+            data_rate_index_region: conf.data_rate_index_region.clone(),
+            rxpk_data_rate_index: conf.rxpk_data_rate_index,
+            forward_rssis: conf.forward_rssis,
+            downlink_fallback: match conf.downlink_fallback.frequency {
+                0 => None,
+                _ => Some(conf.downlink_fallback.clone()),
+            },
+            downlink_power: conf.downlink_power.clone(),
+            downlink_plan: conf.downlink_plan.clone(),
+            rssi_offsets: conf.rssi_offsets.clone(),
+            stat_fields: conf.stat_fields.clone(),
             keepalive_max_failures: conf.keepalive_max_failures,
-            gateway_id: gateway_id.clone(),
-            push_data_token: Mutex::new(0),
-            push_data_sent: Mutex::new(0),
-            push_data_acked: Mutex::new(0),
+            stats_interval: match conf.stats_interval_secs {
+                0 => None,
+                v => Some(time::Duration::from_secs(v)),
+            },
+            interval_jitter_percent: conf.interval_jitter_percent,
+            // The TCP transport resolves the hostname on each reconnect.
+            dns_refresh_interval: match conf.dns_refresh_interval_secs {
+                0 => None,
+                _ if conf.transport == Transport::Tcp => None,
+                v => Some(time::Duration::from_secs(v)),
+            },
+            dns_refresh_missed_acks: match conf.transport {
+                Transport::Tcp => 0,
+                Transport::Udp => conf.dns_refresh_missed_acks,
+            },
+            dns_refresh_requested: Mutex::new(false),
+            address_family: conf.address_family,
+            downlink_port: conf.downlink_port,
+            gateway_id: gateway_id.to_vec(),
+            server_gateway_id,
+            push_data_acks: Mutex::new(acks::AckTracker::new()),
+            tokens: tokens::TokenGenerator::new(conf.token_seed),
             pull_data_token: Mutex::new(0),
             pull_data_token_acked: Mutex::new(0),
             rxfw: Mutex::new(0),
-            event_sock: Mutex::new(
-                events::get_socket(&event_url).expect("get events client error"),
-            ),
-            command_sock: Mutex::new(
-                commands::get_socket(&command_url).expect("get commands client error"),
-            ),
+            replay_cache: match conf.replay_window_secs {
+                0 => None,
+                _ => Some(Mutex::new(replay::ReplayCache::new(
+                    time::Duration::from_secs(conf.replay_window_secs),
+                    store.clone(),
+                ))),
+            },
+            dedup_cache: match conf.dedup_ttl_ms {
+                0 => None,
+                _ => Some(Mutex::new(dedup::DedupCache::new(
+                    time::Duration::from_millis(conf.dedup_ttl_ms),
+                    conf.dedup_tmst_window_us,
+                    store.clone(),
+                ))),
+            },
+            filters: filters.clone(),
+            default_route: conf.default_route,
+            routes: routes.clone(),
+            channel_plan: channel_plan.clone(),
+            enricher: match conf.enricher.url.as_str() {
+                "" => None,
+                _ => Some(enricher::Enricher::new(&conf.enricher)),
+            },
+            retransmitter: match conf.push_data_retransmit_count {
+                0 => None,
+                _ if conf.read_only => None,
+                _ => Some(Mutex::new(retransmit::Retransmitter::new(
+                    time::Duration::from_millis(conf.push_data_retransmit_timeout_ms),
+                    conf.push_data_retransmit_count,
+                ))),
+            },
+            buffer: buffer.clone(),
+            batcher: match conf.batch_window_ms {
+                0 => None,
+                _ => Some(Mutex::new(batch::Batcher::new(
+                    time::Duration::from_millis(conf.batch_window_ms),
+                    conf.batch_max_size,
+                ))),
+            },
+            connected: Mutex::new(false),
+            draining: Mutex::new(false),
+            connection: Mutex::new(ConnectionTracker::new(
+                keepalive_interval,
+                conf.keepalive_max_failures,
+            )),
+            synthetic_stats: match conf.synthetic_stats.interval_secs {
+                0 => None,
+                _ => Some(conf.synthetic_stats.clone()),
+            },
+            stats_counters: Mutex::new(stats::Counters::default()),
+            bridge_counters: Mutex::new(stats::BridgeCounters::default()),
+            last_stats: Mutex::new(Instant::now()),
+            pending_stats: Mutex::new(None),
+            next_stats: Mutex::new(None),
+            backend: dispatcher.backend(),
         };
         let state = Arc::new(state);
+        metrics::set_server_connection_state(&state.server, ConnectionState::Unknown);
+        status::set_connection_state(&state.server, ConnectionState::Unknown.as_str());
 
         // Signal pool so that we can stop all threads in case of x failed
         // keepalive frames and start over again.
@@ -153,213 +728,1041 @@ pub fn start(conf: &Server, event_url: String, command_url: String, gateway_id:
         // let mut signal_pool = signals::SignalPool::new();
         let mut threads: Vec<thread::JoinHandle<()>> = vec![];
 
-        // UDP receive loop
+        // UDP send loop
         threads.push(thread::spawn({
             let state = state.clone();
             let stop_receive = signal_pool.new_receiver();
 
             move || {
-                udp_receive_loop(state, stop_receive);
+                send_loop(state, stop_receive);
             }
         }));
 
-        // event thread.
+        // UDP receive loop
         threads.push(thread::spawn({
             let state = state.clone();
             let stop_receive = signal_pool.new_receiver();
 
             move || {
-                events_loop(state, stop_receive);
+                udp_receive_loop(state, false, stop_receive);
             }
         }));
 
-        // PULL_DATA thread.
+        // Downlink socket receive and uplink socket keepalive loops.
+        if state.downlink_socket.is_some() {
+            threads.push(thread::spawn({
+                let state = state.clone();
+                let stop_receive = signal_pool.new_receiver();
+
+                move || {
+                    udp_receive_loop(state, true, stop_receive);
+                }
+            }));
+
+            if !state.read_only {
+                threads.push(thread::spawn({
+                    let state = state.clone();
+                    let stop_receive = signal_pool.new_receiver();
+
+                    move || {
+                        push_data_keepalive_loop(state, stop_receive);
+                    }
+                }));
+            }
+        }
+
+        // event thread.
         threads.push(thread::spawn({
-            let state = state.clone();
+            let frontend = UdpFrontend(state.clone());
+            let events = dispatcher.subscribe();
+            let stop_receive = signal_pool.new_receiver();
 
             move || {
-                pull_data_loop(state, signal_pool);
+                frontend::run(&frontend, events, stop_receive);
             }
         }));
 
+        // Downlink thread.
+        if !state.read_only {
+            restore_downlinks(&state);
+
+            threads.push(thread::spawn({
+                let state = state.clone();
+                let stop_receive = signal_pool.new_receiver();
+
+                move || {
+                    downlink_loop(state, stop_receive);
+                }
+            }));
+        }
+
+        // PUSH_DATA retransmit thread.
+        if state.retransmitter.is_some() {
+            threads.push(thread::spawn({
+                let state = state.clone();
+                let stop_receive = signal_pool.new_receiver();
+
+                move || {
+                    retransmit_loop(state, stop_receive);
+                }
+            }));
+        }
+
+        // Uplink batching thread.
+        if state.batcher.is_some() {
+            threads.push(thread::spawn({
+                let state = state.clone();
+                let stop_receive = signal_pool.new_receiver();
+
+                move || {
+                    batch_loop(state, stop_receive);
+                }
+            }));
+        }
+
+        // Synthetic stats thread.
+        if state.synthetic_stats.is_some() {
+            threads.push(thread::spawn({
+                let state = state.clone();
+                let stop_receive = signal_pool.new_receiver();
+
+                move || {
+                    synthetic_stats_loop(state, stop_receive);
+                }
+            }));
+        }
+
+        // DNS refresh thread.
+        if state.dns_refresh_interval.is_some() || state.dns_refresh_missed_acks != 0 {
+            threads.push(thread::spawn({
+                let state = state.clone();
+                let stop_receive = signal_pool.new_receiver();
+
+                move || {
+                    dns_refresh_loop(state, stop_receive);
+                }
+            }));
+        }
+
+        // PULL_DATA loop, this blocks until the forwarder must be stopped or
+        // restarted.
+        let exit = pull_data_loop(state.clone(), signal_pool, stop_receive);
+
         for t in threads {
             t.join().unwrap();
         }
 
-        warn!("Forwarder stopped, server: {}", conf.server);
+        match exit {
+            Exit::Stopped => {
+                info!("Forwarder stopped, server: {}", conf.server);
+                status::remove(&conf.server);
+                return None;
+            }
+            Exit::Restart => warn!("Forwarder stopped, server: {}", conf.server),
+            Exit::Reload(v) => {
+                info!("Reloading forwarder, server: {}", conf.server);
+
+                // All threads have stopped, thus the state is no longer
+                // shared.
+                let sockets = Arc::try_unwrap(state).ok().map(|state| {
+                    (
+                        state.socket,
+                        state.downlink_socket,
+                        state.server_addrs.into_inner().unwrap(),
+                    )
+                });
+                return Some((
+                    *v,
+                    Reused {
+                        sockets,
+                        buffer,
+                        store,
+                    },
+                ));
+            }
+        }
+    }
+}
+
+// The buffer is shared by the forwarder restarts, as the forwarder is
+// restarted when the server is unreachable.
+fn new_buffer(conf: &Server) -> Option<Arc<Mutex<buffer::UplinkBuffer>>> {
+    match conf.buffer_max_size {
+        0 => None,
+        _ if conf.read_only => None,
+        _ => Some(Arc::new(Mutex::new(buffer::UplinkBuffer::new(
+            conf.buffer_max_size,
+            time::Duration::from_secs(conf.buffer_max_age_secs),
+            match conf.buffer_path.as_str() {
+                "" => None,
+                v => Some(v.into()),
+            },
+        )))),
+    }
+}
+
+// Waits before retrying to setup the forwarder, e.g. when the server could
+// not be resolved. Returns the signal when the forwarder has been stopped or
+// reloaded in the meantime.
+fn wait_retry(stop_receive: &Receiver<signals::Signal>) -> Option<signals::Signal> {
+    let deadline = Instant::now() + RETRY_INTERVAL;
+    loop {
+        match stop_receive.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(signals::Signal::PullData) => continue,
+            Ok(v) => return Some(v),
+            Err(RecvTimeoutError::Timeout) => return None,
+            Err(RecvTimeoutError::Disconnected) => return Some(signals::Signal::Stop),
+        }
     }
 }
 
-fn pull_data_loop(state: Arc<State>, signal_pool: signals::SignalPool) {
+// Returns how the forwarder must continue, see Exit.
+fn pull_data_loop(
+    state: Arc<State>,
+    signal_pool: signals::SignalPool,
+    stop_receive: &Receiver<signals::Signal>,
+) -> Exit {
+    // Nothing is sent in read-only mode, thus there is nothing to acknowledge.
+    if state.read_only {
+        let exit = loop {
+            match stop_receive.recv() {
+                Ok(signals::Signal::PullData) => continue,
+                Ok(signals::Signal::Reload(v)) => break Exit::Reload(v),
+                _ => break Exit::Stopped,
+            }
+        };
+        signal_pool.send_signal(signals::Signal::Stop);
+
+        debug!("Terminating PULL_DATA loop, server: {}", state.server);
+        return exit;
+    }
+
     let mut missed_acks: u32 = 0;
+    let mut reannounce = false;
 
     loop {
-        if state.get_pull_data_token() != state.get_pull_data_token_acked() {
+        update_connection(&state, false);
+
+        // A re-announced PULL_DATA is sent before the previous one could have
+        // been acknowledged.
+        if reannounce {
+            reannounce = false;
+        } else if state.get_pull_data_token() != state.get_pull_data_token_acked() {
             warn!(
                 "Server did not acknowledge PULL_DATA, server: {}, token: {}",
                 state.server,
                 state.get_pull_data_token()
             );
             missed_acks += 1;
+            set_connected(&state, false);
+
+            if state.dns_refresh_missed_acks != 0 && missed_acks >= state.dns_refresh_missed_acks {
+                *state.dns_refresh_requested.lock().unwrap() = true;
+            }
         } else {
             missed_acks = 0;
         }
 
-        if state.keepalive_max_failures != 0 && missed_acks > state.keepalive_max_failures {
+        let uplink_failed = *state.uplink_keepalive_failed.lock().unwrap();
+        if uplink_failed
+            || (state.keepalive_max_failures != 0 && missed_acks > state.keepalive_max_failures)
+        {
             warn!(
-                "Max missed keepalive frames missed, server: {}",
-                state.server
+                "Max missed keepalive frames missed, server: {}, uplink_socket: {}",
+                state.server, uplink_failed
             );
             signal_pool.send_signal(signals::Signal::Stop);
 
             debug!("Terminating PULL_DATA loop, server: {}", state.server);
-            return;
+            return Exit::Restart;
         }
 
-        let mut id: [u8; 8] = [0; 8];
-        id.copy_from_slice(&state.gateway_id);
-
-        let pull_data = structs::PullData {
-            gateway_id: id,
+        let pull_data = protocol::PullData {
+            gateway_id: state.server_gateway_id,
             random_token: state.set_pull_data_token(),
         };
         let bytes = pull_data.to_bytes();
 
         info!("Sending PULL_DATA to server, server: {}", state.server);
-        if let Err(e) = state.socket.send(&bytes) {
-            error!("UDP send error: {}, server: {}", e, state.server);
-        };
+        state.send(sendq::Priority::High, &bytes, 0);
 
         metrics::incr_udp_sent_count(&state.server, "PULL_DATA");
         metrics::incr_udp_sent_bytes(&state.server, "PULL_DATA", bytes.len());
+        usage::sent(&state.server, bytes.len());
+
+        flush_held_stat(&state);
+
+        match stop_receive.recv_timeout(helpers::jitter(
+            state.keepalive_interval,
+            state.interval_jitter_percent,
+        )) {
+            Err(RecvTimeoutError::Timeout) => continue,
+            Ok(signals::Signal::PullData) => {
+                info!("Re-announcing PULL_DATA, server: {}", state.server);
+                reannounce = true;
+                continue;
+            }
+            Ok(signals::Signal::Shutdown(deadline)) => drain(&state, deadline),
+            Ok(signals::Signal::Reload(v)) => {
+                signal_pool.send_signal(signals::Signal::Stop);
+
+                debug!("Terminating PULL_DATA loop, server: {}", state.server);
+                return Exit::Reload(v);
+            }
+            _ => {}
+        }
+
+        signal_pool.send_signal(signals::Signal::Stop);
 
-        thread::sleep(state.keepalive_interval);
+        debug!("Terminating PULL_DATA loop, server: {}", state.server);
+        return Exit::Stopped;
     }
 }
 
-fn udp_receive_loop(state: Arc<State>, stop_receive: Receiver<signals::Signal>) {
-    let mut buffer: [u8; 65535] = [0; 65535];
+// Stops accepting uplinks, sends the pending batch and the final stat and
+// waits until the send queue (including the pending TX_ACKs) is empty or the
+// deadline is reached.
+fn drain(state: &Arc<State>, deadline: Instant) {
+    info!("Draining forwarder, server: {}", state.server);
+    *state.draining.lock().unwrap() = true;
+
+    if let Some(batcher) = &state.batcher {
+        let batch = batcher.lock().unwrap().take();
+        if let Some(rxpk) = batch {
+            send_rxpk(state, rxpk);
+        }
+    }
+    send_final_stat(state);
+
+    while state.send_queue.len() != 0 && Instant::now() < deadline {
+        thread::sleep(time::Duration::from_millis(10));
+    }
+
+    match state.send_queue.len() {
+        0 => info!("Forwarder drained, server: {}", state.server),
+        n => warn!(
+            "Drain timeout, dropping queued datagrams, server: {}, datagrams: {}",
+            state.server, n
+        ),
+    }
+}
+
+fn retransmit_loop(state: Arc<State>, stop_receive: Receiver<signals::Signal>) {
+    let retransmitter = match &state.retransmitter {
+        Some(v) => v,
+        None => return,
+    };
 
     loop {
         if stop_receive
-            .recv_timeout(time::Duration::from_millis(0))
+            .recv_timeout(time::Duration::from_millis(50))
             .is_ok()
         {
-            debug!("Terminating UDP receive loop, server: {}", state.server);
+            debug!("Terminating retransmit loop, server: {}", state.server);
             return;
-        };
+        }
 
-        let size = match state.socket.recv(&mut buffer) {
-            Ok(v) => v,
-            Err(_) => {
-                // Most likely, a timeout occured.
-                continue;
-            }
-        };
+        let (datagrams, dropped) = retransmitter.lock().unwrap().due(Instant::now());
+        state
+            .bridge_counters
+            .lock()
+            .unwrap()
+            .retransmitted(datagrams.len());
 
-        if size < 4 {
+        for bytes in datagrams {
+            debug!(
+                "Retransmitting unacknowledged PUSH_DATA, server: {}",
+                state.server
+            );
+            state.send(sendq::Priority::Low, &bytes, 0);
+
+            metrics::incr_udp_sent_count(&state.server, "PUSH_DATA_RXPK_RETRY");
+            metrics::incr_udp_sent_bytes(&state.server, "PUSH_DATA_RXPK_RETRY", bytes.len());
+            usage::sent(&state.server, bytes.len());
+        }
+
+        for _ in 0..dropped {
             warn!(
-                "At least 4 bytes are expected, received: {}, server: {}",
-                size, state.server
+                "PUSH_DATA not acknowledged after max. retries, server: {}",
+                state.server
             );
-            continue;
+            metrics::incr_uplink_dropped_count(&state.server, "NOT_ACKNOWLEDGED");
         }
+    }
+}
 
-        match buffer[3] {
-            0x01 => {
-                metrics::incr_udp_received_count(&state.server, "PUSH_ACK");
-                metrics::incr_udp_received_bytes(&state.server, "PUSH_ACK", size);
+fn batch_loop(state: Arc<State>, stop_receive: Receiver<signals::Signal>) {
+    let batcher = match &state.batcher {
+        Some(v) => v,
+        None => return,
+    };
 
-                if let Err(e) = handle_push_ack(&state, &buffer[..size]) {
-                    warn!("Handling PUSH_ACK error: {}, server: {}", e, state.server);
-                };
+    loop {
+        if stop_receive
+            .recv_timeout(time::Duration::from_millis(5))
+            .is_ok()
+        {
+            if let Some(rxpk) = batcher.lock().unwrap().take() {
+                send_rxpk(&state, rxpk);
             }
-            0x03 => {
-                metrics::incr_udp_received_count(&state.server, "PULL_RESP");
-                metrics::incr_udp_received_bytes(&state.server, "PULL_RESP", size);
 
-                if let Err(e) = handle_pull_resp(&state, &buffer[..size]) {
-                    warn!("handling PULL_RESP error: {}, server: {}", e, state.server);
-                };
-            }
-            0x04 => {
-                metrics::incr_udp_received_count(&state.server, "PULL_ACK");
-                metrics::incr_udp_received_bytes(&state.server, "PULL_ACK", size);
+            debug!("Terminating batch loop, server: {}", state.server);
+            return;
+        }
 
-                if let Err(e) = handle_pull_ack(&state, &buffer[..size]) {
-                    warn!("Handling PULL_ACK error: {}, server: {}", e, state.server);
-                };
-            }
-            _ => {
-                metrics::incr_udp_received_count(&state.server, "UNKNOWN");
-                metrics::incr_udp_received_bytes(&state.server, "UNKNOWN", size);
+        let batch = batcher.lock().unwrap().due(Instant::now());
+        if let Some(rxpk) = batch {
+            send_rxpk(&state, rxpk);
+        }
+    }
+}
 
-                warn!(
-                    "Ignoring unexepcted command, cid: {}, server: {}",
-                    buffer[3], state.server
-                );
+fn synthetic_stats_loop(state: Arc<State>, stop_receive: Receiver<signals::Signal>) {
+    let conf = match &state.synthetic_stats {
+        Some(v) => v,
+        None => return,
+    };
+    let interval = time::Duration::from_secs(conf.interval_secs);
+
+    loop {
+        if stop_receive
+            .recv_timeout(time::Duration::from_secs(1))
+            .is_ok()
+        {
+            debug!("Terminating synthetic stats loop, server: {}", state.server);
+            return;
+        }
+
+        // Stats received from the Concentratord take precedence.
+        {
+            let mut last_stats = state.last_stats.lock().unwrap();
+            if last_stats.elapsed() < interval {
                 continue;
             }
+            *last_stats = Instant::now();
         }
+
+        let stat = state
+            .stats_counters
+            .lock()
+            .unwrap()
+            .get_and_reset(conf, Utc::now());
+
+        debug!("Synthesizing gateway stats, server: {}", state.server);
+        send_stat(&state, stat);
     }
 }
 
-fn events_loop(state: Arc<State>, stop_receive: Receiver<signals::Signal>) {
-    let event_sock = state.event_sock.lock().unwrap();
-    let reader = events::Reader::new(&event_sock, time::Duration::from_millis(100));
+// Re-resolves the server hostname periodically and when the PULL_ACKs
+// stopped. When the current address is no longer returned, the socket is
+// connected to the new address, without restarting the forwarder.
+fn dns_refresh_loop(state: Arc<State>, stop_receive: Receiver<signals::Signal>) {
+    let mut last_refresh = Instant::now();
 
-    for cmd in reader {
+    loop {
         if stop_receive
-            .recv_timeout(time::Duration::from_millis(0))
+            .recv_timeout(time::Duration::from_secs(1))
             .is_ok()
         {
-            debug!("Terminating events loop, server: {}", state.server);
+            debug!("Terminating DNS refresh loop, server: {}", state.server);
             return;
         }
 
-        match cmd {
-            events::Event::Uplink(up) => {
-                events_up(&state, *up);
-            }
-            events::Event::Stats(stats) => {
-                events_stats(&state, *stats);
-            }
-            events::Event::Timeout => {
-                continue;
-            }
-            events::Event::Error(err) => {
-                error!("Read event error, error: {}", err);
-            }
-            events::Event::Unknown(event, _) => {
-                warn!("Unknown event received, event: {}", event);
-            }
+        let requested = std::mem::take(&mut *state.dns_refresh_requested.lock().unwrap());
+        let due = match state.dns_refresh_interval {
+            Some(interval) => last_refresh.elapsed() >= interval,
+            None => false,
+        };
+        if !(requested || due) {
+            continue;
+        }
+        last_refresh = Instant::now();
+
+        if requested {
+            debug!(
+                "Re-resolving server, PULL_ACKs stopped, server: {}",
+                state.server
+            );
         }
+        refresh_server_address(&state);
     }
 }
 
-fn events_stats(state: &Arc<State>, stats: chirpstack_api::gw::GatewayStats) {
-    let mut stat = match structs::Stat::from_proto(&stats) {
+fn refresh_server_address(state: &Arc<State>) {
+    let current = match state.socket.peer_addr() {
         Ok(v) => v,
-        Err(err) => {
-            error!("Stats from proto message error: {}", err);
+        Err(e) => {
+            warn!("Get peer address error: {}, server: {}", e, state.server);
             return;
         }
     };
-    stat.rxfw = state.get_and_reset_rxfw();
-
-    let pd_sent = state.get_and_reset_push_data_sent();
-    let pd_acked = state.get_and_reset_push_data_acked();
-    if pd_sent != 0 {
-        stat.ackr = pd_acked as f32 / pd_sent as f32 * 100.0
-    }
 
-    let mut id: [u8; 8] = [0; 8];
-    id.copy_from_slice(&state.gateway_id);
+    let addrs = match transport::resolve(&state.server, state.address_family) {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("Resolve server error: {}, server: {}", e, state.server);
+            return;
+        }
+    };
+
+    *state.server_addrs.lock().unwrap() = addrs.clone();
+
+    let addr = match addrs.first() {
+        Some(v) if !addrs.contains(&current) => *v,
+        _ => return,
+    };
+
+    info!(
+        "Server address changed, old: {}, new: {}, server: {}",
+        current, addr, state.server
+    );
+    metrics::incr_server_address_changed_count(&state.server);
+    if let Err(e) = state.socket.set_peer_addr(addr) {
+        error!("Connect udp socket error: {}, server: {}", e, state.server);
+    }
+    if let Some(socket) = &state.downlink_socket {
+        let addr = downlink_addrs(&[addr], state.downlink_port)[0];
+        if let Err(e) = socket.set_peer_addr(addr) {
+            error!(
+                "Connect downlink udp socket error: {}, server: {}",
+                e, state.server
+            );
+        }
+    }
+}
+
+// Sends an empty PUSH_DATA on the uplink socket when no PUSH_DATA has been
+// sent within the keepalive interval, to keep the NAT mapping of the uplink
+// socket open. As the PULL_DATA keepalive only covers the downlink socket,
+// the forwarder is restarted when the PUSH_ACKs stopped.
+fn push_data_keepalive_loop(state: Arc<State>, stop_receive: Receiver<signals::Signal>) {
+    loop {
+        if stop_receive
+            .recv_timeout(helpers::jitter(
+                state.keepalive_interval,
+                state.interval_jitter_percent,
+            ))
+            .is_ok()
+        {
+            debug!(
+                "Terminating PUSH_DATA keepalive loop, server: {}",
+                state.server
+            );
+            return;
+        }
+
+        let timeout = state.keepalive_interval * (state.keepalive_max_failures + 1);
+        if state.keepalive_max_failures != 0
+            && state.push_ack_received_at.lock().unwrap().elapsed() > timeout
+        {
+            *state.uplink_keepalive_failed.lock().unwrap() = true;
+        }
+
+        if state.push_data_sent_at.lock().unwrap().elapsed() < state.keepalive_interval {
+            continue;
+        }
+
+        let push_data = protocol::PushData {
+            random_token: state.new_push_data_token(),
+            gateway_id: state.server_gateway_id,
+            payload: protocol::PushDataPayload {
+                stat: None,
+                rxpk: vec![],
+            },
+        };
+        let bytes = push_data.to_bytes();
+
+        debug!("Sending PUSH_DATA keepalive, server: {}", state.server);
+        state.send(sendq::Priority::Low, &bytes, 0);
+        state.push_data_sent(push_data.random_token);
+
+        metrics::incr_udp_sent_count(&state.server, "PUSH_DATA_KEEPALIVE");
+        metrics::incr_udp_sent_bytes(&state.server, "PUSH_DATA_KEEPALIVE", bytes.len());
+        usage::sent(&state.server, bytes.len());
+    }
+}
+
+// Binds an UDP socket, sending to the first address which is routable (e.g. an
+// IPv6 address might not be on an IPv4-only backhaul). The socket is only
+// left unconnected when datagrams from allowlisted addresses must be received.
+// Returns the socket, the optional downlink socket and the server addresses.
+fn udp_sockets(
+    conf: &Server,
+    bind: Option<SocketAddr>,
+    addrs: Vec<SocketAddr>,
+) -> Result<(
+    transport::Socket,
+    Option<transport::Socket>,
+    Vec<SocketAddr>,
+)> {
+    let socket = udp_socket(conf, bind, &addrs)?;
+
+    // The downlink socket is bound to a random port, such that both sockets
+    // have their own source port.
+    let downlink_socket = match conf.split_sockets {
+        false => None,
+        true => Some(udp_socket(
+            conf,
+            bind.map(|a| SocketAddr::new(a.ip(), 0)),
+            &downlink_addrs(&addrs, conf.downlink_port),
+        )?),
+    };
+
+    Ok((socket, downlink_socket, addrs))
+}
+
+fn udp_socket(
+    conf: &Server,
+    bind: Option<SocketAddr>,
+    addrs: &[SocketAddr],
+) -> Result<transport::Socket> {
+    let socket = udp::bind(&udp::BindOptions {
+        addr: bind,
+        interface: conf.bind_interface.clone(),
+        reuse_port: conf.reuse_port,
+    })
+    .map_err(|e| anyhow!("bind udp socket error: {}", e))?;
+    let addr = match conf.source_allowlist.is_empty() {
+        true => addrs
+            .iter()
+            .find(|addr| udp::connect(&socket, **addr).is_ok()),
+        false => addrs.iter().find(|addr| udp::is_routable(**addr)),
+    }
+    .copied()
+    .ok_or_else(|| anyhow!("no routable server address"))?;
+    socket
+        .set_read_timeout(Some(time::Duration::from_millis(100)))
+        .map_err(|e| anyhow!("set udp socket read timeout error: {}", e))?;
+    if let Err(e) = udp::enable_timestamps(&socket) {
+        warn!(
+            "Enable UDP receive timestamps error: {}, server: {}",
+            e, conf.server
+        );
+    }
+    match udp::set_options(
+        &socket,
+        &udp::SocketOptions {
+            dscp: conf.dscp,
+            send_buffer_size: conf.send_buffer_size,
+            recv_buffer_size: conf.recv_buffer_size,
+        },
+    ) {
+        Ok((dscp, send_buffer_size, recv_buffer_size)) => info!(
+            "UDP socket options, dscp: {}, send_buffer_size: {}, recv_buffer_size: {}, server: {}",
+            dscp, send_buffer_size, recv_buffer_size, conf.server
+        ),
+        Err(e) => warn!(
+            "Set UDP socket options error: {}, server: {}",
+            e, conf.server
+        ),
+    }
+
+    Ok(match conf.source_allowlist.is_empty() {
+        true => transport::Socket::Udp(socket),
+        false => transport::Socket::UdpUnconnected(socket, Mutex::new(addr)),
+    })
+}
+
+// Returns the server addresses of the downlink socket, 0 keeps the port of the
+// server.
+fn downlink_addrs(addrs: &[SocketAddr], downlink_port: u16) -> Vec<SocketAddr> {
+    addrs
+        .iter()
+        .map(|a| match downlink_port {
+            0 => *a,
+            port => SocketAddr::new(a.ip(), port),
+        })
+        .collect()
+}
+
+// Sends the queued datagrams, the time-critical datagrams (PULL_DATA, TX_ACK)
+// before the PUSH_DATA.
+fn send_loop(state: Arc<State>, stop_receive: Receiver<signals::Signal>) {
+    loop {
+        if stop_receive.try_recv().is_ok() {
+            debug!("Terminating UDP send loop, server: {}", state.server);
+            return;
+        }
+
+        let datagram = match state.send_queue.pop(time::Duration::from_millis(100)) {
+            Some(v) => v,
+            None => continue,
+        };
+
+        let socket = state.socket_for(&datagram.bytes);
+        let bytes = state.sign(&datagram.bytes);
+        match socket.send(&bytes) {
+            Ok(_) => {
+                state.incr_rxfw(datagram.rxpk_count);
+                capture::datagram("SENT", &state.server, socket, &bytes);
+            }
+            Err(e) => error!("UDP send error: {}, server: {}", e, state.server),
+        }
+    }
+}
+
+// Receives the datagrams of the (downlink) socket.
+fn udp_receive_loop(state: Arc<State>, downlink: bool, stop_receive: Receiver<signals::Signal>) {
+    let socket = match (&state.downlink_socket, downlink) {
+        (Some(v), true) => v,
+        _ => &state.socket,
+    };
+    let mut buffer: [u8; 65535] = [0; 65535];
+
+    loop {
+        if stop_receive
+            .recv_timeout(time::Duration::from_millis(0))
+            .is_ok()
+        {
+            debug!("Terminating UDP receive loop, server: {}", state.server);
+            return;
+        };
+
+        let (size, received_at, source) = match socket.recv(&mut buffer) {
+            Ok(v) => v,
+            Err(_) => {
+                // Most likely, a timeout occured.
+                continue;
+            }
+        };
+
+        let allowed = state.source_filter.is_allowed(
+            source,
+            socket.peer_addr().ok(),
+            &state.server_addrs.lock().unwrap(),
+        );
+        if !allowed {
+            warn!(
+                "Rejecting datagram from unknown source, source: {}, server: {}",
+                source, state.server
+            );
+            metrics::incr_udp_rejected_count(&state.server, "SOURCE");
+            continue;
+        }
+
+        capture::datagram("RECEIVED", &state.server, socket, &buffer[..size]);
+
+        if size < 4 {
+            warn!(
+                "At least 4 bytes are expected, received: {}, server: {}",
+                size, state.server
+            );
+            continue;
+        }
+
+        let size = match verify_frame(&state, &buffer[..size]) {
+            Some(v) => v,
+            None => continue,
+        };
+
+        let message_type = match protocol::MessageType::try_from(buffer[3]) {
+            Ok(v) => v.as_str(),
+            Err(_) => "UNKNOWN",
+        };
+        metrics::incr_udp_received_count(&state.server, message_type);
+        metrics::incr_udp_received_bytes(&state.server, message_type, size);
+        usage::received(&state.server, size);
+
+        let res = match protocol::Frame::from_bytes(&buffer[..size]) {
+            Ok(protocol::Frame::PushAck(v)) => handle_push_ack(&state, v, received_at),
+            Ok(protocol::Frame::PullResp(v)) => handle_pull_resp(&state, v),
+            Ok(protocol::Frame::PullAck(v)) => handle_pull_ack(&state, v),
+            Ok(_) => Err(anyhow!("unexpected message type")),
+            Err(e) => Err(e.into()),
+        };
+
+        if let Err(e) = res {
+            warn!(
+                "Handling {} error: {}, server: {}",
+                message_type, e, state.server
+            );
+        }
+    }
+}
+
+// Downlink waiting in the downlink queue.
+struct QueuedDownlink {
+    token: u16,
+    pl: gw::DownlinkFrame,
+    beacon: bool,
+    // EIRP and conducted power (dBm) and whether the power has been capped.
+    power: (i32, i32, bool),
+}
+
+impl QueuedDownlink {
+    fn store_key(token: u16) -> Vec<u8> {
+        [&b"downlink/"[..], &token.to_be_bytes()].concat()
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let (eirp, conducted, capped) = self.power;
+        let mut b = vec![self.beacon as u8, capped as u8];
+        b.extend_from_slice(&eirp.to_be_bytes());
+        b.extend_from_slice(&conducted.to_be_bytes());
+        b.extend_from_slice(&self.pl.encode_to_vec());
+        b
+    }
+
+    fn from_store(key: &[u8], b: &[u8]) -> Result<Self> {
+        if key.len() != 11 || b.len() < 10 {
+            return Err(anyhow!("invalid key or value size"));
+        }
+
+        Ok(QueuedDownlink {
+            token: u16::from_be_bytes([key[9], key[10]]),
+            pl: gw::DownlinkFrame::decode(&b[10..])?,
+            beacon: b[0] != 0,
+            power: (
+                i32::from_be_bytes(b[2..6].try_into()?),
+                i32::from_be_bytes(b[6..10].try_into()?),
+                b[1] != 0,
+            ),
+        })
+    }
+}
+
+// Verifies the HMAC trailer of the received frame and returns the size of the
+// frame without the trailer, or None when the frame must be rejected.
+fn verify_frame(state: &Arc<State>, frame: &[u8]) -> Option<usize> {
+    let mut session = match &state.hmac {
+        Some(v) => v.lock().unwrap(),
+        None => return Some(frame.len()),
+    };
+
+    let pull_resp = matches!(
+        protocol::MessageType::try_from(frame[3]),
+        Ok(protocol::MessageType::PullResp)
+    );
+
+    let negotiated = session.is_negotiated();
+    match session.verify(frame, pull_resp) {
+        hmac::Verified::Signed(size) => {
+            if !negotiated {
+                info!(
+                    "Server supports HMAC, signing frames, server: {}",
+                    state.server
+                );
+            }
+            Some(size)
+        }
+        hmac::Verified::Unsigned => Some(frame.len()),
+        hmac::Verified::Missing => {
+            warn!("Rejecting PULL_RESP without HMAC, server: {}", state.server);
+            metrics::incr_udp_rejected_count(&state.server, "HMAC");
+            None
+        }
+        hmac::Verified::Invalid => {
+            warn!(
+                "Rejecting datagram with invalid HMAC, server: {}",
+                state.server
+            );
+            metrics::incr_udp_rejected_count(&state.server, "HMAC");
+            None
+        }
+    }
+}
+
+// Semtech UDP frontend, forwarding the events of the backend to the server.
+struct UdpFrontend(Arc<State>);
+
+impl Frontend for UdpFrontend {
+    fn name(&self) -> &str {
+        &self.0.server
+    }
+
+    fn handle_uplink(&self, up: gw::UplinkFrame) {
+        if *self.0.draining.lock().unwrap() {
+            return;
+        }
+
+        crash::record(format!(
+            "uplink, uplink_id: {}, server: {}",
+            up.rx_info.as_ref().map(|v| v.uplink_id).unwrap_or_default(),
+            self.0.server
+        ));
+        events_up(&self.0, up);
+    }
+
+    fn handle_stats(&self, stats: gw::GatewayStats) {
+        if *self.0.draining.lock().unwrap() {
+            return;
+        }
+
+        crash::record(format!("stats, server: {}", self.0.server));
+        events_stats(&self.0, stats);
+    }
+}
+
+fn events_stats(state: &Arc<State>, stats: chirpstack_api::gw::GatewayStats) {
+    let mut stat = match protocol::Stat::from_proto(&stats) {
+        Ok(v) => v,
+        Err(err) => {
+            error!("Stats from proto message error: {}", err);
+            return;
+        }
+    };
+
+    if state.stat_fields.forward_metadata {
+        stat.set_metadata(&stats.metadata);
+    }
+
+    *state.last_stats.lock().unwrap() = Instant::now();
+    state.stats_counters.lock().unwrap().reset();
+    report_objects(state);
+
+    // The stats received within the stats interval are merged and sent at
+    // once.
+    let stat = match state.stats_interval {
+        Some(interval) => {
+            let mut pending = state.pending_stats.lock().unwrap();
+            let stat = stats::merge(pending.take(), stat);
+
+            let mut next_stats = state.next_stats.lock().unwrap();
+            if matches!(*next_stats, Some(v) if Instant::now() < v) {
+                *pending = Some(stat);
+                return;
+            }
+            *next_stats =
+                Some(Instant::now() + helpers::jitter(interval, state.interval_jitter_percent));
+            stat
+        }
+        None => stat,
+    };
+
+    send_stat(state, stat);
+}
+
+// Reports the number of objects held by the subsystems, see the /memory
+// status endpoint.
+fn report_objects(state: &Arc<State>) {
+    let mut objects = BTreeMap::new();
+    objects.insert("send_queue", state.send_queue.len());
+    objects.insert(
+        "push_data_pending",
+        state.push_data_acks.lock().unwrap().len(),
+    );
+    if let Some(v) = &state.retransmitter {
+        objects.insert("retransmit_pending", v.lock().unwrap().len());
+    }
+    if let Some(v) = &state.buffer {
+        objects.insert("buffered", v.lock().unwrap().len());
+    }
+    if let Some(v) = &state.batcher {
+        objects.insert("batched", v.lock().unwrap().len());
+    }
+    if let Some(v) = &state.replay_cache {
+        objects.insert("replay_cache", v.lock().unwrap().len());
+    }
+    if let Some(v) = &state.dedup_cache {
+        objects.insert("dedup_cache", v.lock().unwrap().len());
+    }
+    status::set_objects(&state.server, objects);
+}
+
+fn send_stat(state: &Arc<State>, mut stat: protocol::Stat) {
+    location::apply(&mut stat);
+    set_stat_fields(state, &mut stat);
+    stat.rxfw = state.get_and_reset_rxfw();
+    stat.ackr = state.get_and_reset_ackr();
+
+    if state.role == ServerRole::Mirror {
+        if !state.mirror_stats {
+            return;
+        }
+
+        // The PUSH_ACKs of a mirror say nothing about the uplink path.
+        stat.ackr = 0.0;
+    }
+    if state.stat_bridge_object {
+        stat.bridge = Some(state.bridge_counters.lock().unwrap().get_and_reset());
+    }
 
-    let push_data = structs::PushData {
-        random_token: state.set_push_data_token(),
-        gateway_id: id,
-        payload: structs::PushDataPayload {
+    if state.read_only {
+        info!(
+            "Read-only mode, not sending PUSH_DATA with stats, server: {}, rxnb: {}, txnb: {}",
+            state.server, stat.rxnb, stat.txnb
+        );
+        return;
+    }
+
+    // The stat is sent with the next PUSH_DATA containing rxpk, see
+    // flush_held_stat for when no uplink is received.
+    if state.stat_with_rxpk {
+        let previous = state
+            .held_stat
+            .lock()
+            .unwrap()
+            .replace((stat, Instant::now()));
+        if let Some((stat, _)) = previous {
+            send_stat_push_data(state, stat);
+        }
+        return;
+    }
+
+    send_stat_push_data(state, stat);
+}
+
+// Sets the configured platform, email, description and custom fields.
+fn set_stat_fields(state: &Arc<State>, stat: &mut protocol::Stat) {
+    let conf = &state.stat_fields;
+    if !conf.platform.is_empty() {
+        stat.pfrm = Some(conf.platform.clone());
+    }
+    if !conf.email.is_empty() {
+        stat.mail = Some(conf.email.clone());
+    }
+    if !conf.description.is_empty() {
+        stat.desc = Some(conf.description.clone());
+    }
+    for (k, v) in &conf.custom {
+        stat.set_custom(k, v);
+    }
+}
+
+// Sends the stats which have not been sent yet on shutdown: the stats pending
+// within the stats interval (or else the counters since the last stats) and
+// the held stat.
+fn send_final_stat(state: &Arc<State>) {
+    let pending = state.pending_stats.lock().unwrap().take();
+    let stat = match pending {
+        Some(v) => v,
+        None => state.stats_counters.lock().unwrap().get_and_reset(
+            &state.synthetic_stats.clone().unwrap_or_default(),
+            Utc::now(),
+        ),
+    };
+    send_stat(state, stat);
+
+    // No rxpk will follow to send the held stat with.
+    let held = state.held_stat.lock().unwrap().take();
+    if let Some((stat, _)) = held {
+        send_stat_push_data(state, stat);
+    }
+}
+
+// Sends the held stat on its own when it has not been sent with an rxpk
+// within the keepalive interval.
+fn flush_held_stat(state: &Arc<State>) {
+    let stat = {
+        let mut held_stat = state.held_stat.lock().unwrap();
+        match &*held_stat {
+            Some((_, held_at)) if held_at.elapsed() >= state.keepalive_interval => {
+                held_stat.take().map(|(stat, _)| stat)
+            }
+            _ => None,
+        }
+    };
+
+    if let Some(stat) = stat {
+        send_stat_push_data(state, stat);
+    }
+}
+
+fn send_stat_push_data(state: &Arc<State>, stat: protocol::Stat) {
+    let push_data = protocol::PushData {
+        random_token: state.new_push_data_token(),
+        gateway_id: state.server_gateway_id,
+        payload: protocol::PushDataPayload {
             stat: Some(stat),
             rxpk: vec![],
         },
@@ -370,183 +1773,952 @@ fn events_stats(state: &Arc<State>, stats: chirpstack_api::gw::GatewayStats) {
         "Sending PUSH_DATA with stats to server, server: {}",
         state.server
     );
-    if let Err(e) = state.socket.send(&bytes) {
-        error!("UDP send error: {}, server: {}", e, state.server);
-    };
-
-    state.incr_push_data_sent();
+    state.send(sendq::Priority::Low, &bytes, 0);
+    state.push_data_sent(push_data.random_token);
 
     metrics::incr_udp_sent_count(&state.server, "PUSH_DATA_STATS");
     metrics::incr_udp_sent_bytes(&state.server, "PUSH_DATA_STATS", bytes.len());
+    usage::sent(&state.server, bytes.len());
 }
 
 fn events_up(state: &Arc<State>, up: chirpstack_api::gw::UplinkFrame) {
     if let Some(rx_info) = &up.rx_info {
-        if !((rx_info.crc_status() == gw::CrcStatus::CrcOk && state.forward_crc_ok)
-            || (rx_info.crc_status() == gw::CrcStatus::BadCrc && state.forward_crc_invalid)
-            || (rx_info.crc_status() == gw::CrcStatus::NoCrc && state.forward_crc_missing))
+        state
+            .stats_counters
+            .lock()
+            .unwrap()
+            .uplink_received(rx_info.crc_status() == gw::CrcStatus::CrcOk);
+
+        if rx_info.context.len() == 4 {
+            let mut tmst: [u8; 4] = [0; 4];
+            tmst.copy_from_slice(&rx_info.context);
+            *state.last_counter.lock().unwrap() = Some((u32::from_be_bytes(tmst), Instant::now()));
+        }
+
+        let (forward, reason) = match rx_info.crc_status() {
+            gw::CrcStatus::CrcOk => (state.forward_crc_ok, "CRC_OK"),
+            gw::CrcStatus::BadCrc => (state.forward_crc_invalid, "CRC_INVALID"),
+            gw::CrcStatus::NoCrc => (state.forward_crc_missing, "CRC_MISSING"),
+        };
+
+        if !forward {
+            debug!(
+                "Dropping uplink by CRC status, server: {}, crc_status: {}",
+                state.server, reason
+            );
+            metrics::incr_uplink_dropped_count(&state.server, reason);
+            return;
+        }
+    }
+
+    if let Some(filters) = &state.filters {
+        if !filters.is_allowed(&up.phy_payload) {
+            debug!("Dropping filtered uplink, server: {}", state.server);
+            metrics::incr_uplink_dropped_count(&state.server, "FILTER");
+            state.bridge_counters.lock().unwrap().filtered();
+            return;
+        }
+
+        if filters.is_routed(&up.phy_payload) {
+            metrics::incr_uplink_routed_count(&state.server, "ALLOW_LIST");
+        }
+    }
+
+    // The default route only receives the uplinks which are not routed to
+    // any of the other servers.
+    if state.default_route {
+        if state.routes.iter().any(|f| f.is_routed(&up.phy_payload)) {
+            debug!(
+                "Dropping uplink routed to other server, server: {}",
+                state.server
+            );
+            metrics::incr_uplink_dropped_count(&state.server, "ROUTED");
+            return;
+        }
+
+        metrics::incr_uplink_routed_count(&state.server, "DEFAULT");
+    }
+
+    if let Some(replay_cache) = &state.replay_cache {
+        if replay_cache
+            .lock()
+            .unwrap()
+            .is_replay(&up.phy_payload, SystemTime::now())
         {
+            warn!("Dropping replayed uplink, server: {}", state.server);
+            metrics::incr_uplink_dropped_count(&state.server, "REPLAY");
+            state.bridge_counters.lock().unwrap().dedup_hit();
             return;
         }
     }
 
-    let rxpk = match structs::RxPk::from_proto(&up) {
+    let mut rxpk = match protocol::RxPk::from_proto(&up) {
         Ok(v) => v,
         Err(err) => {
             error!("RxPk from proto message error: {}", err);
             return;
         }
     };
+    rxpk.tmms = rxpk.tmms.map(gps_time::correct_tmms);
 
-    let mut id: [u8; 8] = [0; 8];
-    id.copy_from_slice(&state.gateway_id);
+    gps_time::learn(
+        rxpk.tmms
+            .unwrap_or_else(|| gps_time::gps_time_ms(rxpk.time)),
+        rxpk.tmst,
+    );
 
-    let push_data = structs::PushData {
-        random_token: state.set_push_data_token(),
-        gateway_id: id,
-        payload: structs::PushDataPayload {
-            stat: None,
-            rxpk: vec![rxpk],
-        },
+    if let Some(dedup_cache) = &state.dedup_cache {
+        if dedup_cache
+            .lock()
+            .unwrap()
+            .is_duplicate(&up.phy_payload, rxpk.tmst, SystemTime::now())
+        {
+            debug!(
+                "Dropping duplicate uplink, server: {}, tmst: {}",
+                state.server, rxpk.tmst
+            );
+            metrics::incr_uplink_dropped_count(&state.server, "DUPLICATE");
+            state.bridge_counters.lock().unwrap().dedup_hit();
+            return;
+        }
+    }
+
+    if let Some(channel_plan) = &state.channel_plan {
+        let frequency = up.tx_info.as_ref().map(|v| v.frequency).unwrap_or_default();
+        for field in channel_plan.check(frequency, &mut rxpk.chan, &mut rxpk.rfch) {
+            warn!(
+                "Implausible uplink {}, server: {}, freq: {}, chan: {}, rfch: {}",
+                field, state.server, frequency, rxpk.chan, rxpk.rfch
+            );
+            metrics::incr_uplink_implausible_count(&state.server, field);
+        }
+
+        // Tag the uplink with the channel it was received on.
+        let channel = channel_plan.get(frequency).map(|(_, c)| c);
+        if let Some(c) = channel.filter(|c| !c.name.is_empty()) {
+            rxpk.set_meta("channel", &c.name);
+        }
+        if let Some(c) = channel.filter(|c| !c.sub_band.is_empty()) {
+            rxpk.set_meta("sub_band", &c.sub_band);
+        }
+        metrics::incr_uplink_channel_count(
+            &state.server,
+            channel.map_or("UNKNOWN", |c| c.name.as_str()),
+        );
+    }
+
+    if state.forward_rssis {
+        if let Some(rx_info) = &up.rx_info {
+            rxpk.set_signal_metrics(&rx_info.metadata);
+        }
+    }
+
+    let antenna = up.rx_info.as_ref().map(|v| v.antenna).unwrap_or_default();
+    if let Some(v) = state
+        .rssi_offsets
+        .iter()
+        .find(|v| v.rf_chain == rxpk.rfch && v.antenna == antenna)
+    {
+        rxpk.apply_rssi_offset(v.offset);
+    }
+
+    if let Some(tx_info) = &up.tx_info {
+        if let Some(airtime) = airtime::uplink(tx_info, up.phy_payload.len()) {
+            let datr = tx_info
+                .modulation
+                .as_ref()
+                .map(airtime::label)
+                .unwrap_or_default();
+            debug!(
+                "Uplink airtime, server: {}, freq: {}, datr: {}, airtime: {:?}",
+                state.server, tx_info.frequency, datr, airtime
+            );
+            metrics::observe_airtime(&state.server, "UPLINK", &datr, airtime);
+        }
+    }
+
+    if let Some(enricher) = &state.enricher {
+        let meta = enricher.enrich(&hex::encode(state.server_gateway_id), &rxpk);
+        for (k, v) in meta.unwrap_or_default() {
+            rxpk.set_meta(&k, &v);
+        }
+    }
+
+    if !state.fine_timestamp {
+        rxpk.ftime = None;
+    }
+
+    if state.rxpk_data_rate_index && !state.data_rate_index_region.is_empty() {
+        rxpk.set_data_rate_index(&state.data_rate_index_region);
+    }
+
+    if state.json_version == 2 {
+        rxpk.to_v2(up.rx_info.as_ref().map(|v| v.antenna).unwrap_or_default());
+    }
+
+    if let Some(buffer) = &state.buffer {
+        // The buffer lock is held while checking the connection state, see
+        // set_connected.
+        let mut buffer = buffer.lock().unwrap();
+        if !state.is_connected() {
+            let dropped = buffer.push(rxpk, Utc::now());
+            status::set_buffered(&state.server, buffer.len());
+            for _ in 0..dropped {
+                metrics::incr_uplink_dropped_count(&state.server, "BUFFER_FULL");
+            }
+
+            debug!(
+                "Server unreachable, buffering uplink, server: {}, buffered: {}",
+                state.server,
+                buffer.len()
+            );
+            return;
+        }
+    }
+
+    if let Some(batcher) = &state.batcher {
+        let size = serde_json::to_vec(&rxpk)
+            .map(|v| v.len())
+            .unwrap_or_default();
+        let batch = batcher.lock().unwrap().push(rxpk, size, Instant::now());
+        if let Some(rxpk) = batch {
+            send_rxpk(state, rxpk);
+        }
+        return;
+    }
+
+    send_rxpk(state, vec![rxpk]);
+}
+
+fn send_rxpk(state: &Arc<State>, rxpk: Vec<protocol::RxPk>) {
+    let count = rxpk.len() as u32;
+
+    if state.read_only {
+        info!(
+            "Read-only mode, not sending PUSH_DATA with rxpk, server: {}, count: {}",
+            state.server, count
+        );
+        return;
+    }
+
+    let stat = match state.stat_with_rxpk {
+        true => state.held_stat.lock().unwrap().take().map(|(stat, _)| stat),
+        false => None,
+    };
+
+    let push_data = protocol::PushData {
+        random_token: state.new_push_data_token(),
+        gateway_id: state.server_gateway_id,
+        payload: protocol::PushDataPayload { stat, rxpk },
     };
     let bytes = push_data.to_bytes();
+    let _span = info_span!("push_data", random_token = push_data.random_token).entered();
+
+    if logging::log_frames() {
+        debug!(
+            "PUSH_DATA: {}, server: {}",
+            String::from_utf8_lossy(&bytes[12..]),
+            state.server
+        );
+    }
 
     info!(
-        "Sending PUSH_DATA with rxpk to server, server: {}",
-        state.server
+        "Sending PUSH_DATA with rxpk to server, server: {}, count: {}, stat: {}",
+        state.server,
+        count,
+        push_data.payload.stat.is_some()
     );
-    if let Err(e) = state.socket.send(&bytes) {
-        error!("UDP send error: {}, server: {}", e, state.server);
-    };
-
-    state.incr_rxfw();
-    state.incr_push_data_sent();
+    // The rxpk are counted as forwarded when actually sent by the send loop.
+    state.send(sendq::Priority::Low, &bytes, count);
+    state.push_data_sent(push_data.random_token);
+
+    if let Some(retransmitter) = &state.retransmitter {
+        retransmitter
+            .lock()
+            .unwrap()
+            .sent(push_data.random_token, bytes.clone(), Instant::now());
+    }
 
     metrics::incr_udp_sent_count(&state.server, "PUSH_DATA_RXPK");
     metrics::incr_udp_sent_bytes(&state.server, "PUSH_DATA_RXPK", bytes.len());
+    usage::sent(&state.server, bytes.len());
 }
 
-fn handle_push_ack(state: &Arc<State>, data: &[u8]) -> Result<()> {
-    let push_ack = structs::PushAck::from_bytes(data)?;
-    let expected_token = state.get_push_data_token();
+// Updates the connection state of the server. When the server becomes
+// reachable again, the buffered uplinks are flushed.
+fn set_connected(state: &Arc<State>, connected: bool) {
+    status::set_connected(&state.server, connected);
 
-    if push_ack.random_token == expected_token {
-        debug!(
-            "PUSH_DATA acknowledged, token: {}, server: {}",
-            expected_token, state.server
+    let buffer = match &state.buffer {
+        Some(v) => v,
+        None => {
+            *state.connected.lock().unwrap() = connected;
+            return;
+        }
+    };
+
+    let mut buffer = buffer.lock().unwrap();
+    let previous = std::mem::replace(&mut *state.connected.lock().unwrap(), connected);
+
+    if previous == connected {
+        return;
+    }
+
+    if !connected {
+        warn!(
+            "Server unreachable, buffering uplinks, server: {}",
+            state.server
         );
+        return;
+    }
+
+    let (rxpk, expired) = buffer.drain(Utc::now());
+    status::set_buffered(&state.server, buffer.len());
+    drop(buffer);
+
+    for _ in 0..expired {
+        metrics::incr_uplink_dropped_count(&state.server, "BUFFER_EXPIRED");
+    }
+
+    if rxpk.is_empty() {
+        return;
+    }
+
+    info!(
+        "Server reachable, flushing buffered uplinks, server: {}, count: {}",
+        state.server,
+        rxpk.len()
+    );
+
+    // The batches are sent in order, an rxpk exceeding the max. size is sent
+    // by itself.
+    let mut batcher = batch::Batcher::new(time::Duration::default(), BUFFER_FLUSH_MAX_SIZE);
+    for rxpk in rxpk {
+        let size = serde_json::to_vec(&rxpk)
+            .map(|v| v.len())
+            .unwrap_or_default();
+        if let Some(batch) = batcher.push(rxpk, size, Instant::now()) {
+            send_rxpk(state, batch);
+        }
+    }
+    if let Some(batch) = batcher.take() {
+        send_rxpk(state, batch);
+    }
+}
+
+// Updates the connection state of the server, ack must be set when an
+// acknowledgement has been received.
+fn update_connection(state: &Arc<State>, ack: bool) {
+    let transition = {
+        let mut connection = state.connection.lock().unwrap();
+        match ack {
+            true => connection.ack_received(Instant::now()),
+            false => connection.update(Instant::now()),
+        }
+    };
+
+    if let Some((previous, current)) = transition {
+        match current {
+            ConnectionState::Online => info!(
+                "Server connection state changed, server: {}, from: {}, to: {}",
+                state.server,
+                previous.as_str(),
+                current.as_str()
+            ),
+            _ => warn!(
+                "Server connection state changed, server: {}, from: {}, to: {}",
+                state.server,
+                previous.as_str(),
+                current.as_str()
+            ),
+        }
+
+        metrics::set_server_connection_state(&state.server, current);
+        status::set_connection_state(&state.server, current.as_str());
+    }
+}
 
-        state.incr_push_data_acked();
+fn handle_push_ack(
+    state: &Arc<State>,
+    push_ack: protocol::PushAck,
+    received_at: SystemTime,
+) -> Result<()> {
+    let _span = info_span!("push_ack", random_token = push_ack.random_token).entered();
+
+    if let Some(retransmitter) = &state.retransmitter {
+        retransmitter.lock().unwrap().acked(push_ack.random_token);
+    }
+
+    if let Some(latency) = state.push_data_acked(push_ack.random_token, received_at) {
+        *state.push_ack_received_at.lock().unwrap() = Instant::now();
+        debug!(
+            "PUSH_DATA acknowledged, token: {}, latency: {:?}, server: {}",
+            push_ack.random_token, latency, state.server
+        );
+        metrics::observe_push_ack_latency(&state.server, latency);
+        state
+            .bridge_counters
+            .lock()
+            .unwrap()
+            .push_ack_received(latency);
+
+        set_connected(state, true);
+        update_connection(state, true);
     }
 
     Ok(())
 }
 
-fn handle_pull_ack(state: &Arc<State>, data: &[u8]) -> Result<()> {
-    let push_ack = structs::PullAck::from_bytes(data)?;
+fn handle_pull_ack(state: &Arc<State>, pull_ack: protocol::PullAck) -> Result<()> {
+    let _span = info_span!("pull_ack", random_token = pull_ack.random_token).entered();
     let expected_token = state.get_pull_data_token();
-    state.set_pull_data_token_acked(push_ack.random_token);
+    state.set_pull_data_token_acked(pull_ack.random_token);
 
-    if push_ack.random_token == expected_token {
+    if pull_ack.random_token == expected_token {
         info!(
             "PULL_DATA acknowledged, token: {}, server: {}",
             expected_token, state.server
         );
+
+        status::pull_ack_received(&state.server);
+        set_connected(state, true);
+        update_connection(state, true);
     }
 
     Ok(())
 }
 
-fn handle_pull_resp(state: &Arc<State>, data: &[u8]) -> Result<()> {
-    let pull_resp = structs::PullResp::from_bytes(data)?;
-    let sock = state.command_sock.lock().unwrap();
+// Validates the downlink timing against the estimated concentrator counter,
+// see downlink::check_timing.
+fn check_downlink_timing(state: &Arc<State>, pl: &gw::DownlinkFrame) -> Option<gw::TxAckStatus> {
+    if !state.downlink_timing_check {
+        return None;
+    }
+
+    let (tmst, received_at) = (*state.last_counter.lock().unwrap())?;
+    let counter = tmst.wrapping_add(received_at.elapsed().as_micros() as u32);
+    downlink::check_timing(pl, counter)
+}
+
+// Returns the frequency and time-on-air of the downlink item.
+pub fn downlink_airtime(item: &gw::DownlinkFrameItem) -> Option<(u32, time::Duration)> {
+    let tx_info = item.tx_info.as_ref()?;
+    let airtime = airtime::downlink(tx_info, item.phy_payload.len())?;
+    Some((tx_info.frequency, airtime))
+}
+
+// TX_ACK status of a downlink exceeding the duty cycle. The Semtech protocol
+// does not define a duty-cycle error, the frequency is reported as not
+// usable. The downlink_failed_count metric tells it apart from a frequency
+// outside the downlink plan (reason DUTY_CYCLE).
+pub const DUTY_CYCLE_TX_ACK_STATUS: gw::TxAckStatus = gw::TxAckStatus::TxFreq;
+
+// Returns true when none of the downlink items can be transmitted within the
+// duty cycle.
+pub fn exceeds_duty_cycle(pl: &gw::DownlinkFrame) -> bool {
+    !pl.items.iter().any(|item| match downlink_airtime(item) {
+        Some((frequency, airtime)) => dutycycle::is_allowed(frequency, airtime),
+        None => true,
+    })
+}
+
+// Converts the GPS time (tmms) of the downlink to the concentrator counter
+// (tmst), for concentrators without GPS. Without (recent) uplinks to derive
+// the counter from, the downlink is sent as-is. Beacons require the GPS
+// precision and are never converted.
+fn convert_tmms(state: &Arc<State>, pull_resp: &mut protocol::PullResp) {
+    let txpk = &mut pull_resp.payload.txpk;
+    if txpk.is_beacon() {
+        return;
+    }
+
+    let tmms = match txpk.tmms {
+        Some(v) if txpk.tmst.is_none() && !txpk.imme.unwrap_or(false) => v,
+        _ => return,
+    };
+
+    if let Some(tmst) = gps_time::to_tmst(tmms) {
+        debug!(
+            "Converted downlink tmms to tmst, token: {}, tmms: {}, time: {}, tmst: {}, server: {}",
+            pull_resp.random_token,
+            tmms,
+            gps_time::utc_time(tmms)
+                .map(|v| v.to_rfc3339())
+                .unwrap_or_default(),
+            tmst,
+            state.server
+        );
+        txpk.tmst = Some(tmst);
+    }
+}
+
+fn handle_pull_resp(state: &Arc<State>, mut pull_resp: protocol::PullResp) -> Result<()> {
+    // The random token is used as downlink_id towards the Concentratord.
+    let _span = info_span!(
+        "pull_resp",
+        random_token = pull_resp.random_token,
+        downlink_id = pull_resp.random_token as u32
+    )
+    .entered();
 
-    let pl = match pull_resp
+    if logging::log_frames() {
+        debug!(
+            "PULL_RESP txpk: {}, server: {}",
+            serde_json::to_string(&pull_resp.payload.txpk).unwrap_or_default(),
+            state.server
+        );
+    }
+
+    if state.read_only {
+        info!(
+            "Read-only mode, ignoring downlink, server: {}, token: {}",
+            state.server, pull_resp.random_token
+        );
+        metrics::incr_downlink_failed_count(&state.server, "READ_ONLY");
+        return Ok(());
+    }
+
+    if state.role == ServerRole::Mirror {
+        debug!(
+            "Mirror role, ignoring downlink, server: {}, token: {}",
+            state.server, pull_resp.random_token
+        );
+        metrics::incr_downlink_failed_count(&state.server, "MIRROR");
+        return Ok(());
+    }
+
+    let connection_state = state.connection.lock().unwrap().state();
+    if connection_state != ConnectionState::Online {
+        return reject_pull_resp(
+            state,
+            pull_resp.random_token,
+            gw::TxAckStatus::Ignored,
+            "NOT_ONLINE",
+            anyhow!(
+                "server connection state is {}, ignoring downlink",
+                connection_state.as_str()
+            ),
+        );
+    }
+
+    crash::record(format!(
+        "downlink, token: {}, server: {}",
+        pull_resp.random_token, state.server
+    ));
+    // Beacons are timed by GPS time, the Concentratord reports
+    // GPS_UNLOCKED when it has no GPS lock and COLLISION_BEACON for
+    // downlinks colliding with a beacon.
+    let beacon = pull_resp.payload.txpk.is_beacon();
+    if beacon {
+        info!(
+            "Class B beacon received, token: {}, tmms: {}, server: {}",
+            pull_resp.random_token,
+            pull_resp.payload.txpk.tmms.unwrap_or_default(),
+            state.server
+        );
+    }
+
+    convert_tmms(state, &mut pull_resp);
+
+    if !state.data_rate_index_region.is_empty() {
+        if let Err(err) = pull_resp
+            .payload
+            .txpk
+            .resolve_data_rate_index(&state.data_rate_index_region)
+        {
+            return reject_pull_resp(
+                state,
+                pull_resp.random_token,
+                gw::TxAckStatus::InternalError,
+                "INVALID_DATR",
+                err.into(),
+            );
+        }
+    }
+
+    let mut pl = match pull_resp
         .payload
         .txpk
         .to_proto(pull_resp.random_token as u32, state.gateway_id.clone())
     {
         Ok(v) => v,
         Err(err) => {
-            return Err(anyhow!("TxPk to proto error: {}", err));
+            return reject_pull_resp(
+                state,
+                pull_resp.random_token,
+                gw::TxAckStatus::InternalError,
+                "INVALID_TXPK",
+                anyhow!("TxPk to proto error: {}", err),
+            );
         }
     };
 
-    let mut buf = Vec::new();
-    pl.encode(&mut buf).unwrap();
+    if let Some(fallback) = &state.downlink_fallback {
+        downlink::add_fallback_item(&mut pl, fallback);
+    }
+
+    let rf_chain = pull_resp.payload.txpk.rfch as u32;
+    let (eirp, conducted, capped) = downlink::apply_power(&mut pl, &state.downlink_power, rf_chain);
+    if capped {
+        warn!(
+            "Downlink power capped, token: {}, power_eirp: {} dBm, rf_chain: {}, server: {}",
+            pull_resp.random_token, eirp, rf_chain, state.server
+        );
+    }
+    let airtime = pl.items.first().and_then(downlink_airtime).map(|v| v.1);
+    info!(
+        "Sending downlink to Concentratord, token: {}, power_eirp: {} dBm, power_conducted: {} dBm, airtime: {:?}, server: {}",
+        pull_resp.random_token, eirp, conducted, airtime.unwrap_or_default(), state.server
+    );
+
+    // A downlink which can not be scheduled is reported to the server
+    // immediately, see DUTY_CYCLE_TX_ACK_STATUS for the duty cycle.
+    let rejected = match downlink::check_plan(&pl, &state.downlink_plan) {
+        Some(v) => Some(v),
+        None => match check_downlink_timing(state, &pl) {
+            Some(status) => Some((status, status.as_str_name())),
+            None if exceeds_duty_cycle(&pl) => Some((DUTY_CYCLE_TX_ACK_STATUS, "DUTY_CYCLE")),
+            None => None,
+        },
+    };
+
+    let queued = QueuedDownlink {
+        token: pull_resp.random_token,
+        pl,
+        beacon,
+        power: (eirp, conducted, capped),
+    };
 
-    // send 'down' command with payload
-    sock.send("down", zmq::SNDMORE).unwrap();
-    sock.send(buf, 0).unwrap();
+    if let Some((status, reason)) = rejected {
+        warn!(
+            "Downlink can not be scheduled, token: {}, reason: {}, frequency: {}, power_eirp: {} dBm, datr: {}, server: {}",
+            pull_resp.random_token,
+            reason,
+            pull_resp.payload.txpk.freq,
+            eirp,
+            queued
+                .pl
+                .items
+                .first()
+                .and_then(|v| v.tx_info.as_ref())
+                .and_then(|v| v.modulation.as_ref())
+                .map(airtime::label)
+                .unwrap_or_default(),
+            state.server
+        );
+        metrics::incr_downlink_failed_count(&state.server, reason);
 
-    // set poller so that we can timeout after 100ms
-    let mut items = [sock.as_poll_item(zmq::POLLIN)];
-    zmq::poll(&mut items, 100).unwrap();
-    if !items[0].is_readable() {
-        return Err(anyhow!("could not read down response"));
+        return send_tx_ack(state, &queued, tx_ack_status(status));
     }
 
-    // read tx ack response.
-    let resp_b: &[u8] = &sock.recv_bytes(0).unwrap();
-    let tx_ack = match chirpstack_api::gw::DownlinkTxAck::decode(resp_b) {
-        Ok(v) => v,
-        Err(err) => {
-            return Err(anyhow!("decode DownlinkTxAck error: {}", err));
+    // The downlink is kept in the state store until it has been sent, such
+    // that it is not lost on a restart.
+    let key = QueuedDownlink::store_key(queued.token);
+    state.store.lock().unwrap().put(
+        &key,
+        &queued.to_bytes(),
+        DOWNLINK_STORE_TTL,
+        SystemTime::now(),
+    );
+
+    // A full queue is reported to the server immediately, instead of backing
+    // up the downlinks.
+    if let Err(queued) = state.downlink_queue.push(queued) {
+        state.store.lock().unwrap().delete(&key);
+        warn!(
+            "Downlink queue full, token: {}, server: {}",
+            queued.token, state.server
+        );
+        metrics::incr_downlink_failed_count(&state.server, "QUEUE_FULL");
+
+        return send_tx_ack(state, &queued, tx_ack_status(gw::TxAckStatus::QueueFull));
+    }
+    metrics::set_downlink_queue_depth(&state.server, state.downlink_queue.len());
+
+    Ok(())
+}
+
+// Queues the downlinks which were not yet sent before the restart.
+fn restore_downlinks(state: &Arc<State>) {
+    let entries = state
+        .store
+        .lock()
+        .unwrap()
+        .scan(b"downlink/", SystemTime::now());
+
+    for (key, value) in entries {
+        let queued = match QueuedDownlink::from_store(&key, &value) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!(
+                    "Invalid queued downlink in state store, error: {}, server: {}",
+                    e, state.server
+                );
+                state.store.lock().unwrap().delete(&key);
+                continue;
+            }
+        };
+
+        info!(
+            "Restoring queued downlink, token: {}, server: {}",
+            queued.token, state.server
+        );
+        if state.downlink_queue.push(queued).is_err() {
+            state.store.lock().unwrap().delete(&key);
         }
+    }
+    metrics::set_downlink_queue_depth(&state.server, state.downlink_queue.len());
+}
+
+// Sends the queued downlinks to the Concentratord.
+fn downlink_loop(state: Arc<State>, stop_receive: Receiver<signals::Signal>) {
+    loop {
+        if stop_receive.try_recv().is_ok() {
+            debug!("Terminating downlink loop, server: {}", state.server);
+            return;
+        }
+
+        let queued = match state.downlink_queue.pop(time::Duration::from_millis(100)) {
+            Some(v) => v,
+            None => continue,
+        };
+        metrics::set_downlink_queue_depth(&state.server, state.downlink_queue.len());
+        state
+            .store
+            .lock()
+            .unwrap()
+            .delete(&QueuedDownlink::store_key(queued.token));
+
+        let _span = info_span!(
+            "downlink",
+            random_token = queued.token,
+            downlink_id = queued.token as u32
+        )
+        .entered();
+
+        // On failure an INTERNAL_ERROR is reported.
+        let tx_ack = match state.backend.send_downlink(&queued.pl) {
+            Ok(v) => v,
+            Err((reason, e)) => {
+                error!(
+                    "Sending downlink to Concentratord failed, token: {}, reason: {}, error: {}, server: {}",
+                    queued.token, reason, e, state.server
+                );
+                metrics::incr_downlink_failed_count(&state.server, reason);
+                tx_ack_status(gw::TxAckStatus::InternalError)
+            }
+        };
+
+        if let Err(e) = send_tx_ack(&state, &queued, tx_ack) {
+            warn!(
+                "Handling TX_ACK error: {}, token: {}, server: {}",
+                e, queued.token, state.server
+            );
+        }
+    }
+}
+
+// Rejects a downlink which could not be converted or must not be sent. The
+// error is reported to the server by a TX_ACK, such that it does not wait for
+// the TX_ACK timeout, and returned to be logged.
+fn reject_pull_resp(
+    state: &Arc<State>,
+    token: u16,
+    status: gw::TxAckStatus,
+    reason: &str,
+    err: anyhow::Error,
+) -> Result<()> {
+    metrics::incr_downlink_failed_count(&state.server, reason);
+
+    let queued = QueuedDownlink {
+        token,
+        pl: gw::DownlinkFrame::default(),
+        beacon: false,
+        power: (0, 0, false),
     };
+    send_tx_ack(state, &queued, tx_ack_status(status))?;
 
-    // udp tx ack
-    let tx_ack_udp = structs::TxAck {
-        random_token: pull_resp.random_token,
-        gateway_id: {
-            let mut id: [u8; 8] = [0; 8];
-            id.copy_from_slice(&state.gateway_id);
-            id
-        },
-        payload: structs::TxAckPayload {
-            txpk_ack: structs::TxAckPayloadError {
-                error: {
-                    if tx_ack.items.len() != 1 {
-                        return Err(anyhow!(""));
-                    }
+    Err(err)
+}
 
-                    match tx_ack.items[0].status() {
-                        chirpstack_api::gw::TxAckStatus::Ok => "".to_string(),
-                        chirpstack_api::gw::TxAckStatus::Ignored => "IGNORED".to_string(),
-                        chirpstack_api::gw::TxAckStatus::TooLate => "TOO_LATE".to_string(),
-                        chirpstack_api::gw::TxAckStatus::TooEarly => "TOO_EARLY".to_string(),
-                        chirpstack_api::gw::TxAckStatus::CollisionPacket => {
-                            "COLLISION_PACKET".to_string()
-                        }
-                        chirpstack_api::gw::TxAckStatus::CollisionBeacon => {
-                            "COLLISION_BEACON".to_string()
-                        }
-                        chirpstack_api::gw::TxAckStatus::TxFreq => "TX_FREQ".to_string(),
-                        chirpstack_api::gw::TxAckStatus::TxPower => "TX_POWER".to_string(),
-                        chirpstack_api::gw::TxAckStatus::GpsUnlocked => "GPS_UNLOCKED".to_string(),
-                        chirpstack_api::gw::TxAckStatus::QueueFull => "QUEUE_FULL".to_string(),
-                        chirpstack_api::gw::TxAckStatus::InternalError => {
-                            "INTERNAL_ERROR".to_string()
-                        }
-                    }
-                },
+fn tx_ack_status(status: gw::TxAckStatus) -> gw::DownlinkTxAck {
+    gw::DownlinkTxAck {
+        items: vec![gw::DownlinkTxAckItem {
+            status: status.into(),
+        }],
+        ..Default::default()
+    }
+}
+
+// Reports the result of the downlink to the server.
+fn send_tx_ack(
+    state: &Arc<State>,
+    queued: &QueuedDownlink,
+    tx_ack: gw::DownlinkTxAck,
+) -> Result<()> {
+    let (eirp, conducted, capped) = queued.power;
+    let (item, status) = downlink::get_tx_ack_status(&tx_ack)?;
+    if let Some(item) = item.and_then(|i| queued.pl.items.get(i)) {
+        if let Some((frequency, airtime)) = downlink_airtime(item) {
+            let datr = item
+                .tx_info
+                .as_ref()
+                .and_then(|v| v.modulation.as_ref())
+                .map(airtime::label)
+                .unwrap_or_default();
+            metrics::observe_airtime(&state.server, "DOWNLINK", &datr, airtime);
+            dutycycle::transmitted(frequency, airtime);
+        }
+    }
+    match item {
+        Some(0) if queued.beacon => metrics::incr_downlink_emitted_count(&state.server, "BEACON"),
+        Some(0) => metrics::incr_downlink_emitted_count(&state.server, "PRIMARY"),
+        Some(i) => {
+            info!(
+                "Downlink emitted using fallback item, item: {}, server: {}",
+                i, state.server
+            );
+            metrics::incr_downlink_emitted_count(&state.server, "FALLBACK");
+        }
+        None => {}
+    }
+
+    // A capped power is reported as TX_POWER warning, with the emitted power
+    // in the reference of the server.
+    let power_warn = match (capped, status) {
+        (true, gw::TxAckStatus::Ok) => Some(match state.downlink_power.reference {
+            PowerReference::Eirp => eirp,
+            PowerReference::Conducted => conducted,
+        }),
+        _ => None,
+    };
+
+    // udp tx ack
+    let tx_ack_udp = protocol::TxAck {
+        random_token: queued.token,
+        gateway_id: state.server_gateway_id,
+        payload: protocol::TxAckPayload {
+            txpk_ack: protocol::TxAckPayloadError {
+                error: protocol::TxAckError::from_proto(status),
+                warn: power_warn.map(|_| protocol::TxAckError::TxPower),
+                value: power_warn,
             },
         },
     };
     let bytes = tx_ack_udp.to_bytes();
 
+    state
+        .stats_counters
+        .lock()
+        .unwrap()
+        .downlink_received(tx_ack_udp.payload.txpk_ack.error == protocol::TxAckError::None);
+
+    if tx_ack_udp.payload.txpk_ack.error == protocol::TxAckError::None
+        && tx_ack_udp.payload.txpk_ack.warn.is_none()
+        && !state.tx_ack_on_success
+    {
+        return Ok(());
+    }
+
     debug!("Sending TX_ACK to server, server: {}", state.server);
-    if let Err(e) = state.socket.send(&bytes) {
-        error!("UDP send error: {}, server: {}", e, state.server);
-    };
+    state.send(sendq::Priority::High, &bytes, 0);
 
-    let metrics_key: String = match tx_ack_udp.payload.txpk_ack.error.as_str() {
-        "" => "TX_ACK_OK".to_string(),
-        _ => "TX_ACK_ERROR_".to_owned() + &tx_ack_udp.payload.txpk_ack.error,
+    let metrics_key: String = match tx_ack_udp.payload.txpk_ack.error {
+        protocol::TxAckError::None => "TX_ACK_OK".to_string(),
+        e => format!("TX_ACK_ERROR_{}", e.as_str()),
     };
 
     metrics::incr_udp_sent_count(&state.server, &metrics_key);
     metrics::incr_udp_sent_bytes(&state.server, &metrics_key, bytes.len());
+    usage::sent(&state.server, bytes.len());
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::sync::mpsc::channel;
+
+    use super::*;
+    use crate::backend;
+    use crate::config;
+
+    #[test]
+    fn test_duty_cycle_tx_ack_status() {
+        assert_eq!(
+            protocol::TxAckError::from_proto(DUTY_CYCLE_TX_ACK_STATUS),
+            protocol::TxAckError::TxFreq
+        );
+    }
+
+    #[test]
+    fn test_read_only_pull_resp() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(time::Duration::from_millis(100)))
+            .unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        // Free port for the forwarder socket, such that the PULL_RESP can be
+        // sent to it without a PULL_DATA.
+        let bind = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let conf = Server {
+            server: server_addr.to_string(),
+            bind: bind.to_string(),
+            read_only: true,
+            ..Default::default()
+        };
+        let backend = backend::Mock::new(&config::MockBackend {
+            uplink_interval_ms: 0,
+            stats_interval_secs: 0,
+            ..Default::default()
+        })
+        .unwrap();
+        let dispatcher = frontend::Dispatcher::start(Box::new(backend));
+
+        let (stop_send, stop_receive) = channel();
+        let forwarder = thread::spawn({
+            let conf = conf.clone();
+            move || {
+                start(
+                    &conf,
+                    dispatcher,
+                    vec![1, 2, 3, 4, 5, 6, 7, 8],
+                    stop_receive,
+                )
+            }
+        });
+
+        let pull_resp: protocol::PullResp = protocol::PullResp::from_bytes(
+            &[
+                &[2, 0, 1, 3][..],
+                br#"{"txpk":{"imme":true,"freq":869.525,"rfch":0,"powe":14,"modu":"LORA","datr":"SF9BW125","codr":"4/5","ipol":true,"size":3,"data":"AQID"}}"#,
+            ]
+            .concat(),
+        )
+        .unwrap();
+
+        // The PULL_RESP is sent until the forwarder has handled it.
+        let mut buffer: [u8; 65535] = [0; 65535];
+        let started = Instant::now();
+        while metrics::get_downlink_failed_count(&conf.server, "READ_ONLY") == 0 {
+            assert!(started.elapsed() < time::Duration::from_secs(5));
+            server.send_to(&pull_resp.to_bytes(), bind).unwrap();
+            assert!(server.recv_from(&mut buffer).is_err());
+        }
+
+        // Neither a TX_ACK nor any other frame is sent to the server.
+        let started = Instant::now();
+        while started.elapsed() < time::Duration::from_millis(500) {
+            assert!(server.recv_from(&mut buffer).is_err());
+        }
+
+        stop_send.send(signals::Signal::Stop).unwrap();
+        forwarder.join().unwrap();
+    }
+}
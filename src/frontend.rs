@@ -0,0 +1,183 @@
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use chirpstack_api::gw;
+
+use super::backend::Backend;
+use super::events::Event;
+use super::signals;
+use super::status;
+
+// Max. number of events waiting to be handled by a frontend.
+const EVENT_QUEUE_SIZE: usize = 128;
+
+// Upstream protocol frontend (e.g. the Semtech UDP client), handling the
+// uplinks and stats of the backend. Downlinks are sent through the backend of
+// the Dispatcher. Other protocols (e.g. Basics Station, MQTT or TCP) can be
+// added by implementing this trait.
+pub trait Frontend: Send + Sync {
+    // Name of the frontend, used in the logs (e.g. the server).
+    fn name(&self) -> &str;
+
+    fn handle_uplink(&self, up: gw::UplinkFrame);
+
+    fn handle_stats(&self, stats: gw::GatewayStats);
+}
+
+// Reads the events of a single backend and passes these to all subscribed
+// frontends, each handling the events in its own thread, such that a slow
+// frontend does not delay the others.
+pub struct Dispatcher {
+    backend: Arc<dyn Backend>,
+    subscribers: Mutex<Vec<SyncSender<Event>>>,
+}
+
+impl Dispatcher {
+    // Starts the thread reading the events of the backend.
+    pub fn start(backend: Box<dyn Backend>) -> Arc<Self> {
+        let dispatcher = Arc::new(Dispatcher {
+            backend: Arc::from(backend),
+            subscribers: Mutex::new(vec![]),
+        });
+
+        thread::spawn({
+            let dispatcher = dispatcher.clone();
+            move || dispatcher.run()
+        });
+
+        dispatcher
+    }
+
+    pub fn backend(&self) -> Arc<dyn Backend> {
+        self.backend.clone()
+    }
+
+    // Returns the receiver of the events for a new frontend. The frontend is
+    // unsubscribed once the receiver has been dropped.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (sender, receiver) = sync_channel(EVENT_QUEUE_SIZE);
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    fn run(&self) {
+        loop {
+            let event = self.backend.next_event(Duration::from_millis(100));
+            match event {
+                Event::Timeout => {}
+                Event::Error(err) => {
+                    error!("Read event error, error: {}", err);
+                }
+                Event::Unknown(event, pl) => {
+                    status::event_received();
+                    warn!(
+                        "Unknown event received, event: {}, size: {}",
+                        event,
+                        pl.len()
+                    );
+                }
+                Event::Uplink(_) | Event::Stats(_) => {
+                    status::event_received();
+                    self.publish(event);
+                }
+            }
+        }
+    }
+
+    fn publish(&self, event: Event) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|s| match s.try_send(event.clone()) {
+                Ok(_) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!("Frontend event queue full, dropping event");
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+    }
+}
+
+// Passes the events to the frontend, until a signal is received through the
+// stop_receive channel.
+pub fn run(
+    frontend: &dyn Frontend,
+    events: Receiver<Event>,
+    stop_receive: Receiver<signals::Signal>,
+) {
+    loop {
+        if stop_receive.recv_timeout(Duration::from_millis(0)).is_ok() {
+            debug!("Terminating events loop, frontend: {}", frontend.name());
+            return;
+        }
+
+        match events.recv_timeout(Duration::from_millis(100)) {
+            Ok(Event::Uplink(up)) => frontend.handle_uplink(*up),
+            Ok(Event::Stats(stats)) => frontend.handle_stats(*stats),
+            Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => thread::sleep(Duration::from_millis(100)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend;
+    use crate::config;
+
+    #[derive(Default)]
+    struct Recorder {
+        uplinks: Mutex<usize>,
+    }
+
+    impl Frontend for Recorder {
+        fn name(&self) -> &str {
+            "recorder"
+        }
+
+        fn handle_uplink(&self, _: gw::UplinkFrame) {
+            *self.uplinks.lock().unwrap() += 1;
+        }
+
+        fn handle_stats(&self, _: gw::GatewayStats) {}
+    }
+
+    #[test]
+    fn test_dispatcher() {
+        let backend = backend::Mock::new(&config::MockBackend {
+            uplink_interval_ms: 10,
+            stats_interval_secs: 0,
+            ..Default::default()
+        })
+        .unwrap();
+        let dispatcher = Dispatcher::start(Box::new(backend));
+
+        // Both frontends receive the uplinks.
+        let frontends = [Arc::new(Recorder::default()), Arc::new(Recorder::default())];
+        let mut stops = vec![];
+        let mut threads = vec![];
+        for f in frontends.iter() {
+            let (stop, stop_receive) = std::sync::mpsc::channel();
+            let events = dispatcher.subscribe();
+            let f = f.clone();
+            stops.push(stop);
+            threads.push(thread::spawn(move || run(f.as_ref(), events, stop_receive)));
+        }
+
+        thread::sleep(Duration::from_millis(100));
+        for stop in &stops {
+            stop.send(signals::Signal::Stop).unwrap();
+        }
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        for f in frontends.iter() {
+            assert!(*f.uplinks.lock().unwrap() > 0);
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, TimeZone, Utc};
+
+// GPS epoch (1980-01-06T00:00:00Z) as UNIX timestamp.
+const GPS_EPOCH_UNIX_SECS: i64 = 315964800;
+
+// Number of uplinks the tmms to tmst mapping is based on.
+const MAX_SAMPLES: usize = 16;
+
+// Max. age of the samples, the concentrator counter wraps every ~71 minutes
+// and drifts relative to the GPS time.
+const MAX_AGE: Duration = Duration::from_secs(600);
+
+// Max. distance between the converted tmms and the most recent sample, within
+// half of the counter range.
+const MAX_DISTANCE_MS: u64 = 30 * 60 * 1000;
+
+// Some gateways report the time since GPS epoch truncated to 32 bits, which
+// wraps every ~49.7 days.
+const TMMS_WRAP: u64 = 1 << 32;
+
+lazy_static! {
+    static ref GPS_TIME: Mutex<GpsTime> = Mutex::new(GpsTime::new(18, false));
+}
+
+// GPS / UTC offset and the mapping between the GPS time and the concentrator
+// counter (tmst), learned from the uplinks. This mapping is shared by all
+// servers, as these share the same concentrator.
+struct GpsTime {
+    leap_seconds: i64,
+    tmms_conversion: bool,
+    // (GPS time in ms, tmst, received at)
+    samples: VecDeque<(u64, u32, Instant)>,
+}
+
+impl GpsTime {
+    fn new(leap_seconds: i64, tmms_conversion: bool) -> Self {
+        GpsTime {
+            leap_seconds,
+            tmms_conversion,
+            samples: VecDeque::new(),
+        }
+    }
+
+    fn gps_time_ms(&self, t: DateTime<Utc>) -> u64 {
+        let secs = t.timestamp() - GPS_EPOCH_UNIX_SECS + self.leap_seconds;
+        if secs < 0 {
+            return 0;
+        }
+
+        secs as u64 * 1000 + t.timestamp_subsec_millis() as u64
+    }
+
+    fn utc_time(&self, tmms: u64) -> Option<DateTime<Utc>> {
+        let ms = tmms as i64 + (GPS_EPOCH_UNIX_SECS - self.leap_seconds) * 1000;
+        Utc.timestamp_millis_opt(ms).single()
+    }
+
+    fn learn(&mut self, tmms: u64, tmst: u32, now: Instant) {
+        // The same uplink is reported once per server.
+        if matches!(self.samples.back(), Some(v) if v.1 == tmst) {
+            return;
+        }
+
+        self.samples.push_back((tmms, tmst, now));
+        if self.samples.len() > MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+    }
+
+    fn estimate_tmst(&mut self, tmms: u64, now: Instant) -> Option<u32> {
+        while let Some((_, _, at)) = self.samples.front() {
+            if now.saturating_duration_since(*at) < MAX_AGE {
+                break;
+            }
+            self.samples.pop_front();
+        }
+
+        let (latest_ms, latest_tmst, _) = *self.samples.back()?;
+        if (tmms as i64 - latest_ms as i64).unsigned_abs() > MAX_DISTANCE_MS {
+            return None;
+        }
+
+        // Each sample estimates the counter at the given tmms, the median
+        // filters out the jitter of the individual samples (e.g. when the GPS
+        // time is derived from the host time).
+        let estimate =
+            |ms: u64, tmst: u32| tmst.wrapping_add(((tmms as i64 - ms as i64) * 1000) as u32);
+        let base = estimate(latest_ms, latest_tmst);
+        let mut offsets: Vec<i32> = self
+            .samples
+            .iter()
+            .map(|(ms, tmst, _)| estimate(*ms, *tmst).wrapping_sub(base) as i32)
+            .collect();
+        offsets.sort_unstable();
+
+        Some(base.wrapping_add(offsets[offsets.len() / 2] as u32))
+    }
+}
+
+// Corrects a tmms value which was truncated to 32 bits, by selecting the value
+// closest to the given GPS time estimate having the same lower 32 bits. A tmms
+// beyond the 32 bit range is not truncated and is returned as-is, as is the
+// tmms when no estimate is available (e.g. the host time is not set).
+fn untruncate_tmms(tmms: u64, estimate: u64) -> u64 {
+    if tmms >= TMMS_WRAP || estimate < TMMS_WRAP {
+        return tmms;
+    }
+
+    let v = (estimate & !(TMMS_WRAP - 1)) | tmms;
+    [v - TMMS_WRAP, v, v + TMMS_WRAP]
+        .iter()
+        .copied()
+        .min_by_key(|v| (*v as i128 - estimate as i128).abs())
+        .unwrap_or(v)
+}
+
+pub fn setup(leap_seconds: i64, tmms_conversion: bool) {
+    *GPS_TIME.lock().unwrap() = GpsTime::new(leap_seconds, tmms_conversion);
+}
+
+// Returns the GPS time (milliseconds since GPS epoch) for the given UTC time.
+pub fn gps_time_ms(t: DateTime<Utc>) -> u64 {
+    GPS_TIME.lock().unwrap().gps_time_ms(t)
+}
+
+// Returns the UTC time for the given GPS time (milliseconds since GPS epoch).
+pub fn utc_time(tmms: u64) -> Option<DateTime<Utc>> {
+    GPS_TIME.lock().unwrap().utc_time(tmms)
+}
+
+// Corrects a tmms value truncated to 32 bits, using the host time as estimate.
+pub fn correct_tmms(tmms: u64) -> u64 {
+    untruncate_tmms(tmms, gps_time_ms(Utc::now()))
+}
+
+// Records the GPS time and counter of an uplink.
+pub fn learn(tmms: u64, tmst: u32) {
+    let mut gps_time = GPS_TIME.lock().unwrap();
+    if gps_time.tmms_conversion {
+        gps_time.learn(tmms, tmst, Instant::now());
+    }
+}
+
+// Returns the counter value for the GPS time, None when the conversion is
+// disabled or no (recent) mapping is available.
+pub fn to_tmst(tmms: u64) -> Option<u32> {
+    let mut gps_time = GPS_TIME.lock().unwrap();
+    if !gps_time.tmms_conversion {
+        return None;
+    }
+    gps_time.estimate_tmst(tmms, Instant::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn test_gps_time() {
+        let mut gps_time = GpsTime::new(18, true);
+        let t = Utc.with_ymd_and_hms(2023, 5, 1, 0, 0, 0).unwrap();
+        let tmms = gps_time.gps_time_ms(t);
+        assert_eq!(tmms, 1366934418000);
+        assert_eq!(gps_time.utc_time(tmms), Some(t));
+
+        let now = Instant::now();
+        assert_eq!(gps_time.estimate_tmst(tmms, now), None);
+
+        // the counter wraps between the samples, one sample is 5 ms off
+        gps_time.learn(tmms, u32::MAX - 999_999, now);
+        gps_time.learn(tmms + 1000, 5000, now);
+        gps_time.learn(tmms + 2000, 1_000_000, now);
+        assert_eq!(gps_time.estimate_tmst(tmms + 3000, now), Some(2_000_000));
+        assert_eq!(
+            gps_time.estimate_tmst(tmms + 500, now),
+            Some(u32::MAX - 499_999)
+        );
+
+        // out of range
+        assert_eq!(
+            gps_time.estimate_tmst(tmms + MAX_DISTANCE_MS + 3000, now),
+            None
+        );
+        assert_eq!(gps_time.estimate_tmst(tmms + 3000, now + MAX_AGE), None);
+    }
+    #[test]
+    fn test_untruncate_tmms() {
+        let estimate =
+            GpsTime::new(18, false).gps_time_ms(Utc.with_ymd_and_hms(2023, 5, 1, 0, 0, 0).unwrap());
+        assert_eq!(estimate, 1366934418000);
+
+        // not truncated
+        assert_eq!(untruncate_tmms(1366934417000, estimate), 1366934417000);
+
+        // truncated
+        assert_eq!(untruncate_tmms(1134817872, estimate), 1366934418000);
+
+        // truncated, with the wrap between the tmms and the estimate
+        assert_eq!(
+            untruncate_tmms(4294963296, 1365799600128 + 1000),
+            1365799596128
+        );
+        assert_eq!(untruncate_tmms(1000, 1365799600128 - 1000), 1365799601128);
+
+        // no estimate available
+        assert_eq!(untruncate_tmms(1000, 0), 1000);
+
+        // randomized, the tmms is recovered as long as the estimate is off by
+        // less than half the wrap period (~24.8 days)
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..10000 {
+            let estimate: u64 = rng.gen_range(TMMS_WRAP..1 << 42);
+            let offset: i64 = rng.gen_range(-(1 << 31) + 1..1 << 31);
+            let tmms = (estimate as i64 + offset) as u64;
+            assert_eq!(
+                untruncate_tmms(tmms % TMMS_WRAP, estimate),
+                tmms,
+                "estimate: {}, offset: {}",
+                estimate,
+                offset
+            );
+        }
+    }
+}
@@ -1,4 +1,7 @@
+use std::time::Duration;
+
 use anyhow::Result;
+use rand::Rng;
 
 use super::commands;
 
@@ -22,3 +25,53 @@ pub fn get_gateway_id(command_url: &str) -> Result<Vec<u8>> {
     let gateway_id = sock.recv_bytes(0).unwrap();
     Ok(gateway_id)
 }
+
+// Parses the gateway ID (EUI64) from its hex representation.
+pub fn parse_gateway_id(s: &str) -> Result<[u8; 8]> {
+    let b = hex::decode(s).map_err(|e| anyhow!("invalid gateway_id: {}, error: {}", s, e))?;
+    if b.len() != 8 {
+        return Err(anyhow!("invalid gateway_id: {}, expected 8 bytes", s));
+    }
+
+    let mut id: [u8; 8] = [0; 8];
+    id.copy_from_slice(&b);
+    Ok(id)
+}
+
+// Returns the interval with a random jitter of +/- the given percentage, to
+// avoid synchronized bursts across a fleet of gateways.
+pub fn jitter(interval: Duration, percent: u8) -> Duration {
+    if percent == 0 {
+        return interval;
+    }
+
+    let percent = percent.min(100) as f64 / 100.0;
+    let factor = rand::thread_rng().gen_range(1.0 - percent..=1.0 + percent);
+    interval.mul_f64(factor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_gateway_id() {
+        assert_eq!(
+            parse_gateway_id("0102030405060708").unwrap(),
+            [1, 2, 3, 4, 5, 6, 7, 8]
+        );
+        assert!(parse_gateway_id("01020304").is_err());
+        assert!(parse_gateway_id("zz02030405060708").is_err());
+    }
+
+    #[test]
+    fn test_jitter() {
+        let interval = Duration::from_secs(10);
+        assert_eq!(jitter(interval, 0), interval);
+
+        for _ in 0..100 {
+            let v = jitter(interval, 10);
+            assert!(v >= Duration::from_secs(9) && v <= Duration::from_secs(11));
+        }
+    }
+}
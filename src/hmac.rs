@@ -0,0 +1,178 @@
+// HMAC-SHA256 frame authentication, a proprietary extension of the Semtech UDP
+// protocol. The trailer (magic + truncated HMAC of the frame) is appended to
+// the frame, after the JSON payload.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+// Magic marking the trailer, a JSON payload never ends with it.
+const MAGIC: [u8; 4] = *b"HMAC";
+
+// Size of the truncated HMAC (128 bits).
+const TAG_SIZE: usize = 16;
+
+pub const TRAILER_SIZE: usize = MAGIC.len() + TAG_SIZE;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Result of verifying a received frame.
+#[derive(Debug, PartialEq)]
+pub enum Verified {
+    // The frame has a valid trailer, the size of the frame without the
+    // trailer is returned.
+    Signed(usize),
+    Unsigned,
+    Invalid,
+    // The frame is unsigned, while the session requires a trailer.
+    Missing,
+}
+
+// HMAC state of the exchange with a server. In OPTIONAL mode the session is
+// negotiated once a signed frame has been received from the server. From then
+// on, the outgoing frames are signed and unsigned PULL_RESP frames are
+// rejected, such that the trailer can not be stripped to downgrade the
+// session.
+pub struct Session {
+    key: Vec<u8>,
+    required: bool,
+    negotiated: bool,
+}
+
+impl Session {
+    pub fn new(key: Vec<u8>, required: bool) -> Self {
+        Session {
+            key,
+            required,
+            negotiated: false,
+        }
+    }
+
+    pub fn is_negotiated(&self) -> bool {
+        self.negotiated
+    }
+
+    // Returns the signed frame, or None when the frame must be sent unsigned.
+    pub fn sign(&self, frame: &[u8]) -> Option<Vec<u8>> {
+        match self.required || self.negotiated {
+            true => Some(sign(&self.key, frame)),
+            false => None,
+        }
+    }
+
+    // Verifies the received frame. Only PULL_RESP frames (downlinks) must be
+    // signed, as the server does not sign its ACK frames.
+    pub fn verify(&mut self, frame: &[u8], pull_resp: bool) -> Verified {
+        match verify(&self.key, frame) {
+            Verified::Signed(size) => {
+                self.negotiated = true;
+                Verified::Signed(size)
+            }
+            Verified::Unsigned if pull_resp && (self.required || self.negotiated) => {
+                Verified::Missing
+            }
+            v => v,
+        }
+    }
+}
+
+// Returns the frame with the trailer appended.
+pub fn sign(key: &[u8], frame: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(frame.len() + TRAILER_SIZE);
+    out.extend_from_slice(frame);
+    out.extend_from_slice(&MAGIC);
+    out.extend_from_slice(&mac(key, frame).finalize().into_bytes()[..TAG_SIZE]);
+    out
+}
+
+// Verifies the trailer of the frame. Frames too short to contain a header
+// and trailer are unsigned.
+pub fn verify(key: &[u8], frame: &[u8]) -> Verified {
+    if frame.len() < 4 + TRAILER_SIZE {
+        return Verified::Unsigned;
+    }
+
+    let size = frame.len() - TRAILER_SIZE;
+    if frame[size..size + MAGIC.len()] != MAGIC {
+        return Verified::Unsigned;
+    }
+
+    // The comparison is constant time.
+    match mac(key, &frame[..size]).verify_truncated_left(&frame[size + MAGIC.len()..]) {
+        Ok(()) => Verified::Signed(size),
+        Err(_) => Verified::Invalid,
+    }
+}
+
+fn mac(key: &[u8], msg: &[u8]) -> HmacSha256 {
+    // HMAC accepts keys of any size.
+    let mut mac = HmacSha256::new_from_slice(key).unwrap();
+    mac.update(msg);
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231, test cases 2 and 6 (truncated to 128 bits).
+        let msg = b"what do ya want for nothing?";
+        assert_eq!(
+            hex::encode(&sign(b"Jefe", msg)[msg.len()..]),
+            format!(
+                "{}{}",
+                hex::encode(MAGIC),
+                "5bdcc146bf60754e6a042426089575c7"
+            )
+        );
+
+        let msg = b"Test Using Larger Than Block-Size Key - Hash Key First";
+        assert_eq!(
+            hex::encode(&sign(&[0xaa; 131], msg)[msg.len() + MAGIC.len()..]),
+            "60e431591ee0b67f0d8a26aacbf5b77f"
+        );
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let frame = [2, 1, 2, 3, b'{', b'}'];
+        let signed = sign(b"secret", &frame);
+        assert_eq!(signed.len(), frame.len() + TRAILER_SIZE);
+        assert_eq!(verify(b"secret", &signed), Verified::Signed(frame.len()));
+        assert_eq!(verify(b"other", &signed), Verified::Invalid);
+        assert_eq!(verify(b"secret", &frame), Verified::Unsigned);
+
+        let mut tampered = signed.clone();
+        tampered[4] = b'[';
+        assert_eq!(verify(b"secret", &tampered), Verified::Invalid);
+    }
+
+    #[test]
+    fn test_session() {
+        let frame = [2, 1, 2, 3, b'{', b'}'];
+        let signed = sign(b"secret", &frame);
+
+        // Optional, not yet negotiated.
+        let mut session = Session::new(b"secret".to_vec(), false);
+        assert_eq!(session.sign(&frame), None);
+        assert_eq!(session.verify(&frame, true), Verified::Unsigned);
+        assert!(!session.is_negotiated());
+
+        // Negotiated, stripping the trailer of a PULL_RESP must not downgrade
+        // the session.
+        assert_eq!(session.verify(&signed, true), Verified::Signed(frame.len()));
+        assert!(session.is_negotiated());
+        assert_eq!(session.sign(&frame), Some(signed.clone()));
+        assert_eq!(
+            session.verify(&signed[..frame.len()], true),
+            Verified::Missing
+        );
+        assert_eq!(session.verify(&frame, false), Verified::Unsigned);
+
+        // Required.
+        let mut session = Session::new(b"secret".to_vec(), true);
+        assert_eq!(session.sign(&frame), Some(signed));
+        assert_eq!(session.verify(&frame, true), Verified::Missing);
+    }
+}
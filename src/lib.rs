@@ -0,0 +1,3 @@
+// Implementation of the Semtech UDP packet-forwarder protocol, shared by the
+// chirpstack-udp-forwarder binary and other tools.
+pub mod protocol;
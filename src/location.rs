@@ -0,0 +1,132 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use chirpstack_udp_forwarder::protocol::Stat;
+
+use super::config;
+
+// Max. age of the gpsd fix, beyond which the static location is used.
+const GPSD_MAX_AGE: Duration = Duration::from_secs(60);
+
+// Delay before reconnecting to gpsd.
+const GPSD_RECONNECT: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    static ref LOCATION: Mutex<Location> = Mutex::new(Location::default());
+}
+
+#[derive(Default)]
+struct Location {
+    // (latitude, longitude, altitude)
+    fixed: Option<(f64, f64, u32)>,
+    gpsd: Option<((f64, f64, u32), Instant)>,
+}
+
+impl Location {
+    fn get(&self, now: Instant) -> Option<(f64, f64, u32)> {
+        match self.gpsd {
+            Some((v, at)) if now.saturating_duration_since(at) < GPSD_MAX_AGE => Some(v),
+            _ => self.fixed,
+        }
+    }
+}
+
+pub fn setup(conf: &config::Location) {
+    let mut location = LOCATION.lock().unwrap();
+    location.fixed = match conf.latitude != 0.0 || conf.longitude != 0.0 {
+        true => Some((conf.latitude, conf.longitude, conf.altitude)),
+        false => None,
+    };
+
+    if !conf.gpsd_server.is_empty() {
+        thread::spawn({
+            let server = conf.gpsd_server.clone();
+            move || gpsd_loop(server)
+        });
+    }
+}
+
+// Sets the location of the stat, when the Concentratord did not report a
+// location. The Concentratord location takes precedence over gpsd, gpsd over
+// the static location.
+pub fn apply(stat: &mut Stat) {
+    if stat.lati != 0.0 || stat.long != 0.0 {
+        return;
+    }
+
+    if let Some((lati, long, alti)) = LOCATION.lock().unwrap().get(Instant::now()) {
+        stat.lati = lati;
+        stat.long = long;
+        stat.alti = alti;
+    }
+}
+
+fn gpsd_loop(server: String) {
+    loop {
+        if let Err(e) = gpsd_watch(&server) {
+            warn!("gpsd error: {}, server: {}", e, server);
+        }
+        thread::sleep(GPSD_RECONNECT);
+    }
+}
+
+fn gpsd_watch(server: &str) -> Result<()> {
+    let mut stream = TcpStream::connect(server)?;
+    stream.write_all(b"?WATCH={\"enable\":true,\"json\":true}\n")?;
+    info!("Watching gpsd, server: {}", server);
+
+    for line in BufReader::new(stream).lines() {
+        if let Some(v) = parse_tpv(&line?) {
+            LOCATION.lock().unwrap().gpsd = Some((v, Instant::now()));
+        }
+    }
+
+    Err(anyhow!("connection closed"))
+}
+
+// Returns the location of a TPV report with a 2D or 3D fix.
+fn parse_tpv(line: &str) -> Option<(f64, f64, u32)> {
+    let v: serde_json::Value = serde_json::from_str(line).ok()?;
+    if v["class"] != "TPV" || v["mode"].as_u64().unwrap_or_default() < 2 {
+        return None;
+    }
+
+    let alt = v["altMSL"].as_f64().or_else(|| v["alt"].as_f64());
+    Some((
+        v["lat"].as_f64()?,
+        v["lon"].as_f64()?,
+        alt.unwrap_or_default() as u32,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_location() {
+        assert_eq!(
+            parse_tpv(r#"{"class":"TPV","mode":3,"lat":46.52,"lon":6.63,"altMSL":410.5}"#),
+            Some((46.52, 6.63, 410))
+        );
+        assert_eq!(
+            parse_tpv(r#"{"class":"TPV","mode":1,"lat":46.52,"lon":6.63}"#),
+            None
+        );
+        assert_eq!(parse_tpv(r#"{"class":"SKY"}"#), None);
+
+        let now = Instant::now();
+        let mut location = Location {
+            fixed: Some((1.0, 2.0, 3)),
+            gpsd: None,
+        };
+        assert_eq!(location.get(now), Some((1.0, 2.0, 3)));
+        location.gpsd = Some(((46.52, 6.63, 410), now));
+        assert_eq!(location.get(now), Some((46.52, 6.63, 410)));
+        assert_eq!(location.get(now + GPSD_MAX_AGE), Some((1.0, 2.0, 3)));
+    }
+}
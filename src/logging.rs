@@ -1,27 +1,404 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
 
 use anyhow::Result;
+use chrono::{SecondsFormat, Utc};
+use log::{LevelFilter, Log, Record};
+use simple_logger::SimpleLogger;
 use syslog::{BasicLogger, Facility, Formatter3164};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::subscriber::Interest;
+use tracing::{Event, Metadata, Subscriber};
+use tracing_log::{AsLog, LogTracer, NormalizeEvent};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::{LookupSpan, Registry};
 
-pub fn setup(name: &str, level: log::Level, syslog: bool) -> Result<()> {
-    if syslog {
-        let formatter = Formatter3164 {
-            facility: Facility::LOG_USER,
-            hostname: None,
-            process: name.to_string(),
-            pid: process::id(),
-        };
-        let logger = match syslog::unix(formatter) {
-            Ok(v) => v,
-            Err(err) => return Err(anyhow!("create syslog logger error: {}", err)),
-        };
+use super::config::{self, LogFormat};
 
-        log::set_boxed_logger(Box::new(BasicLogger::new(logger)))
-            .map(|()| log::set_max_level(level.to_level_filter()))
-            .unwrap();
+// Crate name, prepended to the module names of the log_levels.
+const CRATE_NAME: &str = "chirpstack_udp_forwarder";
+
+static LOG_FRAMES: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref LEVELS: RwLock<Levels> = RwLock::new(Levels {
+        default: LevelFilter::Info,
+        modules: vec![],
+    });
+}
+
+struct Levels {
+    default: LevelFilter,
+    // By module, the most specific module first.
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Levels {
+    fn get(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .find(|(m, _)| {
+                target == m.as_str()
+                    || (target.starts_with(m.as_str()) && target[m.len()..].starts_with("::"))
+            })
+            .map_or(self.default, |(_, l)| *l)
+    }
+}
+
+// Fields of a span (e.g. random_token), stored in the span extensions.
+struct SpanFields(Vec<(&'static str, String)>);
+
+// Collects the message and fields of an event or span. The fields added by
+// tracing-log to the log records (log.target, log.file, ...) are skipped.
+#[derive(Default)]
+struct Visitor {
+    message: String,
+    fields: Vec<(&'static str, String)>,
+}
+
+impl Visitor {
+    fn record(&mut self, field: &Field, value: String) {
+        match field.name() {
+            "message" => self.message = value,
+            name if name.starts_with("log.") => {}
+            name => self.fields.push((name, value)),
+        }
+    }
+}
+
+impl Visit for Visitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record(field, value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record(field, format!("{:?}", value));
+    }
+}
+
+enum Output {
+    // Logger (text to stdout or syslog), the fields are appended to the
+    // message.
+    Log(Box<dyn Log>),
+    // One JSON object per line to stdout, with the fields as keys.
+    Json,
+}
+
+impl Output {
+    fn write(&self, level: log::Level, target: &str, message: &str, fields: &[(&str, String)]) {
+        match self {
+            Output::Log(logger) => {
+                let mut message = message.to_string();
+                for (k, v) in fields {
+                    message.push_str(&format!(", {}: {}", k, v));
+                }
+                logger.log(
+                    &Record::builder()
+                        .level(level)
+                        .target(target)
+                        .args(format_args!("{}", message))
+                        .build(),
+                );
+            }
+            Output::Json => {
+                let line = json_line(level, target, message, fields);
+                let mut stdout = std::io::stdout().lock();
+                let _ = writeln!(stdout, "{}", line);
+            }
+        }
+    }
+}
+
+fn json_line(level: log::Level, target: &str, message: &str, fields: &[(&str, String)]) -> String {
+    let mut obj = serde_json::Map::new();
+    obj.insert(
+        "time".into(),
+        Utc::now()
+            .to_rfc3339_opts(SecondsFormat::Millis, true)
+            .into(),
+    );
+    obj.insert("level".into(), level.as_str().into());
+    obj.insert("target".into(), target.into());
+    obj.insert("message".into(), message.into());
+    for (k, v) in fields {
+        obj.insert(k.to_string(), v.clone().into());
+    }
+    serde_json::Value::Object(obj).to_string()
+}
+
+// Writes the tracing events, including the log records forwarded by
+// tracing-log, to the outputs together with the fields of the enclosing
+// spans. The log levels of the modules are applied, see set_levels.
+struct LogLayer {
+    outputs: Vec<Output>,
+}
+
+impl<S> Layer<S> for LogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn register_callsite(&self, _: &'static Metadata<'static>) -> Interest {
+        // The levels can be changed on reload, the interest can not be
+        // cached.
+        Interest::sometimes()
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, _: Context<'_, S>) -> bool {
+        metadata.level().as_log() <= LEVELS.read().unwrap().get(metadata.target())
+    }
+
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut visitor = Visitor::default();
+        attrs.record(&mut visitor);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanFields(visitor.fields));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        // The metadata of the log record, for the records forwarded by
+        // tracing-log.
+        let normalized = event.normalized_metadata();
+        let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+
+        let mut visitor = Visitor::default();
+        event.record(&mut visitor);
+
+        let mut fields = vec![];
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(v) = span.extensions().get::<SpanFields>() {
+                    fields.extend(v.0.iter().cloned());
+                }
+            }
+        }
+        fields.extend(visitor.fields);
+
+        for output in &self.outputs {
+            output.write(
+                metadata.level().as_log(),
+                metadata.target(),
+                &visitor.message,
+                &fields,
+            );
+        }
+    }
+}
+
+pub fn setup(name: &str, conf: &config::UdpForwarder) -> Result<()> {
+    let mut outputs = vec![];
+
+    if conf.log_to_syslog {
+        outputs.push(Output::Log(Box::new(syslog_logger(name, &conf.syslog)?)));
+    }
+
+    if !conf.log_to_syslog || conf.syslog.stdout {
+        outputs.push(match conf.log_format {
+            LogFormat::Text => Output::Log(Box::new(SimpleLogger::new())),
+            LogFormat::Json => Output::Json,
+        });
+    }
+
+    tracing::subscriber::set_global_default(Registry::default().with(LogLayer { outputs }))?;
+    // Forwards the records of the log macros to tracing.
+    LogTracer::init()?;
+    set_log_frames(conf.log_frames);
+
+    Ok(())
+}
+
+// Returns the syslog logger, logging to the local syslog daemon or to the
+// remote (udp:// or tcp://) syslog server. The log levels are mapped to the
+// syslog severities (TRACE and DEBUG to debug).
+fn syslog_logger(name: &str, conf: &config::Syslog) -> Result<BasicLogger> {
+    let facility = Facility::from_str(&conf.facility.to_lowercase())
+        .map_err(|_| anyhow!("invalid syslog facility: {}", conf.facility))?;
+    let mut formatter = Formatter3164 {
+        facility,
+        hostname: None,
+        process: name.to_string(),
+        pid: process::id(),
+    };
+
+    let logger = if let Some(server) = conf.server.strip_prefix("udp://") {
+        formatter.hostname = hostname();
+        // Bind to the address family of the syslog server.
+        let local = match server.to_socket_addrs().ok().and_then(|mut v| v.next()) {
+            Some(SocketAddr::V6(_)) => "[::]:0",
+            _ => "0.0.0.0:0",
+        };
+        syslog::udp(formatter, local, server)
+    } else if let Some(server) = conf.server.strip_prefix("tcp://") {
+        formatter.hostname = hostname();
+        syslog::tcp(formatter, server)
+    } else if conf.server.is_empty() {
+        syslog::unix(formatter)
     } else {
-        simple_logger::init_with_level(level).unwrap();
+        return Err(anyhow!("unsupported syslog server: {}", conf.server));
+    };
+
+    match logger {
+        Ok(v) => Ok(BasicLogger::new(v)),
+        Err(err) => Err(anyhow!("create syslog logger error: {}", err)),
     }
+}
+
+// The hostname is part of the messages sent to a remote syslog server.
+fn hostname() -> Option<String> {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .map(|v| v.trim().to_string())
+}
+
+// Sets the default log level and the log levels by module (e.g. forwarder or
+// protocol::structs).
+pub fn set_levels(level: log::Level, modules: &BTreeMap<String, String>) -> Result<()> {
+    let mut levels = Levels {
+        default: level.to_level_filter(),
+        modules: vec![],
+    };
+
+    for (k, v) in modules {
+        let module = match k.starts_with(CRATE_NAME) {
+            true => k.clone(),
+            false => format!("{}::{}", CRATE_NAME, k),
+        };
+        let level = LevelFilter::from_str(v)
+            .map_err(|e| anyhow!("parse log level of {} error: {}", k, e))?;
+        levels.modules.push((module, level));
+    }
+    levels.modules.sort_by_key(|m| Reverse(m.0.len()));
+
+    let max = levels
+        .modules
+        .iter()
+        .map(|(_, l)| *l)
+        .fold(levels.default, |a, b| a.max(b));
+    *LEVELS.write().unwrap() = levels;
+    log::set_max_level(max);
 
     Ok(())
 }
+
+pub fn set_log_frames(enabled: bool) {
+    LOG_FRAMES.store(enabled, Ordering::Relaxed);
+}
+
+// Returns true when the rxpk / txpk JSON must be logged (at debug level).
+pub fn log_frames() -> bool {
+    LOG_FRAMES.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_levels() {
+        let levels = Levels {
+            default: LevelFilter::Info,
+            modules: vec![
+                (
+                    "chirpstack_udp_forwarder::protocol::structs".into(),
+                    LevelFilter::Trace,
+                ),
+                (
+                    "chirpstack_udp_forwarder::forwarder".into(),
+                    LevelFilter::Debug,
+                ),
+            ],
+        };
+
+        assert_eq!(
+            levels.get("chirpstack_udp_forwarder::forwarder"),
+            LevelFilter::Debug
+        );
+        assert_eq!(
+            levels.get("chirpstack_udp_forwarder::protocol::structs"),
+            LevelFilter::Trace
+        );
+        assert_eq!(
+            levels.get("chirpstack_udp_forwarder::forwarder2"),
+            LevelFilter::Info
+        );
+        assert_eq!(levels.get("zmq"), LevelFilter::Info);
+    }
+
+    #[test]
+    fn test_layer() {
+        // Logger capturing the messages.
+        struct Capture(Arc<Mutex<Vec<String>>>);
+
+        impl Log for Capture {
+            fn enabled(&self, _: &log::Metadata) -> bool {
+                true
+            }
+
+            fn log(&self, record: &Record) {
+                self.0.lock().unwrap().push(format!(
+                    "{} {} {}",
+                    record.level(),
+                    record.target(),
+                    record.args()
+                ));
+            }
+
+            fn flush(&self) {}
+        }
+
+        let messages = Arc::new(Mutex::new(vec![]));
+        let layer = LogLayer {
+            outputs: vec![Output::Log(Box::new(Capture(messages.clone())))],
+        };
+
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            let _a = tracing::info_span!("push_data", random_token = 123).entered();
+            {
+                let _b = tracing::info_span!("downlink", downlink_id = 456).entered();
+                tracing::warn!(target: "chirpstack_udp_forwarder::forwarder", "first");
+            }
+
+            // Log record, as forwarded by the LogTracer.
+            tracing_log::format_trace(
+                &Record::builder()
+                    .level(log::Level::Info)
+                    .target("chirpstack_udp_forwarder::forwarder")
+                    .args(format_args!("second, server: {}", "a"))
+                    .build(),
+            )
+            .unwrap();
+
+            // Below the log level.
+            tracing::debug!(target: "chirpstack_udp_forwarder::forwarder", "third");
+        });
+
+        assert_eq!(
+            *messages.lock().unwrap(),
+            vec![
+                "WARN chirpstack_udp_forwarder::forwarder first, random_token: 123, downlink_id: 456",
+                "INFO chirpstack_udp_forwarder::forwarder second, server: a, random_token: 123",
+            ]
+        );
+
+        let line: serde_json::Value = serde_json::from_str(&json_line(
+            log::Level::Info,
+            "chirpstack_udp_forwarder::forwarder",
+            "message",
+            &[("random_token", "123".into())],
+        ))
+        .unwrap();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["message"], "message");
+        assert_eq!(line["random_token"], "123");
+    }
+}
@@ -0,0 +1,111 @@
+use anyhow::Result;
+
+// Minimal LoRaWAN PHYPayload inspection. The PHYPayload is not decrypted nor
+// validated, only the fields needed by the uplink path are extracted.
+
+pub enum Payload {
+    // Unconfirmed or confirmed data-up.
+    DataUp { dev_addr: [u8; 4], f_cnt: u16 },
+
+    // Join-request.
+    JoinRequest { join_eui: [u8; 8] },
+
+    // Any other message type.
+    Other,
+}
+
+pub struct PhyPayload {
+    pub payload: Payload,
+    pub mic: [u8; 4],
+}
+
+impl PhyPayload {
+    pub fn from_slice(b: &[u8]) -> Result<Self> {
+        // MHDR + MIC
+        if b.len() < 5 {
+            return Err(anyhow!("expected at least 5 bytes, got: {}", b.len()));
+        }
+
+        let mut mic: [u8; 4] = [0; 4];
+        mic.copy_from_slice(&b[b.len() - 4..]);
+
+        let payload = match b[0] >> 5 {
+            // JoinRequest
+            0x00 => {
+                // MHDR + JoinEUI + DevEUI + DevNonce + MIC
+                if b.len() != 23 {
+                    return Err(anyhow!("expected 23 bytes, got: {}", b.len()));
+                }
+
+                // JoinEUI is little-endian encoded
+                let mut join_eui: [u8; 8] = [0; 8];
+                join_eui.copy_from_slice(&b[1..9]);
+                join_eui.reverse();
+
+                Payload::JoinRequest { join_eui }
+            }
+            // UnconfirmedDataUp and ConfirmedDataUp
+            0x02 | 0x04 => {
+                // MHDR + DevAddr + FCtrl + FCnt + MIC
+                if b.len() < 12 {
+                    return Err(anyhow!("expected at least 12 bytes, got: {}", b.len()));
+                }
+
+                // DevAddr is little-endian encoded
+                let mut dev_addr: [u8; 4] = [0; 4];
+                dev_addr.copy_from_slice(&b[1..5]);
+                dev_addr.reverse();
+
+                Payload::DataUp {
+                    dev_addr,
+                    f_cnt: u16::from_le_bytes([b[6], b[7]]),
+                }
+            }
+            _ => Payload::Other,
+        };
+
+        Ok(PhyPayload { payload, mic })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_data_up() {
+        let b = vec![
+            0x40, 0x04, 0x03, 0x02, 0x01, 0x00, 0x0a, 0x01, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06,
+        ];
+        let phy = PhyPayload::from_slice(&b).unwrap();
+        assert_eq!(phy.mic, [0x03, 0x04, 0x05, 0x06]);
+
+        match phy.payload {
+            Payload::DataUp { dev_addr, f_cnt } => {
+                assert_eq!(dev_addr, [0x01, 0x02, 0x03, 0x04]);
+                assert_eq!(f_cnt, 266);
+            }
+            _ => panic!("DataUp expected"),
+        }
+    }
+
+    #[test]
+    fn test_join_request() {
+        let mut b = vec![0x00, 0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01];
+        b.extend_from_slice(&[0; 14]);
+        let phy = PhyPayload::from_slice(&b).unwrap();
+
+        match phy.payload {
+            Payload::JoinRequest { join_eui } => {
+                assert_eq!(join_eui, [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08]);
+            }
+            _ => panic!("JoinRequest expected"),
+        }
+    }
+
+    #[test]
+    fn test_too_short() {
+        assert!(PhyPayload::from_slice(&[0x40, 0x01, 0x02]).is_err());
+        assert!(PhyPayload::from_slice(&[0x40, 0x01, 0x02, 0x03, 0x04, 0x05]).is_err());
+    }
+}
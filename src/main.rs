@@ -6,40 +6,172 @@ extern crate log;
 extern crate anyhow;
 
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
+mod acks;
+mod airtime;
+mod allocator;
+mod backend;
+mod banner;
+mod batch;
+mod buffer;
+mod capture;
+mod channels;
+mod check;
 mod commands;
 mod config;
+mod configfile;
+mod connection;
+mod crash;
+mod decode;
+mod dedup;
+mod downlink;
+mod downq;
+mod dutycycle;
+mod encryption;
+mod enricher;
 mod events;
+mod filters;
 mod forwarder;
+mod frontend;
+mod gps_time;
 mod helpers;
+mod hmac;
+mod location;
 mod logging;
+mod lorawan;
 mod metrics;
+mod migrate;
+mod mqtt;
+mod pcap;
+mod probe;
+mod reload;
+mod replay;
+mod retransmit;
+mod sendq;
 mod signals;
 mod socket;
-mod structs;
+mod source_filter;
+mod stats;
+mod status;
+mod store;
+mod test_downlink;
+mod tls;
+mod tokens;
+mod transport;
+mod udp;
+mod usage;
+
+#[global_allocator]
+static ALLOCATOR: allocator::Counting = allocator::Counting;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
     #[arg(short, long, value_name = "FILE")]
     config: Vec<String>,
+
+    /// Validate the configuration and exit
+    #[arg(long)]
+    check_config: bool,
+
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Print the version and exit
+    Version {
+        /// Also print the build information and enabled subsystems
+        #[arg(short, long)]
+        verbose: bool,
+    },
+    /// Print the configuration file template, documenting all options
+    Configfile,
+    /// Upgrade the configuration file(s) to the current schema
+    MigrateConfig {
+        /// Write the changes to the configuration file(s)
+        #[arg(short, long)]
+        write: bool,
+    },
+    /// Decode a (hex or base64 encoded) Semtech UDP datagram
+    Decode {
+        /// Datagram, e.g. from a packet capture
+        data: String,
+    },
+    /// Send a test downlink to the Concentratord, without network server
+    TestDownlink(test_downlink::Args),
 }
 
 fn main() {
     let cli = Cli::parse();
+
+    match &cli.command {
+        Some(Commands::Version { verbose }) => {
+            print_version(&cli.config, *verbose);
+            return;
+        }
+        Some(Commands::Configfile) => {
+            configfile::run();
+            return;
+        }
+        Some(Commands::MigrateConfig { write }) => {
+            if let Err(e) = migrate::run(&cli.config, *write) {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Commands::Decode { data }) => {
+            if let Err(e) = decode::run(data) {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Commands::TestDownlink(args)) => {
+            if let Err(e) = test_downlink::run(&cli.config, args) {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        None => {}
+    }
+
+    if cli.check_config {
+        if let Err(e) = check::run(&cli.config) {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let config = config::Configuration::get(&cli.config).expect("read configuration error");
     let log_level =
         log::Level::from_str(&config.udp_forwarder.log_level).expect("parse log_level error");
 
-    logging::setup(
-        "chirpstack-udp-forwarder",
-        log_level,
-        config.udp_forwarder.log_to_syslog,
-    )
-    .expect("setup logger error");
+    logging::setup("chirpstack-udp-forwarder", &config.udp_forwarder).expect("setup logger error");
+    logging::set_levels(log_level, &config.udp_forwarder.log_levels)
+        .expect("parse log_levels error");
+
+    crash::install(config.udp_forwarder.crash_report_path.clone());
+    dutycycle::setup(config.udp_forwarder.duty_cycle);
+    gps_time::setup(
+        config.udp_forwarder.gps_leap_seconds,
+        config.udp_forwarder.tmms_conversion,
+    );
+    location::setup(&config.udp_forwarder.location);
+    capture::setup(&config.udp_forwarder.capture);
+    usage::setup(
+        config.udp_forwarder.usage_path.clone(),
+        config.udp_forwarder.usage_retention_days,
+    );
 
     info!(
         "Starting ChirpStack UDP Forwarder (version: {}, docs: {})",
@@ -47,38 +179,118 @@ fn main() {
         "https://github.com/chirpstack/chirpstack-udp-forwarder",
     );
 
-    // read gateway id.
-    let gateway_id = helpers::get_gateway_id(&config.concentratord.command_url)
-        .expect("get gateway_id from concentratord failed, is concentratord running?");
+    for (k, v) in banner::build_info()
+        .into_iter()
+        .chain(banner::capabilities(&config))
+    {
+        info!("Startup report, {}: {}", k, v);
+    }
+
+    // read gateway id, wait for the Concentratord in case it is not (yet)
+    // running. The mock backend uses the configured gateway id.
+    let gateway_id = match config.udp_forwarder.backend {
+        config::BackendType::Mock => {
+            helpers::parse_gateway_id(&config.udp_forwarder.mock_backend.gateway_id)
+                .expect("parse mock_backend gateway_id error")
+                .to_vec()
+        }
+        config::BackendType::Concentratord => loop {
+            match helpers::get_gateway_id(&config.concentratord.command_url) {
+                Ok(v) => break v,
+                Err(e) => {
+                    warn!(
+                        "Get gateway_id from Concentratord error: {}, is Concentratord running?",
+                        e
+                    );
+                    thread::sleep(Duration::from_secs(1));
+                }
+            }
+        },
+    };
 
     info!(
-        "Received gateway ID from Concentratord, gateway_id: {}",
+        "Received gateway ID from {:?} backend, gateway_id: {}",
+        config.udp_forwarder.backend,
         hex::encode(&gateway_id)
     );
 
-    // setup threads
-    let mut threads: Vec<thread::JoinHandle<()>> = vec![];
+    // backend events, these are dispatched to the forwarders
+    let backend = backend::new(
+        &config.udp_forwarder,
+        &config.concentratord.event_url,
+        &config.concentratord.command_url,
+    )
+    .expect("setup backend error");
+    let dispatcher = frontend::Dispatcher::start(backend);
+
+    // MQTT frontend
+    if !config.udp_forwarder.mqtt.server.is_empty() {
+        thread::spawn({
+            let conf = config.udp_forwarder.mqtt.clone();
+            let downlink_plan = config.udp_forwarder.downlink_plan.clone();
+            let dispatcher = dispatcher.clone();
+            let gateway_id = gateway_id.clone();
+            move || mqtt::start(conf, downlink_plan, dispatcher, gateway_id)
+        });
+    }
 
     // servers
-    for server in config.udp_forwarder.servers {
-        threads.push(thread::spawn({
-            let gateway_id = gateway_id.clone();
-            let event_url = config.concentratord.event_url.clone();
-            let command_url = config.concentratord.command_url.clone();
+    let mut supervisor = reload::Supervisor::new(dispatcher, gateway_id);
+    supervisor.apply(config.udp_forwarder.get_servers());
+    let supervisor = Arc::new(Mutex::new(supervisor));
 
-            move || forwarder::start(&server, event_url, command_url, gateway_id)
-        }));
+    // Concentratord restarts
+    if config.udp_forwarder.backend == config::BackendType::Concentratord {
+        thread::spawn({
+            let command_url = config.concentratord.command_url.clone();
+            let supervisor = supervisor.clone();
+            move || reload::watch_concentratord(command_url, supervisor)
+        });
     }
 
+    // graceful shutdown
+    thread::spawn({
+        let drain_timeout = Duration::from_secs(config.udp_forwarder.drain_timeout_secs);
+        let supervisor = supervisor.clone();
+        move || reload::shutdown_on_signal(drain_timeout, supervisor)
+    });
+
     // metrics
     if !config.udp_forwarder.metrics_bind.is_empty() {
-        threads.push(thread::spawn({
-            let bind = config.udp_forwarder.metrics_bind;
+        thread::spawn({
+            let bind = config.udp_forwarder.metrics_bind.clone();
             move || metrics::start(bind)
-        }));
+        });
+    }
+
+    // status
+    if !config.udp_forwarder.status_bind.is_empty() {
+        thread::spawn({
+            let bind = config.udp_forwarder.status_bind.clone();
+            move || status::start(bind)
+        });
+    }
+
+    // configuration reload, this blocks forever
+    reload::start(&cli.config, config, supervisor);
+}
+
+fn print_version(config_files: &[String], verbose: bool) {
+    println!("chirpstack-udp-forwarder {}", config::VERSION);
+    if !verbose {
+        return;
+    }
+
+    for (k, v) in banner::build_info() {
+        println!("{}: {}", k, v);
+    }
+
+    if config_files.is_empty() {
+        return;
     }
 
-    for t in threads {
-        t.join().unwrap();
+    let config = config::Configuration::get(config_files).expect("read configuration error");
+    for (k, v) in banner::capabilities(&config) {
+        println!("{}: {}", k, v);
     }
 }
@@ -2,7 +2,13 @@ use std::io::{Read, Write};
 use std::net::{TcpListener, TcpStream};
 use std::thread;
 
-use prometheus::{Encoder, IntCounterVec, Opts, Registry};
+use std::time::Duration;
+
+use prometheus::{
+    CounterVec, Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry,
+};
+
+use super::connection::ConnectionState;
 
 lazy_static! {
     static ref REGISTRY: Registry = Registry::new();
@@ -14,6 +20,48 @@ lazy_static! {
     // UDP received
     static ref UDP_RECEIVED_COUNT: IntCounterVec = IntCounterVec::new(Opts::new("udp_received_count", "Number of UDP datagrams received"), &["server", "type"]).unwrap();
     static ref UDP_RECEIVED_BYTES: IntCounterVec = IntCounterVec::new(Opts::new("udp_received_bytes", "Number of bytes received over UDP"), &["server", "type"]).unwrap();
+
+    // Uplinks dropped
+    static ref UPLINK_DROPPED_COUNT: IntCounterVec = IntCounterVec::new(Opts::new("uplink_dropped_count", "Number of uplinks that were not forwarded"), &["server", "reason"]).unwrap();
+
+    // Uplinks routed
+    static ref UPLINK_ROUTED_COUNT: IntCounterVec = IntCounterVec::new(Opts::new("uplink_routed_count", "Number of uplinks routed to the server, by route"), &["server", "route"]).unwrap();
+
+    // Uplinks implausible
+    static ref UPLINK_IMPLAUSIBLE_COUNT: IntCounterVec = IntCounterVec::new(Opts::new("uplink_implausible_count", "Number of uplinks with a chan, rfch or freq not matching the channel plan, by field"), &["server", "field"]).unwrap();
+
+    // Uplinks by channel
+    static ref UPLINK_CHANNEL_COUNT: IntCounterVec = IntCounterVec::new(Opts::new("uplink_channel_count", "Number of uplinks by channel of the channel plan"), &["server", "channel"]).unwrap();
+
+    // Downlinks emitted
+    static ref DOWNLINK_EMITTED_COUNT: IntCounterVec = IntCounterVec::new(Opts::new("downlink_emitted_count", "Number of downlinks emitted, by downlink item"), &["server", "item"]).unwrap();
+
+    static ref DOWNLINK_FAILED_COUNT: IntCounterVec = IntCounterVec::new(Opts::new("downlink_failed_count", "Number of downlinks which could not be handed to the Concentratord, by reason"), &["server", "reason"]).unwrap();
+
+    // Downlink queue depth
+    static ref DOWNLINK_QUEUE_DEPTH: IntGaugeVec = IntGaugeVec::new(Opts::new("downlink_queue_depth", "Number of downlinks waiting to be sent to the Concentratord"), &["server"]).unwrap();
+
+    // PUSH_ACK latency
+    static ref PUSH_ACK_LATENCY: HistogramVec = HistogramVec::new(HistogramOpts::new("push_ack_latency_seconds", "Time between sending a PUSH_DATA and receiving its PUSH_ACK"), &["server"]).unwrap();
+
+    // Server connection state
+    static ref AIRTIME: CounterVec = CounterVec::new(Opts::new("airtime_seconds", "Time-on-air of the uplinks and downlinks, by data-rate"), &["server", "direction", "datr"]).unwrap();
+
+    // Duty cycle
+    static ref DUTY_CYCLE_REMAINING: IntGaugeVec = IntGaugeVec::new(Opts::new("duty_cycle_remaining_ms", "Remaining time-on-air within the duty cycle window, by sub-band"), &["sub_band"]).unwrap();
+
+    // UDP rejected
+    static ref UDP_REJECTED_COUNT: IntCounterVec = IntCounterVec::new(Opts::new("udp_rejected_count", "Number of UDP datagrams rejected, by reason (SOURCE, HMAC or ENCRYPTION)"), &["server", "reason"]).unwrap();
+
+    // Server address changes
+    static ref SERVER_ADDRESS_CHANGED_COUNT: IntCounterVec = IntCounterVec::new(Opts::new("server_address_changed_count", "Number of times the server address changed after re-resolving the hostname"), &["server"]).unwrap();
+
+    // MQTT
+    static ref MQTT_PUBLISHED_COUNT: IntCounterVec = IntCounterVec::new(Opts::new("mqtt_published_count", "Number of MQTT messages published, acknowledged by the broker for the ack_required_events, by event"), &["server", "event"]).unwrap();
+    static ref MQTT_REDELIVERED_COUNT: IntCounterVec = IntCounterVec::new(Opts::new("mqtt_redelivered_count", "Number of MQTT messages redelivered as no PUBACK was received, by event"), &["server", "event"]).unwrap();
+
+    // Server connection state
+    static ref SERVER_CONNECTION_STATE: IntGaugeVec = IntGaugeVec::new(Opts::new("server_connection_state", "Connection state of the server, 1 for the current state"), &["server", "state"]).unwrap();
 }
 
 pub fn start(bind: String) {
@@ -26,6 +74,49 @@ pub fn start(bind: String) {
     REGISTRY
         .register(Box::new(UDP_RECEIVED_BYTES.clone()))
         .unwrap();
+    REGISTRY
+        .register(Box::new(UPLINK_DROPPED_COUNT.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(UPLINK_ROUTED_COUNT.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(UPLINK_IMPLAUSIBLE_COUNT.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(UPLINK_CHANNEL_COUNT.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(DOWNLINK_EMITTED_COUNT.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(DOWNLINK_FAILED_COUNT.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(PUSH_ACK_LATENCY.clone()))
+        .unwrap();
+    REGISTRY.register(Box::new(AIRTIME.clone())).unwrap();
+    REGISTRY
+        .register(Box::new(DUTY_CYCLE_REMAINING.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(DOWNLINK_QUEUE_DEPTH.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(UDP_REJECTED_COUNT.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(SERVER_ADDRESS_CHANGED_COUNT.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(SERVER_CONNECTION_STATE.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(MQTT_PUBLISHED_COUNT.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(MQTT_REDELIVERED_COUNT.clone()))
+        .unwrap();
 
     info!("Starting Prometheus metrics server, bind: {}", bind);
     let listener = TcpListener::bind(bind).expect("bind metrics server error");
@@ -62,6 +153,105 @@ pub fn incr_udp_received_bytes(server: &str, typ: &str, count: usize) {
         .inc_by(count as u64);
 }
 
+pub fn incr_uplink_dropped_count(server: &str, reason: &str) {
+    UPLINK_DROPPED_COUNT
+        .with_label_values(&[server, reason])
+        .inc();
+}
+
+pub fn incr_uplink_routed_count(server: &str, route: &str) {
+    UPLINK_ROUTED_COUNT
+        .with_label_values(&[server, route])
+        .inc();
+}
+
+pub fn incr_uplink_implausible_count(server: &str, field: &str) {
+    UPLINK_IMPLAUSIBLE_COUNT
+        .with_label_values(&[server, field])
+        .inc();
+}
+
+pub fn incr_uplink_channel_count(server: &str, channel: &str) {
+    UPLINK_CHANNEL_COUNT
+        .with_label_values(&[server, channel])
+        .inc();
+}
+
+pub fn incr_downlink_emitted_count(server: &str, item: &str) {
+    DOWNLINK_EMITTED_COUNT
+        .with_label_values(&[server, item])
+        .inc();
+}
+
+pub fn incr_downlink_failed_count(server: &str, reason: &str) {
+    DOWNLINK_FAILED_COUNT
+        .with_label_values(&[server, reason])
+        .inc();
+}
+
+#[cfg(test)]
+pub fn get_downlink_failed_count(server: &str, reason: &str) -> u64 {
+    DOWNLINK_FAILED_COUNT
+        .with_label_values(&[server, reason])
+        .get()
+}
+
+pub fn observe_push_ack_latency(server: &str, latency: Duration) {
+    PUSH_ACK_LATENCY
+        .with_label_values(&[server])
+        .observe(latency.as_secs_f64());
+}
+
+pub fn observe_airtime(server: &str, direction: &str, datr: &str, airtime: Duration) {
+    AIRTIME
+        .with_label_values(&[server, direction, datr])
+        .inc_by(airtime.as_secs_f64());
+}
+
+pub fn set_duty_cycle_remaining(sub_band: &str, remaining: Duration) {
+    DUTY_CYCLE_REMAINING
+        .with_label_values(&[sub_band])
+        .set(remaining.as_millis() as i64);
+}
+
+pub fn set_downlink_queue_depth(server: &str, depth: usize) {
+    DOWNLINK_QUEUE_DEPTH
+        .with_label_values(&[server])
+        .set(depth as i64);
+}
+
+pub fn incr_udp_rejected_count(server: &str, reason: &str) {
+    UDP_REJECTED_COUNT
+        .with_label_values(&[server, reason])
+        .inc();
+}
+
+pub fn incr_mqtt_published_count(server: &str, event: &str) {
+    MQTT_PUBLISHED_COUNT
+        .with_label_values(&[server, event])
+        .inc();
+}
+
+pub fn incr_mqtt_redelivered_count(server: &str, event: &str) {
+    MQTT_REDELIVERED_COUNT
+        .with_label_values(&[server, event])
+        .inc();
+}
+
+pub fn incr_server_address_changed_count(server: &str) {
+    SERVER_ADDRESS_CHANGED_COUNT
+        .with_label_values(&[server])
+        .inc();
+}
+
+pub fn set_server_connection_state(server: &str, state: ConnectionState) {
+    for s in ConnectionState::ALL.iter() {
+        SERVER_CONNECTION_STATE
+            .with_label_values(&[server, s.as_str()])
+            .set((*s == state) as i64);
+    }
+}
+
 fn handle_request(stream: TcpStream) {
     handle_read(&stream);
     handle_write(stream);
@@ -0,0 +1,176 @@
+use std::fs;
+
+use anyhow::Result;
+
+use super::config;
+
+// A single line changed by the migration.
+#[derive(Debug, PartialEq)]
+pub struct Change {
+    pub line: usize,
+    pub old: String,
+    pub new: String,
+}
+
+// Migrates the configuration to the current schema. This works line by line
+// (instead of re-serializing the TOML), so that comments and formatting are
+// preserved.
+pub fn migrate(content: &str) -> (String, Vec<Change>) {
+    let mut out = String::with_capacity(content.len());
+    let mut changes = vec![];
+    let mut section = String::new();
+
+    for (i, line) in content.lines().enumerate() {
+        let new = migrate_line(line, &mut section);
+        if new != line {
+            changes.push(Change {
+                line: i + 1,
+                old: line.to_string(),
+                new: new.clone(),
+            });
+        }
+        out.push_str(&new);
+        out.push('\n');
+    }
+
+    (out, changes)
+}
+
+fn migrate_line(line: &str, section: &mut String) -> String {
+    let trimmed = line.split('#').next().unwrap_or_default().trim();
+
+    // Section headers, the [udp_bridge] section (v3) has been renamed to
+    // [udp_forwarder].
+    if trimmed.starts_with('[') {
+        let name = trimmed.trim_matches(|c| c == '[' || c == ']').trim();
+        let renamed = match name.strip_prefix("udp_bridge") {
+            Some(rest) if rest.is_empty() || rest.starts_with('.') => {
+                format!("udp_forwarder{}", rest)
+            }
+            _ => name.to_string(),
+        };
+        *section = renamed.clone();
+
+        if renamed != name {
+            return line.replacen(name, &renamed, 1);
+        }
+        return line.to_string();
+    }
+
+    // The log_level was a number (v3), it is now the name of the level.
+    if section == "udp_forwarder" {
+        if let Some((key, value)) = trimmed.split_once('=') {
+            if key.trim() == "log_level" {
+                if let Ok(v) = value.trim().parse::<u8>() {
+                    let level = match v {
+                        0..=2 => "ERROR",
+                        3 => "WARN",
+                        4 => "INFO",
+                        5 => "DEBUG",
+                        _ => "TRACE",
+                    };
+                    let indent = &line[..line.len() - line.trim_start().len()];
+                    let comment = line.find('#').map(|i| &line[i..]).unwrap_or_default();
+                    return format!("{}log_level=\"{}\" {}", indent, level, comment)
+                        .trim_end()
+                        .to_string();
+                }
+            }
+        }
+    }
+
+    line.to_string()
+}
+
+// Migrates the given configuration files, printing the changes as diff. When
+// write is set, the files are updated in-place. As the configuration can be
+// split over multiple files, these are validated together.
+pub fn run(filenames: &[String], write: bool) -> Result<()> {
+    let mut combined = String::new();
+    let mut updated: Vec<(&String, String)> = vec![];
+
+    for file_name in filenames {
+        let content = fs::read_to_string(file_name)
+            .map_err(|e| anyhow!("read config file error: {}, file: {}", e, file_name))?;
+        let (migrated, changes) = migrate(&content);
+        combined.push_str(&migrated);
+
+        if changes.is_empty() {
+            println!("# {}: up to date", file_name);
+            continue;
+        }
+
+        println!("--- {}", file_name);
+        println!("+++ {}", file_name);
+        for c in &changes {
+            println!("@@ -{} +{} @@", c.line, c.line);
+            println!("-{}", c.old);
+            println!("+{}", c.new);
+        }
+
+        updated.push((file_name, migrated));
+    }
+
+    if let Err(e) = toml::from_str::<config::Configuration>(&combined) {
+        return Err(anyhow!("migrated config is invalid: {}", e));
+    }
+
+    if write {
+        for (file_name, migrated) in updated {
+            fs::write(file_name, migrated)
+                .map_err(|e| anyhow!("write config file error: {}, file: {}", e, file_name))?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate() {
+        let content = r#"# UDP Bridge configuration.
+[udp_bridge]
+  log_level=4 # info
+  log_to_syslog=false
+
+  [[udp_bridge.servers]]
+    server="localhost:1700"
+
+[concentratord]
+  event_url="ipc:///tmp/concentratord_event"
+"#;
+
+        let (migrated, changes) = migrate(content);
+        assert_eq!(
+            changes,
+            vec![
+                Change {
+                    line: 2,
+                    old: "[udp_bridge]".into(),
+                    new: "[udp_forwarder]".into(),
+                },
+                Change {
+                    line: 3,
+                    old: "  log_level=4 # info".into(),
+                    new: "  log_level=\"INFO\" # info".into(),
+                },
+                Change {
+                    line: 6,
+                    old: "  [[udp_bridge.servers]]".into(),
+                    new: "  [[udp_forwarder.servers]]".into(),
+                },
+            ]
+        );
+
+        let conf: config::Configuration = toml::from_str(&migrated).unwrap();
+        assert_eq!(conf.udp_forwarder.log_level, "INFO");
+        assert_eq!(conf.udp_forwarder.servers[0].server, "localhost:1700");
+
+        // Already migrated.
+        let (_, changes) = migrate(&migrated);
+        assert!(changes.is_empty());
+    }
+}
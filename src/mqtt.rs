@@ -0,0 +1,875 @@
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use chirpstack_api::gw;
+use prost::Message;
+
+use super::backend::Backend;
+use super::config;
+use super::downlink;
+use super::dutycycle;
+use super::forwarder;
+use super::frontend::{self, Frontend};
+use super::metrics;
+use super::signals;
+
+// MQTT 3.1.1 packet types (including the fixed header flags).
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x82;
+const PINGREQ: u8 = 0xc0;
+
+// Flags of the PUBLISH fixed header.
+const QOS_1: u8 = 0x02;
+const DUP: u8 = 0x08;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Interval in which the connection is polled for incoming packets, this is
+// also the resolution of the keep-alive.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Max. remaining length of an incoming packet, the downlink commands are
+// small.
+const MAX_PACKET_SIZE: usize = 64 * 1024;
+
+// Minimal MQTT 3.1.1 client (QoS 0 and 1, without TLS), sending the packets.
+// The incoming packets are read from a clone of the stream.
+struct Client {
+    server: String,
+    stream: Mutex<TcpStream>,
+    last_write: Mutex<Instant>,
+    // Events published with QoS 1.
+    ack_required_events: Vec<String>,
+    inflight: Arc<Mutex<Inflight>>,
+    redelivery_interval: Duration,
+    read_only: bool,
+}
+
+impl Client {
+    fn send(&self, b: &[u8]) -> Result<()> {
+        self.stream.lock().unwrap().write_all(b)?;
+        *self.last_write.lock().unwrap() = Instant::now();
+        Ok(())
+    }
+
+    // Publishes the event. The ack_required_events are published with QoS 1
+    // and only counted as published once acknowledged by the broker. Nothing
+    // is published in read-only mode.
+    fn publish(&self, event: &str, topic: &str, payload: &[u8]) -> Result<()> {
+        if self.read_only {
+            debug!(
+                "Read-only mode, not publishing MQTT message, event: {}, server: {}",
+                event, self.server
+            );
+            return Ok(());
+        }
+
+        if !self.ack_required_events.iter().any(|v| v == event) {
+            self.send(&publish_packet(topic, None, payload))?;
+            metrics::incr_mqtt_published_count(&self.server, event);
+            return Ok(());
+        }
+
+        if self
+            .inflight
+            .lock()
+            .unwrap()
+            .push(event, topic, payload)
+            .is_none()
+        {
+            if event == "up" {
+                metrics::incr_uplink_dropped_count(&self.server, "MQTT_INFLIGHT_FULL");
+            }
+            return Err(anyhow!("in-flight window is full, dropping message"));
+        }
+        self.send_inflight(false)
+    }
+
+    // Sends the QoS 1 messages which have not been sent yet, or which have
+    // not been acknowledged within the redelivery interval. When all is set
+    // (e.g. after a reconnect), all unacknowledged messages are sent.
+    fn send_inflight(&self, all: bool) -> Result<()> {
+        let due = self
+            .inflight
+            .lock()
+            .unwrap()
+            .due(Instant::now(), self.redelivery_interval, all);
+
+        for (event, packet) in due {
+            if packet[0] & DUP != 0 {
+                debug!(
+                    "Redelivering MQTT message, event: {}, server: {}",
+                    event, self.server
+                );
+                metrics::incr_mqtt_redelivered_count(&self.server, &event);
+            }
+            self.send(&packet)?;
+        }
+        Ok(())
+    }
+
+    fn handle_puback(&self, body: &[u8]) {
+        if body.len() != 2 {
+            return;
+        }
+
+        let packet_id = u16::from_be_bytes([body[0], body[1]]);
+        if let Some(event) = self.inflight.lock().unwrap().ack(packet_id) {
+            metrics::incr_mqtt_published_count(&self.server, &event);
+        }
+    }
+}
+
+// QoS 1 messages waiting for their PUBACK. These are kept over reconnects and
+// redelivered (with the DUP flag) until acknowledged by the broker. The
+// number of messages is limited by the window (max_inflight).
+struct Inflight {
+    max: usize,
+    next_id: u16,
+    messages: BTreeMap<u16, InflightMessage>,
+}
+
+struct InflightMessage {
+    event: String,
+    topic: String,
+    payload: Vec<u8>,
+    sent_at: Option<Instant>,
+}
+
+impl Inflight {
+    fn new(max: usize) -> Self {
+        Inflight {
+            max,
+            next_id: 0,
+            messages: BTreeMap::new(),
+        }
+    }
+
+    // Adds the message and returns its packet identifier, None when the
+    // window is full.
+    fn push(&mut self, event: &str, topic: &str, payload: &[u8]) -> Option<u16> {
+        if self.messages.len() >= self.max {
+            return None;
+        }
+
+        // The packet identifier must be non-zero and unused.
+        loop {
+            self.next_id = self.next_id.wrapping_add(1).max(1);
+            if !self.messages.contains_key(&self.next_id) {
+                break;
+            }
+        }
+
+        self.messages.insert(
+            self.next_id,
+            InflightMessage {
+                event: event.to_string(),
+                topic: topic.to_string(),
+                payload: payload.to_vec(),
+                sent_at: None,
+            },
+        );
+        Some(self.next_id)
+    }
+
+    // Removes the acknowledged message and returns its event.
+    fn ack(&mut self, packet_id: u16) -> Option<String> {
+        self.messages.remove(&packet_id).map(|v| v.event)
+    }
+
+    // Returns the event and PUBLISH packet of the messages which must be
+    // (re)sent and marks these as sent.
+    fn due(&mut self, now: Instant, interval: Duration, all: bool) -> Vec<(String, Vec<u8>)> {
+        let mut out = vec![];
+        for (packet_id, m) in self.messages.iter_mut() {
+            let redelivery = match m.sent_at {
+                None => false,
+                Some(_) if all => true,
+                Some(v) if now.duration_since(v) >= interval => true,
+                Some(_) => continue,
+            };
+
+            let mut packet = publish_packet(&m.topic, Some(*packet_id), &m.payload);
+            if redelivery {
+                packet[0] |= DUP;
+            }
+            m.sent_at = Some(now);
+            out.push((m.event.clone(), packet));
+        }
+        out
+    }
+}
+
+// MQTT frontend, publishing the uplinks and stats to the ChirpStack v4
+// gateway event topics.
+struct MqttFrontend {
+    server: String,
+    topic: String,
+    client: Arc<Client>,
+}
+
+impl MqttFrontend {
+    fn publish_event(&self, event: &str, b: &[u8]) {
+        let topic = format!("{}/event/{}", self.topic, event);
+        if let Err(e) = self.client.publish(event, &topic, b) {
+            error!(
+                "MQTT publish error: {}, topic: {}, server: {}",
+                e, topic, self.server
+            );
+        }
+    }
+}
+
+impl Frontend for MqttFrontend {
+    fn name(&self) -> &str {
+        &self.server
+    }
+
+    fn handle_uplink(&self, up: gw::UplinkFrame) {
+        self.publish_event("up", &up.encode_to_vec());
+    }
+
+    fn handle_stats(&self, stats: gw::GatewayStats) {
+        self.publish_event("stats", &stats.encode_to_vec());
+    }
+}
+
+// Connects to the MQTT broker and forwards the events and the downlink
+// commands, reconnecting on error. This blocks forever.
+pub fn start(
+    conf: config::Mqtt,
+    downlink_plan: config::DownlinkPlan,
+    dispatcher: Arc<frontend::Dispatcher>,
+    gateway_id: Vec<u8>,
+) {
+    let gateway_id = hex::encode(gateway_id);
+    let client_id = match conf.client_id.as_str() {
+        "" => gateway_id.clone(),
+        v => v.to_string(),
+    };
+    let topic = format!("{}/gateway/{}", conf.topic_prefix, gateway_id);
+    let inflight = Arc::new(Mutex::new(Inflight::new(conf.max_inflight)));
+
+    if conf.read_only {
+        warn!(
+            "MQTT read-only mode, nothing will be published or sent to Concentratord, server: {}",
+            conf.server
+        );
+    }
+
+    loop {
+        if let Err(e) = run(
+            &conf,
+            &downlink_plan,
+            &dispatcher,
+            &client_id,
+            &topic,
+            &inflight,
+        ) {
+            error!("MQTT error: {}, server: {}", e, conf.server);
+        }
+        thread::sleep(Duration::from_secs(conf.reconnect_interval_secs));
+    }
+}
+
+fn run(
+    conf: &config::Mqtt,
+    downlink_plan: &config::DownlinkPlan,
+    dispatcher: &Arc<frontend::Dispatcher>,
+    client_id: &str,
+    topic: &str,
+    inflight: &Arc<Mutex<Inflight>>,
+) -> Result<()> {
+    let (client, mut reader) = connect(conf, client_id, inflight.clone())?;
+    let client = Arc::new(client);
+    client.send(&subscribe_packet(1, &format!("{}/command/down", topic)))?;
+    info!("MQTT connected, server: {}, topic: {}", conf.server, topic);
+
+    // The messages which were not acknowledged before the connection was
+    // lost.
+    client.send_inflight(true)?;
+
+    let (stop, stop_receive) = channel();
+    let events_thread = thread::spawn({
+        let frontend = MqttFrontend {
+            server: conf.server.clone(),
+            topic: topic.to_string(),
+            client: client.clone(),
+        };
+        let events = dispatcher.subscribe();
+
+        move || frontend::run(&frontend, events, stop_receive)
+    });
+
+    let res = receive_loop(
+        conf,
+        downlink_plan,
+        dispatcher.backend().as_ref(),
+        &client,
+        &mut reader,
+        topic,
+    );
+
+    let _ = stop.send(signals::Signal::Stop);
+    events_thread.join().unwrap();
+    res
+}
+
+// Returns the client and the reader of the incoming packets.
+fn connect(
+    conf: &config::Mqtt,
+    client_id: &str,
+    inflight: Arc<Mutex<Inflight>>,
+) -> Result<(Client, PacketReader<TcpStream>)> {
+    let server = conf.server.strip_prefix("tcp://").unwrap_or(&conf.server);
+    let addr = server
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow!("could not resolve server"))?;
+
+    let mut stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+    stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+    stream.write_all(&connect_packet(
+        client_id,
+        &conf.username,
+        &conf.password,
+        conf.keep_alive_secs,
+    ))?;
+
+    let mut reader = PacketReader::new(stream.try_clone()?);
+    match reader.read_packet()? {
+        Some((CONNACK, body)) if body.len() == 2 => {
+            if body[1] != 0 {
+                return Err(anyhow!("connection refused, return code: {}", body[1]));
+            }
+        }
+        _ => return Err(anyhow!("expected CONNACK")),
+    }
+
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    let client = Client {
+        server: conf.server.clone(),
+        stream: Mutex::new(stream),
+        last_write: Mutex::new(Instant::now()),
+        ack_required_events: conf.ack_required_events.clone(),
+        inflight,
+        redelivery_interval: Duration::from_secs(conf.redelivery_interval_secs),
+        read_only: conf.read_only,
+    };
+    Ok((client, reader))
+}
+
+// Reads the incoming packets and handles the downlink commands, until the
+// connection fails.
+fn receive_loop(
+    conf: &config::Mqtt,
+    downlink_plan: &config::DownlinkPlan,
+    backend: &dyn Backend,
+    client: &Client,
+    reader: &mut PacketReader<TcpStream>,
+    topic: &str,
+) -> Result<()> {
+    let keep_alive = Duration::from_secs(conf.keep_alive_secs.into());
+    let mut last_read = Instant::now();
+
+    loop {
+        if !keep_alive.is_zero() {
+            if client.last_write.lock().unwrap().elapsed() >= keep_alive / 2 {
+                client.send(&[PINGREQ, 0])?;
+            }
+            if last_read.elapsed() > keep_alive * 3 / 2 {
+                return Err(anyhow!("keep-alive timeout"));
+            }
+        }
+
+        client.send_inflight(false)?;
+
+        let (header, body) = match reader.read_packet()? {
+            Some(v) => v,
+            None => continue,
+        };
+        last_read = Instant::now();
+
+        if header == PUBACK {
+            client.handle_puback(&body);
+            continue;
+        }
+        if header & 0xf0 != PUBLISH {
+            continue;
+        }
+
+        let (publish_topic, payload) = parse_publish(header, &body)?;
+        if publish_topic.ends_with("/command/down") {
+            if let Err(e) = handle_downlink(conf, downlink_plan, backend, client, topic, payload) {
+                error!("MQTT downlink error: {}, server: {}", e, conf.server);
+            }
+        }
+    }
+}
+
+// Sends the downlink to the backend and publishes the TX ack. The downlink
+// plan and duty cycle are enforced in the same way as for the UDP servers.
+fn handle_downlink(
+    conf: &config::Mqtt,
+    downlink_plan: &config::DownlinkPlan,
+    backend: &dyn Backend,
+    client: &Client,
+    topic: &str,
+    b: &[u8],
+) -> Result<()> {
+    let pl = gw::DownlinkFrame::decode(b)?;
+    info!(
+        "MQTT downlink received, downlink_id: {}, server: {}",
+        pl.downlink_id, conf.server
+    );
+
+    if conf.read_only {
+        info!(
+            "Read-only mode, ignoring MQTT downlink, downlink_id: {}, server: {}",
+            pl.downlink_id, conf.server
+        );
+        metrics::incr_downlink_failed_count(&conf.server, "READ_ONLY");
+        return Ok(());
+    }
+
+    let rejected = match downlink::check_plan(&pl, downlink_plan) {
+        Some(v) => Some(v),
+        None if forwarder::exceeds_duty_cycle(&pl) => {
+            Some((forwarder::DUTY_CYCLE_TX_ACK_STATUS, "DUTY_CYCLE"))
+        }
+        None => None,
+    };
+
+    let tx_ack = match rejected {
+        Some((status, reason)) => {
+            warn!(
+                "Downlink can not be scheduled, downlink_id: {}, reason: {}, server: {}",
+                pl.downlink_id, reason, conf.server
+            );
+            metrics::incr_downlink_failed_count(&conf.server, reason);
+
+            gw::DownlinkTxAck {
+                gateway_id: pl.gateway_id.clone(),
+                downlink_id: pl.downlink_id,
+                items: vec![gw::DownlinkTxAckItem {
+                    status: status.into(),
+                }],
+                ..Default::default()
+            }
+        }
+        None => backend.send_downlink(&pl).map_err(|(reason, e)| {
+            metrics::incr_downlink_failed_count(&conf.server, reason);
+            anyhow!("send downlink error: {}, reason: {}", e, reason)
+        })?,
+    };
+
+    if let (Some(i), _) = downlink::get_tx_ack_status(&tx_ack)? {
+        if let Some((frequency, airtime)) = pl.items.get(i).and_then(forwarder::downlink_airtime) {
+            dutycycle::transmitted(frequency, airtime);
+        }
+        metrics::incr_downlink_emitted_count(
+            &conf.server,
+            if i == 0 { "PRIMARY" } else { "FALLBACK" },
+        );
+    }
+
+    client.publish(
+        "ack",
+        &format!("{}/event/ack", topic),
+        &tx_ack.encode_to_vec(),
+    )
+}
+
+fn encode_str(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+// Returns the packet, prefixed with the fixed header (the packet type and
+// the variable length encoded remaining length).
+fn encode_packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![header];
+    let mut len = body.len();
+    loop {
+        let mut b = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            b |= 0x80;
+        }
+        out.push(b);
+        if len == 0 {
+            break;
+        }
+    }
+    out.extend_from_slice(body);
+    out
+}
+
+fn connect_packet(client_id: &str, username: &str, password: &str, keep_alive: u16) -> Vec<u8> {
+    // Clean session.
+    let mut flags: u8 = 0x02;
+    if !username.is_empty() {
+        flags |= 0x80;
+    }
+    if !password.is_empty() {
+        flags |= 0x40;
+    }
+
+    let mut body = vec![];
+    encode_str(&mut body, "MQTT");
+    body.push(4);
+    body.push(flags);
+    body.extend_from_slice(&keep_alive.to_be_bytes());
+    encode_str(&mut body, client_id);
+    if !username.is_empty() {
+        encode_str(&mut body, username);
+    }
+    if !password.is_empty() {
+        encode_str(&mut body, password);
+    }
+    encode_packet(CONNECT, &body)
+}
+
+// Returns the PUBLISH packet, with QoS 1 when a packet identifier is given.
+fn publish_packet(topic: &str, packet_id: Option<u16>, payload: &[u8]) -> Vec<u8> {
+    let mut body = vec![];
+    encode_str(&mut body, topic);
+    if let Some(v) = packet_id {
+        body.extend_from_slice(&v.to_be_bytes());
+    }
+    body.extend_from_slice(payload);
+    encode_packet(
+        match packet_id {
+            Some(_) => PUBLISH | QOS_1,
+            None => PUBLISH,
+        },
+        &body,
+    )
+}
+
+fn subscribe_packet(packet_id: u16, topic: &str) -> Vec<u8> {
+    let mut body = packet_id.to_be_bytes().to_vec();
+    encode_str(&mut body, topic);
+    // QoS 0
+    body.push(0);
+    encode_packet(SUBSCRIBE, &body)
+}
+
+// Reads the incoming packets. A packet might be received partially within the
+// read timeout (poll interval), the received bytes are buffered until the
+// packet is complete.
+struct PacketReader<R: Read> {
+    reader: R,
+    buffer: Vec<u8>,
+}
+
+impl<R: Read> PacketReader<R> {
+    fn new(reader: R) -> Self {
+        PacketReader {
+            reader,
+            buffer: vec![],
+        }
+    }
+
+    // Returns the fixed header and the remaining bytes of the next packet,
+    // None when no complete packet was received within the read timeout.
+    fn read_packet(&mut self) -> io::Result<Option<(u8, Vec<u8>)>> {
+        loop {
+            if let Some(v) = self.take_packet()? {
+                return Ok(Some(v));
+            }
+
+            let mut b = [0; 4096];
+            match self.reader.read(&mut b) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(size) => self.buffer.extend_from_slice(&b[..size]),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Removes the next packet from the buffer, when it is complete.
+    fn take_packet(&mut self) -> io::Result<Option<(u8, Vec<u8>)>> {
+        let mut len: usize = 0;
+        for i in 0..4 {
+            let b = match self.buffer.get(1 + i) {
+                Some(v) => *v,
+                None => return Ok(None),
+            };
+            len |= ((b & 0x7f) as usize) << (7 * i);
+            if b & 0x80 != 0 {
+                continue;
+            }
+
+            if len > MAX_PACKET_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("packet too large, size: {}", len),
+                ));
+            }
+
+            let start = 2 + i;
+            if self.buffer.len() < start + len {
+                return Ok(None);
+            }
+
+            let header = self.buffer[0];
+            let body = self.buffer[start..start + len].to_vec();
+            self.buffer.drain(..start + len);
+            return Ok(Some((header, body)));
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid remaining length",
+        ))
+    }
+}
+
+// Returns the topic and payload of the PUBLISH packet.
+fn parse_publish(header: u8, body: &[u8]) -> Result<(String, &[u8])> {
+    if body.len() < 2 {
+        return Err(anyhow!("PUBLISH packet too short"));
+    }
+    let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+    let mut offset = 2 + topic_len;
+    // QoS > 0 packets contain a packet identifier.
+    if header & 0x06 != 0 {
+        offset += 2;
+    }
+    if body.len() < offset {
+        return Err(anyhow!("PUBLISH packet too short"));
+    }
+
+    let topic = String::from_utf8(body[2..2 + topic_len].to_vec())?;
+    Ok((topic, &body[offset..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::backend;
+
+    #[test]
+    fn test_packets() {
+        let b = publish_packet("eu868/gateway/0102030405060708/event/up", None, &[0; 300]);
+        // Remaining length of 341 bytes, encoded using two bytes.
+        assert_eq!(&b[..3], &[PUBLISH, 0xd5, 0x02]);
+
+        let (header, body) = PacketReader::new(b.as_slice())
+            .read_packet()
+            .unwrap()
+            .unwrap();
+        assert_eq!(header, PUBLISH);
+        let (topic, payload) = parse_publish(header, &body).unwrap();
+        assert_eq!(topic, "eu868/gateway/0102030405060708/event/up");
+        assert_eq!(payload, &[0; 300][..]);
+
+        assert_eq!(
+            connect_packet("gw", "user", "", 30),
+            vec![
+                CONNECT, 20, 0, 4, b'M', b'Q', b'T', b'T', 4, 0x82, 0, 30, 0, 2, b'g', b'w', 0, 4,
+                b'u', b's', b'e', b'r'
+            ]
+        );
+        assert_eq!(
+            subscribe_packet(1, "a/b"),
+            vec![SUBSCRIBE, 8, 0, 1, 0, 3, b'a', b'/', b'b', 0]
+        );
+
+        assert!(PacketReader::new([].as_ref()).read_packet().is_err());
+
+        // QoS 1, with packet identifier.
+        let b = publish_packet("a/b", Some(0x0102), &[7]);
+        assert_eq!(b, vec![PUBLISH | QOS_1, 8, 0, 3, b'a', b'/', b'b', 1, 2, 7]);
+        assert_eq!(
+            parse_publish(b[0], &b[2..]).unwrap(),
+            ("a/b".into(), &[7][..])
+        );
+    }
+
+    #[test]
+    fn test_inflight() {
+        let interval = Duration::from_secs(10);
+        let now = Instant::now();
+        let mut inflight = Inflight::new(2);
+
+        assert_eq!(inflight.push("up", "a/up", &[1]), Some(1));
+        assert_eq!(inflight.push("up", "a/up", &[2]), Some(2));
+        // The window is full.
+        assert_eq!(inflight.push("up", "a/up", &[3]), None);
+
+        // Both are sent once, without DUP flag.
+        let due = inflight.due(now, interval, false);
+        assert_eq!(
+            due,
+            vec![
+                ("up".to_string(), publish_packet("a/up", Some(1), &[1])),
+                ("up".to_string(), publish_packet("a/up", Some(2), &[2])),
+            ]
+        );
+        assert!(inflight.due(now + interval / 2, interval, false).is_empty());
+
+        // Acknowledged, or unknown packet identifier.
+        assert_eq!(inflight.ack(1), Some("up".to_string()));
+        assert_eq!(inflight.ack(1), None);
+
+        // Not acknowledged within the interval, redelivered with DUP flag.
+        let mut packet = publish_packet("a/up", Some(2), &[2]);
+        packet[0] |= DUP;
+        let due = inflight.due(now + interval, interval, false);
+        assert_eq!(due, vec![("up".to_string(), packet.clone())]);
+
+        // After a reconnect, all messages are sent.
+        assert_eq!(inflight.push("stats", "a/stats", &[3]), Some(3));
+        let due = inflight.due(now + interval, interval, true);
+        assert_eq!(
+            due,
+            vec![
+                ("up".to_string(), packet),
+                (
+                    "stats".to_string(),
+                    publish_packet("a/stats", Some(3), &[3])
+                ),
+            ]
+        );
+
+        // The packet identifiers wrap, skipping 0 and the identifiers in use.
+        inflight.next_id = u16::MAX;
+        assert_eq!(inflight.ack(3), Some("stats".to_string()));
+        assert_eq!(inflight.push("up", "a/up", &[4]), Some(1));
+        assert_eq!(inflight.ack(2), Some("up".to_string()));
+        inflight.next_id = 0;
+        assert_eq!(inflight.push("up", "a/up", &[5]), Some(2));
+    }
+
+    #[test]
+    fn test_packet_reader() {
+        // Reader returning the chunks, followed by a read timeout.
+        struct Chunks(Vec<Vec<u8>>);
+
+        impl Read for Chunks {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.0.is_empty() {
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                let chunk = self.0.remove(0);
+                buf[..chunk.len()].copy_from_slice(&chunk);
+                Ok(chunk.len())
+            }
+        }
+
+        let a = publish_packet("a/b", None, &[1; 200]);
+        let b = publish_packet("c/d", None, &[2; 10]);
+
+        // The packets are split over multiple reads (polls), the second packet
+        // starts within the read of the first.
+        let mut reader = PacketReader::new(Chunks(vec![a[..1].to_vec(), a[1..2].to_vec()]));
+        assert_eq!(reader.read_packet().unwrap(), None);
+
+        reader.reader.0.push([&a[2..], &b[..5]].concat());
+        let (header, body) = reader.read_packet().unwrap().unwrap();
+        assert_eq!(header, PUBLISH);
+        assert_eq!(
+            parse_publish(header, &body).unwrap(),
+            ("a/b".into(), &[1; 200][..])
+        );
+        assert_eq!(reader.read_packet().unwrap(), None);
+
+        reader.reader.0.push(b[5..].to_vec());
+        let (header, body) = reader.read_packet().unwrap().unwrap();
+        assert_eq!(
+            parse_publish(header, &body).unwrap(),
+            ("c/d".into(), &[2; 10][..])
+        );
+        assert_eq!(reader.read_packet().unwrap(), None);
+
+        // The remaining length is limited.
+        let mut reader = PacketReader::new(Chunks(vec![vec![PUBLISH, 0xff, 0xff, 0xff, 0x7f]]));
+        assert_eq!(
+            reader.read_packet().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        let mut reader =
+            PacketReader::new(Chunks(vec![vec![PUBLISH, 0xff, 0xff, 0xff, 0xff, 0x01]]));
+        assert_eq!(
+            reader.read_packet().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+    #[test]
+    fn test_read_only() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut broker, _) = listener.accept().unwrap();
+        broker
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+
+        let conf = config::Mqtt {
+            server: "tcp://read-only:1883".into(),
+            ack_required_events: vec!["ack".into()],
+            read_only: true,
+            ..Default::default()
+        };
+        let client = Client {
+            server: conf.server.clone(),
+            stream: Mutex::new(stream),
+            last_write: Mutex::new(Instant::now()),
+            ack_required_events: conf.ack_required_events.clone(),
+            inflight: Arc::new(Mutex::new(Inflight::new(conf.max_inflight))),
+            redelivery_interval: Duration::from_secs(conf.redelivery_interval_secs),
+            read_only: conf.read_only,
+        };
+        let backend = backend::Mock::new(&config::MockBackend::default()).unwrap();
+        let topic = "eu868/gateway/0102030405060708";
+
+        client
+            .publish("up", &format!("{}/event/up", topic), &[1, 2, 3])
+            .unwrap();
+
+        let pl = gw::DownlinkFrame {
+            downlink_id: 123,
+            gateway_id: "0102030405060708".into(),
+            items: vec![gw::DownlinkFrameItem::default()],
+            ..Default::default()
+        };
+        handle_downlink(
+            &conf,
+            &config::DownlinkPlan::default(),
+            &backend,
+            &client,
+            topic,
+            &pl.encode_to_vec(),
+        )
+        .unwrap();
+        assert_eq!(
+            metrics::get_downlink_failed_count(&conf.server, "READ_ONLY"),
+            1
+        );
+
+        // Neither the uplink nor the ack has been published.
+        let mut b = [0; 1];
+        assert!(broker.read(&mut b).is_err());
+        assert!(client.inflight.lock().unwrap().messages.is_empty());
+    }
+}
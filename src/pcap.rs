@@ -0,0 +1,205 @@
+use std::net::{IpAddr, SocketAddr};
+
+// Raw IPv4 / IPv6 packets, without link-layer header.
+const LINKTYPE_RAW: u16 = 101;
+
+const BLOCK_SHB: u32 = 0x0a0d_0d0a;
+const BLOCK_IDB: u32 = 0x0000_0001;
+const BLOCK_EPB: u32 = 0x0000_0006;
+
+const OPT_END: u16 = 0;
+const OPT_EPB_FLAGS: u16 = 2;
+
+const IPPROTO_UDP: u8 = 17;
+
+// Returns the section header and interface description blocks, which must
+// precede the packets of a pcapng file.
+pub fn header() -> Vec<u8> {
+    let mut shb = vec![];
+    shb.extend_from_slice(&0x1a2b_3c4d_u32.to_le_bytes()); // byte-order magic
+    shb.extend_from_slice(&1_u16.to_le_bytes()); // major version
+    shb.extend_from_slice(&0_u16.to_le_bytes()); // minor version
+    shb.extend_from_slice(&(-1_i64).to_le_bytes()); // section length (unknown)
+
+    let mut idb = vec![];
+    idb.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    idb.extend_from_slice(&0_u16.to_le_bytes()); // reserved
+    idb.extend_from_slice(&0_u32.to_le_bytes()); // snaplen (no limit)
+
+    let mut b = block(BLOCK_SHB, &shb);
+    b.extend(block(BLOCK_IDB, &idb));
+    b
+}
+
+// Returns the enhanced packet block of the UDP datagram, with the IP and UDP
+// headers added. The timestamp is in microseconds since the UNIX epoch.
+pub fn packet(
+    outbound: bool,
+    src: SocketAddr,
+    dst: SocketAddr,
+    payload: &[u8],
+    timestamp: u64,
+) -> Vec<u8> {
+    let data = ip_udp(src, dst, payload);
+
+    let mut epb = vec![];
+    epb.extend_from_slice(&0_u32.to_le_bytes()); // interface ID
+    epb.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+    epb.extend_from_slice(&(timestamp as u32).to_le_bytes());
+    epb.extend_from_slice(&(data.len() as u32).to_le_bytes()); // captured length
+    epb.extend_from_slice(&(data.len() as u32).to_le_bytes()); // original length
+    epb.extend_from_slice(&data);
+    pad(&mut epb);
+
+    // The direction flags are 01 for inbound and 10 for outbound.
+    epb.extend_from_slice(&OPT_EPB_FLAGS.to_le_bytes());
+    epb.extend_from_slice(&4_u16.to_le_bytes());
+    epb.extend_from_slice(&(if outbound { 2_u32 } else { 1_u32 }).to_le_bytes());
+    epb.extend_from_slice(&OPT_END.to_le_bytes());
+    epb.extend_from_slice(&0_u16.to_le_bytes());
+
+    block(BLOCK_EPB, &epb)
+}
+
+fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+    let len = (body.len() + 12) as u32;
+    let mut b = vec![];
+    b.extend_from_slice(&block_type.to_le_bytes());
+    b.extend_from_slice(&len.to_le_bytes());
+    b.extend_from_slice(body);
+    b.extend_from_slice(&len.to_le_bytes());
+    b
+}
+
+fn pad(b: &mut Vec<u8>) {
+    b.resize(b.len() + (4 - b.len() % 4) % 4, 0);
+}
+
+// Returns the IP packet containing the UDP datagram. When the addresses are
+// of a different family, IPv4 is mapped to IPv6.
+fn ip_udp(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let udp_len = (payload.len() + 8) as u16;
+    let mut udp = vec![];
+    udp.extend_from_slice(&src.port().to_be_bytes());
+    udp.extend_from_slice(&dst.port().to_be_bytes());
+    udp.extend_from_slice(&udp_len.to_be_bytes());
+    udp.extend_from_slice(&[0, 0]); // checksum
+    udp.extend_from_slice(payload);
+
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            let mut pseudo = vec![];
+            pseudo.extend_from_slice(&s.octets());
+            pseudo.extend_from_slice(&d.octets());
+            pseudo.extend_from_slice(&[0, IPPROTO_UDP]);
+            pseudo.extend_from_slice(&udp_len.to_be_bytes());
+            set_udp_checksum(&mut udp, &pseudo);
+
+            let mut ip = vec![0x45, 0];
+            ip.extend_from_slice(&(udp.len() as u16 + 20).to_be_bytes());
+            ip.extend_from_slice(&[0, 0, 0x40, 0, 64, IPPROTO_UDP, 0, 0]); // id, DF, ttl
+            ip.extend_from_slice(&s.octets());
+            ip.extend_from_slice(&d.octets());
+            let sum = checksum(&ip);
+            ip[10..12].copy_from_slice(&sum.to_be_bytes());
+            ip.extend(udp);
+            ip
+        }
+        (s, d) => {
+            let s = match s {
+                IpAddr::V4(v) => v.to_ipv6_mapped(),
+                IpAddr::V6(v) => v,
+            };
+            let d = match d {
+                IpAddr::V4(v) => v.to_ipv6_mapped(),
+                IpAddr::V6(v) => v,
+            };
+
+            let mut pseudo = vec![];
+            pseudo.extend_from_slice(&s.octets());
+            pseudo.extend_from_slice(&d.octets());
+            pseudo.extend_from_slice(&(udp_len as u32).to_be_bytes());
+            pseudo.extend_from_slice(&[0, 0, 0, IPPROTO_UDP]);
+            set_udp_checksum(&mut udp, &pseudo);
+
+            let mut ip = vec![0x60, 0, 0, 0];
+            ip.extend_from_slice(&udp_len.to_be_bytes());
+            ip.extend_from_slice(&[IPPROTO_UDP, 64]); // next header, hop limit
+            ip.extend_from_slice(&s.octets());
+            ip.extend_from_slice(&d.octets());
+            ip.extend(udp);
+            ip
+        }
+    }
+}
+
+fn set_udp_checksum(udp: &mut [u8], pseudo: &[u8]) {
+    let mut b = pseudo.to_vec();
+    b.extend_from_slice(udp);
+    let sum = match checksum(&b) {
+        // A computed checksum of 0 is transmitted as all ones.
+        0 => 0xffff,
+        v => v,
+    };
+    udp[6..8].copy_from_slice(&sum.to_be_bytes());
+}
+
+// Internet checksum (RFC 1071).
+fn checksum(b: &[u8]) -> u16 {
+    let mut sum: u32 = b
+        .chunks(2)
+        .map(|c| u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packet() {
+        let h = header();
+        assert_eq!(h.len(), 28 + 20);
+        assert_eq!(h[0..4], [0x0a, 0x0d, 0x0d, 0x0a]);
+        assert_eq!(h[28 + 8..28 + 10], LINKTYPE_RAW.to_le_bytes());
+
+        let src: SocketAddr = "192.168.1.2:45000".parse().unwrap();
+        let dst: SocketAddr = "10.0.0.1:1700".parse().unwrap();
+        let b = packet(true, src, dst, &[2, 0, 1, 1, 0xff], 0x1_0000_0002);
+        // 28 bytes header + 33 bytes packet (padded to 36) + 12 bytes options
+        // + 4 bytes trailing length
+        assert_eq!(b.len(), 28 + 36 + 12 + 4);
+        assert_eq!(b[4..8], 80_u32.to_le_bytes());
+        assert_eq!(b[b.len() - 4..], 80_u32.to_le_bytes());
+        assert_eq!(b[12..16], 1_u32.to_le_bytes());
+        assert_eq!(b[16..20], 2_u32.to_le_bytes());
+
+        let ip = &b[28..28 + 33];
+        assert_eq!(checksum(&ip[..20]), 0);
+        assert_eq!(ip[20..22], 45000_u16.to_be_bytes());
+        assert_eq!(ip[22..24], 1700_u16.to_be_bytes());
+        assert_eq!(ip[24..26], 13_u16.to_be_bytes());
+
+        // the checksum of the pseudo header and the datagram must be 0
+        let mut pseudo = vec![192, 168, 1, 2, 10, 0, 0, 1, 0, 17, 0, 13];
+        pseudo.extend_from_slice(&ip[20..]);
+        assert_eq!(checksum(&pseudo), 0);
+
+        let src: SocketAddr = "[2001:db8::1]:45000".parse().unwrap();
+        let dst: SocketAddr = "10.0.0.1:1700".parse().unwrap();
+        let ip = ip_udp(src, dst, &[2, 0, 1, 1]);
+        assert_eq!(ip.len(), 40 + 8 + 4);
+        assert_eq!(ip[0], 0x60);
+        assert_eq!(
+            ip[24..40],
+            "::ffff:10.0.0.1"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets()
+        );
+    }
+}
@@ -0,0 +1,182 @@
+use std::net::SocketAddr;
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use chirpstack_udp_forwarder::protocol;
+use rand::Rng;
+
+use super::config::Server;
+use super::transport;
+use super::udp;
+
+// Max. time to wait for the PUSH_ACK of a probe.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Resolves the server and, when it resolves to both IPv4 and IPv6 addresses,
+// probes these simultaneously by sending an empty PUSH_DATA. A PULL_DATA is
+// not used, as the server would route the PULL_RESP frames of the gateway to
+// the (short-lived) probe socket. The address for which the PUSH_ACK is
+// received first is returned. When no PUSH_ACK is received, the first
+// resolved address is returned.
+//
+// The probe sockets are bound to the configured bind address (on a random
+// port) and bind_interface, such that the same route is probed as used by
+// the forwarder.
+pub fn select_address(
+    conf: &Server,
+    bind: Option<SocketAddr>,
+    gateway_id: [u8; 8],
+) -> Result<SocketAddr> {
+    let addrs = transport::resolve(&conf.server, conf.address_family)?;
+    let first = addrs[0];
+
+    let candidates = candidates(&addrs);
+    if candidates.len() < 2 {
+        return Ok(first);
+    }
+
+    let (tx, rx) = channel();
+    for addr in candidates {
+        let tx = tx.clone();
+        let opts = udp::BindOptions {
+            addr: bind.map(|a| SocketAddr::new(a.ip(), 0)),
+            interface: conf.bind_interface.clone(),
+            reuse_port: false,
+        };
+        thread::spawn(move || match probe(&opts, addr, gateway_id) {
+            Ok(rtt) => {
+                let _ = tx.send((addr, rtt));
+            }
+            Err(e) => debug!("Probe error: {}, addr: {}", e, addr),
+        });
+    }
+    drop(tx);
+
+    match rx.recv_timeout(PROBE_TIMEOUT) {
+        Ok((addr, rtt)) => {
+            info!(
+                "Selected server address, server: {}, addr: {}, rtt: {:?}",
+                conf.server, addr, rtt
+            );
+            Ok(addr)
+        }
+        Err(_) => {
+            warn!(
+                "No server address responded to probe, server: {}, addr: {}",
+                conf.server, first
+            );
+            Ok(first)
+        }
+    }
+}
+
+// Returns the first address of each address family, in the order returned by
+// the resolver.
+fn candidates(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let mut out: Vec<SocketAddr> = vec![];
+    for addr in addrs {
+        if !out.iter().any(|v| v.is_ipv4() == addr.is_ipv4()) {
+            out.push(*addr);
+        }
+    }
+    out
+}
+
+// Sends an empty PUSH_DATA to the given address and returns the time until
+// the PUSH_ACK was received. The probe fails when the address can not be
+// reached from the bind address, e.g. an IPv6 address from an IPv4 bind
+// address.
+fn probe(opts: &udp::BindOptions, addr: SocketAddr, gateway_id: [u8; 8]) -> Result<Duration> {
+    let socket = udp::bind(opts)?;
+    udp::connect(&socket, addr)?;
+    socket.set_read_timeout(Some(PROBE_TIMEOUT))?;
+
+    let random_token: u16 = rand::thread_rng().gen();
+    let started = Instant::now();
+    socket.send(&probe_frame(random_token, gateway_id))?;
+
+    let mut buffer: [u8; 65535] = [0; 65535];
+    while started.elapsed() < PROBE_TIMEOUT {
+        let size = socket.recv(&mut buffer)?;
+        if is_probe_ack(&buffer[..size], random_token) {
+            return Ok(started.elapsed());
+        }
+    }
+
+    Err(anyhow!("timeout"))
+}
+
+fn probe_frame(random_token: u16, gateway_id: [u8; 8]) -> Vec<u8> {
+    protocol::PushData {
+        random_token,
+        gateway_id,
+        payload: protocol::PushDataPayload {
+            rxpk: vec![],
+            stat: None,
+        },
+    }
+    .to_bytes()
+}
+
+fn is_probe_ack(b: &[u8], random_token: u16) -> bool {
+    matches!(protocol::Frame::from_bytes(b), Ok(protocol::Frame::PushAck(v)) if v.random_token == random_token)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+
+    use super::*;
+
+    #[test]
+    fn test_candidates() {
+        let addrs: Vec<SocketAddr> = vec![
+            "[2001:db8::1]:1700".parse().unwrap(),
+            "[2001:db8::2]:1700".parse().unwrap(),
+            "192.0.2.1:1700".parse().unwrap(),
+            "192.0.2.2:1700".parse().unwrap(),
+        ];
+        assert_eq!(
+            candidates(&addrs),
+            vec![
+                "[2001:db8::1]:1700".parse::<SocketAddr>().unwrap(),
+                "192.0.2.1:1700".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_probe() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let mut buffer: [u8; 65535] = [0; 65535];
+            let (size, source) = server.recv_from(&mut buffer).unwrap();
+            let push_data = protocol::PushData::from_bytes(&buffer[..size]).unwrap();
+            assert_eq!(push_data.gateway_id, [1, 2, 3, 4, 5, 6, 7, 8]);
+            assert!(push_data.payload.rxpk.is_empty());
+
+            // A PUSH_ACK with another token is ignored.
+            for random_token in [
+                push_data.random_token.wrapping_add(1),
+                push_data.random_token,
+            ] {
+                server
+                    .send_to(&protocol::PushAck { random_token }.to_bytes(), source)
+                    .unwrap();
+            }
+        });
+
+        let opts = udp::BindOptions {
+            addr: Some("127.0.0.1:0".parse().unwrap()),
+            ..Default::default()
+        };
+        assert!(probe(&opts, addr, [1, 2, 3, 4, 5, 6, 7, 8]).is_ok());
+        handle.join().unwrap();
+
+        // An IPv6 address can not be probed from an IPv4 bind address.
+        assert!(probe(&opts, "[::1]:1700".parse().unwrap(), [0; 8]).is_err());
+    }
+}
@@ -0,0 +1,136 @@
+use super::structs::DataRate;
+
+const BW125: u32 = 125000;
+const BW250: u32 = 250000;
+const BW500: u32 = 500000;
+
+// LoRa data-rates (SF, BW) by LoRaWAN data-rate index (RP002-1.0.3), None
+// for non-LoRa or RFU indices.
+const EU868: [Option<(u32, u32)>; 7] = [
+    Some((12, BW125)),
+    Some((11, BW125)),
+    Some((10, BW125)),
+    Some((9, BW125)),
+    Some((8, BW125)),
+    Some((7, BW125)),
+    Some((7, BW250)),
+];
+
+const US915: [Option<(u32, u32)>; 14] = [
+    Some((10, BW125)),
+    Some((9, BW125)),
+    Some((8, BW125)),
+    Some((7, BW125)),
+    Some((8, BW500)),
+    None,
+    None,
+    None,
+    Some((12, BW500)),
+    Some((11, BW500)),
+    Some((10, BW500)),
+    Some((9, BW500)),
+    Some((8, BW500)),
+    Some((7, BW500)),
+];
+
+const AU915: [Option<(u32, u32)>; 14] = [
+    Some((12, BW125)),
+    Some((11, BW125)),
+    Some((10, BW125)),
+    Some((9, BW125)),
+    Some((8, BW125)),
+    Some((7, BW125)),
+    Some((8, BW500)),
+    None,
+    Some((12, BW500)),
+    Some((11, BW500)),
+    Some((10, BW500)),
+    Some((9, BW500)),
+    Some((8, BW500)),
+    Some((7, BW500)),
+];
+
+fn table(region: &str) -> Option<&'static [Option<(u32, u32)>]> {
+    match region {
+        "EU868" | "EU433" | "CN779" | "AS923" | "IN865" | "KR920" | "RU864" | "CN470" => {
+            Some(&EU868)
+        }
+        "US915" => Some(&US915),
+        "AU915" => Some(&AU915),
+        _ => None,
+    }
+}
+
+// Returns true when the region is supported for data-rate index conversion.
+pub fn is_known_region(region: &str) -> bool {
+    table(region).is_some()
+}
+
+// Returns the LoRa data-rate for the data-rate index of the region.
+pub fn data_rate_from_index(region: &str, dr: u32) -> Option<DataRate> {
+    let (sf, bw) = (*table(region)?.get(dr as usize)?)?;
+    Some(DataRate::Lora(sf, bw))
+}
+
+// Returns the (lowest) data-rate index of the LoRa data-rate in the region.
+pub fn data_rate_to_index(region: &str, datr: &DataRate) -> Option<u32> {
+    let (sf, bw) = match datr {
+        DataRate::Lora(sf, bw) => (*sf, *bw),
+        _ => return None,
+    };
+
+    table(region)?
+        .iter()
+        .position(|v| *v == Some((sf, bw)))
+        .map(|i| i as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::structs::TxPk;
+    use super::*;
+
+    #[test]
+    fn test_data_rate_index() {
+        assert_eq!(
+            data_rate_from_index("EU868", 0),
+            Some(DataRate::Lora(12, 125000))
+        );
+        assert_eq!(
+            data_rate_from_index("EU868", 6),
+            Some(DataRate::Lora(7, 250000))
+        );
+        assert_eq!(data_rate_from_index("EU868", 7), None);
+        assert_eq!(
+            data_rate_from_index("US915", 8),
+            Some(DataRate::Lora(12, 500000))
+        );
+        assert_eq!(data_rate_from_index("US915", 5), None);
+        assert_eq!(data_rate_from_index("XX000", 0), None);
+
+        assert_eq!(
+            data_rate_to_index("US915", &DataRate::Lora(8, 500000)),
+            Some(4)
+        );
+        assert_eq!(
+            data_rate_to_index("AU915", &DataRate::Lora(12, 125000)),
+            Some(0)
+        );
+        assert_eq!(data_rate_to_index("EU868", &DataRate::Fsk(50000)), None);
+    }
+
+    #[test]
+    fn test_txpk_data_rate_index() {
+        let mut txpk: TxPk = serde_json::from_str(
+            r#"{"imme":true,"freq":869.525,"rfch":0,"powe":27,"modu":"LORA","datr":0,"codr":"4/5","ipol":true,"size":3,"data":"AQID"}"#,
+        )
+        .unwrap();
+        assert_eq!(txpk.datr, DataRate::Fsk(0));
+
+        txpk.resolve_data_rate_index("EU868").unwrap();
+        assert_eq!(txpk.datr, DataRate::Lora(12, 125000));
+
+        txpk.datr = DataRate::Fsk(7);
+        assert!(txpk.resolve_data_rate_index("EU868").is_err());
+    }
+}
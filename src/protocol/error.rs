@@ -0,0 +1,42 @@
+use thiserror::Error;
+
+// Errors returned when parsing or converting the Semtech UDP protocol
+// structures.
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("expected {expected} bytes, got: {got}")]
+    InvalidLength { expected: usize, got: usize },
+
+    #[error("expected at least {min} bytes, got: {got}")]
+    TooShort { min: usize, got: usize },
+
+    #[error("expected protocol version: {expected}, got: {got}")]
+    ProtocolVersion { expected: u8, got: u8 },
+
+    #[error("invalid identifier: {0}")]
+    InvalidIdentifier(u8),
+
+    #[error("{0} must not be None")]
+    MissingField(&'static str),
+
+    #[error("unsupported modulation")]
+    UnsupportedModulation,
+
+    #[error("{0} DataRate expected")]
+    DataRateMismatch(&'static str),
+
+    #[error("invalid data-rate index: {0}")]
+    InvalidDataRateIndex(u32),
+
+    #[error("no timing information found")]
+    MissingTiming,
+
+    #[error("invalid GPS time: {0}")]
+    InvalidGpsTime(String),
+
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("base64 decode payload error: {0}")]
+    Base64(#[from] base64::DecodeError),
+}
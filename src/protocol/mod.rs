@@ -0,0 +1,132 @@
+use std::convert::TryFrom;
+
+mod datarate;
+mod error;
+mod structs;
+
+pub use self::datarate::*;
+pub use self::error::Error;
+pub use self::structs::*;
+
+// Semtech UDP message types, as encoded in the 4th byte of each frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    PushData = 0x00,
+    PushAck = 0x01,
+    PullData = 0x02,
+    PullResp = 0x03,
+    PullAck = 0x04,
+    TxAck = 0x05,
+}
+
+impl MessageType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageType::PushData => "PUSH_DATA",
+            MessageType::PushAck => "PUSH_ACK",
+            MessageType::PullData => "PULL_DATA",
+            MessageType::PullResp => "PULL_RESP",
+            MessageType::PullAck => "PULL_ACK",
+            MessageType::TxAck => "TX_ACK",
+        }
+    }
+}
+
+impl TryFrom<u8> for MessageType {
+    type Error = Error;
+
+    fn try_from(v: u8) -> Result<Self, Error> {
+        Ok(match v {
+            0x00 => MessageType::PushData,
+            0x01 => MessageType::PushAck,
+            0x02 => MessageType::PullData,
+            0x03 => MessageType::PullResp,
+            0x04 => MessageType::PullAck,
+            0x05 => MessageType::TxAck,
+            _ => return Err(Error::InvalidIdentifier(v)),
+        })
+    }
+}
+
+// Semtech UDP frame, of any of the message types.
+pub enum Frame {
+    PushData(PushData),
+    PushAck(PushAck),
+    PullData(PullData),
+    PullResp(PullResp),
+    PullAck(PullAck),
+    TxAck(TxAck),
+}
+
+impl Frame {
+    // Decodes the frame, based on the message type.
+    pub fn from_bytes(b: &[u8]) -> Result<Self, Error> {
+        if b.len() < 4 {
+            return Err(Error::TooShort {
+                min: 4,
+                got: b.len(),
+            });
+        }
+
+        Ok(match MessageType::try_from(b[3])? {
+            MessageType::PushData => Frame::PushData(PushData::from_bytes(b)?),
+            MessageType::PushAck => Frame::PushAck(PushAck::from_bytes(b)?),
+            MessageType::PullData => Frame::PullData(PullData::from_bytes(b)?),
+            MessageType::PullResp => Frame::PullResp(PullResp::from_bytes(b)?),
+            MessageType::PullAck => Frame::PullAck(PullAck::from_bytes(b)?),
+            MessageType::TxAck => Frame::TxAck(TxAck::from_bytes(b)?),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Frame::PushData(v) => v.to_bytes(),
+            Frame::PushAck(v) => v.to_bytes(),
+            Frame::PullData(v) => v.to_bytes(),
+            Frame::PullResp(v) => v.to_bytes(),
+            Frame::PullAck(v) => v.to_bytes(),
+            Frame::TxAck(v) => v.to_bytes(),
+        }
+    }
+
+    pub fn message_type(&self) -> MessageType {
+        match self {
+            Frame::PushData(_) => MessageType::PushData,
+            Frame::PushAck(_) => MessageType::PushAck,
+            Frame::PullData(_) => MessageType::PullData,
+            Frame::PullResp(_) => MessageType::PullResp,
+            Frame::PullAck(_) => MessageType::PullAck,
+            Frame::TxAck(_) => MessageType::TxAck,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_from_bytes() {
+        assert_eq!(MessageType::try_from(0x04).unwrap(), MessageType::PullAck);
+        assert!(MessageType::try_from(0x06).is_err());
+
+        match Frame::from_bytes(&[0x02, 0x01, 0x02, 0x01]).unwrap() {
+            Frame::PushAck(v) => assert_eq!(v.random_token, 258),
+            _ => panic!("PUSH_ACK expected"),
+        }
+
+        assert!(Frame::from_bytes(&[0x02, 0x01, 0x02]).is_err());
+        assert!(matches!(
+            Frame::from_bytes(&[0x02, 0x01, 0x02, 0x02]),
+            Err(Error::InvalidLength {
+                expected: 12,
+                got: 4
+            })
+        ));
+
+        let b = [0x02, 0x01, 0x02, 0x02, 1, 2, 3, 4, 5, 6, 7, 8];
+        let frame = Frame::from_bytes(&b).unwrap();
+        assert_eq!(frame.message_type(), MessageType::PullData);
+        assert_eq!(frame.to_bytes(), b.to_vec());
+    }
+}
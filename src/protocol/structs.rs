@@ -0,0 +1,1998 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use base64::{engine::general_purpose, Engine as _};
+use chrono::{DateTime, Utc};
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+
+use chirpstack_api::gw;
+
+use super::error::Error as ProtocolError;
+
+type Result<T, E = ProtocolError> = std::result::Result<T, E>;
+
+const PROTOCOL_VERSION: u8 = 0x02;
+
+// Fields of the stat object, which can not be set as custom field.
+const STAT_FIELDS: [&str; 15] = [
+    "time", "lati", "long", "alti", "rxnb", "rxok", "rxfw", "ackr", "dwnb", "txnb", "temp", "pfrm",
+    "mail", "desc", "bridge",
+];
+
+// Validates the header of the frame and returns the random token.
+fn read_header(b: &[u8], min: usize, identifier: u8) -> Result<u16> {
+    if b.len() < min {
+        return Err(ProtocolError::TooShort { min, got: b.len() });
+    }
+
+    if b[0] != PROTOCOL_VERSION {
+        return Err(ProtocolError::ProtocolVersion {
+            expected: PROTOCOL_VERSION,
+            got: b[0],
+        });
+    }
+
+    if b[3] != identifier {
+        return Err(ProtocolError::InvalidIdentifier(b[3]));
+    }
+
+    let mut rt: [u8; 2] = [0; 2];
+    rt.copy_from_slice(&b[1..3]);
+    Ok(u16::from_be_bytes(rt))
+}
+
+// Reads the gateway ID, following the header.
+fn read_gateway_id(b: &[u8]) -> [u8; 8] {
+    let mut gateway_id: [u8; 8] = [0; 8];
+    gateway_id.copy_from_slice(&b[4..12]);
+    gateway_id
+}
+
+// Returns the JSON payload, without the (optional) NUL termination.
+fn read_payload(b: &[u8]) -> &[u8] {
+    let mut end = b.len();
+    while end > 0 && b[end - 1] == 0 {
+        end -= 1;
+    }
+    &b[..end]
+}
+
+// Encodes the header of the frame, followed by the (optional) gateway ID.
+fn write_header(random_token: u16, identifier: u8, gateway_id: Option<&[u8; 8]>) -> Vec<u8> {
+    let mut b = Vec::new();
+    b.push(PROTOCOL_VERSION);
+    b.extend_from_slice(&random_token.to_be_bytes());
+    b.push(identifier);
+    if let Some(v) = gateway_id {
+        b.extend_from_slice(v);
+    }
+    b
+}
+
+pub enum Crc {
+    Ok,
+    Invalid,
+    Missing,
+}
+
+impl Serialize for Crc {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            Crc::Ok => serializer.serialize_i32(1),
+            Crc::Invalid => serializer.serialize_i32(-1),
+            Crc::Missing => serializer.serialize_i32(0),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Crc {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match i32::deserialize(deserializer)? {
+            1 => Ok(Crc::Ok),
+            -1 => Ok(Crc::Invalid),
+            0 => Ok(Crc::Missing),
+            _ => Err(D::Error::custom("unexpected value")),
+        }
+    }
+}
+
+pub enum Modulation {
+    Lora,
+    Fsk,
+    LrFhss,
+}
+
+impl Serialize for Modulation {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            Modulation::Lora => serializer.serialize_str("LORA"),
+            Modulation::Fsk => serializer.serialize_str("FSK"),
+            Modulation::LrFhss => serializer.serialize_str("LR-FHSS"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Modulation {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        match s.as_str() {
+            "LORA" => Ok(Modulation::Lora),
+            "FSK" => Ok(Modulation::Fsk),
+            "LR-FHSS" => Ok(Modulation::LrFhss),
+            _ => Err(D::Error::custom("unexpected value"))?,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataRate {
+    Lora(u32, u32), // SF and BW (kHz)
+    Fsk(u32),       // bitrate
+    LrFhss(u32),    // operating channel width (Hz)
+    Index(u32),     // LoRaWAN data-rate index, see data_rate_to_index
+}
+
+impl Serialize for DataRate {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            DataRate::Lora(sf, bw) => serializer.serialize_str(&format!("SF{}BW{}", sf, bw / 1000)),
+            DataRate::Fsk(bitrate) => serializer.serialize_u32(*bitrate),
+            DataRate::LrFhss(ocw) => serializer.serialize_str(&format!("M0CW{}", ocw / 1000)),
+            DataRate::Index(dr) => serializer.serialize_u32(*dr),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for DataRate {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match Value::deserialize(deserializer)? {
+            Value::String(v) if v.starts_with("M0CW") => match v[4..].parse::<u32>() {
+                Ok(ocw) => Ok(DataRate::LrFhss(ocw * 1000)),
+                Err(err) => Err(D::Error::custom(format!("parse ocw error: {}", err))),
+            },
+            Value::String(v) => {
+                let s: Vec<&str> = v.split(char::is_alphabetic).collect();
+                if s.len() != 5 {
+                    return Err(D::Error::custom("invalid datarate string"));
+                }
+
+                let sf: u32 = match s[2].parse() {
+                    Ok(v) => v,
+                    Err(err) => {
+                        return Err(D::Error::custom(format!("parse sf error: {}", err)));
+                    }
+                };
+                let bw: u32 = match s[4].parse() {
+                    Ok(v) => v,
+                    Err(err) => {
+                        return Err(D::Error::custom(format!("parse bw error: {}", err)));
+                    }
+                };
+
+                Ok(DataRate::Lora(sf, bw * 1000))
+            }
+            Value::Number(v) => {
+                // let bitrate = u32::deserialize(deserializer)?;
+                let br = v.as_u64().unwrap();
+                Ok(DataRate::Fsk(br as u32))
+            }
+            _ => Err(D::Error::custom("unexpected type")),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum CodeRate {
+    Undefined,
+    LoRa4_5,
+    LoRa4_6,
+    LoRa4_7,
+    LoRa4_8,
+    LrFhss3_8,
+    LrFhss2_6,
+    LrFhss1_4,
+    LrFhss1_6,
+    LrFhss5_6,
+}
+
+impl CodeRate {
+    fn from_proto(cr: gw::CodeRate) -> Self {
+        match cr {
+            gw::CodeRate::Cr45 => CodeRate::LoRa4_5,
+            gw::CodeRate::Cr46 => CodeRate::LoRa4_6,
+            gw::CodeRate::Cr47 => CodeRate::LoRa4_7,
+            gw::CodeRate::Cr48 => CodeRate::LoRa4_8,
+            gw::CodeRate::Cr38 => CodeRate::LrFhss3_8,
+            gw::CodeRate::Cr26 => CodeRate::LrFhss2_6,
+            gw::CodeRate::Cr14 => CodeRate::LrFhss1_4,
+            gw::CodeRate::Cr16 => CodeRate::LrFhss1_6,
+            gw::CodeRate::Cr56 => CodeRate::LrFhss5_6,
+            _ => CodeRate::Undefined,
+        }
+    }
+
+    fn to_proto(self) -> gw::CodeRate {
+        match self {
+            CodeRate::LoRa4_5 => gw::CodeRate::Cr45,
+            CodeRate::LoRa4_6 => gw::CodeRate::Cr46,
+            CodeRate::LoRa4_7 => gw::CodeRate::Cr47,
+            CodeRate::LoRa4_8 => gw::CodeRate::Cr48,
+            CodeRate::LrFhss3_8 => gw::CodeRate::Cr38,
+            CodeRate::LrFhss2_6 => gw::CodeRate::Cr26,
+            CodeRate::LrFhss1_4 => gw::CodeRate::Cr14,
+            CodeRate::LrFhss1_6 => gw::CodeRate::Cr16,
+            CodeRate::LrFhss5_6 => gw::CodeRate::Cr56,
+            CodeRate::Undefined => gw::CodeRate::CrUndefined,
+        }
+    }
+}
+
+impl Serialize for CodeRate {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            CodeRate::LoRa4_5 => serializer.serialize_str("4/5"),
+            CodeRate::LoRa4_6 => serializer.serialize_str("4/6"),
+            CodeRate::LoRa4_7 => serializer.serialize_str("4/7"),
+            CodeRate::LoRa4_8 => serializer.serialize_str("4/8"),
+            CodeRate::LrFhss3_8 => serializer.serialize_str("3/8"),
+            CodeRate::LrFhss2_6 => serializer.serialize_str("2/6"),
+            CodeRate::LrFhss1_4 => serializer.serialize_str("1/4"),
+            CodeRate::LrFhss1_6 => serializer.serialize_str("1/6"),
+            CodeRate::LrFhss5_6 => serializer.serialize_str("5/6"),
+            _ => serializer.serialize_none(),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for CodeRate {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        match s.as_str() {
+            "4/5" => Ok(CodeRate::LoRa4_5),
+            "4/6" => Ok(CodeRate::LoRa4_6),
+            "4/7" => Ok(CodeRate::LoRa4_7),
+            "4/8" => Ok(CodeRate::LoRa4_8),
+            "3/8" => Ok(CodeRate::LrFhss3_8),
+            "2/6" => Ok(CodeRate::LrFhss2_6),
+            "1/4" => Ok(CodeRate::LrFhss1_4),
+            "1/6" => Ok(CodeRate::LrFhss1_6),
+            "5/6" => Ok(CodeRate::LrFhss5_6),
+            _ => Ok(CodeRate::Undefined),
+        }
+    }
+}
+
+pub struct PushData {
+    pub random_token: u16,
+    pub gateway_id: [u8; 8],
+    pub payload: PushDataPayload,
+}
+
+impl PushData {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut b = write_header(self.random_token, 0x00, Some(&self.gateway_id));
+        b.append(&mut serde_json::to_vec(&self.payload).unwrap());
+        b
+    }
+
+    pub fn from_bytes(b: &[u8]) -> Result<Self> {
+        let random_token = read_header(b, 12, 0x00)?;
+
+        Ok(PushData {
+            random_token,
+            gateway_id: read_gateway_id(b),
+            payload: serde_json::from_slice(read_payload(&b[12..]))?,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PushDataPayload {
+    #[serde(default)]
+    pub rxpk: Vec<RxPk>,
+    pub stat: Option<Stat>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RxPk {
+    /// UTC time of pkt RX, us precision, ISO 8601 'compact' format
+    #[serde(with = "compact_time_format")]
+    pub time: DateTime<Utc>,
+    /// GPS time of pkt RX, number of milliseconds since 06.Jan.1980
+    pub tmms: Option<u64>,
+    /// Internal timestamp of "RX finished" event (32b unsigned)
+    pub tmst: u32,
+    /// RX central frequency in MHz (unsigned float, Hz precision)
+    pub freq: f64,
+    /// Concentrator "IF" channel used for RX (unsigned integer)
+    pub chan: u32,
+    /// Concentrator "RF chain" used for RX (unsigned integer)
+    pub rfch: u32,
+    /// Crc status: 1 = OK, -1 = fail, 0 = no Crc
+    pub stat: Crc,
+    /// Modulation identifier "LORA" or "FSK"
+    pub modu: Modulation,
+    /// LoRa datarate identifier (eg. SF12BW500)}
+    pub datr: DataRate,
+    /// LoRa coding rate.
+    pub codr: Option<CodeRate>,
+    /// LR-FHSS hopping grid number of steps.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hpw: Option<u32>,
+    /// RSSI in dBm (signed integer, 1 dB precision). Not set in the v2
+    /// format, see rsig.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rssi: Option<i32>,
+    /// Channel RSSI in dBm (signed integer, 1 dB precision). Not set in the
+    /// v2 format, see rsig.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rssic: Option<i32>,
+    /// Signal RSSI in dBm (signed integer, 1 dB precision).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rssis: Option<i32>,
+    /// Lora SNR ratio in dB (signed float, 0.1 dB precision). Not set in the
+    /// v2 format, see rsig.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lsnr: Option<f32>,
+    /// Min. Lora SNR ratio in dB during the reception (signed float, 0.1 dB
+    /// precision). Not set in the v2 format, see rsig.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lsnr_min: Option<f32>,
+    /// Max. Lora SNR ratio in dB during the reception (signed float, 0.1 dB
+    /// precision). Not set in the v2 format, see rsig.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lsnr_max: Option<f32>,
+    /// Fine timestamp, number of nanoseconds since the last PPS.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ftime: Option<u32>,
+    /// Frequency offset in Hz. This is not provided by the Concentratord, but
+    /// is sent by some packet-forwarders.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub foff: Option<i32>,
+    /// Signal information per antenna (v2 format).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rsig: Option<Vec<RSig>>,
+    /// Additional key / value metadata, e.g. the channel name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<BTreeMap<String, String>>,
+    /// RF packet payload size in bytes (unsigned integer).
+    pub size: u8,
+    /// Base64 encoded RF packet payload, padded.
+    pub data: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RSig {
+    /// Antenna number on which the signal has been received.
+    pub ant: u32,
+    /// Concentrator "IF" channel used for RX (unsigned integer).
+    pub chan: u32,
+    /// RSSI of the channel in dBm (signed integer, 1 dB precision).
+    pub rssic: i32,
+    /// Signal RSSI in dBm (signed integer, 1 dB precision).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rssis: Option<i32>,
+    /// Lora SNR ratio in dB (signed float, 0.1 dB precision).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lsnr: Option<f32>,
+    /// Min. Lora SNR ratio in dB during the reception.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lsnr_min: Option<f32>,
+    /// Max. Lora SNR ratio in dB during the reception.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lsnr_max: Option<f32>,
+    /// Fine timestamp, number of nanoseconds since the last PPS.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ftime: Option<u32>,
+}
+
+impl RxPk {
+    // Sets the channel RSSI (rssic), signal RSSI (rssis) and min. / max. SNR
+    // (lsnr_min, lsnr_max), when provided by the concentrator in the uplink
+    // metadata (using the same keys). These are not derived from the rssi and
+    // lsnr, which the Concentratord always reports.
+    pub fn set_signal_metrics(&mut self, metadata: &HashMap<String, String>) {
+        let get = |k: &str| metadata.get(k).and_then(|v| v.trim().parse::<f32>().ok());
+
+        self.rssic = get("rssic").map(|v| v.round() as i32);
+        self.rssis = get("rssis").map(|v| v.round() as i32);
+        self.lsnr_min = get("lsnr_min");
+        self.lsnr_max = get("lsnr_max");
+    }
+
+    // Corrects the RSSI by the offset (dB), e.g. for the gain of the
+    // front-end of the RF chain.
+    pub fn apply_rssi_offset(&mut self, offset: i32) {
+        if let Some(v) = self.rssi.as_mut() {
+            *v += offset;
+        }
+        if let Some(v) = self.rssic.as_mut() {
+            *v += offset;
+        }
+        if let Some(v) = self.rssis.as_mut() {
+            *v += offset;
+        }
+    }
+
+    // Replaces the LoRa datr by the LoRaWAN data-rate index of the region,
+    // for servers expecting the numeric datr.
+    pub fn set_data_rate_index(&mut self, region: &str) {
+        if let Some(dr) = super::data_rate_to_index(region, &self.datr) {
+            self.datr = DataRate::Index(dr);
+        }
+    }
+
+    pub fn set_meta(&mut self, key: &str, value: &str) {
+        self.meta
+            .get_or_insert_with(BTreeMap::new)
+            .insert(key.to_string(), value.to_string());
+    }
+
+    // Converts the rxpk to the v2 format, in which the signal information is
+    // reported per antenna (rsig) instead of by the rssi, rssic, rssis, lsnr
+    // (lsnr_min, lsnr_max) and ftime fields.
+    pub fn to_v2(&mut self, ant: u32) {
+        let rssi = self.rssi.take();
+        self.rsig = Some(vec![RSig {
+            ant,
+            chan: self.chan,
+            rssic: self.rssic.take().or(rssi).unwrap_or_default(),
+            rssis: self.rssis.take(),
+            lsnr: self.lsnr.take(),
+            lsnr_min: self.lsnr_min.take(),
+            lsnr_max: self.lsnr_max.take(),
+            ftime: self.ftime.take(),
+        }]);
+    }
+
+    pub fn from_proto(up: &chirpstack_api::gw::UplinkFrame) -> Result<Self> {
+        let rx_info = match &up.rx_info {
+            Some(v) => v,
+            None => {
+                return Err(ProtocolError::MissingField("rx_info"));
+            }
+        };
+
+        let tx_info = match &up.tx_info {
+            Some(v) => v,
+            None => {
+                return Err(ProtocolError::MissingField("tx_info"));
+            }
+        };
+
+        Ok(RxPk {
+            time: match &rx_info.time {
+                Some(v) => match TryInto::<SystemTime>::try_into(v.clone()) {
+                    Ok(v) => v.into(),
+                    Err(_) => Utc::now(),
+                },
+                None => Utc::now(),
+            },
+            tmms: rx_info
+                .time_since_gps_epoch
+                .as_ref()
+                .and_then(duration_to_ms),
+            tmst: {
+                let mut bytes: [u8; 4] = [0; 4];
+                bytes.copy_from_slice(&rx_info.context);
+                u32::from_be_bytes(bytes)
+            },
+            freq: tx_info.frequency as f64 / 1000000.0,
+            chan: rx_info.channel,
+            rfch: rx_info.rf_chain,
+            stat: match rx_info.crc_status() {
+                gw::CrcStatus::CrcOk => Crc::Ok,
+                gw::CrcStatus::BadCrc => Crc::Invalid,
+                gw::CrcStatus::NoCrc => Crc::Missing,
+            },
+            modu: match &tx_info.modulation {
+                Some(v) => match &v.parameters {
+                    Some(v) => match &v {
+                        gw::modulation::Parameters::Lora(_) => Modulation::Lora,
+                        gw::modulation::Parameters::Fsk(_) => Modulation::Fsk,
+                        gw::modulation::Parameters::LrFhss(_) => Modulation::LrFhss,
+                    },
+                    None => {
+                        return Err(ProtocolError::MissingField("parameters"));
+                    }
+                },
+                None => {
+                    return Err(ProtocolError::MissingField("modulation_info"));
+                }
+            },
+            datr: match &tx_info.modulation {
+                Some(v) => match &v.parameters {
+                    Some(v) => match &v {
+                        gw::modulation::Parameters::Lora(v) => {
+                            DataRate::Lora(v.spreading_factor, v.bandwidth)
+                        }
+                        gw::modulation::Parameters::Fsk(v) => DataRate::Fsk(v.datarate),
+                        gw::modulation::Parameters::LrFhss(v) => {
+                            DataRate::LrFhss(v.operating_channel_width)
+                        }
+                    },
+                    None => {
+                        return Err(ProtocolError::MissingField("parameters"));
+                    }
+                },
+                None => {
+                    return Err(ProtocolError::MissingField("modulation_info"));
+                }
+            },
+            codr: match &tx_info.modulation {
+                Some(v) => match &v.parameters {
+                    Some(gw::modulation::Parameters::Lora(v)) => {
+                        Some(CodeRate::from_proto(v.code_rate()))
+                    }
+                    Some(gw::modulation::Parameters::LrFhss(v)) => {
+                        Some(CodeRate::from_proto(v.code_rate()))
+                    }
+                    _ => None,
+                },
+                None => None,
+            },
+            hpw: match &tx_info.modulation {
+                Some(v) => match &v.parameters {
+                    Some(gw::modulation::Parameters::LrFhss(v)) => Some(v.grid_steps),
+                    _ => None,
+                },
+                None => None,
+            },
+            rssi: Some(rx_info.rssi),
+            rssic: None,
+            rssis: None,
+            lsnr: match &tx_info.modulation {
+                Some(v) => match &v.parameters {
+                    Some(gw::modulation::Parameters::Lora(_))
+                    | Some(gw::modulation::Parameters::LrFhss(_)) => Some(rx_info.snr),
+                    _ => None,
+                },
+                None => None,
+            },
+            lsnr_min: None,
+            lsnr_max: None,
+            ftime: rx_info
+                .fine_time_since_gps_epoch
+                .as_ref()
+                .map(|v| v.nanos as u32),
+            foff: None,
+            rsig: None,
+            meta: None,
+            size: up.phy_payload.len() as u8,
+            data: general_purpose::STANDARD.encode(up.phy_payload.clone()),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Stat {
+    /// UTC 'system' time of the gateway, ISO 8601 'expanded' format.
+    #[serde(with = "expanded_time_format")]
+    pub time: DateTime<Utc>,
+    /// GPS latitude of the gateway in degree (float, N is +).
+    #[serde(default)]
+    pub lati: f64,
+    /// GPS latitude of the gateway in degree (float, E is +).
+    #[serde(default)]
+    pub long: f64,
+    /// GPS altitude of the gateway in meter RX (integer).
+    #[serde(default)]
+    pub alti: u32,
+    /// Number of radio packets received (unsigned integer).
+    pub rxnb: u32,
+    /// Number of radio packets received with a valid PHY Crc.
+    pub rxok: u32,
+    /// Number of radio packets forwarded (unsigned integer).
+    pub rxfw: u32,
+    /// Percentage of upstream datagrams that were acknowledged.
+    pub ackr: f32,
+    /// Number of downlink datagrams received (unsigned integer).
+    pub dwnb: u32,
+    /// Number of packets emitted (unsigned integer).
+    pub txnb: u32,
+    /// Gateway temperature in degree Celsius (float).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temp: Option<f32>,
+    /// Platform definition (string).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pfrm: Option<String>,
+    /// Email of the gateway operator (string).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mail: Option<String>,
+    /// Public description of the gateway (string).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub desc: Option<String>,
+    /// Forwarder specific stats (extension, not part of the protocol).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bridge: Option<BridgeStat>,
+    /// Custom key / value pairs, added to the stat object.
+    #[serde(flatten)]
+    pub custom: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+pub struct BridgeStat {
+    /// Average PUSH_DATA to PUSH_ACK round-trip time in ms.
+    pub ack_rtt_ms: f32,
+    /// Number of PUSH_DATA retransmissions.
+    pub retransmits: u32,
+    /// Number of uplinks dropped as duplicate (replay window).
+    pub dedup_hits: u32,
+    /// Number of uplinks dropped by the filters.
+    pub filtered: u32,
+}
+
+impl Stat {
+    pub fn from_proto(stats: &chirpstack_api::gw::GatewayStats) -> Result<Self> {
+        Ok(Stat {
+            time: match &stats.time {
+                Some(v) => match TryInto::<SystemTime>::try_into(v.clone()) {
+                    Ok(v) => v.into(),
+                    Err(_) => Utc::now(),
+                },
+                None => Utc::now(),
+            },
+            lati: match &stats.location {
+                Some(v) => v.latitude,
+                None => 0.0,
+            },
+            long: match &stats.location {
+                Some(v) => v.longitude,
+                None => 0.0,
+            },
+            alti: match &stats.location {
+                Some(v) => v.altitude as u32,
+                None => 0,
+            },
+            rxnb: stats.rx_packets_received,
+            rxok: stats.rx_packets_received_ok,
+            rxfw: 0,
+            ackr: 0.0,
+            dwnb: stats.tx_packets_received,
+            txnb: stats.tx_packets_emitted,
+            temp: None,
+            pfrm: None,
+            mail: None,
+            desc: None,
+            bridge: None,
+            custom: BTreeMap::new(),
+        })
+    }
+
+    // Sets a custom field, the fields of the stat object can not be
+    // overwritten.
+    pub fn set_custom(&mut self, key: &str, value: &str) {
+        if !STAT_FIELDS.contains(&key) {
+            self.custom.insert(key.to_string(), value.to_string());
+        }
+    }
+
+    // Adds the GatewayStats metadata as custom fields, a temperature value
+    // is reported as temp.
+    pub fn set_metadata(&mut self, metadata: &HashMap<String, String>) {
+        for (k, v) in metadata {
+            match (k.as_str(), v.parse()) {
+                ("temperature", Ok(temp)) => self.temp = Some(temp),
+                _ => self.set_custom(k, v),
+            }
+        }
+    }
+}
+
+pub struct PushAck {
+    pub random_token: u16,
+}
+
+impl PushAck {
+    pub fn from_bytes(b: &[u8]) -> Result<Self> {
+        if b.len() != 4 {
+            return Err(ProtocolError::InvalidLength {
+                expected: 4,
+                got: b.len(),
+            });
+        }
+
+        Ok(PushAck {
+            random_token: read_header(b, 4, 0x01)?,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        write_header(self.random_token, 0x01, None)
+    }
+}
+
+pub struct PullData {
+    pub random_token: u16,
+    pub gateway_id: [u8; 8],
+}
+
+impl PullData {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        write_header(self.random_token, 0x02, Some(&self.gateway_id))
+    }
+
+    pub fn from_bytes(b: &[u8]) -> Result<Self> {
+        if b.len() != 12 {
+            return Err(ProtocolError::InvalidLength {
+                expected: 12,
+                got: b.len(),
+            });
+        }
+
+        Ok(PullData {
+            random_token: read_header(b, 12, 0x02)?,
+            gateway_id: read_gateway_id(b),
+        })
+    }
+}
+
+pub struct PullAck {
+    pub random_token: u16,
+}
+
+impl PullAck {
+    pub fn from_bytes(b: &[u8]) -> Result<Self> {
+        if b.len() != 4 {
+            return Err(ProtocolError::InvalidLength {
+                expected: 4,
+                got: b.len(),
+            });
+        }
+
+        Ok(PullAck {
+            random_token: read_header(b, 4, 0x04)?,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        write_header(self.random_token, 0x04, None)
+    }
+}
+
+pub struct PullResp {
+    pub random_token: u16,
+    pub payload: PullRespPayload,
+}
+
+impl PullResp {
+    pub fn from_bytes(b: &[u8]) -> Result<Self> {
+        let random_token = read_header(b, 5, 0x03)?;
+
+        Ok(PullResp {
+            random_token,
+            payload: serde_json::from_slice(&b[4..])?,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut b = write_header(self.random_token, 0x03, None);
+        b.append(&mut serde_json::to_vec(&self.payload).unwrap());
+        b
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PullRespPayload {
+    pub txpk: TxPk,
+}
+
+// Optional fields are omitted when not set, as packet-forwarders use the
+// presence of e.g. tmst to select the timing.
+#[derive(Serialize, Deserialize)]
+pub struct TxPk {
+    /// Send packet immediately (will ignore tmst & time).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub imme: Option<bool>,
+    /// Send packet on a certain timestamp value (will ignore time).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tmst: Option<u32>,
+    /// Send packet at a certain GPS time (GPS synchronization required).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tmms: Option<u64>,
+    /// TX central frequency in MHz (unsigned float, Hz precision).
+    pub freq: f64,
+    /// Concentrator "RF chain" used for TX (unsigned integer).
+    pub rfch: u8,
+    /// TX output power in dBm (unsigned integer, dBm precision).
+    pub powe: u8,
+    /// Modulation identifier "LORA" or "FSK".
+    pub modu: Modulation,
+    /// LoRa datarate identifier (eg. SF12BW500).
+    pub datr: DataRate,
+    /// LoRa ECC coding rate identifier.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub codr: Option<CodeRate>,
+    /// LR-FHSS hopping grid number of steps.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hpw: Option<u32>,
+    /// FSK frequency deviation (unsigned integer, in Hz) .
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fdev: Option<u32>,
+    /// Lora modulation polarization inversion.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipol: Option<bool>,
+    /// RF preamble size (unsigned integer).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prea: Option<u8>,
+    /// RF packet payload size in bytes (unsigned integer).
+    pub size: u8,
+    /// Base64 encoded RF packet payload, padding optional.
+    pub data: String,
+    /// If true, disable the Crc of the physical layer (optional).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ncrc: Option<bool>,
+}
+
+impl TxPk {
+    // Returns true for a Class B beacon, a LoRa broadcast timed by GPS time
+    // without polarization inversion (or without CRC, when ipol is omitted).
+    pub fn is_beacon(&self) -> bool {
+        self.tmms.is_some()
+            && self.tmst.is_none()
+            && !self.imme.unwrap_or(false)
+            && matches!(self.modu, Modulation::Lora)
+            && match self.ipol {
+                Some(ipol) => !ipol,
+                None => self.ncrc.unwrap_or(false),
+            }
+    }
+
+    // Some servers send the LoRa datr as LoRaWAN data-rate index instead of
+    // "SFxxBWyyy", which is decoded as FSK bitrate. This converts the index
+    // into the LoRa data-rate of the region.
+    pub fn resolve_data_rate_index(&mut self, region: &str) -> Result<()> {
+        if let (Modulation::Lora, DataRate::Fsk(dr)) = (&self.modu, self.datr) {
+            self.datr = super::data_rate_from_index(region, dr)
+                .ok_or(ProtocolError::InvalidDataRateIndex(dr))?;
+        }
+        Ok(())
+    }
+
+    pub fn to_proto(
+        &self,
+        downlink_id: u32,
+        gateway_id: Vec<u8>,
+    ) -> Result<chirpstack_api::gw::DownlinkFrame> {
+        let tx_info = chirpstack_api::gw::DownlinkTxInfo {
+            frequency: (self.freq * 1_000_000.0) as u32,
+            power: self.powe as i32,
+            modulation: Some(gw::Modulation {
+                parameters: Some(match self.modu {
+                    Modulation::Lora => match self.datr {
+                        DataRate::Lora(sf, bw) => {
+                            gw::modulation::Parameters::Lora(gw::LoraModulationInfo {
+                                bandwidth: bw,
+                                spreading_factor: sf,
+                                code_rate: self
+                                    .codr
+                                    .unwrap_or(CodeRate::Undefined)
+                                    .to_proto()
+                                    .into(),
+                                // Beacons are sent without polarization
+                                // inversion.
+                                polarization_inversion: self.ipol.unwrap_or(!self.is_beacon()),
+                                ..Default::default()
+                            })
+                        }
+                        _ => {
+                            return Err(ProtocolError::DataRateMismatch("LoRa"));
+                        }
+                    },
+                    Modulation::Fsk => match self.datr {
+                        DataRate::Fsk(v) => {
+                            gw::modulation::Parameters::Fsk(gw::FskModulationInfo {
+                                datarate: v,
+                                frequency_deviation: self.fdev.unwrap_or(0),
+                            })
+                        }
+                        _ => {
+                            return Err(ProtocolError::DataRateMismatch("FSK"));
+                        }
+                    },
+                    Modulation::LrFhss => match self.datr {
+                        DataRate::LrFhss(v) => {
+                            gw::modulation::Parameters::LrFhss(gw::LrFhssModulationInfo {
+                                operating_channel_width: v,
+                                code_rate: self
+                                    .codr
+                                    .unwrap_or(CodeRate::Undefined)
+                                    .to_proto()
+                                    .into(),
+                                grid_steps: self.hpw.unwrap_or(0),
+                                ..Default::default()
+                            })
+                        }
+                        _ => {
+                            return Err(ProtocolError::DataRateMismatch("LR-FHSS"));
+                        }
+                    },
+                }),
+            }),
+            board: 0,
+            antenna: 0,
+            timing: Some(gw::Timing {
+                parameters: Some(if self.imme.unwrap_or(false) {
+                    gw::timing::Parameters::Immediately(gw::ImmediatelyTimingInfo {})
+                } else if self.tmst.is_some() {
+                    gw::timing::Parameters::Delay(gw::DelayTimingInfo {
+                        delay: Some(prost_types::Duration {
+                            // This is correct! The delay is already added to the tmst which is
+                            // used to set the context.
+                            seconds: 0,
+                            nanos: 0,
+                        }),
+                    })
+                } else if let Some(v) = self.tmms {
+                    gw::timing::Parameters::GpsEpoch(gw::GpsEpochTimingInfo {
+                        time_since_gps_epoch: Some(
+                            Duration::from_millis(v)
+                                .try_into()
+                                .map_err(|e| ProtocolError::InvalidGpsTime(format!("{:?}", e)))?,
+                        ),
+                    })
+                } else {
+                    return Err(ProtocolError::MissingTiming);
+                }),
+            }),
+            context: self
+                .tmst
+                .map(|v| v.to_be_bytes().to_vec())
+                .unwrap_or_default(),
+        };
+
+        Ok(chirpstack_api::gw::DownlinkFrame {
+            downlink_id,
+            gateway_id: hex::encode(gateway_id),
+            items: vec![chirpstack_api::gw::DownlinkFrameItem {
+                tx_info: Some(tx_info),
+                phy_payload: general_purpose::STANDARD.decode(&self.data)?,
+                ..Default::default()
+            }],
+            ..Default::default()
+        })
+    }
+}
+
+pub struct TxAck {
+    pub random_token: u16,
+    pub gateway_id: [u8; 8],
+    pub payload: TxAckPayload,
+}
+
+impl TxAck {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut b = write_header(self.random_token, 0x05, Some(&self.gateway_id));
+        b.append(&mut serde_json::to_vec(&self.payload).unwrap());
+        b
+    }
+
+    pub fn from_bytes(b: &[u8]) -> Result<Self> {
+        let random_token = read_header(b, 12, 0x05)?;
+        let payload = read_payload(&b[12..]);
+
+        Ok(TxAck {
+            random_token,
+            gateway_id: read_gateway_id(b),
+            // Some packet-forwarders omit the payload on success.
+            payload: if payload.is_empty() {
+                TxAckPayload {
+                    txpk_ack: TxAckPayloadError {
+                        error: TxAckError::None,
+                        warn: None,
+                        value: None,
+                    },
+                }
+            } else {
+                serde_json::from_slice(payload)?
+            },
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TxAckPayload {
+    pub txpk_ack: TxAckPayloadError,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TxAckPayloadError {
+    #[serde(default = "default_tx_ack_error")]
+    pub error: TxAckError,
+    /// Warning in case the packet was emitted with altered parameters, e.g.
+    /// TX_POWER when the power was adjusted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warn: Option<TxAckError>,
+    /// Value of the altered parameter, e.g. the emitted TX power.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<i32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxAckError {
+    None,
+    Ignored,
+    TooLate,
+    TooEarly,
+    CollisionPacket,
+    CollisionBeacon,
+    TxFreq,
+    TxPower,
+    GpsUnlocked,
+    QueueFull,
+    InternalError,
+}
+
+fn default_tx_ack_error() -> TxAckError {
+    TxAckError::None
+}
+
+impl TxAckError {
+    pub fn from_proto(status: gw::TxAckStatus) -> Self {
+        match status {
+            gw::TxAckStatus::Ok => TxAckError::None,
+            gw::TxAckStatus::Ignored => TxAckError::Ignored,
+            gw::TxAckStatus::TooLate => TxAckError::TooLate,
+            gw::TxAckStatus::TooEarly => TxAckError::TooEarly,
+            gw::TxAckStatus::CollisionPacket => TxAckError::CollisionPacket,
+            gw::TxAckStatus::CollisionBeacon => TxAckError::CollisionBeacon,
+            gw::TxAckStatus::TxFreq => TxAckError::TxFreq,
+            gw::TxAckStatus::TxPower => TxAckError::TxPower,
+            gw::TxAckStatus::GpsUnlocked => TxAckError::GpsUnlocked,
+            gw::TxAckStatus::QueueFull => TxAckError::QueueFull,
+            gw::TxAckStatus::InternalError => TxAckError::InternalError,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TxAckError::None => "NONE",
+            TxAckError::Ignored => "IGNORED",
+            TxAckError::TooLate => "TOO_LATE",
+            TxAckError::TooEarly => "TOO_EARLY",
+            TxAckError::CollisionPacket => "COLLISION_PACKET",
+            TxAckError::CollisionBeacon => "COLLISION_BEACON",
+            TxAckError::TxFreq => "TX_FREQ",
+            TxAckError::TxPower => "TX_POWER",
+            TxAckError::GpsUnlocked => "GPS_UNLOCKED",
+            TxAckError::QueueFull => "QUEUE_FULL",
+            TxAckError::InternalError => "INTERNAL_ERROR",
+        }
+    }
+}
+
+impl Serialize for TxAckError {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for TxAckError {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        match s.as_str() {
+            // Some forwarders send an empty string on success.
+            "" | "NONE" => Ok(TxAckError::None),
+            "IGNORED" => Ok(TxAckError::Ignored),
+            "TOO_LATE" => Ok(TxAckError::TooLate),
+            "TOO_EARLY" => Ok(TxAckError::TooEarly),
+            "COLLISION_PACKET" => Ok(TxAckError::CollisionPacket),
+            "COLLISION_BEACON" => Ok(TxAckError::CollisionBeacon),
+            "TX_FREQ" => Ok(TxAckError::TxFreq),
+            "TX_POWER" => Ok(TxAckError::TxPower),
+            "GPS_UNLOCKED" => Ok(TxAckError::GpsUnlocked),
+            "QUEUE_FULL" => Ok(TxAckError::QueueFull),
+            "INTERNAL_ERROR" => Ok(TxAckError::InternalError),
+            _ => Err(D::Error::custom(format!("unexpected value: {}", s))),
+        }
+    }
+}
+
+// Converts the duration to milliseconds, without going through a float
+// (which would lose precision). Negative durations are invalid.
+fn duration_to_ms(d: &prost_types::Duration) -> Option<u64> {
+    if d.seconds < 0 || d.nanos < 0 {
+        return None;
+    }
+
+    (d.seconds as u64)
+        .checked_mul(1000)?
+        .checked_add(d.nanos as u64 / 1_000_000)
+}
+
+// see: https://serde.rs/custom-date-format.html
+mod expanded_time_format {
+    use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+    use serde::de::Error;
+    use serde::{self, Deserialize, Deserializer, Serializer};
+
+    const FORMAT: &str = "%Y-%m-%d %H:%M:%S %Z";
+
+    pub fn serialize<S>(date: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let s = format!("{}", date.format(FORMAT));
+        serializer.serialize_str(&s)
+    }
+
+    // The timezone is either UTC or GMT, which chrono can not parse.
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        let (datetime, tz) = s
+            .rsplit_once(' ')
+            .ok_or_else(|| D::Error::custom(format!("invalid time: {}", s)))?;
+        if tz != "UTC" && tz != "GMT" {
+            return Err(D::Error::custom(format!("unexpected timezone: {}", tz)));
+        }
+
+        NaiveDateTime::parse_from_str(datetime, "%Y-%m-%d %H:%M:%S")
+            .map(|v| Utc.from_utc_datetime(&v))
+            .map_err(D::Error::custom)
+    }
+}
+
+mod compact_time_format {
+    use chrono::{DateTime, Utc};
+    use serde::de::Error;
+    use serde::{self, Deserialize, Deserializer, Serializer};
+
+    const FORMAT: &str = "%+";
+
+    pub fn serialize<S>(date: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let s = format!("{}", date.format(FORMAT));
+        serializer.serialize_str(&s)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        DateTime::parse_from_rfc3339(&s)
+            .map(|v| v.with_timezone(&Utc))
+            .map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str;
+    use std::time::{Duration, SystemTime};
+
+    use chirpstack_api::{common, gw};
+
+    #[test]
+    fn test_push_data_rxpk_lora() {
+        let rx_info = gw::UplinkRxInfo {
+            gateway_id: "0102030405060708".into(),
+            time: Some(SystemTime::UNIX_EPOCH.into()),
+            time_since_gps_epoch: Some(Duration::from_secs(1).try_into().unwrap()),
+            rssi: -160,
+            snr: 5.5,
+            board: 2,
+            fine_time_since_gps_epoch: Some(
+                Duration::from_nanos(1_000_000_500).try_into().unwrap(),
+            ),
+            channel: 1,
+            rf_chain: 1,
+            antenna: 3,
+            context: vec![1, 2, 3, 4],
+            crc_status: gw::CrcStatus::CrcOk.into(),
+            ..Default::default()
+        };
+
+        let tx_info = gw::UplinkTxInfo {
+            frequency: 868300000,
+            modulation: Some(gw::Modulation {
+                parameters: Some(gw::modulation::Parameters::Lora(gw::LoraModulationInfo {
+                    bandwidth: 125000,
+                    spreading_factor: 12,
+                    code_rate: gw::CodeRate::Cr45.into(),
+                    polarization_inversion: true,
+                    ..Default::default()
+                })),
+            }),
+        };
+
+        let uf = gw::UplinkFrame {
+            rx_info: Some(rx_info),
+            tx_info: Some(tx_info),
+            phy_payload: vec![1, 2, 3],
+            ..Default::default()
+        };
+
+        let rxpk = RxPk::from_proto(&uf).unwrap();
+        let pd = PushData {
+            random_token: 123,
+            gateway_id: [1, 2, 3, 4, 5, 6, 7, 8],
+            payload: PushDataPayload {
+                rxpk: vec![rxpk],
+                stat: None,
+            },
+        };
+
+        let b = pd.to_bytes();
+        assert_eq!(
+            b[0..12].to_vec(),
+            vec![2, 0, 123, 0, 1, 2, 3, 4, 5, 6, 7, 8]
+        );
+
+        assert_eq!(
+            str::from_utf8(&b[12..]).unwrap(),
+            r#"{"rxpk":[{"time":"1970-01-01T00:00:00+00:00","tmms":1000,"tmst":16909060,"freq":868.3,"chan":1,"rfch":1,"stat":1,"modu":"LORA","datr":"SF12BW125","codr":"4/5","rssi":-160,"lsnr":5.5,"ftime":500,"size":3,"data":"AQID"}],"stat":null}"#
+        );
+    }
+
+    #[test]
+    fn test_push_data_rxpk_fsk() {
+        let rx_info = gw::UplinkRxInfo {
+            gateway_id: "0102030405060708".into(),
+            time: Some(SystemTime::UNIX_EPOCH.into()),
+            time_since_gps_epoch: Some(Duration::from_secs(1).try_into().unwrap()),
+            rssi: -160,
+            channel: 1,
+            rf_chain: 2,
+            board: 3,
+            antenna: 4,
+            context: vec![1, 2, 3, 4],
+            crc_status: gw::CrcStatus::CrcOk.into(),
+            ..Default::default()
+        };
+
+        let tx_info = gw::UplinkTxInfo {
+            frequency: 868300000,
+            modulation: Some(gw::Modulation {
+                parameters: Some(gw::modulation::Parameters::Fsk(gw::FskModulationInfo {
+                    datarate: 50000,
+                    ..Default::default()
+                })),
+            }),
+        };
+
+        let uf = gw::UplinkFrame {
+            rx_info: Some(rx_info),
+            tx_info: Some(tx_info),
+            phy_payload: vec![1, 2, 3],
+            ..Default::default()
+        };
+
+        let rxpk = RxPk::from_proto(&uf).unwrap();
+        let pd = PushData {
+            random_token: 123,
+            gateway_id: [1, 2, 3, 4, 5, 6, 7, 8],
+            payload: PushDataPayload {
+                rxpk: vec![rxpk],
+                stat: None,
+            },
+        };
+
+        let b = pd.to_bytes();
+        assert_eq!(
+            b[0..12].to_vec(),
+            vec![2, 0, 123, 0, 1, 2, 3, 4, 5, 6, 7, 8]
+        );
+
+        assert_eq!(
+            str::from_utf8(&b[12..]).unwrap(),
+            r#"{"rxpk":[{"time":"1970-01-01T00:00:00+00:00","tmms":1000,"tmst":16909060,"freq":868.3,"chan":1,"rfch":2,"stat":1,"modu":"FSK","datr":50000,"codr":null,"rssi":-160,"size":3,"data":"AQID"}],"stat":null}"#
+        );
+    }
+
+    #[test]
+    fn test_push_data_rxpk_lr_fhss() {
+        let rx_info = gw::UplinkRxInfo {
+            gateway_id: "0102030405060708".into(),
+            time: Some(SystemTime::UNIX_EPOCH.into()),
+            rssi: -120,
+            snr: -2.5,
+            channel: 1,
+            rf_chain: 0,
+            context: vec![1, 2, 3, 4],
+            crc_status: gw::CrcStatus::CrcOk.into(),
+            ..Default::default()
+        };
+
+        let tx_info = gw::UplinkTxInfo {
+            frequency: 868300000,
+            modulation: Some(gw::Modulation {
+                parameters: Some(gw::modulation::Parameters::LrFhss(
+                    gw::LrFhssModulationInfo {
+                        operating_channel_width: 137000,
+                        code_rate: gw::CodeRate::Cr26.into(),
+                        grid_steps: 52,
+                        ..Default::default()
+                    },
+                )),
+            }),
+        };
+
+        let uf = gw::UplinkFrame {
+            rx_info: Some(rx_info),
+            tx_info: Some(tx_info),
+            phy_payload: vec![1, 2, 3],
+            ..Default::default()
+        };
+
+        let mut rxpk = RxPk::from_proto(&uf).unwrap();
+        assert_eq!(
+            serde_json::to_string(&rxpk).unwrap(),
+            r#"{"time":"1970-01-01T00:00:00+00:00","tmms":null,"tmst":16909060,"freq":868.3,"chan":1,"rfch":0,"stat":1,"modu":"LR-FHSS","datr":"M0CW137","codr":"2/6","hpw":52,"rssi":-120,"lsnr":-2.5,"size":3,"data":"AQID"}"#
+        );
+
+        // The signal metrics are only set when provided by the concentrator.
+        rxpk.set_signal_metrics(&HashMap::new());
+        assert_eq!((rxpk.rssic, rxpk.rssis), (None, None));
+
+        rxpk.set_signal_metrics(
+            &[
+                ("rssic", "-119.6"),
+                ("rssis", "-124"),
+                ("lsnr_min", "-4.25"),
+                ("lsnr_max", "invalid"),
+            ]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+        );
+        rxpk.apply_rssi_offset(-3);
+        assert_eq!(
+            serde_json::to_string(&rxpk).unwrap(),
+            r#"{"time":"1970-01-01T00:00:00+00:00","tmms":null,"tmst":16909060,"freq":868.3,"chan":1,"rfch":0,"stat":1,"modu":"LR-FHSS","datr":"M0CW137","codr":"2/6","hpw":52,"rssi":-123,"rssic":-123,"rssis":-127,"lsnr":-2.5,"lsnr_min":-4.25,"size":3,"data":"AQID"}"#
+        );
+
+        rxpk.to_v2(1);
+        assert_eq!(
+            serde_json::to_string(&rxpk).unwrap(),
+            r#"{"time":"1970-01-01T00:00:00+00:00","tmms":null,"tmst":16909060,"freq":868.3,"chan":1,"rfch":0,"stat":1,"modu":"LR-FHSS","datr":"M0CW137","codr":"2/6","hpw":52,"rsig":[{"ant":1,"chan":1,"rssic":-123,"rssis":-127,"lsnr":-2.5,"lsnr_min":-4.25}],"size":3,"data":"AQID"}"#
+        );
+        let rxpk: RxPk = serde_json::from_str(&serde_json::to_string(&rxpk).unwrap()).unwrap();
+        assert_eq!(rxpk.rsig.unwrap()[0].rssic, -123);
+
+        let txpk: TxPk = serde_json::from_str(
+            r#"{"imme":true,"freq":868.3,"rfch":0,"powe":14,"modu":"LR-FHSS","datr":"M0CW137","codr":"2/6","hpw":52,"size":3,"data":"AQID"}"#,
+        )
+        .unwrap();
+        let pl = txpk.to_proto(1, vec![1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        assert_eq!(
+            pl.items[0].tx_info.as_ref().unwrap().modulation,
+            Some(gw::Modulation {
+                parameters: Some(gw::modulation::Parameters::LrFhss(
+                    gw::LrFhssModulationInfo {
+                        operating_channel_width: 137000,
+                        code_rate: gw::CodeRate::Cr26.into(),
+                        grid_steps: 52,
+                        ..Default::default()
+                    }
+                )),
+            })
+        );
+    }
+
+    #[test]
+    fn test_push_data_stat() {
+        let gs = gw::GatewayStats {
+            gateway_id: "0102030405060708".into(),
+            time: Some(SystemTime::UNIX_EPOCH.into()),
+            location: Some(common::Location {
+                latitude: 1.123,
+                longitude: 2.123,
+                altitude: 3.123,
+                ..Default::default()
+            }),
+            rx_packets_received: 10,
+            rx_packets_received_ok: 5,
+            tx_packets_received: 14,
+            tx_packets_emitted: 7,
+            metadata: vec![
+                ("temperature".to_string(), "41.5".to_string()),
+                ("rxnb".to_string(), "0".to_string()),
+                ("firmware".to_string(), "6.2.1".to_string()),
+            ]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        };
+
+        let mut stat = Stat::from_proto(&gs).unwrap();
+        stat.pfrm = Some("Wifx L1".into());
+        stat.set_metadata(&gs.metadata);
+        let pd = PushData {
+            random_token: 123,
+            gateway_id: [1, 2, 3, 4, 5, 6, 7, 8],
+            payload: PushDataPayload {
+                rxpk: vec![],
+                stat: Some(stat),
+            },
+        };
+
+        let b = pd.to_bytes();
+        assert_eq!(
+            b[0..12].to_vec(),
+            vec![2, 0, 123, 0, 1, 2, 3, 4, 5, 6, 7, 8]
+        );
+
+        assert_eq!(
+            str::from_utf8(&b[12..]).unwrap(),
+            r#"{"rxpk":[],"stat":{"time":"1970-01-01 00:00:00 UTC","lati":1.123,"long":2.123,"alti":3,"rxnb":10,"rxok":5,"rxfw":0,"ackr":0.0,"dwnb":14,"txnb":7,"temp":41.5,"pfrm":"Wifx L1","firmware":"6.2.1"}}"#
+        );
+    }
+
+    #[test]
+    fn test_push_ack() {
+        let b: [u8; 4] = [2, 0, 123, 1];
+
+        let push_ack = PushAck::from_bytes(&b).unwrap();
+        assert_eq!(push_ack.random_token, 123);
+    }
+
+    #[test]
+    fn test_pull_data() {
+        let pull_data = PullData {
+            random_token: 123,
+            gateway_id: [1, 2, 3, 4, 5, 6, 7, 8],
+        };
+
+        let b = pull_data.to_bytes();
+        assert_eq!(b, [2, 0, 123, 2, 1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn test_pull_ack() {
+        let b: [u8; 4] = [2, 0, 123, 4];
+
+        let pull_ack = PullAck::from_bytes(&b).unwrap();
+        assert_eq!(pull_ack.random_token, 123);
+    }
+
+    #[test]
+    fn test_pull_resp_lora_immediately() {
+        let txpk = r#"{"txpk":{
+            "imme":true,
+            "freq":864.123456,
+            "rfch":0,
+            "powe":14,
+            "modu":"LORA",
+            "datr":"SF11BW125",
+            "codr":"4/6",
+            "ipol":false,
+            "size":32,
+            "data":"H3P3N2i9qc4yt7rK7ldqoeCVJGBybzPY5h1Dd7P7p8s="}}"#;
+        let mut txpk = txpk.as_bytes().to_vec();
+
+        let mut b: Vec<u8> = vec![2, 0, 123, 3];
+        b.append(&mut txpk);
+
+        let pull_resp = PullResp::from_bytes(&b).unwrap();
+
+        assert_eq!(pull_resp.random_token, 123);
+
+        let downlink_frame = pull_resp
+            .payload
+            .txpk
+            .to_proto(0, vec![1, 2, 3, 4, 5, 6, 7, 8])
+            .unwrap();
+
+        let tx_info = gw::DownlinkTxInfo {
+            frequency: 864123456,
+            power: 14,
+            board: 0,
+            antenna: 0,
+            context: vec![],
+            timing: Some(gw::Timing {
+                parameters: Some(gw::timing::Parameters::Immediately(
+                    gw::ImmediatelyTimingInfo {},
+                )),
+            }),
+            modulation: Some(gw::Modulation {
+                parameters: Some(gw::modulation::Parameters::Lora(gw::LoraModulationInfo {
+                    bandwidth: 125000,
+                    spreading_factor: 11,
+                    code_rate: gw::CodeRate::Cr46.into(),
+                    polarization_inversion: false,
+                    ..Default::default()
+                })),
+            }),
+        };
+
+        assert_eq!(
+            downlink_frame,
+            gw::DownlinkFrame {
+                downlink_id: 0,
+                gateway_id: "0102030405060708".into(),
+                items: vec![gw::DownlinkFrameItem {
+                    phy_payload: general_purpose::STANDARD
+                        .decode("H3P3N2i9qc4yt7rK7ldqoeCVJGBybzPY5h1Dd7P7p8s=")
+                        .unwrap(),
+                    tx_info: Some(tx_info),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_pull_resp_lora_delay() {
+        let txpk = r#"{"txpk":{
+            "freq":864.123456,
+            "rfch":0,
+            "powe":14,
+            "modu":"LORA",
+            "datr":"SF11BW125",
+            "codr":"4/5",
+            "ipol":false,
+            "size":32,
+            "tmst": 5000000,
+            "data":"H3P3N2i9qc4yt7rK7ldqoeCVJGBybzPY5h1Dd7P7p8s="}}"#;
+        let mut txpk = txpk.as_bytes().to_vec();
+
+        let mut b: Vec<u8> = vec![2, 0, 123, 3];
+        b.append(&mut txpk);
+
+        let pull_resp = PullResp::from_bytes(&b).unwrap();
+
+        assert_eq!(pull_resp.random_token, 123);
+
+        let downlink_frame = pull_resp
+            .payload
+            .txpk
+            .to_proto(0, vec![1, 2, 3, 4, 5, 6, 7, 8])
+            .unwrap();
+
+        let tx_info = gw::DownlinkTxInfo {
+            frequency: 864123456,
+            power: 14,
+            board: 0,
+            antenna: 0,
+            context: vec![0, 76, 75, 64], // == 5000000
+            timing: Some(gw::Timing {
+                parameters: Some(gw::timing::Parameters::Delay(gw::DelayTimingInfo {
+                    delay: Some(Duration::from_secs(0).try_into().unwrap()),
+                })),
+            }),
+            modulation: Some(gw::Modulation {
+                parameters: Some(gw::modulation::Parameters::Lora(gw::LoraModulationInfo {
+                    bandwidth: 125000,
+                    spreading_factor: 11,
+                    code_rate: gw::CodeRate::Cr45.into(),
+                    polarization_inversion: false,
+                    ..Default::default()
+                })),
+            }),
+        };
+
+        assert_eq!(
+            downlink_frame,
+            gw::DownlinkFrame {
+                downlink_id: 0,
+                gateway_id: "0102030405060708".into(),
+                items: vec![gw::DownlinkFrameItem {
+                    phy_payload: general_purpose::STANDARD
+                        .decode("H3P3N2i9qc4yt7rK7ldqoeCVJGBybzPY5h1Dd7P7p8s=")
+                        .unwrap(),
+                    tx_info: Some(tx_info),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_pull_resp_lora_gps() {
+        let txpk = r#"{"txpk":{
+            "freq":864.123456,
+            "rfch":0,
+            "powe":14,
+            "modu":"LORA",
+            "datr":"SF11BW125",
+            "codr":"4/5",
+            "ipol":false,
+            "size":32,
+            "tmms": 5000000,
+            "data":"H3P3N2i9qc4yt7rK7ldqoeCVJGBybzPY5h1Dd7P7p8s="}}"#;
+        let mut txpk = txpk.as_bytes().to_vec();
+
+        let mut b: Vec<u8> = vec![2, 0, 123, 3];
+        b.append(&mut txpk);
+
+        let pull_resp = PullResp::from_bytes(&b).unwrap();
+
+        assert_eq!(pull_resp.random_token, 123);
+
+        let downlink_frame = pull_resp
+            .payload
+            .txpk
+            .to_proto(0, vec![1, 2, 3, 4, 5, 6, 7, 8])
+            .unwrap();
+
+        let tx_info = gw::DownlinkTxInfo {
+            frequency: 864123456,
+            power: 14,
+            board: 0,
+            antenna: 0,
+            context: vec![],
+            timing: Some(gw::Timing {
+                parameters: Some(gw::timing::Parameters::GpsEpoch(gw::GpsEpochTimingInfo {
+                    time_since_gps_epoch: Some(Duration::from_secs(5000).try_into().unwrap()),
+                })),
+            }),
+            modulation: Some(gw::Modulation {
+                parameters: Some(gw::modulation::Parameters::Lora(gw::LoraModulationInfo {
+                    bandwidth: 125000,
+                    spreading_factor: 11,
+                    code_rate: gw::CodeRate::Cr45.into(),
+                    polarization_inversion: false,
+                    ..Default::default()
+                })),
+            }),
+        };
+
+        assert_eq!(
+            downlink_frame,
+            gw::DownlinkFrame {
+                downlink_id: 0,
+                gateway_id: "0102030405060708".into(),
+                items: vec![gw::DownlinkFrameItem {
+                    phy_payload: general_purpose::STANDARD
+                        .decode("H3P3N2i9qc4yt7rK7ldqoeCVJGBybzPY5h1Dd7P7p8s=")
+                        .unwrap(),
+                    tx_info: Some(tx_info),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_pull_resp_lora_beacon() {
+        let txpk: TxPk = serde_json::from_str(
+            r#"{"tmms":1366934418000,"freq":869.525,"rfch":0,"powe":14,"modu":"LORA","datr":"SF9BW125","codr":"4/5","prea":10,"ncrc":true,"size":17,"data":"AAAAAAAAAAAAAAAAAAAAAAA="}"#,
+        )
+        .unwrap();
+        assert!(txpk.is_beacon());
+
+        let pl = txpk.to_proto(0, vec![1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        let tx_info = pl.items[0].tx_info.as_ref().unwrap();
+        match tx_info
+            .modulation
+            .as_ref()
+            .and_then(|v| v.parameters.as_ref())
+        {
+            Some(gw::modulation::Parameters::Lora(v)) => assert!(!v.polarization_inversion),
+            _ => panic!("LoRa modulation expected"),
+        }
+        assert!(matches!(
+            tx_info.timing.as_ref().and_then(|v| v.parameters.as_ref()),
+            Some(gw::timing::Parameters::GpsEpoch(_))
+        ));
+
+        // Class B ping-slot downlink
+        let txpk: TxPk = serde_json::from_str(
+            r#"{"tmms":1366934418000,"freq":869.525,"rfch":0,"powe":14,"modu":"LORA","datr":"SF9BW125","codr":"4/5","ipol":true,"size":1,"data":"AA=="}"#,
+        )
+        .unwrap();
+        assert!(!txpk.is_beacon());
+    }
+
+    #[test]
+    fn test_pull_resp_fsk_delay() {
+        let txpk = r#"{"txpk":{
+            "freq":861.3,
+            "rfch":0,
+            "powe":12,
+            "modu":"FSK",
+            "datr":50000,
+            "fdev":3000,
+            "size":32,
+            "tmst": 5000000,
+            "data":"H3P3N2i9qc4yt7rK7ldqoeCVJGBybzPY5h1Dd7P7p8s="}}"#;
+        let mut txpk = txpk.as_bytes().to_vec();
+
+        let mut b: Vec<u8> = vec![2, 0, 123, 3];
+        b.append(&mut txpk);
+
+        let pull_resp = PullResp::from_bytes(&b).unwrap();
+
+        assert_eq!(pull_resp.random_token, 123);
+
+        let downlink_frame = pull_resp
+            .payload
+            .txpk
+            .to_proto(0, vec![1, 2, 3, 4, 5, 6, 7, 8])
+            .unwrap();
+
+        let tx_info = gw::DownlinkTxInfo {
+            frequency: 861300000,
+            power: 12,
+            board: 0,
+            antenna: 0,
+            context: vec![0, 76, 75, 64], // == 5000000
+            timing: Some(gw::Timing {
+                parameters: Some(gw::timing::Parameters::Delay(gw::DelayTimingInfo {
+                    delay: Some(Duration::from_secs(0).try_into().unwrap()),
+                })),
+            }),
+            modulation: Some(gw::Modulation {
+                parameters: Some(gw::modulation::Parameters::Fsk(gw::FskModulationInfo {
+                    frequency_deviation: 3000,
+                    datarate: 50000,
+                })),
+            }),
+        };
+
+        assert_eq!(
+            downlink_frame,
+            gw::DownlinkFrame {
+                downlink_id: 0,
+                gateway_id: "0102030405060708".into(),
+                items: vec![gw::DownlinkFrameItem {
+                    phy_payload: general_purpose::STANDARD
+                        .decode("H3P3N2i9qc4yt7rK7ldqoeCVJGBybzPY5h1Dd7P7p8s=")
+                        .unwrap(),
+                    tx_info: Some(tx_info),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_tx_ack() {
+        let tx_ack = TxAck {
+            random_token: 123,
+            gateway_id: [1, 2, 3, 4, 5, 6, 7, 8],
+            payload: TxAckPayload {
+                txpk_ack: TxAckPayloadError {
+                    error: TxAckError::TooLate,
+                    warn: None,
+                    value: None,
+                },
+            },
+        };
+
+        let b = tx_ack.to_bytes();
+        assert_eq!(
+            b[0..12].to_vec(),
+            vec![2, 0, 123, 5, 1, 2, 3, 4, 5, 6, 7, 8],
+        );
+
+        assert_eq!(
+            str::from_utf8(&b[12..]).unwrap(),
+            r#"{"txpk_ack":{"error":"TOO_LATE"}}"#,
+        );
+
+        let tx_ack = TxAck {
+            random_token: 123,
+            gateway_id: [1, 2, 3, 4, 5, 6, 7, 8],
+            payload: TxAckPayload {
+                txpk_ack: TxAckPayloadError {
+                    error: TxAckError::None,
+                    warn: Some(TxAckError::TxPower),
+                    value: Some(20),
+                },
+            },
+        };
+
+        let b = tx_ack.to_bytes();
+        assert_eq!(
+            str::from_utf8(&b[12..]).unwrap(),
+            r#"{"txpk_ack":{"error":"NONE","warn":"TX_POWER","value":20}}"#,
+        );
+    }
+
+    #[test]
+    fn test_from_bytes_errors() {
+        assert!(matches!(
+            PushAck::from_bytes(&[2, 0, 1]),
+            Err(ProtocolError::InvalidLength {
+                expected: 4,
+                got: 3
+            })
+        ));
+        assert!(matches!(
+            PushAck::from_bytes(&[1, 0, 1, 1]),
+            Err(ProtocolError::ProtocolVersion {
+                expected: 2,
+                got: 1
+            })
+        ));
+        assert!(matches!(
+            PullAck::from_bytes(&[2, 0, 1, 1]),
+            Err(ProtocolError::InvalidIdentifier(1))
+        ));
+        assert!(matches!(
+            PullResp::from_bytes(&[2, 0, 1, 3, b'{']),
+            Err(ProtocolError::Json(_))
+        ));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut b = vec![2, 0, 123, 0, 1, 2, 3, 4, 5, 6, 7, 8];
+        b.extend_from_slice(
+            br#"{"stat":{"time":"2014-01-12 08:59:28 GMT","rxnb":2,"rxok":2,"rxfw":2,"ackr":100.0,"dwnb":2,"txnb":2}}"#,
+        );
+        let push_data = PushData::from_bytes(&b).unwrap();
+        assert_eq!(push_data.random_token, 123);
+        assert_eq!(push_data.gateway_id, [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert!(push_data.payload.rxpk.is_empty());
+        let stat = push_data.payload.stat.as_ref().unwrap();
+        assert_eq!(stat.time.timestamp(), 1389517168);
+        assert_eq!(stat.rxnb, 2);
+        assert_eq!(stat.lati, 0.0);
+
+        let pull_data = PullData {
+            random_token: 123,
+            gateway_id: [1, 2, 3, 4, 5, 6, 7, 8],
+        };
+        let pull_data = PullData::from_bytes(&pull_data.to_bytes()).unwrap();
+        assert_eq!(pull_data.random_token, 123);
+        assert_eq!(pull_data.gateway_id, [1, 2, 3, 4, 5, 6, 7, 8]);
+
+        assert_eq!(PushAck { random_token: 123 }.to_bytes(), vec![2, 0, 123, 1]);
+        assert_eq!(PullAck { random_token: 123 }.to_bytes(), vec![2, 0, 123, 4]);
+
+        let mut b = vec![2, 0, 123, 3];
+        b.extend_from_slice(
+            br#"{"txpk":{"imme":true,"freq":868.1,"rfch":0,"powe":14,"modu":"LORA","datr":"SF7BW125","codr":"4/5","ipol":true,"size":3,"data":"AQID"}}"#,
+        );
+        let pull_resp = PullResp::from_bytes(&b).unwrap();
+        assert_eq!(pull_resp.to_bytes(), b);
+
+        let tx_ack = TxAck::from_bytes(&[2, 0, 123, 5, 1, 2, 3, 4, 5, 6, 7, 8, 0]).unwrap();
+        assert_eq!(tx_ack.payload.txpk_ack.error, TxAckError::None);
+
+        let mut b = vec![2, 0, 123, 5, 1, 2, 3, 4, 5, 6, 7, 8];
+        b.extend_from_slice(br#"{"txpk_ack":{"warn":"TX_POWER","value":20}}"#);
+        let tx_ack = TxAck::from_bytes(&b).unwrap();
+        assert_eq!(tx_ack.payload.txpk_ack.error, TxAckError::None);
+        assert_eq!(tx_ack.payload.txpk_ack.warn, Some(TxAckError::TxPower));
+        assert_eq!(tx_ack.payload.txpk_ack.value, Some(20));
+    }
+
+    #[test]
+    fn test_rxpk_round_trip() {
+        // (input, serialized), the serialized rxpk must deserialize to the
+        // same rxpk, e.g. for the uplink buffer.
+        let tests = [
+            // all fields
+            (
+                r#"{"time":"2023-05-01T00:00:00.000001+00:00","tmms":1366934418000,"tmst":16909060,"freq":868.3,"chan":1,"rfch":1,"stat":1,"modu":"LORA","datr":"SF12BW125","codr":"4/5","hpw":8,"rssi":-60,"rssic":-61,"rssis":-62,"lsnr":5.5,"lsnr_min":4.5,"lsnr_max":6.5,"ftime":500,"foff":-120,"rsig":[{"ant":0,"chan":1,"rssic":-60,"rssis":-62,"lsnr":5.5,"lsnr_min":4.5,"lsnr_max":6.5,"ftime":500},{"ant":1,"chan":1,"rssic":-70}],"meta":{"channel":"ch1","owner":"acme"},"size":3,"data":"AQID"}"#,
+                None,
+            ),
+            // no optional fields
+            (
+                r#"{"time":"1970-01-01T00:00:00+00:00","tmst":1,"freq":868.1,"chan":0,"rfch":0,"stat":-1,"modu":"FSK","datr":50000,"size":0,"data":""}"#,
+                Some(
+                    r#"{"time":"1970-01-01T00:00:00+00:00","tmms":null,"tmst":1,"freq":868.1,"chan":0,"rfch":0,"stat":-1,"modu":"FSK","datr":50000,"codr":null,"size":0,"data":""}"#,
+                ),
+            ),
+            // optional fields set to null
+            (
+                r#"{"time":"1970-01-01T00:00:00+00:00","tmms":null,"tmst":1,"freq":868.1,"chan":0,"rfch":0,"stat":0,"modu":"LORA","datr":"SF7BW125","codr":null,"hpw":null,"rssi":null,"rssic":null,"rssis":null,"lsnr":null,"lsnr_min":null,"lsnr_max":null,"ftime":null,"foff":null,"rsig":null,"meta":null,"size":1,"data":"AA=="}"#,
+                Some(
+                    r#"{"time":"1970-01-01T00:00:00+00:00","tmms":null,"tmst":1,"freq":868.1,"chan":0,"rfch":0,"stat":0,"modu":"LORA","datr":"SF7BW125","codr":null,"size":1,"data":"AA=="}"#,
+                ),
+            ),
+        ];
+
+        for (input, serialized) in tests {
+            let rxpk: RxPk = serde_json::from_str(input).unwrap();
+            let out = serde_json::to_string(&rxpk).unwrap();
+            assert_eq!(out, serialized.unwrap_or(input));
+
+            let rxpk: RxPk = serde_json::from_str(&out).unwrap();
+            assert_eq!(serde_json::to_string(&rxpk).unwrap(), out);
+        }
+    }
+
+    #[test]
+    fn test_rxpk_to_v2() {
+        let mut rxpk: RxPk = serde_json::from_str(
+            r#"{"time":"1970-01-01T00:00:00+00:00","tmst":1,"freq":868.1,"chan":2,"rfch":0,"stat":1,"modu":"LORA","datr":"SF7BW125","codr":"4/5","rssi":-60,"lsnr":7.25,"ftime":500,"size":1,"data":"AA=="}"#,
+        )
+        .unwrap();
+        rxpk.to_v2(1);
+        assert_eq!(
+            serde_json::to_string(&rxpk).unwrap(),
+            r#"{"time":"1970-01-01T00:00:00+00:00","tmms":null,"tmst":1,"freq":868.1,"chan":2,"rfch":0,"stat":1,"modu":"LORA","datr":"SF7BW125","codr":"4/5","rsig":[{"ant":1,"chan":2,"rssic":-60,"lsnr":7.25,"ftime":500}],"size":1,"data":"AA=="}"#
+        );
+
+        // FSK, without SNR.
+        let mut rxpk: RxPk = serde_json::from_str(
+            r#"{"time":"1970-01-01T00:00:00+00:00","tmst":1,"freq":868.8,"chan":8,"rfch":1,"stat":1,"modu":"FSK","datr":50000,"rssi":-80,"size":1,"data":"AA=="}"#,
+        )
+        .unwrap();
+        rxpk.to_v2(0);
+        assert_eq!(
+            serde_json::to_string(&rxpk).unwrap(),
+            r#"{"time":"1970-01-01T00:00:00+00:00","tmms":null,"tmst":1,"freq":868.8,"chan":8,"rfch":1,"stat":1,"modu":"FSK","datr":50000,"codr":null,"rsig":[{"ant":0,"chan":8,"rssic":-80}],"size":1,"data":"AA=="}"#
+        );
+    }
+
+    #[test]
+    fn test_tx_ack_error() {
+        assert_eq!(
+            serde_json::to_string(&TxAckError::None).unwrap(),
+            r#""NONE""#
+        );
+        assert_eq!(
+            TxAckError::from_proto(gw::TxAckStatus::CollisionBeacon),
+            TxAckError::CollisionBeacon
+        );
+
+        for (s, e) in [
+            (r#""""#, TxAckError::None),
+            (r#""NONE""#, TxAckError::None),
+            (r#""QUEUE_FULL""#, TxAckError::QueueFull),
+        ] {
+            assert_eq!(serde_json::from_str::<TxAckError>(s).unwrap(), e);
+        }
+        assert!(serde_json::from_str::<TxAckError>(r#""FOO""#).is_err());
+    }
+
+    #[test]
+    fn test_tmms() {
+        // No precision loss.
+        assert_eq!(
+            duration_to_ms(&prost_types::Duration {
+                seconds: 1366934418,
+                nanos: 999_999_999,
+            }),
+            Some(1366934418999)
+        );
+        assert_eq!(
+            duration_to_ms(&prost_types::Duration {
+                seconds: -1,
+                nanos: 0,
+            }),
+            None
+        );
+    }
+}
@@ -0,0 +1,477 @@
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+use super::config::{Configuration, Server};
+use super::forwarder;
+use super::frontend;
+use super::helpers;
+use super::logging;
+use super::signals;
+
+// Interval in which the Concentratord is queried for its gateway ID, to detect
+// restarts and gateway ID changes.
+const CONCENTRATORD_WATCH_INTERVAL: Duration = Duration::from_secs(10);
+
+struct Forwarder {
+    conf: Server,
+    stop: Sender<signals::Signal>,
+    // Disconnected once the forwarder has stopped.
+    stopped: Receiver<()>,
+}
+
+impl Forwarder {
+    // Waits until the forwarder has stopped, returns false when it did not
+    // stop before the deadline.
+    fn wait(&self, deadline: Option<Instant>) -> bool {
+        match deadline {
+            Some(v) => {
+                self.stopped
+                    .recv_timeout(v.saturating_duration_since(Instant::now()))
+                    != Err(RecvTimeoutError::Timeout)
+            }
+            None => {
+                let _ = self.stopped.recv();
+                true
+            }
+        }
+    }
+}
+
+pub struct Supervisor {
+    dispatcher: Arc<frontend::Dispatcher>,
+    gateway_id: Vec<u8>,
+    forwarders: Vec<Forwarder>,
+}
+
+impl Supervisor {
+    pub fn new(dispatcher: Arc<frontend::Dispatcher>, gateway_id: Vec<u8>) -> Self {
+        Supervisor {
+            dispatcher,
+            gateway_id,
+            forwarders: vec![],
+        }
+    }
+
+    // Forwarders with an unchanged configuration keep running. The changes
+    // which do not affect the sockets, uplink buffer or state store are
+    // applied to the running forwarder (see can_reload). The other
+    // forwarders are stopped and, once these have stopped, the forwarders for
+    // the new or changed servers are started.
+    pub fn apply(&mut self, mut servers: Vec<Server>) {
+        let mut forwarders: Vec<Forwarder> = vec![];
+        let mut changed: Vec<Forwarder> = vec![];
+        let mut stopped: Vec<Forwarder> = vec![];
+
+        for f in self.forwarders.drain(..) {
+            match servers.iter().position(|s| *s == f.conf) {
+                Some(i) => {
+                    servers.remove(i);
+                    forwarders.push(f);
+                }
+                None => changed.push(f),
+            }
+        }
+
+        for f in changed {
+            if let Some(i) = servers.iter().position(|s| can_reload(&f.conf, s)) {
+                let conf = servers.remove(i);
+
+                // The forwarder stops on an invalid configuration, it keeps
+                // running using the old configuration instead.
+                if let Err(e) = forwarder::validate(&conf) {
+                    error!(
+                        "Invalid configuration, not reloading forwarder: {}, server: {}",
+                        e, conf.server
+                    );
+                    forwarders.push(f);
+                    continue;
+                }
+
+                info!("Reloading forwarder, server: {}", conf.server);
+                if f.stop
+                    .send(signals::Signal::Reload(Box::new(conf.clone())))
+                    .is_ok()
+                {
+                    forwarders.push(Forwarder { conf, ..f });
+                    continue;
+                }
+
+                // The forwarder has stopped, e.g. because of an invalid
+                // configuration.
+                servers.push(conf);
+            }
+
+            info!("Stopping forwarder, server: {}", f.conf.server);
+            let _ = f.stop.send(signals::Signal::Stop);
+            stopped.push(f);
+        }
+
+        // The old and new forwarder might share e.g. the buffer_path or the
+        // bind address.
+        for f in stopped {
+            f.wait(None);
+        }
+
+        for conf in servers {
+            forwarders.push(self.spawn(conf));
+        }
+
+        self.forwarders = forwarders;
+    }
+
+    // Restarts all forwarders with the new gateway ID, in case it has changed.
+    pub fn set_gateway_id(&mut self, gateway_id: Vec<u8>) {
+        if gateway_id == self.gateway_id {
+            return;
+        }
+
+        self.gateway_id = gateway_id;
+        for f in &self.forwarders {
+            info!("Stopping forwarder, server: {}", f.conf.server);
+            let _ = f.stop.send(signals::Signal::Stop);
+        }
+
+        let mut servers: Vec<Server> = vec![];
+        for f in self.forwarders.drain(..) {
+            f.wait(None);
+            servers.push(f.conf);
+        }
+        self.apply(servers);
+    }
+
+    // Makes all forwarders send a PULL_DATA immediately, such that the
+    // downlink path is restored.
+    pub fn reannounce(&self) {
+        for f in &self.forwarders {
+            let _ = f.stop.send(signals::Signal::PullData);
+        }
+    }
+
+    // Drains and stops all forwarders, waiting until these have stopped or the
+    // timeout has expired.
+    pub fn shutdown(&mut self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        for f in &self.forwarders {
+            let _ = f.stop.send(signals::Signal::Shutdown(deadline));
+        }
+
+        // Some margin for stopping the threads after draining.
+        let deadline = deadline + Duration::from_secs(1);
+        for f in self.forwarders.drain(..) {
+            if !f.wait(Some(deadline)) {
+                warn!("Forwarder did not stop in time, server: {}", f.conf.server);
+            }
+        }
+    }
+
+    fn spawn(&self, conf: Server) -> Forwarder {
+        let (stop, stop_receive) = channel();
+        let (done, stopped) = channel();
+
+        thread::spawn({
+            let conf = conf.clone();
+            let gateway_id = self.gateway_id.clone();
+            let dispatcher = self.dispatcher.clone();
+
+            move || {
+                // Dropped when the forwarder returns (or panics).
+                let _done: Sender<()> = done;
+                forwarder::start(&conf, dispatcher, gateway_id, stop_receive)
+            }
+        });
+
+        Forwarder {
+            conf,
+            stop,
+            stopped,
+        }
+    }
+}
+
+// Returns true when the changes can be applied to the running forwarder, i.e.
+// when the server, sockets, uplink buffer and state store settings are
+// unchanged.
+fn can_reload(old: &Server, new: &Server) -> bool {
+    old.server == new.server
+        && old.transport == new.transport
+        && old.encryption == new.encryption
+        && old.tls == new.tls
+        && old.address_family == new.address_family
+        && old.probe_addresses == new.probe_addresses
+        && old.bind == new.bind
+        && old.bind_interface == new.bind_interface
+        && old.reuse_port == new.reuse_port
+        && old.dscp == new.dscp
+        && old.send_buffer_size == new.send_buffer_size
+        && old.recv_buffer_size == new.recv_buffer_size
+        && old.split_sockets == new.split_sockets
+        && old.downlink_port == new.downlink_port
+        && old.source_allowlist == new.source_allowlist
+        && old.read_only == new.read_only
+        && old.buffer_max_size == new.buffer_max_size
+        && old.buffer_max_age_secs == new.buffer_max_age_secs
+        && old.buffer_path == new.buffer_path
+        && old.store_backend == new.store_backend
+        && old.store_path == new.store_path
+        && old.store_url == new.store_url
+}
+
+// Periodically queries the Concentratord for its gateway ID. When the
+// Concentratord becomes reachable again (e.g. after a restart), the forwarders
+// re-announce themselves to the servers. When the gateway ID has changed, the
+// forwarders are restarted using the new gateway ID.
+pub fn watch_concentratord(command_url: String, supervisor: Arc<Mutex<Supervisor>>) {
+    let mut reachable = true;
+
+    loop {
+        thread::sleep(CONCENTRATORD_WATCH_INTERVAL);
+
+        let gateway_id = match helpers::get_gateway_id(&command_url) {
+            Ok(v) => v,
+            Err(e) => {
+                if reachable {
+                    warn!("Concentratord unreachable, error: {}", e);
+                }
+                reachable = false;
+                continue;
+            }
+        };
+
+        let mut supervisor = supervisor.lock().unwrap();
+        if gateway_id != supervisor.gateway_id {
+            warn!(
+                "Concentratord gateway ID changed, gateway_id: {}, restarting forwarders",
+                hex::encode(&gateway_id)
+            );
+            supervisor.set_gateway_id(gateway_id);
+        } else if !reachable {
+            info!("Concentratord reachable again, re-announcing to servers");
+            supervisor.reannounce();
+        }
+        reachable = true;
+    }
+}
+
+// Drains the forwarders on SIGTERM or SIGINT and exits, instead of stopping
+// in the middle of a frame.
+pub fn shutdown_on_signal(drain_timeout: Duration, supervisor: Arc<Mutex<Supervisor>>) {
+    let mut signals = Signals::new([SIGTERM, SIGINT]).expect("setup signal handler error");
+    if let Some(signal) = signals.forever().next() {
+        info!(
+            "Received {}, shutting down, drain_timeout: {:?}",
+            match signal {
+                SIGINT => "SIGINT",
+                _ => "SIGTERM",
+            },
+            drain_timeout
+        );
+        supervisor.lock().unwrap().shutdown(drain_timeout);
+        info!("Shutdown complete");
+        log::logger().flush();
+        std::process::exit(0);
+    }
+}
+
+pub fn start(filenames: &[String], config: Configuration, supervisor: Arc<Mutex<Supervisor>>) {
+    let mut signals = Signals::new([SIGHUP]).expect("setup signal handler error");
+    let mut current = config;
+
+    for _ in signals.forever() {
+        info!("Received SIGHUP, reloading configuration");
+
+        let config = match Configuration::get(filenames) {
+            Ok(v) => v,
+            Err(err) => {
+                error!("Reload configuration error: {}", err);
+                continue;
+            }
+        };
+
+        match log::Level::from_str(&config.udp_forwarder.log_level) {
+            Ok(v) => {
+                if let Err(err) = logging::set_levels(v, &config.udp_forwarder.log_levels) {
+                    error!("Set log levels error: {}", err);
+                }
+            }
+            Err(err) => error!("Parse log_level error: {}", err),
+        }
+        logging::set_log_frames(config.udp_forwarder.log_frames);
+
+        if config.udp_forwarder.log_format != current.udp_forwarder.log_format
+            || config.udp_forwarder.log_to_syslog != current.udp_forwarder.log_to_syslog
+            || config.udp_forwarder.syslog != current.udp_forwarder.syslog
+        {
+            warn!("Changes to log_format, log_to_syslog or syslog require a restart");
+        }
+
+        if config.concentratord != current.concentratord {
+            warn!("Changes to the concentratord configuration require a restart");
+        }
+
+        if config.udp_forwarder.metrics_bind != current.udp_forwarder.metrics_bind {
+            warn!("Changes to metrics_bind require a restart");
+        }
+
+        if config.udp_forwarder.status_bind != current.udp_forwarder.status_bind {
+            warn!("Changes to status_bind require a restart");
+        }
+
+        if config.udp_forwarder.crash_report_path != current.udp_forwarder.crash_report_path {
+            warn!("Changes to crash_report_path require a restart");
+        }
+
+        if config.udp_forwarder.usage_path != current.udp_forwarder.usage_path
+            || config.udp_forwarder.usage_retention_days
+                != current.udp_forwarder.usage_retention_days
+        {
+            warn!("Changes to usage_path or usage_retention_days require a restart");
+        }
+
+        if config.udp_forwarder.duty_cycle != current.udp_forwarder.duty_cycle {
+            warn!("Changes to duty_cycle require a restart");
+        }
+
+        if config.udp_forwarder.gps_leap_seconds != current.udp_forwarder.gps_leap_seconds
+            || config.udp_forwarder.tmms_conversion != current.udp_forwarder.tmms_conversion
+        {
+            warn!("Changes to gps_leap_seconds or tmms_conversion require a restart");
+        }
+
+        if config.udp_forwarder.drain_timeout_secs != current.udp_forwarder.drain_timeout_secs {
+            warn!("Changes to drain_timeout_secs require a restart");
+        }
+
+        if config.udp_forwarder.backend != current.udp_forwarder.backend
+            || config.udp_forwarder.mock_backend != current.udp_forwarder.mock_backend
+        {
+            warn!("Changes to backend or mock_backend require a restart");
+        }
+
+        if config.udp_forwarder.mqtt != current.udp_forwarder.mqtt {
+            warn!("Changes to mqtt require a restart");
+        }
+
+        if config.udp_forwarder.location != current.udp_forwarder.location {
+            warn!("Changes to location require a restart");
+        }
+
+        if config.udp_forwarder.capture != current.udp_forwarder.capture {
+            warn!("Changes to capture require a restart");
+        }
+
+        supervisor
+            .lock()
+            .unwrap()
+            .apply(config.udp_forwarder.get_servers());
+        current = config;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, UdpSocket};
+
+    use super::*;
+    use crate::backend;
+    use crate::config::MockBackend;
+
+    // Returns the source address of the next PULL_DATA received within the
+    // timeout.
+    fn recv_pull_data(socket: &UdpSocket, timeout: Duration) -> Option<SocketAddr> {
+        let deadline = Instant::now() + timeout;
+        let mut buffer = [0; 65535];
+        while let Some(v) = deadline.checked_duration_since(Instant::now()) {
+            socket.set_read_timeout(Some(v)).unwrap();
+            match socket.recv_from(&mut buffer) {
+                Ok((size, addr)) if size == 12 && buffer[3] == 0x02 => return Some(addr),
+                Ok(_) => continue,
+                Err(_) => return None,
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn test_can_reload() {
+        let old = Server::default();
+
+        let mut new = old.clone();
+        new.keepalive_interval_secs = 20;
+        new.forward_crc_invalid = true;
+        new.enricher.url = "unix:///tmp/enricher.sock".into();
+        assert!(can_reload(&old, &new));
+
+        for f in [
+            |s: &mut Server| s.server = "127.0.0.1:1701".into(),
+            |s: &mut Server| s.bind = "0.0.0.0:1700".into(),
+            |s: &mut Server| s.split_sockets = true,
+            |s: &mut Server| s.tls.enabled = true,
+            |s: &mut Server| s.source_allowlist = vec!["192.0.2.0/24".into()],
+            |s: &mut Server| s.buffer_path = "/tmp/buffer.jsonl".into(),
+            |s: &mut Server| s.store_path = "/tmp/store".into(),
+        ] {
+            let mut new = old.clone();
+            f(&mut new);
+            assert!(!can_reload(&old, &new));
+        }
+    }
+
+    #[test]
+    fn test_supervisor() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let conf = Server {
+            server: server.local_addr().unwrap().to_string(),
+            keepalive_interval_secs: 60,
+            ..Default::default()
+        };
+
+        let backend = backend::Mock::new(&MockBackend::default()).unwrap();
+        let dispatcher = frontend::Dispatcher::start(Box::new(backend));
+        let mut supervisor = Supervisor::new(dispatcher, vec![0, 0, 0, 0, 0, 0, 0, 1]);
+
+        supervisor.apply(vec![conf.clone()]);
+        let addr = recv_pull_data(&server, Duration::from_secs(5)).unwrap();
+
+        // Unchanged, the forwarder keeps running.
+        supervisor.apply(vec![conf.clone()]);
+        assert_eq!(recv_pull_data(&server, Duration::from_millis(500)), None);
+
+        // Reloaded, the socket is kept.
+        let conf = Server {
+            keepalive_interval_secs: 50,
+            forward_crc_invalid: true,
+            ..conf
+        };
+        supervisor.apply(vec![conf.clone()]);
+        assert_eq!(recv_pull_data(&server, Duration::from_secs(5)), Some(addr));
+
+        // Invalid, the forwarder keeps running using the old configuration.
+        supervisor.apply(vec![Server {
+            interval_jitter_percent: 80,
+            ..conf.clone()
+        }]);
+        assert_eq!(recv_pull_data(&server, Duration::from_millis(500)), None);
+        assert!(supervisor.forwarders[0].conf == conf);
+        supervisor.reannounce();
+        assert_eq!(recv_pull_data(&server, Duration::from_secs(5)), Some(addr));
+
+        // Restarted using a new socket.
+        let conf = Server {
+            bind: "127.0.0.1:0".into(),
+            ..conf
+        };
+        supervisor.apply(vec![conf]);
+        let new_addr = recv_pull_data(&server, Duration::from_secs(5)).unwrap();
+        assert_ne!(new_addr, addr);
+
+        supervisor.apply(vec![]);
+        assert!(supervisor.forwarders.is_empty());
+        assert_eq!(recv_pull_data(&server, Duration::from_millis(500)), None);
+    }
+}
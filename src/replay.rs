@@ -0,0 +1,89 @@
+use std::time::{Duration, SystemTime};
+
+use super::lorawan;
+use super::store::SharedStore;
+
+// Cache of the (DevAddr, FCnt, MIC) tuples of the data-up frames seen within
+// the configured window. A frame with the same tuple is considered a replay.
+// Note that this also drops uplink repetitions (NbTrans > 1) of unconfirmed
+// frames when these are received within the window.
+//
+// The tuples are kept in the state store, such that (depending on the store
+// backend) replays are also detected after a restart.
+pub struct ReplayCache {
+    window: Duration,
+    store: SharedStore,
+}
+
+impl ReplayCache {
+    pub fn new(window: Duration, store: SharedStore) -> Self {
+        ReplayCache { window, store }
+    }
+
+    pub fn len(&self) -> usize {
+        self.store
+            .lock()
+            .unwrap()
+            .scan(b"replay/", SystemTime::now())
+            .len()
+    }
+
+    // Returns true when the PHYPayload is a replay of a frame seen within the
+    // window.
+    pub fn is_replay(&mut self, phy_payload: &[u8], now: SystemTime) -> bool {
+        let phy = match lorawan::PhyPayload::from_slice(phy_payload) {
+            Ok(v) => v,
+            Err(_) => return false,
+        };
+
+        let key = match phy.payload {
+            lorawan::Payload::DataUp { dev_addr, f_cnt } => {
+                let mut b = b"replay/".to_vec();
+                b.extend_from_slice(&dev_addr);
+                b.extend_from_slice(&f_cnt.to_be_bytes());
+                b.extend_from_slice(&phy.mic);
+                b
+            }
+            _ => return false,
+        };
+
+        let mut store = self.store.lock().unwrap();
+        let seen = store.get(&key, now).is_some();
+        store.put(&key, &[], self.window, now);
+        seen
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::store::MemoryStore;
+
+    #[test]
+    fn test_replay_cache() {
+        let store: SharedStore = Arc::new(Mutex::new(Box::new(MemoryStore::default())));
+        let mut cache = ReplayCache::new(Duration::from_secs(10), store);
+        let now = SystemTime::now();
+
+        let a = vec![
+            0x40, 0x04, 0x03, 0x02, 0x01, 0x00, 0x0a, 0x01, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06,
+        ];
+        let b = vec![
+            0x40, 0x04, 0x03, 0x02, 0x01, 0x00, 0x0b, 0x01, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06,
+        ];
+
+        assert!(!cache.is_replay(&a, now));
+        assert!(!cache.is_replay(&b, now));
+        assert!(cache.is_replay(&a, now + Duration::from_secs(5)));
+
+        // outside the window
+        assert!(!cache.is_replay(&b, now + Duration::from_secs(11)));
+
+        // non data-up frames are never replays
+        let join = vec![0x00; 23];
+        assert!(!cache.is_replay(&join, now));
+        assert!(!cache.is_replay(&join, now));
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+struct Pending {
+    bytes: Vec<u8>,
+    sent_at: Instant,
+    retries: u32,
+}
+
+// Keeps the PUSH_DATA datagrams which have not yet been acknowledged by the
+// server, keyed by their random token.
+pub struct Retransmitter {
+    timeout: Duration,
+    max_retries: u32,
+    pending: HashMap<u16, Pending>,
+}
+
+impl Retransmitter {
+    pub fn new(timeout: Duration, max_retries: u32) -> Self {
+        Retransmitter {
+            timeout,
+            max_retries,
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn sent(&mut self, token: u16, bytes: Vec<u8>, now: Instant) {
+        self.pending.insert(
+            token,
+            Pending {
+                bytes,
+                sent_at: now,
+                retries: 0,
+            },
+        );
+    }
+
+    pub fn acked(&mut self, token: u16) -> bool {
+        self.pending.remove(&token).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    // Returns the datagrams that must be retransmitted and the number of
+    // datagrams that were dropped as the max. number of retries was reached.
+    pub fn due(&mut self, now: Instant) -> (Vec<Vec<u8>>, u32) {
+        let mut out: Vec<Vec<u8>> = vec![];
+        let mut dropped = 0;
+        let timeout = self.timeout;
+        let max_retries = self.max_retries;
+
+        self.pending.retain(|_, p| {
+            if now.saturating_duration_since(p.sent_at) < timeout {
+                return true;
+            }
+
+            if p.retries >= max_retries {
+                dropped += 1;
+                return false;
+            }
+
+            p.retries += 1;
+            p.sent_at = now;
+            out.push(p.bytes.clone());
+            true
+        });
+
+        (out, dropped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn test_retransmitter() {
+        let mut r = Retransmitter::new(Duration::from_millis(100), 2);
+        let now = Instant::now();
+
+        r.sent(1, vec![1], now);
+        r.sent(2, vec![2], now);
+
+        let (out, dropped) = r.due(now + Duration::from_millis(50));
+        assert!(out.is_empty());
+        assert_eq!(dropped, 0);
+
+        assert!(r.acked(1));
+        assert!(!r.acked(1));
+
+        let (out, dropped) = r.due(now + Duration::from_millis(100));
+        assert_eq!(out, vec![vec![2]]);
+        assert_eq!(dropped, 0);
+
+        let (out, dropped) = r.due(now + Duration::from_millis(200));
+        assert_eq!(out, vec![vec![2]]);
+        assert_eq!(dropped, 0);
+
+        let (out, dropped) = r.due(now + Duration::from_millis(300));
+        assert!(out.is_empty());
+        assert_eq!(dropped, 1);
+        assert!(!r.acked(2));
+    }
+
+    // Drives the retransmitter through randomized schedules (using a mock
+    // clock) and checks that a datagram is never retransmitted before the
+    // timeout or after it was acknowledged, and never dropped while it can
+    // still be retransmitted.
+    #[test]
+    fn test_retransmitter_randomized() {
+        struct Model {
+            last_sent: Duration,
+            retries: u32,
+            acked: bool,
+            dropped: bool,
+        }
+
+        let timeout = Duration::from_millis(100);
+        let max_retries = 3;
+
+        for seed in 0..200 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut r = Retransmitter::new(timeout, max_retries);
+            let mut models: HashMap<u16, Model> = HashMap::new();
+            let start = Instant::now();
+            let mut now = Duration::from_millis(0);
+            let mut next_token: u16 = 0;
+
+            for _ in 0..100 {
+                now += Duration::from_millis(rng.gen_range(1..150));
+
+                match rng.gen_range(0..3) {
+                    0 => {
+                        next_token += 1;
+                        r.sent(next_token, next_token.to_be_bytes().to_vec(), start + now);
+                        models.insert(
+                            next_token,
+                            Model {
+                                last_sent: now,
+                                retries: 0,
+                                acked: false,
+                                dropped: false,
+                            },
+                        );
+                    }
+                    1 if next_token != 0 => {
+                        let token = rng.gen_range(1..=next_token);
+                        let m = models.get_mut(&token).unwrap();
+                        let pending = !m.acked && !m.dropped;
+                        assert_eq!(r.acked(token), pending, "seed: {}", seed);
+                        m.acked = true;
+                    }
+                    _ => {}
+                }
+
+                let (out, dropped) = r.due(start + now);
+                for bytes in out {
+                    let token = u16::from_be_bytes([bytes[0], bytes[1]]);
+                    let m = models.get_mut(&token).unwrap();
+                    assert!(!m.acked && !m.dropped, "seed: {}", seed);
+                    assert!(now - m.last_sent >= timeout, "seed: {}", seed);
+                    assert!(m.retries < max_retries, "seed: {}", seed);
+                    m.retries += 1;
+                    m.last_sent = now;
+                }
+
+                // The datagrams which reached the max. retries and timed out
+                // must be dropped, no others.
+                let mut expected = 0;
+                for m in models.values_mut() {
+                    if !m.acked
+                        && !m.dropped
+                        && m.retries == max_retries
+                        && now - m.last_sent >= timeout
+                    {
+                        m.dropped = true;
+                        expected += 1;
+                    }
+                }
+                assert_eq!(dropped, expected, "seed: {}", seed);
+
+                // A datagram past its timeout is retransmitted, not left
+                // pending.
+                for m in models.values() {
+                    if !m.acked && !m.dropped {
+                        assert!(now - m.last_sent < timeout, "seed: {}", seed);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Priority {
+    // Time-critical datagrams: PULL_DATA (keepalive) and TX_ACK.
+    High,
+    // Bulk datagrams: PUSH_DATA with rxpk or stats.
+    Low,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Datagram {
+    pub bytes: Vec<u8>,
+    // Number of rxpk in the datagram, counted as forwarded once sent.
+    pub rxpk_count: u32,
+}
+
+#[derive(Default)]
+struct Queues {
+    high: VecDeque<Datagram>,
+    low: VecDeque<Datagram>,
+}
+
+// Send queue, such that time-critical datagrams are sent before the bulk
+// datagrams queued under load (e.g. an uplink flood).
+pub struct SendQueue {
+    queues: Mutex<Queues>,
+    ready: Condvar,
+    max_low: usize,
+}
+
+impl SendQueue {
+    pub fn new(max_low: usize) -> Self {
+        SendQueue {
+            queues: Mutex::new(Queues::default()),
+            ready: Condvar::new(),
+            max_low,
+        }
+    }
+
+    // Queues the datagram. When the low priority queue is full, its oldest
+    // datagram is dropped and returned. High priority datagrams are never
+    // dropped.
+    pub fn push(&self, priority: Priority, datagram: Datagram) -> Option<Datagram> {
+        let mut queues = self.queues.lock().unwrap();
+        let dropped = match priority {
+            Priority::High => {
+                queues.high.push_back(datagram);
+                None
+            }
+            Priority::Low => {
+                let dropped = if queues.low.len() >= self.max_low {
+                    queues.low.pop_front()
+                } else {
+                    None
+                };
+                queues.low.push_back(datagram);
+                dropped
+            }
+        };
+
+        self.ready.notify_one();
+        dropped
+    }
+
+    pub fn len(&self) -> usize {
+        let queues = self.queues.lock().unwrap();
+        queues.high.len() + queues.low.len()
+    }
+
+    // Returns the next datagram to send, high priority datagrams first.
+    // Returns None when no datagram has been queued within the timeout.
+    pub fn pop(&self, timeout: Duration) -> Option<Datagram> {
+        let queues = self.queues.lock().unwrap();
+        let (mut queues, _) = self
+            .ready
+            .wait_timeout_while(queues, timeout, |q| q.high.is_empty() && q.low.is_empty())
+            .unwrap();
+
+        queues.high.pop_front().or_else(|| queues.low.pop_front())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+
+    fn datagram(b: u8) -> Datagram {
+        Datagram {
+            bytes: vec![b],
+            rxpk_count: 1,
+        }
+    }
+
+    #[test]
+    fn test_send_queue() {
+        let q = SendQueue::new(10);
+        assert_eq!(q.pop(Duration::from_millis(1)), None);
+
+        // Uplink flood, only the most recent datagrams are kept.
+        let mut dropped = 0;
+        for i in 0..100 {
+            if q.push(Priority::Low, datagram(i)).is_some() {
+                dropped += 1;
+            }
+        }
+        assert_eq!(dropped, 90);
+
+        q.push(Priority::High, datagram(200));
+        q.push(Priority::High, datagram(201));
+
+        let out: Vec<u8> = (0..12)
+            .map(|_| q.pop(Duration::from_millis(1)).unwrap().bytes[0])
+            .collect();
+        assert_eq!(out, vec![200, 201, 90, 91, 92, 93, 94, 95, 96, 97, 98, 99]);
+        assert_eq!(q.pop(Duration::from_millis(1)), None);
+    }
+
+    #[test]
+    fn test_send_queue_wakeup() {
+        let q = Arc::new(SendQueue::new(10));
+
+        let t = thread::spawn({
+            let q = q.clone();
+            move || q.pop(Duration::from_secs(5))
+        });
+        q.push(Priority::High, datagram(1));
+
+        assert_eq!(t.join().unwrap(), Some(datagram(1)));
+    }
+}
@@ -1,8 +1,18 @@
 use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::Instant;
+
+use super::config::Server;
 
 #[derive(Clone)]
 pub enum Signal {
     Stop,
+    // Send a PULL_DATA immediately, e.g. after a Concentratord restart.
+    PullData,
+    // Drain the forwarder before stopping, within the deadline.
+    Shutdown(Instant),
+    // Continue with the new configuration, keeping the sockets, uplink buffer
+    // and state store.
+    Reload(Box<Server>),
 }
 
 pub struct SignalPool {
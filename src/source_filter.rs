@@ -0,0 +1,112 @@
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::Result;
+
+// IP network, configured in CIDR notation, e.g. "192.0.2.0/24" or
+// "2001:db8::/32". When the prefix length is omitted, the full address must
+// match.
+struct Cidr {
+    addr: IpAddr,
+    bits: u32,
+}
+
+impl Cidr {
+    fn parse(s: &str) -> Result<Self> {
+        let (addr, bits) = match s.split_once('/') {
+            Some((addr, bits)) => (addr.parse::<IpAddr>()?, Some(bits.parse::<u32>()?)),
+            None => (s.parse::<IpAddr>()?, None),
+        };
+
+        let size = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let bits = bits.unwrap_or(size);
+        if bits > size {
+            return Err(anyhow!("expected at most {} bits, cidr: {}", size, s));
+        }
+
+        Ok(Cidr { addr, bits })
+    }
+
+    fn matches(&self, ip: IpAddr) -> bool {
+        if self.bits == 0 {
+            return self.addr.is_ipv4() == ip.is_ipv4();
+        }
+
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let shift = 32 - self.bits;
+                (u32::from(net) >> shift) == (u32::from(ip) >> shift)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let shift = 128 - self.bits;
+                (u128::from(net) >> shift) == (u128::from(ip) >> shift)
+            }
+            _ => false,
+        }
+    }
+}
+
+// Accepts the datagrams received from the server, i.e. from the address the
+// socket is sending to or one of the resolved server addresses, or from one
+// of the allowlisted networks. Any other source could inject (spoofed)
+// PULL_RESP downlinks.
+pub struct SourceFilter {
+    allowlist: Vec<Cidr>,
+}
+
+impl SourceFilter {
+    pub fn new(allowlist: &[String]) -> Result<Self> {
+        Ok(SourceFilter {
+            allowlist: allowlist
+                .iter()
+                .map(|s| Cidr::parse(s))
+                .collect::<Result<Vec<Cidr>>>()?,
+        })
+    }
+
+    pub fn is_allowed(
+        &self,
+        source: SocketAddr,
+        peer: Option<SocketAddr>,
+        server_addrs: &[SocketAddr],
+    ) -> bool {
+        peer == Some(source)
+            || server_addrs.iter().any(|a| a.ip() == source.ip())
+            || self.allowlist.iter().any(|c| c.matches(source.ip()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_filter() {
+        let filter = SourceFilter::new(&["192.0.2.0/24".into(), "2001:db8::1".into()]).unwrap();
+        let peer: SocketAddr = "198.51.100.1:1700".parse().unwrap();
+        let server_addrs = vec![peer, "198.51.100.2:1700".parse().unwrap()];
+
+        for (source, allowed) in [
+            ("198.51.100.1:1700", true),
+            ("198.51.100.1:1701", true),
+            ("198.51.100.2:1702", true),
+            ("198.51.100.3:1700", false),
+            ("192.0.2.10:1700", true),
+            ("192.0.3.10:1700", false),
+            ("[2001:db8::1]:1700", true),
+            ("[2001:db8::2]:1700", false),
+        ] {
+            assert_eq!(
+                filter.is_allowed(source.parse().unwrap(), Some(peer), &server_addrs),
+                allowed,
+                "source: {}",
+                source
+            );
+        }
+
+        assert!(SourceFilter::new(&["192.0.2.0/33".into()]).is_err());
+        assert!(SourceFilter::new(&["example.com".into()]).is_err());
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use chirpstack_udp_forwarder::protocol::{BridgeStat, Stat};
+use chrono::{DateTime, Utc};
+
+use super::config;
+
+// Counters kept by the forwarder itself, used to synthesize the gateway stats
+// when no stats are received from the Concentratord.
+#[derive(Default)]
+pub struct Counters {
+    rxnb: u32,
+    rxok: u32,
+    dwnb: u32,
+    txnb: u32,
+}
+
+impl Counters {
+    pub fn uplink_received(&mut self, crc_ok: bool) {
+        self.rxnb += 1;
+        if crc_ok {
+            self.rxok += 1;
+        }
+    }
+
+    pub fn downlink_received(&mut self, emitted: bool) {
+        self.dwnb += 1;
+        if emitted {
+            self.txnb += 1;
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Counters::default();
+    }
+
+    // Returns the synthesized stats and resets the counters. The rxfw and
+    // ackr fields are set by the forwarder.
+    pub fn get_and_reset(&mut self, conf: &config::SyntheticStats, now: DateTime<Utc>) -> Stat {
+        let stat = Stat {
+            time: now,
+            lati: conf.latitude,
+            long: conf.longitude,
+            alti: conf.altitude,
+            rxnb: self.rxnb,
+            rxok: self.rxok,
+            rxfw: 0,
+            ackr: 0.0,
+            dwnb: self.dwnb,
+            txnb: self.txnb,
+            temp: None,
+            pfrm: None,
+            mail: None,
+            desc: None,
+            bridge: None,
+            custom: BTreeMap::new(),
+        };
+        self.reset();
+        stat
+    }
+}
+
+// Merges the stats with the stats accumulated within the stats interval. The
+// counters are summed, the time and location are those of the latest stats.
+pub fn merge(acc: Option<Stat>, stat: Stat) -> Stat {
+    match acc {
+        Some(acc) => Stat {
+            rxnb: acc.rxnb + stat.rxnb,
+            rxok: acc.rxok + stat.rxok,
+            dwnb: acc.dwnb + stat.dwnb,
+            txnb: acc.txnb + stat.txnb,
+            ..stat
+        },
+        None => stat,
+    }
+}
+
+// Counters reported in the bridge object of the stats, when enabled.
+#[derive(Default)]
+pub struct BridgeCounters {
+    ack_rtt_sum: Duration,
+    ack_rtt_count: u32,
+    retransmits: u32,
+    dedup_hits: u32,
+    filtered: u32,
+}
+
+impl BridgeCounters {
+    pub fn push_ack_received(&mut self, rtt: Duration) {
+        self.ack_rtt_sum += rtt;
+        self.ack_rtt_count += 1;
+    }
+
+    pub fn retransmitted(&mut self, count: usize) {
+        self.retransmits += count as u32;
+    }
+
+    pub fn dedup_hit(&mut self) {
+        self.dedup_hits += 1;
+    }
+
+    pub fn filtered(&mut self) {
+        self.filtered += 1;
+    }
+
+    pub fn get_and_reset(&mut self) -> BridgeStat {
+        let stat = BridgeStat {
+            ack_rtt_ms: match self.ack_rtt_count {
+                0 => 0.0,
+                n => self.ack_rtt_sum.as_secs_f32() * 1000.0 / n as f32,
+            },
+            retransmits: self.retransmits,
+            dedup_hits: self.dedup_hits,
+            filtered: self.filtered,
+        };
+        *self = BridgeCounters::default();
+        stat
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters() {
+        let conf = config::SyntheticStats {
+            interval_secs: 30,
+            latitude: 46.5,
+            longitude: 6.6,
+            altitude: 400,
+        };
+        let mut c = Counters::default();
+        c.uplink_received(true);
+        c.uplink_received(false);
+        c.downlink_received(true);
+        c.downlink_received(false);
+
+        let stat = c.get_and_reset(&conf, Utc::now());
+        assert_eq!(stat.rxnb, 2);
+        assert_eq!(stat.rxok, 1);
+        assert_eq!(stat.dwnb, 2);
+        assert_eq!(stat.txnb, 1);
+        assert_eq!(stat.alti, 400);
+
+        let stat = c.get_and_reset(&conf, Utc::now());
+        assert_eq!(stat.rxnb, 0);
+        assert_eq!(stat.dwnb, 0);
+
+        c.uplink_received(true);
+        c.downlink_received(true);
+        let a = c.get_and_reset(&conf, Utc::now());
+        c.uplink_received(false);
+        let b = c.get_and_reset(&conf, Utc::now());
+        let stat = merge(Some(merge(None, a)), b);
+        assert_eq!(stat.rxnb, 2);
+        assert_eq!(stat.rxok, 1);
+        assert_eq!(stat.dwnb, 1);
+        assert_eq!(stat.txnb, 1);
+
+        let mut c = BridgeCounters::default();
+        c.push_ack_received(Duration::from_millis(10));
+        c.push_ack_received(Duration::from_millis(30));
+        c.retransmitted(2);
+        c.dedup_hit();
+        c.filtered();
+        assert_eq!(
+            c.get_and_reset(),
+            BridgeStat {
+                ack_rtt_ms: 20.0,
+                retransmits: 2,
+                dedup_hits: 1,
+                filtered: 1,
+            }
+        );
+        assert_eq!(c.get_and_reset(), BridgeStat::default());
+    }
+}
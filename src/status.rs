@@ -0,0 +1,384 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+use super::allocator;
+use super::config;
+use super::usage;
+
+// The Concentratord is considered disconnected when no event has been
+// received within this duration. The Concentratord publishes stats every 30
+// seconds by default.
+const CONCENTRATORD_TIMEOUT: Duration = Duration::from_secs(120);
+
+// Max. duration to read the request or write the response, such that a slow
+// or stalled client does not hold a connection.
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Max. size of the request head (request line and headers).
+const MAX_REQUEST_SIZE: usize = 8192;
+
+// Max. number of connections handled concurrently, additional connections
+// are closed immediately.
+const MAX_CONNECTIONS: usize = 8;
+
+lazy_static! {
+    static ref STATUS: Mutex<Status> = Mutex::new(Status::default());
+}
+
+#[derive(Default)]
+struct Status {
+    last_event: Option<Instant>,
+    servers: BTreeMap<String, ServerStatus>,
+}
+
+#[derive(Default)]
+struct ServerStatus {
+    connected: bool,
+    connection_state: &'static str,
+    last_pull_ack: Option<Instant>,
+    buffered: usize,
+    objects: BTreeMap<&'static str, usize>,
+}
+
+// Connection slot, released on drop.
+struct Connection(Arc<AtomicUsize>);
+
+impl Connection {
+    fn acquire(connections: &Arc<AtomicUsize>) -> Option<Connection> {
+        connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |v| {
+                if v < MAX_CONNECTIONS {
+                    Some(v + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()
+            .map(|_| Connection(connections.clone()))
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub fn event_received() {
+    STATUS.lock().unwrap().last_event = Some(Instant::now());
+}
+
+pub fn set_connected(server: &str, connected: bool) {
+    update(server, |s| s.connected = connected);
+}
+
+pub fn set_connection_state(server: &str, state: &'static str) {
+    update(server, |s| s.connection_state = state);
+}
+
+pub fn pull_ack_received(server: &str) {
+    update(server, |s| s.last_pull_ack = Some(Instant::now()));
+}
+
+pub fn set_buffered(server: &str, buffered: usize) {
+    update(server, |s| s.buffered = buffered);
+}
+
+// Sets the number of objects (queued frames, cache entries, ...) held by the
+// subsystems of the server, exposed by /memory.
+pub fn set_objects(server: &str, objects: BTreeMap<&'static str, usize>) {
+    update(server, |s| s.objects = objects);
+}
+
+pub fn remove(server: &str) {
+    STATUS.lock().unwrap().servers.remove(server);
+}
+
+pub fn start(bind: String) {
+    info!("Starting status server, bind: {}", bind);
+    let listener = TcpListener::bind(bind).expect("bind status server error");
+    serve(listener);
+}
+
+fn serve(listener: TcpListener) {
+    let connections = Arc::new(AtomicUsize::new(0));
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => match Connection::acquire(&connections) {
+                Some(conn) => {
+                    thread::spawn(move || {
+                        handle_request(stream);
+                        drop(conn);
+                    });
+                }
+                None => {
+                    warn!("Too many status connections, closing connection");
+                }
+            },
+            Err(err) => {
+                error!("Unable to connect, error: {}", err);
+            }
+        }
+    }
+}
+
+fn handle_request(mut stream: TcpStream) {
+    if let Err(err) = stream
+        .set_read_timeout(Some(IO_TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(IO_TIMEOUT)))
+    {
+        error!("Set status connection timeout error: {}", err);
+        return;
+    }
+
+    let request = match read_request(&mut stream) {
+        Ok(v) => v,
+        Err(err) => {
+            error!("Read http request error: {}", err);
+            return;
+        }
+    };
+
+    // e.g. "GET /health HTTP/1.1"
+    let request = String::from_utf8_lossy(&request);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+
+    let (status_line, body) = match path {
+        "/health" => {
+            if concentratord_connected(&STATUS.lock().unwrap()) {
+                ("200 OK", json!({"status": "ok"}))
+            } else {
+                (
+                    "503 Service Unavailable",
+                    json!({"status": "concentratord unreachable"}),
+                )
+            }
+        }
+        "/status" => ("200 OK", get_status()),
+        "/usage" => ("200 OK", json!(usage::get())),
+        "/memory" => ("200 OK", get_memory()),
+        _ => ("404 Not Found", json!({"error": "not found"})),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nConnection: close\r\n\r\n{}",
+        status_line, body
+    );
+
+    if let Err(err) = stream.write_all(response.as_bytes()) {
+        error!("Write status response error: {}", err);
+    }
+}
+
+// Reads the request head, up to the empty line terminating the headers. The
+// request is read in multiple reads when the client sends it in parts.
+fn read_request<R: Read>(r: &mut R) -> std::io::Result<Vec<u8>> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+
+    loop {
+        let size = r.read(&mut buffer)?;
+        if size == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "connection closed before end of request",
+            ));
+        }
+        request.extend_from_slice(&buffer[..size]);
+
+        if request.windows(4).any(|w| w == b"\r\n\r\n") {
+            return Ok(request);
+        }
+        if request.len() > MAX_REQUEST_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "request exceeds max. size",
+            ));
+        }
+    }
+}
+
+fn update<F: FnOnce(&mut ServerStatus)>(server: &str, f: F) {
+    let mut status = STATUS.lock().unwrap();
+    f(status.servers.entry(server.to_string()).or_default());
+}
+
+fn concentratord_connected(status: &Status) -> bool {
+    match status.last_event {
+        Some(v) => v.elapsed() < CONCENTRATORD_TIMEOUT,
+        None => false,
+    }
+}
+
+fn get_status() -> serde_json::Value {
+    let status = STATUS.lock().unwrap();
+
+    let servers: Vec<serde_json::Value> = status
+        .servers
+        .iter()
+        .map(|(server, s)| {
+            json!({
+                "server": server,
+                "connected": s.connected,
+                "connection_state": s.connection_state,
+                "last_pull_ack_age_secs": s.last_pull_ack.map(|v| v.elapsed().as_secs()),
+                "buffered": s.buffered,
+            })
+        })
+        .collect();
+
+    json!({
+        "version": config::VERSION,
+        "concentratord": {
+            "connected": concentratord_connected(&status),
+            "last_event_age_secs": status.last_event.map(|v| v.elapsed().as_secs()),
+        },
+        "servers": servers,
+    })
+}
+
+// Memory usage of the process, as reported by the kernel, the heap usage as
+// counted by the global allocator and the number of objects held per server,
+// such that slow leaks can be spotted remotely.
+fn get_memory() -> serde_json::Value {
+    let mut process = serde_json::Map::new();
+    if let Ok(s) = fs::read_to_string("/proc/self/status") {
+        for line in s.lines() {
+            let mut parts = line.split_whitespace();
+            let (key, value) = match (parts.next(), parts.next()) {
+                (Some(k), Some(v)) => (k.trim_end_matches(':'), v),
+                _ => continue,
+            };
+            // e.g. "VmRSS:     2048 kB"
+            if let ("VmRSS" | "VmHWM" | "VmSize" | "VmData", Ok(v)) = (key, value.parse::<u64>()) {
+                process.insert(format!("{}_kb", key.to_lowercase()), json!(v));
+            }
+        }
+    }
+
+    let status = STATUS.lock().unwrap();
+    let servers: Vec<serde_json::Value> = status
+        .servers
+        .iter()
+        .map(|(server, s)| {
+            json!({
+                "server": server,
+                "objects": s.objects,
+            })
+        })
+        .collect();
+
+    let heap = allocator::stats();
+
+    json!({
+        "process": process,
+        "heap": {
+            "allocated_bytes": heap.allocated,
+            "peak_bytes": heap.peak,
+            "allocations": heap.allocations,
+            "live_allocations": heap.live,
+        },
+        "usage_days": usage::get().len(),
+        "servers": servers,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(addr: std::net::SocketAddr, request: &[&[u8]]) -> (String, serde_json::Value) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        for part in request {
+            stream.write_all(part).unwrap();
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (
+            head.lines().next().unwrap().to_string(),
+            serde_json::from_str(body).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_read_request() {
+        let tests: Vec<(&[u8], Option<&str>)> = vec![
+            (
+                b"GET /status HTTP/1.1\r\nHost: localhost\r\n\r\n",
+                Some("/status"),
+            ),
+            (b"GET /status HTTP/1.1\r\nHost: localhost\r\n", None),
+            (b"", None),
+        ];
+
+        for (input, path) in tests {
+            let mut r = input;
+            let out = read_request(&mut r).ok();
+            assert_eq!(
+                out.as_ref()
+                    .map(|v| String::from_utf8_lossy(v)
+                        .split_whitespace()
+                        .nth(1)
+                        .unwrap()
+                        .to_string())
+                    .as_deref(),
+                path
+            );
+        }
+
+        let mut large = b"GET / HTTP/1.1\r\n".to_vec();
+        large.resize(MAX_REQUEST_SIZE * 2, b'a');
+        let mut r = &large[..];
+        assert_eq!(
+            read_request(&mut r).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve(listener));
+
+        // The request is sent in parts.
+        let (status, body) = get(
+            addr,
+            &[b"GET /memory HTTP/1.1\r\n", b"Host: localhost\r\n\r\n"],
+        );
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(body["heap"]["allocated_bytes"].as_u64().unwrap() > 0);
+        assert!(body["heap"]["live_allocations"].as_u64().unwrap() > 0);
+
+        let (status, body) = get(addr, &[b"GET /unknown HTTP/1.1\r\n\r\n"]);
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+        assert_eq!(body, json!({"error": "not found"}));
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let conns: Vec<Connection> = (0..MAX_CONNECTIONS)
+            .filter_map(|_| Connection::acquire(&connections))
+            .collect();
+        assert_eq!(conns.len(), MAX_CONNECTIONS);
+        assert!(Connection::acquire(&connections).is_none());
+
+        drop(conns);
+        assert_eq!(connections.load(Ordering::SeqCst), 0);
+        assert!(Connection::acquire(&connections).is_some());
+    }
+}
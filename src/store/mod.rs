@@ -0,0 +1,350 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+
+use super::config::{Server, StoreBackend};
+
+#[cfg(feature = "redis")]
+mod redis_store;
+#[cfg(feature = "sled")]
+mod sled_store;
+
+// Min. number of records written before the file is compacted.
+const COMPACT_MIN_WRITTEN: usize = 64;
+
+// Key-value store of which each entry expires after its TTL, keeping the
+// forwarder state (e.g. the replay cache). The backend is configurable, such
+// that the state survives a restart or is shared by multiple hosts.
+pub trait StateStore: Send {
+    // Returns the value, None when not found or expired.
+    fn get(&mut self, key: &[u8], now: SystemTime) -> Option<Vec<u8>>;
+
+    fn put(&mut self, key: &[u8], value: &[u8], ttl: Duration, now: SystemTime);
+
+    fn delete(&mut self, key: &[u8]);
+
+    // Returns the entries (not expired) of which the key starts with the
+    // prefix, ordered by key.
+    fn scan(&mut self, prefix: &[u8], now: SystemTime) -> Vec<(Vec<u8>, Vec<u8>)>;
+}
+
+// Store shared by the caches of a forwarder.
+pub type SharedStore = Arc<Mutex<Box<dyn StateStore>>>;
+
+// Opens the store configured for the server. The namespace separates the keys
+// of the forwarders sharing a (Redis) store.
+pub fn new(conf: &Server, namespace: &str) -> Result<SharedStore> {
+    let store: Box<dyn StateStore> = match conf.store_backend {
+        StoreBackend::Memory => Box::new(MemoryStore::default()),
+        StoreBackend::File => Box::new(FileStore::new(PathBuf::from(&conf.store_path))?),
+        StoreBackend::Sled => open_sled(&conf.store_path)?,
+        StoreBackend::Redis => open_redis(&conf.store_url, namespace)?,
+    };
+
+    Ok(Arc::new(Mutex::new(store)))
+}
+
+#[cfg(feature = "sled")]
+fn open_sled(path: &str) -> Result<Box<dyn StateStore>> {
+    Ok(Box::new(sled_store::SledStore::new(path)?))
+}
+
+#[cfg(not(feature = "sled"))]
+fn open_sled(_path: &str) -> Result<Box<dyn StateStore>> {
+    Err(anyhow!(
+        "store backend SLED requires the sled feature, which is not enabled in this build"
+    ))
+}
+
+#[cfg(feature = "redis")]
+fn open_redis(url: &str, namespace: &str) -> Result<Box<dyn StateStore>> {
+    Ok(Box::new(redis_store::RedisStore::new(url, namespace)?))
+}
+
+#[cfg(not(feature = "redis"))]
+fn open_redis(_url: &str, _namespace: &str) -> Result<Box<dyn StateStore>> {
+    Err(anyhow!(
+        "store backend REDIS requires the redis feature, which is not enabled in this build"
+    ))
+}
+
+// Returns the expiry time as milliseconds since the UNIX epoch, as stored by
+// the persistent backends.
+fn to_millis(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    entries: BTreeMap<Vec<u8>, (Vec<u8>, SystemTime)>,
+    // The keys ordered by expiry time, such that the expired entries are
+    // removed without iterating over all the entries.
+    expiry: BTreeSet<(SystemTime, Vec<u8>)>,
+}
+
+impl MemoryStore {
+    fn insert(&mut self, key: Vec<u8>, value: Vec<u8>, expires_at: SystemTime) {
+        if let Some((_, old)) = self.entries.insert(key.clone(), (value, expires_at)) {
+            self.expiry.remove(&(old, key.clone()));
+        }
+        self.expiry.insert((expires_at, key));
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some((_, expires_at)) = self.entries.remove(key) {
+            self.expiry.remove(&(expires_at, key.to_vec()));
+        }
+    }
+
+    fn purge(&mut self, now: SystemTime) {
+        loop {
+            match self.expiry.first() {
+                Some((expires_at, _)) if *expires_at <= now => {}
+                _ => return,
+            }
+
+            if let Some((_, key)) = self.expiry.pop_first() {
+                self.entries.remove(&key);
+            }
+        }
+    }
+}
+
+impl StateStore for MemoryStore {
+    fn get(&mut self, key: &[u8], now: SystemTime) -> Option<Vec<u8>> {
+        match self.entries.get(key) {
+            Some((value, expires_at)) if *expires_at > now => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8], ttl: Duration, now: SystemTime) {
+        self.purge(now);
+        self.insert(key.to_vec(), value.to_vec(), now + ttl);
+    }
+
+    fn delete(&mut self, key: &[u8]) {
+        self.remove(key);
+    }
+
+    fn scan(&mut self, prefix: &[u8], now: SystemTime) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.entries
+            .range(prefix.to_vec()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .filter(|(_, (_, expires_at))| *expires_at > now)
+            .map(|(k, (v, _))| (k.clone(), v.clone()))
+            .collect()
+    }
+}
+
+// Memory store which is also written to disk as an append-only file, one JSON
+// encoded (key, value, expires_at) tuple per line, so that the state survives
+// a restart. A deleted entry is written as an already expired record.
+pub struct FileStore {
+    memory: MemoryStore,
+    path: PathBuf,
+    // Number of lines written to the file since it was last rewritten.
+    written: usize,
+}
+
+impl FileStore {
+    pub fn new(path: PathBuf) -> Result<Self> {
+        let mut s = FileStore {
+            memory: MemoryStore::default(),
+            path,
+            written: 0,
+        };
+        s.load()?;
+        Ok(s)
+    }
+
+    fn load(&mut self) -> Result<()> {
+        let f = match File::open(&self.path) {
+            Ok(v) => v,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        for line in BufReader::new(f).lines() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+
+            match parse_record(&line) {
+                Ok((key, value, expires_at)) => self.memory.insert(key, value, expires_at),
+                Err(e) => warn!(
+                    "Skipping invalid state store record, path: {}, error: {}",
+                    self.path.display(),
+                    e
+                ),
+            }
+        }
+
+        self.memory.purge(SystemTime::now());
+
+        info!(
+            "State store loaded from disk, path: {}, count: {}",
+            self.path.display(),
+            self.memory.entries.len()
+        );
+
+        self.rewrite()
+    }
+
+    fn append(&mut self, key: &[u8], value: &[u8], expires_at: SystemTime) -> Result<()> {
+        // Compact the file once it contains (a lot) more records than the
+        // store, e.g. because of expired entries.
+        if self.written >= (self.memory.entries.len() * 2).max(COMPACT_MIN_WRITTEN) {
+            return self.rewrite();
+        }
+
+        let mut f = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        f.write_all(&record(key, value, expires_at)?)?;
+        self.written += 1;
+
+        Ok(())
+    }
+
+    fn log_error(&self, res: Result<()>) {
+        if let Err(e) = res {
+            error!(
+                "Writing state store to disk error: {}, path: {}",
+                e,
+                self.path.display()
+            );
+        }
+    }
+
+    fn rewrite(&mut self) -> Result<()> {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+
+        let mut b: Vec<u8> = vec![];
+        for (key, (value, expires_at)) in &self.memory.entries {
+            b.extend_from_slice(&record(key, value, *expires_at)?);
+        }
+
+        fs::write(&tmp, &b)?;
+        fs::rename(&tmp, &self.path)?;
+        self.written = self.memory.entries.len();
+
+        Ok(())
+    }
+}
+
+impl StateStore for FileStore {
+    fn get(&mut self, key: &[u8], now: SystemTime) -> Option<Vec<u8>> {
+        self.memory.get(key, now)
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8], ttl: Duration, now: SystemTime) {
+        self.memory.put(key, value, ttl, now);
+
+        let res = self.append(key, value, now + ttl);
+        self.log_error(res);
+    }
+
+    fn delete(&mut self, key: &[u8]) {
+        self.memory.remove(key);
+
+        let res = self.append(key, &[], UNIX_EPOCH);
+        self.log_error(res);
+    }
+
+    fn scan(&mut self, prefix: &[u8], now: SystemTime) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.memory.scan(prefix, now)
+    }
+}
+
+fn record(key: &[u8], value: &[u8], expires_at: SystemTime) -> Result<Vec<u8>> {
+    let mut line =
+        serde_json::to_vec(&(hex::encode(key), hex::encode(value), to_millis(expires_at)))?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+fn parse_record(line: &str) -> Result<(Vec<u8>, Vec<u8>, SystemTime)> {
+    let (key, value, expires_at): (String, String, u64) = serde_json::from_str(line)?;
+    Ok((
+        hex::decode(key)?,
+        hex::decode(value)?,
+        UNIX_EPOCH + Duration::from_millis(expires_at),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_store() {
+        let mut s = MemoryStore::default();
+        let now = SystemTime::now();
+
+        s.put(b"a", b"one", Duration::from_secs(1), now);
+        s.put(b"b", b"two", Duration::from_secs(10), now);
+        s.put(b"a", b"three", Duration::from_secs(20), now);
+        assert_eq!(s.expiry.len(), 2);
+
+        // The expired entries are removed on the next put.
+        let later = now + Duration::from_secs(15);
+        assert_eq!(s.get(b"b", later), None);
+        s.put(b"c", b"four", Duration::from_secs(1), later);
+        assert_eq!(s.entries.len(), 2);
+        assert_eq!(s.expiry.len(), 2);
+        assert_eq!(s.get(b"a", later), Some(b"three".to_vec()));
+
+        s.delete(b"a");
+        assert_eq!(s.get(b"a", later), None);
+        assert_eq!(s.expiry.len(), 1);
+    }
+
+    #[test]
+    fn test_file_store() {
+        let path = std::env::temp_dir().join(format!("store-test-{}", std::process::id()));
+        let now = SystemTime::now();
+
+        let mut s = FileStore::new(path.clone()).unwrap();
+        s.put(b"a/1", b"one", Duration::from_secs(60), now);
+        s.put(b"a/2", b"two", Duration::from_secs(60), now);
+        s.put(b"b/1", b"three", Duration::from_secs(60), now);
+        s.put(b"b/2", b"deleted", Duration::from_secs(60), now);
+        s.delete(b"b/2");
+        s.put(
+            b"a/3",
+            b"expired",
+            Duration::from_secs(1),
+            now - Duration::from_secs(10),
+        );
+
+        let later = now + Duration::from_secs(1);
+        assert_eq!(s.get(b"a/1", later), Some(b"one".to_vec()));
+        assert_eq!(s.get(b"a/3", later), None);
+        assert_eq!(
+            s.scan(b"a/", later),
+            vec![
+                (b"a/1".to_vec(), b"one".to_vec()),
+                (b"a/2".to_vec(), b"two".to_vec())
+            ]
+        );
+
+        // The entries are restored from disk, corrupt records are skipped.
+        let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+        f.write_all(b"[\"zz\",\"00\",0]\nnot json\n").unwrap();
+        let mut s = FileStore::new(path.clone()).unwrap();
+        assert_eq!(s.get(b"b/1", later), Some(b"three".to_vec()));
+        assert_eq!(s.get(b"b/2", later), None);
+        assert_eq!(s.memory.entries.len(), 3);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
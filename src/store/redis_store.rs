@@ -0,0 +1,137 @@
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+
+use super::StateStore;
+
+// Timeout for connecting to the Redis server.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Store backed by a Redis server, e.g. to share the state between multiple
+// hosts. The keys are prefixed by the namespace, the expiry is handled by
+// Redis (using its own clock, the now argument is ignored).
+pub struct RedisStore {
+    client: redis::Client,
+    conn: Option<redis::Connection>,
+    namespace: Vec<u8>,
+}
+
+impl RedisStore {
+    pub fn new(url: &str, namespace: &str) -> Result<Self> {
+        let client = redis::Client::open(url)?;
+        let conn = client.get_connection_with_timeout(CONNECT_TIMEOUT)?;
+
+        info!("State store connected, url: {}", url);
+
+        Ok(RedisStore {
+            client,
+            conn: Some(conn),
+            namespace: format!("{}/", namespace).into_bytes(),
+        })
+    }
+
+    fn key(&self, key: &[u8]) -> Vec<u8> {
+        let mut b = self.namespace.clone();
+        b.extend_from_slice(key);
+        b
+    }
+
+    // Runs the function using the connection, which is re-connected on the
+    // next call after an error.
+    fn with_conn<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut redis::Connection) -> redis::RedisResult<T>,
+    {
+        let mut conn = match self.conn.take() {
+            Some(v) => v,
+            None => self.client.get_connection_with_timeout(CONNECT_TIMEOUT)?,
+        };
+
+        let out = f(&mut conn)?;
+        self.conn = Some(conn);
+        Ok(out)
+    }
+
+    fn try_scan(&mut self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut pattern = vec![];
+        for b in self.key(prefix) {
+            if matches!(b, b'*' | b'?' | b'[' | b']' | b'\\') {
+                pattern.push(b'\\');
+            }
+            pattern.push(b);
+        }
+        pattern.push(b'*');
+
+        let mut keys: Vec<Vec<u8>> = self.with_conn(|c| {
+            let mut keys = vec![];
+            let mut cursor: u64 = 0;
+            loop {
+                let (next, batch): (u64, Vec<Vec<u8>>) = redis::cmd("SCAN")
+                    .arg(cursor)
+                    .arg("MATCH")
+                    .arg(&pattern)
+                    .arg("COUNT")
+                    .arg(100)
+                    .query(c)?;
+                keys.extend(batch);
+                if next == 0 {
+                    return Ok(keys);
+                }
+                cursor = next;
+            }
+        })?;
+        keys.sort();
+        keys.dedup();
+
+        let mut out = vec![];
+        for key in keys {
+            // The key might have expired since the SCAN.
+            let value: Option<Vec<u8>> =
+                self.with_conn(|c| redis::cmd("GET").arg(&key).query(c))?;
+            if let Some(v) = value {
+                out.push((key[self.namespace.len()..].to_vec(), v));
+            }
+        }
+        Ok(out)
+    }
+}
+
+impl StateStore for RedisStore {
+    fn get(&mut self, key: &[u8], _now: SystemTime) -> Option<Vec<u8>> {
+        let key = self.key(key);
+        self.with_conn(|c| redis::cmd("GET").arg(key).query(c))
+            .unwrap_or_else(|e| {
+                error!("Reading state store error: {}", e);
+                None
+            })
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8], ttl: Duration, _now: SystemTime) {
+        let key = self.key(key);
+        let ttl = (ttl.as_millis() as u64).max(1);
+        if let Err(e) = self.with_conn(|c| {
+            redis::cmd("SET")
+                .arg(key)
+                .arg(value)
+                .arg("PX")
+                .arg(ttl)
+                .query::<()>(c)
+        }) {
+            error!("Writing state store error: {}", e);
+        }
+    }
+
+    fn delete(&mut self, key: &[u8]) {
+        let key = self.key(key);
+        if let Err(e) = self.with_conn(|c| redis::cmd("DEL").arg(key).query::<()>(c)) {
+            error!("Writing state store error: {}", e);
+        }
+    }
+
+    fn scan(&mut self, prefix: &[u8], _now: SystemTime) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.try_scan(prefix).unwrap_or_else(|e| {
+            error!("Reading state store error: {}", e);
+            vec![]
+        })
+    }
+}
@@ -0,0 +1,202 @@
+use std::convert::TryInto;
+use std::io::ErrorKind;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+
+use super::{to_millis, StateStore};
+
+// The lock of a database which has just been closed (e.g. on a forwarder
+// restart) is released in the background, opening is retried meanwhile.
+const OPEN_RETRIES: u32 = 20;
+const OPEN_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+// Store backed by a sled database. The values are prefixed with their expiry
+// time (BE milliseconds since the UNIX epoch). A second tree indexes the keys
+// by expiry time, such that the expired entries are removed in order.
+pub struct SledStore {
+    entries: sled::Tree,
+    expiry: sled::Tree,
+}
+
+impl SledStore {
+    pub fn new(path: &str) -> Result<Self> {
+        let db = open(path)?;
+        let s = SledStore {
+            entries: db.open_tree("entries")?,
+            expiry: db.open_tree("expiry")?,
+        };
+
+        info!(
+            "State store opened, path: {}, count: {}",
+            path,
+            s.entries.len()
+        );
+
+        Ok(s)
+    }
+
+    fn try_get(&self, key: &[u8], now: SystemTime) -> Result<Option<Vec<u8>>> {
+        Ok(match self.entries.get(key)? {
+            Some(v) => decode_value(&v, now).map(|v| v.to_vec()),
+            None => None,
+        })
+    }
+
+    fn try_put(&self, key: &[u8], value: &[u8], ttl: Duration, now: SystemTime) -> Result<()> {
+        self.purge(now)?;
+
+        let expires_at = to_millis(now + ttl).to_be_bytes();
+        let mut b = expires_at.to_vec();
+        b.extend_from_slice(value);
+
+        if let Some(old) = self.entries.insert(key, b)? {
+            if old.len() >= 8 {
+                self.expiry.remove(expiry_key(&old[..8], key))?;
+            }
+        }
+        self.expiry.insert(expiry_key(&expires_at, key), &[])?;
+
+        Ok(())
+    }
+
+    fn try_delete(&self, key: &[u8]) -> Result<()> {
+        if let Some(old) = self.entries.remove(key)? {
+            if old.len() >= 8 {
+                self.expiry.remove(expiry_key(&old[..8], key))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn try_scan(&self, prefix: &[u8], now: SystemTime) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut out = vec![];
+        for kv in self.entries.scan_prefix(prefix) {
+            let (k, v) = kv?;
+            if let Some(v) = decode_value(&v, now) {
+                out.push((k.to_vec(), v.to_vec()));
+            }
+        }
+        Ok(out)
+    }
+
+    fn purge(&self, now: SystemTime) -> Result<()> {
+        let now = to_millis(now).to_be_bytes();
+        for k in self.expiry.range(..now.to_vec()) {
+            let (k, _) = k?;
+            self.expiry.remove(&k)?;
+
+            // Do not remove the entry when it was updated in the meantime.
+            let key = &k[8..];
+            if let Some(v) = self.entries.get(key)? {
+                if v.len() >= 8 && v[..8] == k[..8] {
+                    self.entries.remove(key)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StateStore for SledStore {
+    fn get(&mut self, key: &[u8], now: SystemTime) -> Option<Vec<u8>> {
+        self.try_get(key, now).unwrap_or_else(|e| {
+            error!("Reading state store error: {}", e);
+            None
+        })
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8], ttl: Duration, now: SystemTime) {
+        if let Err(e) = self.try_put(key, value, ttl, now) {
+            error!("Writing state store error: {}", e);
+        }
+    }
+
+    fn delete(&mut self, key: &[u8]) {
+        if let Err(e) = self.try_delete(key) {
+            error!("Writing state store error: {}", e);
+        }
+    }
+
+    fn scan(&mut self, prefix: &[u8], now: SystemTime) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.try_scan(prefix, now).unwrap_or_else(|e| {
+            error!("Reading state store error: {}", e);
+            vec![]
+        })
+    }
+}
+
+fn open(path: &str) -> sled::Result<sled::Db> {
+    let mut retries = 0;
+    loop {
+        match sled::open(path) {
+            Err(sled::Error::Io(e))
+                if e.kind() == ErrorKind::WouldBlock && retries < OPEN_RETRIES =>
+            {
+                retries += 1;
+                thread::sleep(OPEN_RETRY_INTERVAL);
+            }
+            v => return v,
+        }
+    }
+}
+
+fn expiry_key(expires_at: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut b = expires_at.to_vec();
+    b.extend_from_slice(key);
+    b
+}
+
+// Returns the value when not expired.
+fn decode_value(b: &[u8], now: SystemTime) -> Option<&[u8]> {
+    if b.len() < 8 {
+        return None;
+    }
+
+    let expires_at = u64::from_be_bytes(b[..8].try_into().unwrap());
+    if expires_at > to_millis(now) {
+        Some(&b[8..])
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sled_store() {
+        let path = std::env::temp_dir().join(format!("sled-store-test-{}", std::process::id()));
+        let now = SystemTime::now();
+
+        {
+            let mut s = SledStore::new(path.to_str().unwrap()).unwrap();
+            s.put(b"a/1", b"one", Duration::from_secs(60), now);
+            s.put(b"a/2", b"two", Duration::from_secs(1), now);
+            s.put(b"a/2", b"three", Duration::from_secs(60), now);
+            s.put(b"b/1", b"four", Duration::from_secs(1), now);
+            s.put(b"b/2", b"five", Duration::from_secs(60), now);
+            s.delete(b"b/2");
+        }
+
+        // The entries are restored, the expired entries are removed.
+        let later = now + Duration::from_secs(10);
+        let mut s = SledStore::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(s.get(b"b/1", later), None);
+        s.put(b"c/1", b"five", Duration::from_secs(60), later);
+        assert_eq!(s.entries.len(), 3);
+        assert_eq!(s.expiry.len(), 3);
+        assert_eq!(
+            s.scan(b"a/", later),
+            vec![
+                (b"a/1".to_vec(), b"one".to_vec()),
+                (b"a/2".to_vec(), b"three".to_vec())
+            ]
+        );
+
+        drop(s);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,160 @@
+use std::fs;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+use base64::{engine::general_purpose, Engine as _};
+use chirpstack_udp_forwarder::protocol::TxPk;
+use serde_json::json;
+
+use super::backend::{self, Backend};
+use super::config;
+use super::downlink;
+use super::helpers;
+
+#[derive(clap::Args)]
+pub struct Args {
+    /// JSON file containing the txpk (or the PULL_RESP payload), the other
+    /// txpk flags are ignored
+    #[arg(short, long, value_name = "FILE")]
+    file: Option<String>,
+
+    /// Frequency (MHz)
+    #[arg(long, default_value_t = 869.525)]
+    freq: f64,
+
+    /// Power (dBm)
+    #[arg(long, default_value_t = 14)]
+    power: u8,
+
+    /// Data-rate (e.g. SF9BW125)
+    #[arg(long, default_value = "SF9BW125")]
+    datr: String,
+
+    /// Code-rate
+    #[arg(long, default_value = "4/5")]
+    codr: String,
+
+    /// RF chain
+    #[arg(long, default_value_t = 0)]
+    rfch: u8,
+
+    /// Polarization inversion (disable to receive the downlink with a
+    /// gateway)
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    ipol: bool,
+
+    /// Payload (hex encoded)
+    #[arg(long, default_value = "48656c6c6f")]
+    payload: String,
+
+    /// Number of downlinks to send
+    #[arg(long, default_value_t = 1)]
+    count: u32,
+
+    /// Interval between the downlinks (ms)
+    #[arg(long, default_value_t = 1000)]
+    interval_ms: u64,
+}
+
+// Crafts the txpk and sends it (immediately, unless set otherwise in the JSON
+// file) to the Concentratord, for on-site TX testing without a network
+// server. The downlink_plan of the configuration is enforced.
+pub fn run(filenames: &[String], args: &Args) -> Result<()> {
+    let config = config::Configuration::get(filenames)?;
+    let txpk = txpk(args)?;
+    let gateway_id = helpers::get_gateway_id(&config.concentratord.command_url)?;
+    let backend = backend::Concentratord::new(
+        &config.concentratord.event_url,
+        &config.concentratord.command_url,
+    )?;
+
+    for i in 0..args.count {
+        if i > 0 {
+            thread::sleep(Duration::from_millis(args.interval_ms));
+        }
+
+        let downlink_id: u32 = rand::random();
+        let pl = txpk.to_proto(downlink_id, gateway_id.clone())?;
+        if let Some((_, reason)) = downlink::check_plan(&pl, &config.udp_forwarder.downlink_plan) {
+            return Err(anyhow!("downlink rejected by downlink_plan: {}", reason));
+        }
+
+        let tx_ack = backend
+            .send_downlink(&pl)
+            .map_err(|(reason, e)| anyhow!("send downlink error: {}, reason: {}", e, reason))?;
+        let (_, status) = downlink::get_tx_ack_status(&tx_ack)?;
+
+        println!(
+            "Downlink {}/{}, downlink_id: {}, status: {}",
+            i + 1,
+            args.count,
+            downlink_id,
+            status.as_str_name()
+        );
+    }
+
+    Ok(())
+}
+
+fn txpk(args: &Args) -> Result<TxPk> {
+    let v: serde_json::Value = match &args.file {
+        Some(file_name) => {
+            let content = fs::read_to_string(file_name)
+                .map_err(|e| anyhow!("read txpk file error: {}, file: {}", e, file_name))?;
+            let v: serde_json::Value = serde_json::from_str(&content)?;
+            match v.get("txpk") {
+                Some(txpk) => txpk.clone(),
+                None => v,
+            }
+        }
+        None => {
+            let payload = hex::decode(&args.payload)
+                .map_err(|e| anyhow!("invalid payload: {}, error: {}", args.payload, e))?;
+            json!({
+                "imme": true,
+                "freq": args.freq,
+                "rfch": args.rfch,
+                "powe": args.power,
+                "modu": "LORA",
+                "datr": args.datr,
+                "codr": args.codr,
+                "ipol": args.ipol,
+                "size": payload.len(),
+                "data": general_purpose::STANDARD.encode(&payload),
+            })
+        }
+    };
+
+    serde_json::from_value(v).map_err(|e| anyhow!("invalid txpk: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chirpstack_udp_forwarder::protocol::{DataRate, Modulation};
+
+    #[test]
+    fn test_txpk() {
+        let args = Args {
+            file: None,
+            freq: 869.525,
+            power: 14,
+            datr: "SF9BW125".into(),
+            codr: "4/5".into(),
+            rfch: 0,
+            ipol: false,
+            payload: "48656c6c6f".into(),
+            count: 1,
+            interval_ms: 1000,
+        };
+        let txpk = txpk(&args).unwrap();
+        assert_eq!(txpk.imme, Some(true));
+        assert_eq!(txpk.freq, 869.525);
+        assert!(matches!(txpk.modu, Modulation::Lora));
+        assert!(matches!(txpk.datr, DataRate::Lora(9, 125000)));
+        assert_eq!(txpk.ipol, Some(false));
+        assert_eq!(txpk.size, 5);
+        assert_eq!(txpk.data, "SGVsbG8=");
+    }
+}
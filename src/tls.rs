@@ -0,0 +1,133 @@
+// TLS of the TCP transport. The server certificate is verified against the
+// configured CA certificate(s), or against the built-in (Mozilla) root
+// certificates when none are configured.
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::TcpStream;
+use std::sync::Arc;
+
+use anyhow::Result;
+use rustls::{Certificate, ClientConfig, ClientConnection, OwnedTrustAnchor, RootCertStore};
+
+use super::config;
+
+#[derive(Clone)]
+pub struct Connector {
+    config: Arc<ClientConfig>,
+    server_name: rustls::ServerName,
+}
+
+impl Connector {
+    // Returns the connector of the server (hostname:port), None when TLS is
+    // disabled.
+    pub fn from_config(conf: &config::Tls, server: &str) -> Result<Option<Connector>> {
+        if !conf.enabled {
+            return Ok(None);
+        }
+
+        let mut roots = RootCertStore::empty();
+        match conf.ca_cert.as_str() {
+            "" => roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    ta.subject,
+                    ta.spki,
+                    ta.name_constraints,
+                )
+            })),
+            path => {
+                let certs = File::open(path)
+                    .and_then(|f| rustls_pemfile::certs(&mut BufReader::new(f)))
+                    .map_err(|e| anyhow!("read tls ca_cert error: {}, path: {}", e, path))?;
+                if certs.is_empty() {
+                    return Err(anyhow!("tls ca_cert contains no certificates: {}", path));
+                }
+                for cert in certs {
+                    roots
+                        .add(&Certificate(cert))
+                        .map_err(|e| anyhow!("invalid tls ca_cert: {}, path: {}", e, path))?;
+                }
+            }
+        }
+
+        let server_name = match conf.server_name.as_str() {
+            "" => host(server),
+            v => v,
+        };
+
+        Ok(Some(Connector {
+            config: Arc::new(
+                ClientConfig::builder()
+                    .with_safe_defaults()
+                    .with_root_certificates(roots)
+                    .with_no_client_auth(),
+            ),
+            server_name: rustls::ServerName::try_from(server_name)
+                .map_err(|e| anyhow!("invalid tls server_name: {}, error: {}", server_name, e))?,
+        }))
+    }
+
+    // Performs the TLS handshake on the connected stream, within the read and
+    // write timeouts of the stream.
+    pub fn connect(&self, tcp: &TcpStream) -> io::Result<ClientConnection> {
+        let mut conn = ClientConnection::new(self.config.clone(), self.server_name.clone())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut &*tcp)?;
+        }
+        Ok(conn)
+    }
+}
+
+// Returns the host of the server (hostname:port, or [IPv6]:port).
+fn host(server: &str) -> &str {
+    let host = match server.rsplit_once(':') {
+        Some((host, _)) => host,
+        None => server,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host() {
+        assert_eq!(host("example.com:1700"), "example.com");
+        assert_eq!(host("[::1]:1700"), "::1");
+        assert_eq!(host("127.0.0.1"), "127.0.0.1");
+    }
+
+    #[test]
+    fn test_from_config() {
+        assert!(
+            Connector::from_config(&config::Tls::default(), "localhost:1700")
+                .unwrap()
+                .is_none()
+        );
+
+        let conf = config::Tls {
+            enabled: true,
+            ..Default::default()
+        };
+        assert!(Connector::from_config(&conf, "localhost:1700")
+            .unwrap()
+            .is_some());
+
+        let conf = config::Tls {
+            enabled: true,
+            ca_cert: "/does/not/exist.pem".into(),
+            ..Default::default()
+        };
+        assert!(Connector::from_config(&conf, "localhost:1700").is_err());
+
+        let conf = config::Tls {
+            enabled: true,
+            server_name: "not a hostname".into(),
+            ..Default::default()
+        };
+        assert!(Connector::from_config(&conf, "localhost:1700").is_err());
+    }
+}
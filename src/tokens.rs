@@ -0,0 +1,48 @@
+use std::sync::Mutex;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+// Generates the random tokens of the PUSH_DATA and PULL_DATA datagrams. When
+// seeded, the token sequence is deterministic, such that (integration) tests
+// and captured-traffic replays produce identical datagrams.
+pub enum TokenGenerator {
+    Random,
+    Seeded(Box<Mutex<StdRng>>),
+}
+
+impl TokenGenerator {
+    // A seed of 0 selects random tokens.
+    pub fn new(seed: u64) -> Self {
+        match seed {
+            0 => TokenGenerator::Random,
+            _ => TokenGenerator::Seeded(Box::new(Mutex::new(StdRng::seed_from_u64(seed)))),
+        }
+    }
+
+    pub fn next(&self) -> u16 {
+        match self {
+            TokenGenerator::Random => rand::thread_rng().gen(),
+            TokenGenerator::Seeded(rng) => rng.lock().unwrap().gen(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_generator() {
+        let a = TokenGenerator::new(42);
+        let b = TokenGenerator::new(42);
+
+        let a: Vec<u16> = (0..10).map(|_| a.next()).collect();
+        let b: Vec<u16> = (0..10).map(|_| b.next()).collect();
+        assert_eq!(a, b);
+
+        let c = TokenGenerator::new(43);
+        let c: Vec<u16> = (0..10).map(|_| c.next()).collect();
+        assert_ne!(a, c);
+    }
+}
@@ -0,0 +1,487 @@
+use std::io;
+use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+// Max. number of attempts and the interval between these, when sending fails
+// with a transient error.
+const SEND_ATTEMPTS: usize = 3;
+const SEND_RETRY_INTERVAL: Duration = Duration::from_millis(5);
+
+// Local address and options of an UDP socket.
+#[derive(Default)]
+pub struct BindOptions {
+    // When None, a dual-stack socket is bound to a random port.
+    pub addr: Option<SocketAddr>,
+    // Network interface to bind to (SO_BINDTODEVICE), e.g. eth0.
+    pub interface: String,
+    // Allows other sockets to bind to the same address and port.
+    pub reuse_port: bool,
+}
+
+// Binds the UDP socket. A dual-stack socket (also bound when the address is
+// the unspecified IPv6 address) can be connected to both IPv6 and
+// (IPv4-mapped) IPv4 addresses. When IPv6 is not available on the host, an
+// IPv4 socket is returned instead.
+#[cfg(target_os = "linux")]
+pub fn bind(opts: &BindOptions) -> io::Result<UdpSocket> {
+    use std::os::unix::io::FromRawFd;
+
+    let addr = opts
+        .addr
+        .unwrap_or_else(|| SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0).into());
+    let family = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+
+    let fd = unsafe { libc::socket(family, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        let e = io::Error::last_os_error();
+        if opts.addr.is_none() && e.raw_os_error() == Some(libc::EAFNOSUPPORT) {
+            return bind(&BindOptions {
+                addr: Some(SocketAddr::from(([0, 0, 0, 0], 0))),
+                interface: opts.interface.clone(),
+                reuse_port: opts.reuse_port,
+            });
+        }
+        return Err(e);
+    }
+
+    // Taking ownership, such that the fd is closed on error.
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+
+    if let SocketAddr::V6(v6) = addr {
+        if v6.ip().is_unspecified() {
+            set_option(
+                fd,
+                libc::IPPROTO_IPV6,
+                libc::IPV6_V6ONLY,
+                &(0 as libc::c_int),
+            )?;
+        }
+    }
+    if opts.reuse_port {
+        set_option(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_REUSEPORT,
+            &(1 as libc::c_int),
+        )?;
+    }
+    if !opts.interface.is_empty() {
+        set_option(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            opts.interface.as_bytes(),
+        )?;
+    }
+
+    let ret = match addr {
+        SocketAddr::V4(v4) => {
+            let mut sa: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+            sa.sin_family = libc::AF_INET as libc::sa_family_t;
+            sa.sin_port = v4.port().to_be();
+            sa.sin_addr.s_addr = u32::from_ne_bytes(v4.ip().octets());
+            unsafe {
+                libc::bind(
+                    fd,
+                    &sa as *const libc::sockaddr_in as *const libc::sockaddr,
+                    std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                )
+            }
+        }
+        SocketAddr::V6(v6) => {
+            let mut sa: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+            sa.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sa.sin6_port = v6.port().to_be();
+            sa.sin6_addr.s6_addr = v6.ip().octets();
+            sa.sin6_scope_id = v6.scope_id();
+            unsafe {
+                libc::bind(
+                    fd,
+                    &sa as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                    std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+                )
+            }
+        }
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(socket)
+}
+
+#[cfg(target_os = "linux")]
+fn set_option<T: ?Sized>(
+    fd: libc::c_int,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: &T,
+) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            value as *const T as *const libc::c_void,
+            std::mem::size_of_val(value) as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn bind(opts: &BindOptions) -> io::Result<UdpSocket> {
+    if opts.reuse_port || !opts.interface.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "reuse_port and bind_interface are only supported on Linux",
+        ));
+    }
+
+    match opts.addr {
+        Some(addr) => UdpSocket::bind(addr),
+        None => UdpSocket::bind("[::]:0").or_else(|_| UdpSocket::bind("0.0.0.0:0")),
+    }
+}
+
+// Socket options of an UDP socket, 0 keeps the system default.
+#[derive(Default)]
+pub struct SocketOptions {
+    // DSCP value (0 - 63) of the outgoing datagrams.
+    pub dscp: u8,
+    pub send_buffer_size: usize,
+    pub recv_buffer_size: usize,
+}
+
+// Sets the socket options and returns the applied (DSCP, send buffer size,
+// receive buffer size). Note that Linux doubles the requested buffer sizes.
+#[cfg(target_os = "linux")]
+pub fn set_options(socket: &UdpSocket, opts: &SocketOptions) -> io::Result<(u8, usize, usize)> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = socket.as_raw_fd();
+    if opts.dscp != 0 {
+        let tos = (opts.dscp as libc::c_int) << 2;
+        // A dual-stack socket uses IP_TOS for IPv4(-mapped) peers.
+        set_option(fd, libc::IPPROTO_IP, libc::IP_TOS, &tos)?;
+        if socket.local_addr()?.is_ipv6() {
+            set_option(fd, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, &tos)?;
+        }
+    }
+    if opts.send_buffer_size != 0 {
+        let size = opts.send_buffer_size as libc::c_int;
+        set_option(fd, libc::SOL_SOCKET, libc::SO_SNDBUF, &size)?;
+    }
+    if opts.recv_buffer_size != 0 {
+        let size = opts.recv_buffer_size as libc::c_int;
+        set_option(fd, libc::SOL_SOCKET, libc::SO_RCVBUF, &size)?;
+    }
+
+    Ok((
+        (get_option(fd, libc::IPPROTO_IP, libc::IP_TOS)? >> 2) as u8,
+        get_option(fd, libc::SOL_SOCKET, libc::SO_SNDBUF)? as usize,
+        get_option(fd, libc::SOL_SOCKET, libc::SO_RCVBUF)? as usize,
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn get_option(fd: libc::c_int, level: libc::c_int, name: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            name,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(value)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_options(_socket: &UdpSocket, opts: &SocketOptions) -> io::Result<(u8, usize, usize)> {
+    if opts.dscp != 0 || opts.send_buffer_size != 0 || opts.recv_buffer_size != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "dscp and socket buffer sizes are only supported on Linux",
+        ));
+    }
+
+    Ok((0, 0, 0))
+}
+
+// Connects the socket to the address, IPv4 addresses are mapped to IPv6 when
+// the socket is an IPv6 (dual-stack) socket.
+pub fn connect(socket: &UdpSocket, addr: SocketAddr) -> io::Result<()> {
+    socket.connect(map(socket, addr)?)
+}
+
+// Returns true when the address is routable (e.g. an IPv6 address might not
+// be on an IPv4-only backhaul), by connecting a temporary socket.
+pub fn is_routable(addr: SocketAddr) -> bool {
+    bind(&BindOptions::default())
+        .and_then(|socket| connect(&socket, addr))
+        .is_ok()
+}
+
+fn map(socket: &UdpSocket, addr: SocketAddr) -> io::Result<SocketAddr> {
+    Ok(match (socket.local_addr()?, addr) {
+        (SocketAddr::V6(_), SocketAddr::V4(v4)) => {
+            SocketAddrV6::new(v4.ip().to_ipv6_mapped(), v4.port(), 0, 0).into()
+        }
+        _ => addr,
+    })
+}
+
+// Returns the peer address of the socket, IPv4-mapped IPv6 addresses are
+// returned as IPv4 address.
+pub fn peer_addr(socket: &UdpSocket) -> io::Result<SocketAddr> {
+    Ok(unmap(socket.peer_addr()?))
+}
+
+fn unmap(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), v6.port()),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+// Enables the kernel receive timestamps (SO_TIMESTAMPNS) on the socket. On
+// other platforms this is a no-op and the datagrams are stamped in userspace.
+#[cfg(target_os = "linux")]
+pub fn enable_timestamps(socket: &UdpSocket) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let enable: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_TIMESTAMPNS,
+            &enable as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn enable_timestamps(_socket: &UdpSocket) -> io::Result<()> {
+    Ok(())
+}
+
+// Receives a datagram and returns its size, receive time and source address
+// (IPv4-mapped IPv6 addresses are returned as IPv4 address). The kernel
+// timestamp is used when available, else the current time.
+#[cfg(target_os = "linux")]
+pub fn recv(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SystemTime, SocketAddr)> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+
+    // Large enough for the SCM_TIMESTAMPNS control message, u64 for alignment.
+    let mut control: [u64; 8] = [0; 8];
+
+    let mut source: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_name = &mut source as *mut libc::sockaddr_storage as *mut libc::c_void;
+    msg.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = std::mem::size_of_val(&control) as _;
+
+    let size = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut received_at = SystemTime::now();
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_TIMESTAMPNS
+            {
+                let ts = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::timespec);
+                received_at =
+                    SystemTime::UNIX_EPOCH + Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32);
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    let source = match source.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let sa = unsafe { &*(&source as *const _ as *const libc::sockaddr_in) };
+            SocketAddr::from((sa.sin_addr.s_addr.to_ne_bytes(), u16::from_be(sa.sin_port)))
+        }
+        libc::AF_INET6 => {
+            let sa = unsafe { &*(&source as *const _ as *const libc::sockaddr_in6) };
+            SocketAddrV6::new(
+                sa.sin6_addr.s6_addr.into(),
+                u16::from_be(sa.sin6_port),
+                sa.sin6_flowinfo,
+                sa.sin6_scope_id,
+            )
+            .into()
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected source address family",
+            ))
+        }
+    };
+
+    Ok((size as usize, received_at, unmap(source)))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn recv(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SystemTime, SocketAddr)> {
+    let (size, source) = socket.recv_from(buf)?;
+    Ok((size, SystemTime::now(), unmap(source)))
+}
+
+// Returns true when the error is expected to be transient, e.g. when the
+// socket buffer is full or the route is (temporarily) unavailable.
+pub fn is_transient(e: &io::Error) -> bool {
+    if matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+    ) {
+        return true;
+    }
+
+    matches!(
+        e.raw_os_error(),
+        Some(libc::ENETUNREACH) | Some(libc::EHOSTUNREACH) | Some(libc::ENOBUFS)
+    )
+}
+
+// Sends the datagram to the address, or to the connected address when None,
+// retrying a few times on transient errors.
+pub fn send_with_retry(
+    socket: &UdpSocket,
+    buf: &[u8],
+    addr: Option<SocketAddr>,
+) -> io::Result<usize> {
+    let addr = match addr {
+        Some(v) => Some(map(socket, v)?),
+        None => None,
+    };
+
+    let mut attempt = 1;
+    loop {
+        let res = match addr {
+            Some(v) => socket.send_to(buf, v),
+            None => socket.send(buf),
+        };
+        match res {
+            Err(e) if attempt < SEND_ATTEMPTS && is_transient(&e) => {
+                attempt += 1;
+                thread::sleep(SEND_RETRY_INTERVAL);
+            }
+            res => return res,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dual_stack() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket = bind(&BindOptions::default()).unwrap();
+        connect(&socket, server.local_addr().unwrap()).unwrap();
+        assert_eq!(peer_addr(&socket).unwrap(), server.local_addr().unwrap());
+
+        socket.send(&[1, 2, 3]).unwrap();
+        let mut b = [0; 3];
+        let (_, addr) = server.recv_from(&mut b).unwrap();
+        assert_eq!(b, [1, 2, 3]);
+
+        // The source of the received datagram is returned as IPv4 address.
+        server.send_to(&[3, 2, 1], addr).unwrap();
+        let (size, _, source) = recv(&socket, &mut b).unwrap();
+        assert_eq!(size, 3);
+        assert_eq!(source, server.local_addr().unwrap());
+
+        // Unconnected socket.
+        let socket = bind(&BindOptions::default()).unwrap();
+        send_with_retry(&socket, &[4, 5, 6], Some(server.local_addr().unwrap())).unwrap();
+        server.recv(&mut b).unwrap();
+        assert_eq!(b, [4, 5, 6]);
+    }
+
+    #[test]
+    fn test_reuse_port() {
+        let opts = BindOptions {
+            addr: Some("127.0.0.1:0".parse().unwrap()),
+            reuse_port: true,
+            ..Default::default()
+        };
+        let a = bind(&opts).unwrap();
+        let opts = BindOptions {
+            addr: Some(a.local_addr().unwrap()),
+            ..opts
+        };
+        let b = bind(&opts).unwrap();
+        assert_eq!(a.local_addr().unwrap(), b.local_addr().unwrap());
+    }
+
+    #[test]
+    fn test_set_options() {
+        let socket = bind(&BindOptions::default()).unwrap();
+        let (dscp, send_buffer_size, recv_buffer_size) = set_options(
+            &socket,
+            &SocketOptions {
+                dscp: 46,
+                send_buffer_size: 65536,
+                recv_buffer_size: 65536,
+            },
+        )
+        .unwrap();
+        assert_eq!(dscp, 46);
+        assert!(send_buffer_size >= 65536);
+        assert!(recv_buffer_size >= 65536);
+    }
+
+    #[test]
+    fn test_is_transient() {
+        assert!(is_transient(&io::Error::from(io::ErrorKind::WouldBlock)));
+        assert!(is_transient(&io::Error::from_raw_os_error(
+            libc::ENETUNREACH
+        )));
+        assert!(!is_transient(&io::Error::from_raw_os_error(
+            libc::ECONNREFUSED
+        )));
+    }
+}
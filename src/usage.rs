@@ -0,0 +1,151 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::{NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+
+// Interval in which the usage is written to the usage file.
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    static ref USAGE: Mutex<Usage> = Mutex::new(Usage::default());
+}
+
+// Bytes sent and received over UDP, per day (UTC, YYYY-MM-DD) and server.
+#[derive(Default)]
+struct Usage {
+    path: String,
+    retention_days: i64,
+    last_saved: Option<Instant>,
+    days: BTreeMap<String, BTreeMap<String, Bandwidth>>,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq)]
+pub struct Bandwidth {
+    pub sent_bytes: u64,
+    pub received_bytes: u64,
+}
+
+// Sets up the usage accounting, loading the previously saved usage (if any).
+pub fn setup(path: String, retention_days: u32) {
+    let mut usage = USAGE.lock().unwrap();
+    usage.retention_days = retention_days.into();
+
+    if !path.is_empty() {
+        match fs::read(&path) {
+            Ok(b) => match serde_json::from_slice(&b) {
+                Ok(v) => usage.days = v,
+                Err(e) => warn!("Parse usage file error: {}, path: {}", e, path),
+            },
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    warn!("Read usage file error: {}, path: {}", e, path);
+                }
+            }
+        }
+    }
+
+    usage.path = path;
+}
+
+pub fn sent(server: &str, bytes: usize) {
+    add(server, |b| b.sent_bytes += bytes as u64);
+}
+
+pub fn received(server: &str, bytes: usize) {
+    add(server, |b| b.received_bytes += bytes as u64);
+}
+
+// Returns the usage per day and server, for the retained days.
+pub fn get() -> BTreeMap<String, BTreeMap<String, Bandwidth>> {
+    USAGE.lock().unwrap().days.clone()
+}
+
+fn add<F: FnOnce(&mut Bandwidth)>(server: &str, f: F) {
+    let mut usage = USAGE.lock().unwrap();
+    usage.add(Utc::now().date_naive(), server, f);
+
+    if !usage.path.is_empty()
+        && !matches!(usage.last_saved, Some(v) if v.elapsed() <= SAVE_INTERVAL)
+    {
+        usage.last_saved = Some(Instant::now());
+        usage.save();
+    }
+}
+
+impl Usage {
+    fn add<F: FnOnce(&mut Bandwidth)>(&mut self, today: NaiveDate, server: &str, f: F) {
+        let key = today.format("%Y-%m-%d").to_string();
+        if !self.days.contains_key(&key) {
+            let retention_days = self.retention_days;
+            self.days
+                .retain(|day, _| match NaiveDate::parse_from_str(day, "%Y-%m-%d") {
+                    Ok(day) => (today - day).num_days() < retention_days,
+                    Err(_) => false,
+                });
+        }
+
+        f(self
+            .days
+            .entry(key)
+            .or_default()
+            .entry(server.to_string())
+            .or_default());
+    }
+
+    fn save(&self) {
+        let b = match serde_json::to_vec(&self.days) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Serialize usage error: {}", e);
+                return;
+            }
+        };
+
+        // Write to a temporary file first, so that the usage file is not
+        // corrupted when the process is stopped while writing.
+        let tmp = format!("{}.tmp", self.path);
+        if let Err(e) = fs::write(&tmp, b).and_then(|_| fs::rename(&tmp, &self.path)) {
+            error!("Write usage file error: {}, path: {}", e, self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usage() {
+        let mut usage = Usage {
+            retention_days: 2,
+            ..Default::default()
+        };
+        let day1 = NaiveDate::from_ymd_opt(2023, 5, 1).unwrap();
+        let day2 = NaiveDate::from_ymd_opt(2023, 5, 2).unwrap();
+        let day3 = NaiveDate::from_ymd_opt(2023, 5, 3).unwrap();
+
+        usage.add(day1, "a", |b| b.sent_bytes += 10);
+        usage.add(day1, "a", |b| b.received_bytes += 5);
+        usage.add(day1, "b", |b| b.sent_bytes += 1);
+        usage.add(day2, "a", |b| b.sent_bytes += 20);
+
+        assert_eq!(
+            Bandwidth {
+                sent_bytes: 10,
+                received_bytes: 5
+            },
+            usage.days["2023-05-01"]["a"]
+        );
+        assert_eq!(20, usage.days["2023-05-02"]["a"].sent_bytes);
+
+        // Day 1 is no longer retained.
+        usage.add(day3, "a", |b| b.sent_bytes += 30);
+        assert_eq!(
+            vec!["2023-05-02", "2023-05-03"],
+            usage.days.keys().collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,271 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+const PACKAGE_NAME: &str = "chirpstack-udp-forwarder";
+const MAINTAINER: &str = "Orne Brocaar <info@brocaar.com>";
+const DESCRIPTION: &str = "ChirpStack UDP Forwarder for Concentratord";
+
+// Files (source relative to the repository, destination relative to the
+// package root) that are installed next to the binary.
+const FILES: &[(&str, &str)] = &[
+    (
+        "packaging/chirpstack-udp-forwarder.toml",
+        "etc/chirpstack-udp-forwarder/chirpstack-udp-forwarder.toml",
+    ),
+    (
+        "packaging/chirpstack-udp-forwarder.service",
+        "lib/systemd/system/chirpstack-udp-forwarder.service",
+    ),
+];
+
+type Result<T> = std::result::Result<T, String>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Ipk,
+    Deb,
+}
+
+impl Format {
+    fn parse(s: &str) -> Result<Self> {
+        match s {
+            "ipk" => Ok(Format::Ipk),
+            "deb" => Ok(Format::Deb),
+            _ => Err(format!("unknown package format: {}", s)),
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Format::Ipk => "ipk",
+            Format::Deb => "deb",
+        }
+    }
+}
+
+// Returns the package architecture for the given Rust target.
+fn package_arch(target: &str, format: Format) -> Result<&'static str> {
+    match (target, format) {
+        ("armv5te-unknown-linux-gnueabi", Format::Ipk) => Ok("arm926ejste"),
+        ("armv5te-unknown-linux-gnueabi", Format::Deb) => Ok("armel"),
+        ("armv7-unknown-linux-gnueabihf", Format::Ipk) => Ok("cortexa7t2hf-neon-vfpv4"),
+        ("armv7-unknown-linux-gnueabihf", Format::Deb) => Ok("armhf"),
+        ("x86_64-unknown-linux-gnu", Format::Ipk) => Ok("x86_64"),
+        ("x86_64-unknown-linux-gnu", Format::Deb) => Ok("amd64"),
+        _ => Err(format!("unsupported target: {}", target)),
+    }
+}
+
+// Returns the strip command for the given Rust target.
+fn strip_command(target: &str) -> String {
+    if let Ok(v) = env::var("STRIP") {
+        return v;
+    }
+
+    match target {
+        "armv5te-unknown-linux-gnueabi" => "arm-linux-gnueabi-strip".into(),
+        "armv7-unknown-linux-gnueabihf" => "arm-linux-gnueabihf-strip".into(),
+        _ => "strip".into(),
+    }
+}
+
+fn package_file_name(version: &str, arch: &str, format: Format) -> String {
+    format!(
+        "{}_{}_{}.{}",
+        PACKAGE_NAME,
+        version,
+        arch,
+        format.extension()
+    )
+}
+
+fn control_file(version: &str, arch: &str) -> String {
+    format!(
+        "Package: {}\nVersion: {}\nArchitecture: {}\nMaintainer: {}\nDescription: {}\nSection: net\nPriority: optional\n",
+        PACKAGE_NAME, version, arch, MAINTAINER, DESCRIPTION
+    )
+}
+
+fn conffiles() -> String {
+    let mut out = String::new();
+    for (_, dest) in FILES {
+        if dest.starts_with("etc/") {
+            out.push_str(&format!("/{}\n", dest));
+        }
+    }
+    out
+}
+
+// Returns the package version from the [package] section of the Cargo.toml.
+fn package_version(cargo_toml: &str) -> Result<String> {
+    let mut in_package = false;
+    for line in cargo_toml.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_package = line == "[package]";
+            continue;
+        }
+
+        if in_package && line.starts_with("version") {
+            if let Some(v) = line.split('"').nth(1) {
+                return Ok(v.to_string());
+            }
+        }
+    }
+
+    Err("version not found in Cargo.toml".into())
+}
+
+fn run(cmd: &mut Command) -> Result<()> {
+    println!("Running: {:?}", cmd);
+    let status = cmd
+        .status()
+        .map_err(|e| format!("execute {:?} error: {}", cmd, e))?;
+    if !status.success() {
+        return Err(format!("{:?} failed: {}", cmd, status));
+    }
+    Ok(())
+}
+
+fn dist(root: &Path, target: &str, format: Format) -> Result<PathBuf> {
+    let version =
+        package_version(&fs::read_to_string(root.join("Cargo.toml")).map_err(|e| e.to_string())?)?;
+    let arch = package_arch(target, format)?;
+
+    run(
+        Command::new(env::var("CARGO").unwrap_or_else(|_| "cargo".into()))
+            .current_dir(root)
+            .args([
+                "build",
+                "--release",
+                "--package",
+                PACKAGE_NAME,
+                "--target",
+                target,
+            ]),
+    )?;
+
+    let stage = root.join("dist").join("stage").join(target);
+    if stage.exists() {
+        fs::remove_dir_all(&stage).map_err(|e| e.to_string())?;
+    }
+
+    let bin = stage.join("usr/bin").join(PACKAGE_NAME);
+    fs::create_dir_all(bin.parent().unwrap()).map_err(|e| e.to_string())?;
+    fs::copy(
+        root.join("target")
+            .join(target)
+            .join("release")
+            .join(PACKAGE_NAME),
+        &bin,
+    )
+    .map_err(|e| format!("copy binary error: {}", e))?;
+    run(Command::new(strip_command(target)).arg(&bin))?;
+
+    for (src, dest) in FILES {
+        let dest = stage.join(dest);
+        fs::create_dir_all(dest.parent().unwrap()).map_err(|e| e.to_string())?;
+        fs::copy(root.join(src), &dest).map_err(|e| format!("copy {} error: {}", src, e))?;
+    }
+
+    let control_dir = stage.join(match format {
+        Format::Ipk => "CONTROL",
+        Format::Deb => "DEBIAN",
+    });
+    fs::create_dir_all(&control_dir).map_err(|e| e.to_string())?;
+    fs::write(control_dir.join("control"), control_file(&version, arch))
+        .map_err(|e| e.to_string())?;
+    fs::write(control_dir.join("conffiles"), conffiles()).map_err(|e| e.to_string())?;
+
+    let out_dir = root.join("dist");
+    let out = out_dir.join(package_file_name(&version, arch, format));
+    match format {
+        Format::Ipk => run(Command::new("opkg-build")
+            .args(["-o", "0", "-g", "0"])
+            .arg(&stage)
+            .arg(&out_dir))?,
+        Format::Deb => run(Command::new("dpkg-deb")
+            .args(["--root-owner-group", "--build"])
+            .arg(&stage)
+            .arg(&out))?,
+    }
+
+    Ok(out)
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: cargo xtask dist --target <TARGET> [--format ipk|deb]");
+    process::exit(1);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(|v| v.as_str()) != Some("dist") {
+        usage();
+    }
+
+    let mut target: Option<String> = None;
+    let mut format = Format::Ipk;
+    let mut i = 1;
+    while i < args.len() {
+        match (args[i].as_str(), args.get(i + 1)) {
+            ("--target", Some(v)) => target = Some(v.clone()),
+            ("--format", Some(v)) => {
+                format = Format::parse(v).unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    usage();
+                })
+            }
+            _ => usage(),
+        }
+        i += 2;
+    }
+
+    let target = match target {
+        Some(v) => v,
+        None => usage(),
+    };
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).parent().unwrap();
+
+    match dist(root, &target, format) {
+        Ok(v) => println!("Package created: {}", v.display()),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_package_version() {
+        let cargo_toml =
+            "[package]\nname = \"x\"\nversion = \"4.1.1\"\n\n[dependencies]\nversion = \"1\"\n";
+        assert_eq!(package_version(cargo_toml).unwrap(), "4.1.1");
+        assert!(package_version("[dependencies]\nversion = \"1\"\n").is_err());
+    }
+
+    #[test]
+    fn test_package_metadata() {
+        let arch = package_arch("armv5te-unknown-linux-gnueabi", Format::Ipk).unwrap();
+        assert_eq!(
+            package_file_name("4.1.1", arch, Format::Ipk),
+            "chirpstack-udp-forwarder_4.1.1_arm926ejste.ipk"
+        );
+        assert!(package_arch("mips-unknown-linux-gnu", Format::Deb).is_err());
+
+        let control = control_file("4.1.1", "armhf");
+        assert!(control.contains("Version: 4.1.1\n"));
+        assert!(control.contains("Architecture: armhf\n"));
+
+        assert_eq!(
+            conffiles(),
+            "/etc/chirpstack-udp-forwarder/chirpstack-udp-forwarder.toml\n"
+        );
+    }
+}